@@ -0,0 +1,73 @@
+// Tiny C++ project used by `cppembedder --self-test`. The self-test asks for
+// the CRC-32 checksum and expects `crc32` to be the best match.
+
+#include <algorithm>
+#include <cstdint>
+#include <map>
+#include <string>
+#include <vector>
+
+// Computes the CRC-32 checksum of a byte buffer, bit by bit.
+uint32_t crc32(const uint8_t* data, size_t length) {
+  uint32_t crc = 0xFFFFFFFF;
+  for (size_t i = 0; i < length; ++i) {
+    crc ^= data[i];
+    for (int bit = 0; bit < 8; ++bit) {
+      crc = (crc >> 1) ^ (0xEDB88320 & (0 - (crc & 1)));
+    }
+  }
+  return ~crc;
+}
+
+// Parses lines of the form `key = value` into a map, ignoring comments.
+std::map<std::string, std::string> parseConfig(const std::vector<std::string>& lines) {
+  std::map<std::string, std::string> config;
+  for (const auto& line : lines) {
+    if (line.empty() || line[0] == '#') {
+      continue;
+    }
+    auto pos = line.find('=');
+    if (pos == std::string::npos) {
+      continue;
+    }
+    config[line.substr(0, pos)] = line.substr(pos + 1);
+  }
+  return config;
+}
+
+// Computes the Levenshtein edit distance between two strings.
+size_t editDistance(const std::string& a, const std::string& b) {
+  std::vector<size_t> row(b.size() + 1);
+  for (size_t j = 0; j <= b.size(); ++j) {
+    row[j] = j;
+  }
+  for (size_t i = 1; i <= a.size(); ++i) {
+    size_t diagonal = row[0];
+    row[0] = i;
+    for (size_t j = 1; j <= b.size(); ++j) {
+      size_t above = row[j];
+      row[j] = std::min({row[j] + 1, row[j - 1] + 1, diagonal + (a[i - 1] != b[j - 1])});
+      diagonal = above;
+    }
+  }
+  return row[b.size()];
+}
+
+// A queue of tasks ordered by priority, highest first.
+class TaskQueue {
+ public:
+  void push(int priority, std::string name) {
+    tasks_.emplace_back(priority, std::move(name));
+    std::push_heap(tasks_.begin(), tasks_.end());
+  }
+
+  std::string pop() {
+    std::pop_heap(tasks_.begin(), tasks_.end());
+    std::string name = std::move(tasks_.back().second);
+    tasks_.pop_back();
+    return name;
+  }
+
+ private:
+  std::vector<std::pair<int, std::string>> tasks_;
+};
@@ -0,0 +1,239 @@
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::collection::resolve_collection;
+
+/// Command line options selecting the ArangoDB database and collection,
+/// shared by the command line tools
+#[derive(clap::Args, Clone, Debug)]
+pub struct ArangoArgs {
+    /// ArangoDB endpoint URL (e.g. "http://localhost:8529"), or several
+    /// separated by commas, e.g. the coordinators of a cluster
+    #[clap(long)]
+    pub arango_endpoint: String,
+
+    /// ArangoDB username
+    #[clap(long)]
+    pub arango_username: String,
+
+    /// ArangoDB password
+    #[clap(long)]
+    pub arango_password: String,
+
+    /// ArangoDB database name
+    #[clap(long)]
+    pub arango_database: String,
+
+    /// ArangoDB collection name
+    #[clap(long, required_unless_present = "collection_prefix")]
+    pub arango_collection: Option<String>,
+
+    /// Use the collection `<prefix>_<project>_chunks` instead
+    #[clap(long, conflicts_with = "arango_collection")]
+    pub collection_prefix: Option<String>,
+
+    /// Name of the project for `--collection-prefix`, by default the last
+    /// component of the project directory if there is one
+    #[clap(long)]
+    pub project_name: Option<String>,
+}
+
+impl ArangoArgs {
+    pub fn client(&self) -> ArangoClient {
+        ArangoClient::new(
+            self.arango_endpoint.clone(),
+            self.arango_username.clone(),
+            self.arango_password.clone(),
+            self.arango_database.clone(),
+        )
+    }
+
+    /// The collection to use, composed from prefix and project name if no
+    /// collection is given explicitly
+    pub fn collection(&self, default_project: Option<&str>) -> Result<String, Box<dyn Error>> {
+        resolve_collection(
+            self.arango_collection.clone(),
+            self.collection_prefix.as_deref(),
+            self.project_name.as_deref().or(default_project),
+        )
+    }
+}
+
+/// Analyzer splitting names into lowercase words and their prefixes, for
+/// the name search
+pub const NAME_ANALYZER: &str = "cppembedder_name";
+
+/// Key of the document holding metadata about a collection, such as when
+/// it expires, rather than a chunk
+pub const META_KEY: &str = "_meta";
+
+/// Name of the ArangoSearch view over the `name` and `src` fields of a
+/// collection
+pub fn search_view_name(collection: &str) -> String {
+    format!("{}_search", collection)
+}
+
+/// Connection to an ArangoDB database, shared by the command line tools.
+///
+/// The endpoint may be a comma-separated list. Requests go to the endpoint
+/// that answered last and fail over to the next one if it cannot be
+/// connected to.
+pub struct ArangoClient {
+    client: Client,
+    endpoints: Vec<String>,
+    /// Index of the endpoint that answered last
+    current: AtomicUsize,
+    username: String,
+    password: String,
+    database: String,
+}
+
+impl ArangoClient {
+    pub fn new(endpoint: String, username: String, password: String, database: String) -> Self {
+        let endpoints = endpoint
+            .split(',')
+            .map(|endpoint| endpoint.trim().trim_end_matches('/').to_string())
+            .filter(|endpoint| !endpoint.is_empty())
+            .collect();
+        Self {
+            client: Client::new(),
+            endpoints,
+            current: AtomicUsize::new(0),
+            username,
+            password,
+            database,
+        }
+    }
+
+    /// Sends an authenticated request built from the database URL
+    /// (`<endpoint>/_db/<database>`), trying the other endpoints in turn if
+    /// the connection fails
+    pub async fn send<F>(&self, request: F) -> Result<Response, Box<dyn Error>>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+    {
+        let start = self.current.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..self.endpoints.len() {
+            let index = (start + i) % self.endpoints.len();
+            let endpoint = &self.endpoints[index];
+            let database_url = format!("{}/_db/{}", endpoint, self.database);
+            let result = request(&self.client, &database_url)
+                .basic_auth(&self.username, Some(&self.password))
+                .send()
+                .await;
+            match result {
+                Ok(response) => {
+                    self.current.store(index, Ordering::Relaxed);
+                    return Ok(response);
+                }
+                Err(e) if e.is_connect() && self.endpoints.len() > 1 => {
+                    eprintln!(
+                        "Warning: cannot connect to ArangoDB endpoint {}, trying the next one: {}",
+                        endpoint, e
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match last_error {
+            Some(e) => Err(format!("No ArangoDB endpoint is reachable: {}", e).into()),
+            None => Err("No ArangoDB endpoint given".into()),
+        }
+    }
+
+    /// Runs an AQL query and follows the cursor until all batches are fetched
+    pub async fn query(&self, query_body: &Value) -> Result<Vec<Value>, Box<dyn Error>> {
+        let mut response = self
+            .send(|client, url| client.post(format!("{}/_api/cursor", url)).json(query_body))
+            .await?;
+
+        let mut results = Vec::new();
+        loop {
+            if !response.status().is_success() {
+                return Err(format!("ArangoDB query failed: {}", response.text().await?).into());
+            }
+
+            let page: Value = response.json().await?;
+            if let Some(batch) = page["result"].as_array() {
+                results.extend(batch.iter().cloned());
+            }
+
+            if !page["hasMore"].as_bool().unwrap_or(false) {
+                break;
+            }
+            let cursor_id = page["id"]
+                .as_str()
+                .ok_or("ArangoDB cursor has more results but no cursor id")?;
+
+            // Fetch the next batch of the cursor, from the endpoint that
+            // created it as long as it is reachable
+            response = self
+                .send(|client, url| client.put(format!("{}/_api/cursor/{}", url, cursor_id)))
+                .await?;
+        }
+
+        Ok(results)
+    }
+
+    /// Fetches a single document by key, returns `None` if it does not exist
+    pub async fn get_document(
+        &self,
+        collection: &str,
+        key: &str,
+    ) -> Result<Option<Value>, Box<dyn Error>> {
+        let response = self
+            .send(|client, url| client.get(format!("{}/_api/document/{}/{}", url, collection, key)))
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch document: {}", response.text().await?).into());
+        }
+        Ok(Some(response.json().await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arango_mock::{accept_all, MockArango};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn unreachable_endpoints_are_skipped() {
+        // A port nobody listens on any more refuses connections
+        let refusing = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let mock = MockArango::start(accept_all).await;
+        let client = |endpoint: String| {
+            ArangoClient::new(endpoint, "root".into(), String::new(), "test".into())
+        };
+
+        let failover = client(format!("{}, {}/", refusing, mock.endpoint));
+        let query = json!({ "query": "RETURN 1" });
+        assert_eq!(failover.query(&query).await.unwrap(), Vec::<Value>::new());
+        // The endpoint that answered is kept for the next request
+        assert_eq!(failover.current.load(Ordering::Relaxed), 1);
+        failover.query(&query).await.unwrap();
+        assert_eq!(mock.requests().len(), 2);
+
+        let error = client(format!("{},{}", refusing, refusing))
+            .query(&query)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("No ArangoDB endpoint is reachable"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request received by the mock server, with the database prefix
+/// `/_db/<database>` removed from the path
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The body parsed as JSON, null if it is not
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    /// The body parsed as one JSON document per line
+    pub fn ndjson(&self) -> Vec<Value> {
+        String::from_utf8_lossy(&self.body)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+type Handler = dyn Fn(&Request) -> (u16, Value) + Send + Sync;
+
+/// A stand-in for the HTTP API of ArangoDB in tests, listening on a random
+/// local port until the test ends. It records every request and answers it
+/// with a handler, e.g. `accept_all`.
+pub struct MockArango {
+    pub endpoint: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockArango {
+    /// Starts a server answering every request with `handler`
+    pub async fn start(handler: impl Fn(&Request) -> (u16, Value) + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, handler.clone(), recorded.clone()));
+            }
+        });
+        Self { endpoint, requests }
+    }
+
+    /// The requests received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The command line arguments connecting to the server
+    pub fn args(&self, collection: &str) -> Vec<String> {
+        [
+            "--arango-endpoint",
+            &self.endpoint,
+            "--arango-username",
+            "root",
+            "--arango-password",
+            "",
+            "--arango-database",
+            "test",
+            "--arango-collection",
+            collection,
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect()
+    }
+}
+
+/// Answers requests like an empty database accepting everything: all
+/// documents are created, collections have no indexes and queries return
+/// nothing
+pub fn accept_all(request: &Request) -> (u16, Value) {
+    let path = request.path.as_str();
+    if path.starts_with("/_api/document/") && request.method == "POST" {
+        let results: Vec<Value> = match request.json() {
+            Value::Array(documents) => documents
+                .iter()
+                .map(|document| json!({ "_key": document["_key"] }))
+                .collect(),
+            document => vec![json!({ "_key": document["_key"] })],
+        };
+        (202, json!(results))
+    } else if path == "/_api/import" {
+        let created = request.ndjson().len();
+        (
+            201,
+            json!({ "created": created, "errors": 0, "details": [] }),
+        )
+    } else if path == "/_api/cursor" {
+        (201, json!({ "result": [], "hasMore": false }))
+    } else if path == "/_api/index" {
+        (200, json!({ "indexes": [] }))
+    } else {
+        (200, json!({}))
+    }
+}
+
+/// Serves the requests of one connection
+async fn serve(stream: TcpStream, handler: Arc<Handler>, requests: Arc<Mutex<Vec<Request>>>) {
+    let mut stream = BufReader::new(stream);
+    while let Some(request) = read_request(&mut stream).await {
+        requests.lock().unwrap().push(request.clone());
+        let (status, body) = handler(&request);
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if stream
+            .get_mut()
+            .write_all(response.as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Reads the next request of a connection, with a body of fixed length or
+/// in chunked transfer encoding. None once the connection is closed.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut content_length = 0;
+    let mut chunked = false;
+    loop {
+        let mut header = String::new();
+        stream.read_line(&mut header).await.ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.trim().parse().ok()?,
+            "transfer-encoding" => chunked = value.trim().eq_ignore_ascii_case("chunked"),
+            _ => {}
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).await.ok()?;
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            stream.read_exact(&mut chunk).await.ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else {
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body).await.ok()?;
+    }
+
+    let path = target.split('?').next().unwrap_or_default();
+    let path = match path.strip_prefix("/_db/") {
+        Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => path,
+    };
+    Some(Request {
+        method,
+        path: path.to_string(),
+        body,
+    })
+}
@@ -0,0 +1,227 @@
+use serde_json::{json, Value};
+use std::error::Error;
+
+use cppembedder::arango::{ArangoArgs, ArangoClient, META_KEY};
+
+use crate::embedding::{assemble_embed_text, embedding_settings_hash, parse_template};
+use crate::embedding_common::{
+    create_embedder, resolve_model_name, with_instruction, EmbedThreads, AUTO_MODEL,
+};
+use crate::src_codec::document_src;
+
+/// Arguments of the `backfill` command
+#[derive(clap::Args, Debug)]
+pub struct BackfillArgs {
+    #[clap(flatten)]
+    arango: ArangoArgs,
+
+    /// Name of the embedding model to use, must be the one the other
+    /// documents of the collection were embedded with
+    #[clap(short, long, default_value = AUTO_MODEL)]
+    embedding_model: String,
+
+    /// Number of tokens after which sources are truncated before embedding,
+    /// overriding the model default (up to the model's maximum)
+    #[clap(long)]
+    tokenizer_truncation_length: Option<usize>,
+
+    /// Threads of ONNX Runtime computing the embeddings, as INTRA or
+    /// INTRA,INTER. Defaults to one intra-op thread per core.
+    #[clap(long, default_value_t = EmbedThreads::default())]
+    embed_threads: EmbedThreads,
+
+    /// Task instruction prepended to every source before embedding it
+    #[clap(long, default_value = "")]
+    embed_instruction: String,
+
+    /// Template of the text embedded for each document, as in `embed`. The
+    /// fields are filled from the stored document; those it does not keep,
+    /// like the comment, render empty.
+    #[clap(long)]
+    embed_template: Option<String>,
+
+    /// Prepend the source path of each document to the embedded text, as
+    /// in `embed`
+    #[clap(long)]
+    embed_path: bool,
+
+    /// Leave documents with fewer non-blank characters than this without
+    /// vector
+    #[clap(long, default_value_t = 10)]
+    min_chunk_chars: usize,
+
+    /// Number of documents fetched, embedded and patched at once
+    #[clap(long, default_value_t = 100)]
+    batch_size: usize,
+}
+
+/// Computes the vectors of documents imported without one (e.g. with
+/// --allow-missing-vectors) from their stored source and patches them into
+/// the existing documents, keeping their keys
+pub async fn run(args: BackfillArgs) -> Result<(), Box<dyn Error>> {
+    let collection = args.arango.collection(None)?;
+    let template = args
+        .embed_template
+        .as_deref()
+        .map(parse_template)
+        .transpose()?;
+    let client = args.arango.client();
+
+    let keys: Vec<String> = client
+        .query(&json!({
+            "query": "FOR doc IN @@chunks FILTER doc.v == null AND doc._key != @meta RETURN doc._key",
+            "bindVars": { "@chunks": collection, "meta": META_KEY },
+        }))
+        .await?
+        .iter()
+        .filter_map(|key| key.as_str().map(str::to_string))
+        .collect();
+    if keys.is_empty() {
+        println!("All documents in {} have a vector", collection);
+        return Ok(());
+    }
+
+    let model = create_embedder(
+        &args.embedding_model,
+        args.tokenizer_truncation_length,
+        args.embed_threads,
+    )?;
+    let model_name = resolve_model_name(&args.embedding_model);
+    let instruction = (!args.embed_instruction.is_empty()).then_some(&args.embed_instruction);
+    let embed_hash = embedding_settings_hash(&json!({
+        "model": model_name,
+        "instruction": instruction,
+        "template": template,
+        "path_prefix": args.embed_path.then_some(true),
+    }));
+    let mut backfilled = 0;
+    let mut skipped = 0;
+    let mut truncated = 0;
+    for batch in keys.chunks(args.batch_size.max(1)) {
+        let docs = client
+            .query(&json!({
+                "query": "FOR doc IN @@chunks FILTER doc._key IN @keys \
+                          RETURN KEEP(doc, '_key', 'name', 'chunk_file', 'src', 'src_encoding', \
+                                      'src_truncated', 'source_file', 'source_path')",
+                "bindVars": { "@chunks": collection, "keys": batch },
+            }))
+            .await?;
+
+        let mut batch_keys = Vec::new();
+        let mut texts = Vec::new();
+        for doc in &docs {
+            // Only the start of the source is stored, its vector would not
+            // match those of the other documents
+            if doc["src_truncated"].as_bool() == Some(true) {
+                truncated += 1;
+                continue;
+            }
+            let src = document_src(doc)?;
+            let trimmed_len = src.trim().chars().count();
+            if trimmed_len == 0 || trimmed_len < args.min_chunk_chars {
+                skipped += 1;
+                continue;
+            }
+            batch_keys.push(doc["_key"].as_str().unwrap_or_default().to_string());
+            let text = assemble_embed_text(
+                template.as_deref(),
+                args.embed_path,
+                doc["chunk_file"].as_str().unwrap_or_default(),
+                &src,
+                None,
+                &document_index(doc),
+            );
+            texts.push(with_instruction(&args.embed_instruction, &text));
+        }
+        if texts.is_empty() {
+            continue;
+        }
+
+        let vectors = model.embed(texts, None)?;
+        let patches: Vec<Value> = batch_keys
+            .iter()
+            .zip(vectors)
+            .map(|(key, v)| {
+                let mut patch = json!({
+                    "_key": key,
+                    "v": v,
+                    "model": model_name,
+                    "embed_hash": embed_hash,
+                });
+                if let Some(instruction) = instruction {
+                    patch["instruction"] = json!(instruction);
+                }
+                patch
+            })
+            .collect();
+        patch_documents(&client, &collection, &patches).await?;
+        backfilled += patches.len();
+        println!("Backfilled {}/{} documents", backfilled, keys.len());
+    }
+
+    if truncated > 0 {
+        eprintln!(
+            "Warning: left {} documents without vector whose source was stored truncated \
+             (--max-src-chars), embed and import them again instead",
+            truncated
+        );
+    }
+    println!(
+        "Backfilled vectors of {} documents in {}, skipped {} too small ones",
+        backfilled, collection, skipped
+    );
+    Ok(())
+}
+
+/// The `_index.txt` entry of a stored document, with the metadata the
+/// embed template and path prefix take from it
+fn document_index(doc: &Value) -> String {
+    let field = |name: &str| doc[name].as_str().unwrap_or_default();
+    format!(
+        "Source file: {}\nSource path: {}\nChunk: {}\n  Name: {}\n",
+        field("source_file"),
+        field("source_path"),
+        field("chunk_file"),
+        field("name")
+    )
+}
+
+/// Patches a batch of documents, identified by their `_key`, with one
+/// request, failing if any of them could not be updated
+async fn patch_documents(
+    client: &ArangoClient,
+    collection: &str,
+    patches: &[Value],
+) -> Result<(), Box<dyn Error>> {
+    let response = client
+        .send(|client, url| {
+            client
+                .patch(format!("{}/_api/document/{}", url, collection))
+                .json(patches)
+        })
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to patch documents: {}", response.text().await?).into());
+    }
+
+    // The batch as a whole succeeds even if single documents fail
+    let results: Value = response.json().await?;
+    let errors: Vec<&Value> = results
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .filter(|result| result["error"].as_bool() == Some(true))
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(first) = errors.first() {
+        return Err(format!(
+            "Failed to patch {} documents of batch, first error: {}",
+            errors.len(),
+            first["errorMessage"].as_str().unwrap_or("unknown error")
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use clap::Parser;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{self, Write};
+
+use cppembedder::arango::ArangoArgs;
+use cppembedder::src_codec::document_src;
+
+/// Program to print a single stored chunk with its metadata and source
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Args {
+    /// Document key of the chunk
+    #[clap(long, conflicts_with = "name", required_unless_present = "name")]
+    key: Option<String>,
+
+    /// Qualified symbol name of the chunk (prints all chunks with this name)
+    #[clap(long)]
+    name: Option<String>,
+
+    #[clap(flatten)]
+    arango: ArangoArgs,
+}
+
+/// Writes the metadata fields of a chunk, only the dimension of its vector,
+/// followed by its source
+fn print_document(doc: &Value, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    if let Some(fields) = doc.as_object() {
+        for (field, value) in fields {
+            match field.as_str() {
+                "src" | "src_encoding" => {}
+                "v" => writeln!(
+                    out,
+                    "v: <{} dimensions>",
+                    value.as_array().map_or(0, |v| v.len())
+                )?,
+                _ => writeln!(out, "{}: {}", field, value)?,
+            }
+        }
+    }
+    writeln!(out, "---------------")?;
+    writeln!(out, "{}", document_src(doc)?)?;
+    writeln!(out, "---------------")?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let collection = args.arango.collection(None)?;
+    let client = args.arango.client();
+
+    // Fetch the document(s) by key or by name
+    let documents = if let Some(key) = &args.key {
+        client
+            .get_document(&collection, key)
+            .await?
+            .into_iter()
+            .collect()
+    } else {
+        let query_body = json!({
+            "query": "FOR doc IN @@chunks FILTER doc.name == @name RETURN doc",
+            "bindVars": {
+                "@chunks": collection,
+                "name": args.name
+            }
+        });
+        client.query(&query_body).await?
+    };
+
+    if documents.is_empty() {
+        println!("No chunk found");
+        return Ok(());
+    }
+
+    for doc in &documents {
+        print_document(doc, &mut io::stdout())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cppembedder::src_codec::{compress_src, GZIP_ENCODING};
+
+    #[test]
+    fn documents_print_their_fields_and_source() {
+        let src = "int add(int a, int b) {\n    return a + b;\n}";
+        let doc = json!({
+            "_key": "add",
+            "name": "add",
+            "source_file": "calc.cpp",
+            "src": compress_src(src).unwrap(),
+            "src_encoding": GZIP_ENCODING,
+            "v": [0.1, 0.2, 0.3]
+        });
+        let mut out = Vec::new();
+        print_document(&doc, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = [
+            r#"_key: "add""#,
+            r#"name: "add""#,
+            r#"source_file: "calc.cpp""#,
+            "v: <3 dimensions>",
+            "---------------",
+            "int add(int a, int b) {",
+            "    return a + b;",
+            "}",
+            "---------------",
+        ];
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+}
@@ -1,12 +1,22 @@
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Read, Write};
+use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
+use crate::fsutil::write_atomic;
+use crate::heuristic;
+use crate::lsp::{LspClient, LspTransport, DEFAULT_MAX_MESSAGE_BYTES};
+use crate::lsp_log::{LspLog, LspLogFormat};
+use crate::metrics::Metrics;
+use crate::progress::{JsonProgress, ProgressMode};
+
 /// Represents a code chunk extracted from a source file
 #[derive(Debug)]
 pub struct CodeChunk {
@@ -14,8 +24,14 @@ pub struct CodeChunk {
     pub content: String,
     pub start_line: usize,
     pub end_line: usize,
-    pub kind: String,           // "function", "class", "method", etc.
-    pub parent: Option<String>, // For methods, this would be the class name
+    pub kind: String,                          // "function", "class", "method", etc.
+    pub parent: Option<String>,                // For methods, this would be the class name
+    pub comment: Option<String>,               // The comment block directly above the symbol
+    pub deprecated: bool,                      // Tagged deprecated by clangd
+    pub name_position: Option<(usize, usize)>, // Line and character of the symbol's name
+    pub signature: Option<String>,             // The declaration before the body, on one line
+    pub context: Option<(usize, usize)>,       // Lines of the content if context was added
+    pub has_body: bool,                        // A definition rather than a declaration
 }
 
 /// Represents the LSP document symbol response structure
@@ -24,8 +40,23 @@ struct Symbol {
     name: String,
     kind: u8,
     range: Range,
+    /// The range of the symbol's name
+    #[serde(rename = "selectionRange")]
+    selection_range: Option<Range>,
     #[serde(default)]
     children: Vec<Symbol>,
+    /// Symbol tags, see `SYMBOL_TAG_DEPRECATED`
+    #[serde(default)]
+    tags: Vec<u8>,
+    /// Older, deprecated way of marking a symbol deprecated
+    #[serde(default)]
+    deprecated: bool,
+}
+
+impl Symbol {
+    fn is_deprecated(&self) -> bool {
+        self.deprecated || self.tags.contains(&SYMBOL_TAG_DEPRECATED)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -37,8 +68,7 @@ struct Range {
 #[derive(Debug, Deserialize)]
 struct Position {
     line: usize,
-    #[serde(rename = "character")]
-    _character: usize,
+    character: usize,
 }
 
 // LSP SymbolKind values (subset)
@@ -46,534 +76,3683 @@ const SYMBOL_KIND_NAMESPACE: u8 = 3;
 const SYMBOL_KIND_CLASS: u8 = 5;
 const SYMBOL_KIND_METHOD: u8 = 6;
 const SYMBOL_KIND_FUNCTION: u8 = 12;
+const SYMBOL_KIND_VARIABLE: u8 = 13;
+const SYMBOL_KIND_CONSTANT: u8 = 14;
+
+// LSP SymbolTag values
+const SYMBOL_TAG_DEPRECATED: u8 = 1;
 
 pub struct Chunker {
     project_dir: String,
     output_dir: String,
     clangd_path: String,
+    git_path: String,
     lsp_log_file: String,
+    options: ChunkerOptions,
 }
 
-fn sanitize_name(s: &str) -> String {
-    let mut r = s
-        .replace("::", "_doublecolon_")
-        .replace("<", "_less_")
-        .replace(">", "_greater_")
-        .replace("/", "_slash_");
-    r.truncate(200);
-    r
+/// Builds a `Chunker` with named setters. The project and output
+/// directories are required, clangd is taken from the `PATH` and the LSP
+/// log is written to `lsp_communication.log` unless set otherwise.
+#[derive(Debug, Default)]
+pub struct ChunkerBuilder {
+    project_dir: Option<String>,
+    output_dir: Option<String>,
+    clangd_path: Option<String>,
+    lsp_log_file: Option<String>,
+    options: ChunkerOptions,
 }
 
-impl Chunker {
-    pub fn new(
-        project_dir: String,
-        output_dir: String,
-        clangd_path: String,
-        lsp_log_file: String,
-    ) -> Self {
-        Self {
-            project_dir,
-            output_dir,
-            clangd_path,
-            lsp_log_file,
-        }
+impl ChunkerBuilder {
+    pub fn project_dir(mut self, project_dir: impl Into<String>) -> Self {
+        self.project_dir = Some(project_dir.into());
+        self
     }
 
-    fn find_cpp_source_files(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
-        let mut cpp_files = Vec::new();
-
-        for entry in WalkDir::new(&self.project_dir) {
-            let entry = entry.map_err(|e| {
-                format!(
-                    "Failed to read directory entry in '{}': {}",
-                    self.project_dir, e
-                )
-            })?;
-            let path = entry.path();
-
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    let ext = extension.to_string_lossy().to_lowercase();
-                    if ext == "cpp"
-                        || ext == "cxx"
-                        || ext == "cc"
-                        || ext == "h"
-                        || ext == "hpp"
-                        || ext == "hxx"
-                    {
-                        cpp_files.push(path.to_path_buf());
-                    }
-                }
-            }
-        }
-
-        Ok(cpp_files)
+    pub fn output_dir(mut self, output_dir: impl Into<String>) -> Self {
+        self.output_dir = Some(output_dir.into());
+        self
     }
 
-    fn extract_chunks(
-        &self,
-        _file_path: &Path,
-        file_content: &str,
-        symbols: &[Symbol],
-    ) -> Result<Vec<CodeChunk>, Box<dyn Error>> {
-        let mut chunks = Vec::new();
-        let lines: Vec<&str> = file_content.lines().collect();
-
-        // Helper function to process symbols recursively
-        fn process_symbols(
-            symbols: &[Symbol],
-            lines: &[&str],
-            chunks: &mut Vec<CodeChunk>,
-            parent: Option<&str>,
-        ) {
-            for symbol in symbols {
-                let kind = match symbol.kind {
-                    SYMBOL_KIND_FUNCTION => "function",
-                    SYMBOL_KIND_METHOD => "method",
-                    SYMBOL_KIND_CLASS => "class",
-                    SYMBOL_KIND_NAMESPACE => "namespace",
-                    _ => continue, // Skip other symbols like variables
-                };
+    pub fn clangd_path(mut self, clangd_path: impl Into<String>) -> Self {
+        self.clangd_path = Some(clangd_path.into());
+        self
+    }
 
-                let start_line = symbol.range.start.line;
-                let end_line = symbol.range.end.line;
+    pub fn lsp_log_file(mut self, lsp_log_file: impl Into<String>) -> Self {
+        self.lsp_log_file = Some(lsp_log_file.into());
+        self
+    }
 
-                // Skip if the range is invalid or too small
-                if start_line >= end_line || end_line >= lines.len() {
-                    continue;
-                }
+    pub fn options(mut self, options: ChunkerOptions) -> Self {
+        self.options = options;
+        self
+    }
 
-                // Extract the content of the chunk
-                let content = lines[start_line..=end_line].join("\n");
+    /// Checks that the required settings are given and not empty
+    pub fn build(self) -> Result<Chunker, Box<dyn Error>> {
+        let project_dir = self
+            .project_dir
+            .filter(|dir| !dir.is_empty())
+            .ok_or("Chunker needs a project directory")?;
+        let output_dir = self
+            .output_dir
+            .filter(|dir| !dir.is_empty())
+            .ok_or("Chunker needs an output directory")?;
+        Ok(Chunker::new(
+            project_dir,
+            output_dir,
+            self.clangd_path.unwrap_or_else(|| "clangd".to_string()),
+            "git".to_string(),
+            self.lsp_log_file
+                .unwrap_or_else(|| "lsp_communication.log".to_string()),
+            self.options,
+        ))
+    }
+}
 
-                // Create a unique name for the chunk
-                let chunk_name = if let Some(parent_name) = parent {
-                    format!("{}::{}", parent_name, symbol.name)
-                } else {
-                    symbol.name.clone()
-                };
+/// Optional settings for the chunker
+#[derive(Clone, Debug, Default)]
+pub struct ChunkerOptions {
+    /// Only process the first N source files
+    pub limit_files: Option<usize>,
+    /// Read the list of source files from this file ("-" for stdin)
+    pub files_from: Option<String>,
+    /// Skip files that look like tests
+    pub exclude_tests: bool,
+    /// Only chunk the public headers below this directory of the project
+    pub only_public_headers: Option<PathBuf>,
+    /// Record the HEAD commit and the git blob SHA of each source file
+    pub record_git: bool,
+    /// Format of the LSP communication log
+    pub lsp_log_format: LspLogFormat,
+    /// Size in bytes after which the LSP log is rotated
+    pub lsp_log_max_size: Option<u64>,
+    /// Largest response accepted from clangd, `DEFAULT_MAX_MESSAGE_BYTES`
+    /// if not given
+    pub lsp_max_response_bytes: Option<usize>,
+    /// Framing of the LSP messages, Content-Length headers for clangd
+    pub lsp_transport: LspTransport,
+    /// Do not descend into symbols nested deeper than this,
+    /// `DEFAULT_MAX_SYMBOL_DEPTH` if not given
+    pub max_symbol_depth: Option<usize>,
+    /// Print the first N chunks of every file
+    pub preview: Option<usize>,
+    /// Additional arguments passed to clangd, in order
+    pub clangd_args: Vec<String>,
+    /// Take the source files from the compilation database
+    pub use_compile_db: bool,
+    /// Keep at most this many chunks per file, the most significant ones
+    pub max_chunks_per_file: Option<usize>,
+    /// How to report the progress, `Off` also silences the per-file lines
+    pub progress: ProgressMode,
+    /// Counters of the run, updated after every file
+    pub metrics: Arc<Metrics>,
+    /// Write the comment block above each symbol to a `.comment` file next
+    /// to its chunk, to be embedded separately
+    pub extract_comments: bool,
+    /// Wait this long for clangd to publish the diagnostics of a file, i.e.
+    /// to finish parsing it, before requesting its symbols
+    pub diagnostics_timeout: Option<Duration>,
+    /// Wait this long for clangd's background index to be built before
+    /// chunking the first file
+    pub index_timeout: Option<Duration>,
+    /// Fail once more than this percentage of the files has no compile
+    /// command
+    pub max_no_compile_command_percent: Option<f64>,
+    /// Ask clangd for the outgoing calls of every function and write the
+    /// caller/callee pairs to `relations.jsonl`
+    pub record_calls: bool,
+    /// Find the chunks with a brace matching heuristic instead of clangd
+    pub no_clangd: bool,
+    /// Re-read the written chunks and check them against the index
+    pub validate_chunks: bool,
+    /// Do not report empty chunks when validating
+    pub allow_empty_chunks: bool,
+    /// Skip class members that are not public, going by the access labels
+    pub public_only: bool,
+    /// Also chunk variables and constants at file or namespace scope
+    pub include_globals: bool,
+    /// Cross-reference the declaration and the definition of each function
+    /// in the indexes once all files are written
+    pub link_decl_def: bool,
+    /// Include up to this many lines before and after each symbol in its
+    /// chunk, stopping at other symbols
+    pub context_lines: usize,
+}
 
-                chunks.push(CodeChunk {
-                    name: chunk_name.clone(),
-                    content,
-                    start_line,
-                    end_line,
-                    kind: kind.to_string(),
-                    parent: parent.map(|s| s.to_string()),
-                });
+/// Directory of the compilation database clangd is pointed to
+const COMPILE_COMMANDS_DIR: &str = "build";
 
-                // Process child symbols (like methods within a class)
-                process_symbols(&symbol.children, lines, chunks, Some(&chunk_name));
-            }
-        }
+/// An entry of `compile_commands.json`, as far as we need it
+#[derive(Debug, Deserialize)]
+struct CompileCommand {
+    directory: String,
+    file: String,
+}
 
-        process_symbols(symbols, &lines, &mut chunks, None);
-        Ok(chunks)
-    }
+/// A chunk as the chunker lays it out in the output directory
+#[derive(Debug)]
+pub struct ChunkFile {
+    pub path: PathBuf,
+    pub content: String,
+    /// The comment above the symbol, if comments are extracted
+    pub comment: Option<String>,
+}
 
-    fn write_chunks(&self, source_file: &Path, chunks: &[CodeChunk]) -> Result<(), Box<dyn Error>> {
-        // Create a directory for this file's chunks
-        let file_stem = source_file
-            .file_stem()
-            .unwrap_or_default()
-            .to_string_lossy();
-        let file_chunks_dir = PathBuf::from(&self.output_dir).join(file_stem.to_string());
-        fs::create_dir_all(&file_chunks_dir).map_err(|e| {
-            format!(
-                "Failed to create chunks directory '{}': {}",
-                file_chunks_dir.display(),
-                e
-            )
-        })?;
+/// The chunks of one source file and their `_index.txt` metadata
+#[derive(Debug)]
+pub struct ChunkFiles {
+    pub source_file: PathBuf,
+    /// Directory of the chunk files in the output directory
+    pub dir: PathBuf,
+    /// Content of the `_index.txt` file
+    pub index: String,
+    pub files: Vec<ChunkFile>,
+}
 
-        // Write index file with metadata about all chunks
-        let mut index = File::create(file_chunks_dir.join("_index.txt")).map_err(|e| {
+/// Writes the chunk files, their comment files and the index of a source
+/// file. Each file is written to a temporary file and renamed once complete.
+fn write_chunk_files(files: &ChunkFiles) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&files.dir).map_err(|e| {
+        format!(
+            "Failed to create chunks directory '{}': {}",
+            files.dir.display(),
+            e
+        )
+    })?;
+    for file in &files.files {
+        write_atomic(&file.path, &file.content).map_err(|e| {
             format!(
-                "Failed to create index file in '{}': {}",
-                file_chunks_dir.display(),
+                "Failed to write chunk file '{}': {}",
+                file.path.display(),
                 e
             )
         })?;
-
-        writeln!(index, "Source file: {}", source_file.display())
-            .map_err(|e| format!("Failed to write to index file: {}", e))?;
-        writeln!(index, "Number of chunks: {}", chunks.len())
-            .map_err(|e| format!("Failed to write to index file: {}", e))?;
-        writeln!(index, "---").map_err(|e| format!("Failed to write to index file: {}", e))?;
-
-        // Write each chunk to a separate file
-        for (i, chunk) in chunks.iter().enumerate() {
-            let sanitized_name = sanitize_name(&chunk.name);
-            let chunk_filename = format!(
-                "{:03}_{}_{}_{}.cpp",
-                i + 1,
-                sanitized_name,
-                chunk.kind,
-                chunk.start_line + 1
-            );
-
-            let chunk_path = file_chunks_dir.join(chunk_filename.clone());
-            fs::write(&chunk_path, &chunk.content).map_err(|e| {
+        if let Some(comment) = &file.comment {
+            let comment_path = file.path.with_extension("comment");
+            write_atomic(&comment_path, comment).map_err(|e| {
                 format!(
-                    "Failed to write chunk file '{}': {}",
-                    chunk_path.display(),
+                    "Failed to write comment file '{}': {}",
+                    comment_path.display(),
                     e
                 )
             })?;
+        }
+    }
 
-            // Add to index
-            writeln!(index, "Chunk: {}", chunk_filename)
-                .map_err(|e| format!("Failed to write to index file: {}", e))?;
-            writeln!(index, "  Name: {}", chunk.name)
-                .map_err(|e| format!("Failed to write to index file: {}", e))?;
-            writeln!(index, "  Kind: {}", chunk.kind)
-                .map_err(|e| format!("Failed to write to index file: {}", e))?;
-            writeln!(
-                index,
-                "  Lines: {}-{}",
-                chunk.start_line + 1,
-                chunk.end_line + 1
-            )
-            .map_err(|e| format!("Failed to write to index file: {}", e))?;
-            if let Some(parent) = &chunk.parent {
-                writeln!(index, "  Parent: {}", parent)
-                    .map_err(|e| format!("Failed to write to index file: {}", e))?;
+    // The index is written last, so a complete index means complete chunks
+    let index_path = files.dir.join("_index.txt");
+    write_atomic(&index_path, &files.index).map_err(|e| {
+        format!(
+            "Failed to write index file '{}': {}",
+            index_path.display(),
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// Checks every chunk listed in the `_index.txt` of a chunk directory: its
+/// file must be valid UTF-8, non-empty unless allowed, and have as many
+/// lines as the recorded line span. Adds a message per problem and returns
+/// the number of checked chunks.
+fn validate_chunk_dir(
+    dir: &Path,
+    allow_empty: bool,
+    problems: &mut Vec<String>,
+) -> Result<usize, Box<dyn Error>> {
+    let index_path = dir.join("_index.txt");
+    let index = fs::read_to_string(&index_path).map_err(|e| {
+        format!(
+            "Failed to read index file '{}': {}",
+            index_path.display(),
+            e
+        )
+    })?;
+
+    let mut checked = 0;
+    let mut current_chunk = None;
+    let mut context = None;
+    for line in index.lines() {
+        if let Some(chunk_file) = line.strip_prefix("Chunk: ") {
+            current_chunk = Some(dir.join(chunk_file.trim()));
+            context = None;
+            continue;
+        }
+        // A chunk with context spans the context lines instead of its symbol
+        if let Some(span) = line.strip_prefix("  Context: ") {
+            context = Some(span);
+            continue;
+        }
+        let (Some(chunk_path), Some(span)) = (
+            &current_chunk,
+            line.strip_prefix("  Lines: ")
+                .map(|span| context.unwrap_or(span)),
+        ) else {
+            continue;
+        };
+        checked += 1;
+        let Some((start, end)) = span
+            .split_once('-')
+            .and_then(|(start, end)| {
+                Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+            })
+            .filter(|(start, end)| start <= end)
+        else {
+            problems.push(format!(
+                "'{}' has an invalid line span '{}' in the index",
+                chunk_path.display(),
+                span
+            ));
+            continue;
+        };
+        let bytes = match fs::read(chunk_path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                problems.push(format!("'{}' cannot be read: {}", chunk_path.display(), e));
+                continue;
             }
-            writeln!(index, "---").map_err(|e| format!("Failed to write to index file: {}", e))?;
+        };
+        let Ok(content) = String::from_utf8(bytes) else {
+            problems.push(format!("'{}' is not valid UTF-8", chunk_path.display()));
+            continue;
+        };
+        if content.trim().is_empty() && !allow_empty {
+            problems.push(format!("'{}' is empty", chunk_path.display()));
+            continue;
+        }
+        // Chunks are the lines of their span joined without a final newline
+        let lines = content.split('\n').count();
+        if lines != end - start + 1 {
+            problems.push(format!(
+                "'{}' has {} lines but spans lines {}-{} ({} lines)",
+                chunk_path.display(),
+                lines,
+                start,
+                end,
+                end - start + 1
+            ));
         }
-
-        println!(
-            "Wrote {} chunks for {}",
-            chunks.len(),
-            source_file.display()
-        );
-        Ok(())
     }
+    Ok(checked)
+}
 
-    pub fn run(&self) -> Result<(), Box<dyn Error>> {
-        // Create output directory if it doesn't exist
-        fs::create_dir_all(&self.output_dir).map_err(|e| {
-            format!(
-                "Failed to create output directory '{}': {}",
-                self.output_dir, e
-            )
-        })?;
+/// A function or method chunk of a written index, for linking declarations
+/// to definitions
+struct LinkCandidate {
+    dir: PathBuf,
+    chunk_file: String,
+    /// Path of the chunk relative to the output directory
+    relative_path: String,
+}
 
-        // Open LSP log file
-        let mut _lsp_log = File::create(&self.lsp_log_file).map_err(|e| {
+/// Links the declaration of every function and method to its definition by
+/// qualified name, adding `Declaration of:` and `Definition of:` entries
+/// with the relative chunk path of the other side to the indexes. Chunks
+/// marked `Has body` in the index count as definitions, the others as
+/// declarations. Names
+/// with more than one declaration or definition, e.g. overloads, are left
+/// out since they cannot be told apart by name. Returns the number of links.
+fn link_declarations(output_dir: &Path, dirs: &[PathBuf]) -> Result<usize, Box<dyn Error>> {
+    let mut candidates: HashMap<String, (Vec<LinkCandidate>, Vec<LinkCandidate>)> = HashMap::new();
+    for dir in dirs {
+        let index_path = dir.join("_index.txt");
+        let index = fs::read_to_string(&index_path).map_err(|e| {
             format!(
-                "Failed to create LSP log file '{}': {}",
-                self.lsp_log_file, e
+                "Failed to read index file '{}': {}",
+                index_path.display(),
+                e
             )
         })?;
+        let mut current: Option<(&str, Option<&str>)> = None;
+        // Function and method chunks by file name, with whether they have a
+        // body
+        let mut entries: Vec<(&str, &str, bool)> = Vec::new();
+        for line in index.lines() {
+            if let Some(chunk_file) = line.strip_prefix("Chunk: ") {
+                current = Some((chunk_file.trim(), None));
+            } else if let (Some((_, name)), Some(value)) =
+                (current.as_mut(), line.strip_prefix("  Name: "))
+            {
+                *name = Some(value);
+            } else if let (Some((chunk_file, Some(name))), Some(kind)) =
+                (current, line.strip_prefix("  Kind: "))
+            {
+                if kind == "function" || kind == "method" {
+                    entries.push((chunk_file, name, false));
+                }
+            } else if line == "  Has body: true" {
+                if let (Some((chunk_file, _)), Some(entry)) = (current, entries.last_mut()) {
+                    if entry.0 == chunk_file {
+                        entry.2 = true;
+                    }
+                }
+            }
+        }
+        for (chunk_file, name, has_body) in entries {
+            let path = dir.join(chunk_file);
+            let candidate = LinkCandidate {
+                dir: dir.clone(),
+                chunk_file: chunk_file.to_string(),
+                relative_path: path
+                    .strip_prefix(output_dir)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let (declarations, definitions) = candidates.entry(name.to_string()).or_default();
+            if has_body {
+                definitions.push(candidate);
+            } else {
+                declarations.push(candidate);
+            }
+        }
+    }
 
-        // Find all C++ source files in the project
-        let source_files = self.find_cpp_source_files().map_err(|e| {
+    // The lines to add per index, by chunk file
+    let mut additions: HashMap<PathBuf, HashMap<String, String>> = HashMap::new();
+    let mut links = 0;
+    for (declarations, definitions) in candidates.values() {
+        let ([declaration], [definition]) = (declarations.as_slice(), definitions.as_slice())
+        else {
+            continue;
+        };
+        additions
+            .entry(declaration.dir.clone())
+            .or_default()
+            .insert(
+                declaration.chunk_file.clone(),
+                format!("  Declaration of: {}\n", definition.relative_path),
+            );
+        additions.entry(definition.dir.clone()).or_default().insert(
+            definition.chunk_file.clone(),
+            format!("  Definition of: {}\n", declaration.relative_path),
+        );
+        links += 1;
+    }
+
+    // Add the links as the last lines of their chunk entries
+    for (dir, lines) in additions {
+        let index_path = dir.join("_index.txt");
+        let index = fs::read_to_string(&index_path)?;
+        let mut linked = String::with_capacity(index.len());
+        let mut current_chunk = None;
+        for line in index.lines() {
+            if let Some(chunk_file) = line.strip_prefix("Chunk: ") {
+                current_chunk = Some(chunk_file.trim());
+            } else if line == "---" {
+                if let Some(link) = current_chunk.take().and_then(|chunk| lines.get(chunk)) {
+                    linked.push_str(link);
+                }
+            }
+            linked.push_str(line);
+            linked.push('\n');
+        }
+        write_atomic(&index_path, &linked).map_err(|e| {
             format!(
-                "Failed to scan project directory '{}': {}",
-                self.project_dir, e
+                "Failed to write index file '{}': {}",
+                index_path.display(),
+                e
             )
         })?;
-        println!("Found {} C++ source files", source_files.len());
+    }
+    Ok(links)
+}
 
-        // Start clangd process
-        let mut clangd = Command::new(&self.clangd_path)
-            .arg("--compile-commands-dir=build")
-            .arg("--log=verbose")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|e| {
-                format!(
-                    "Failed to start clangd process at '{}': {}",
-                    self.clangd_path, e
-                )
-            })?;
+/// Outcome of chunking one file
+#[derive(Debug)]
+struct FileStats {
+    /// Number of chunks written
+    chunks: usize,
+    /// Whether chunks were dropped because of the per-file limit
+    truncated: bool,
+    /// Number of chunks dropped because an earlier file produced them
+    duplicates: usize,
+    /// Outgoing calls of the file's functions, if recorded
+    calls: Vec<Call>,
+    /// Whether clangd compiled the file with fallback flags because the
+    /// compilation database has no command for it
+    no_compile_command: bool,
+}
 
-        let mut clangd_stdin = clangd.stdin.take().expect("Failed to open clangd stdin");
-        let mut clangd_stdout =
-            BufReader::new(clangd.stdout.take().expect("Failed to open clangd stdout"));
-
-        // Send LSP initialization request
-        let initialize_request = json!({
-            "jsonrpc": "2.0",
-            "id": 1,
-            "method": "initialize",
-            "params": {
-                "processId": std::process::id(),
-                "rootUri": format!("file://{}", fs::canonicalize(&self.project_dir)
-                    .map_err(|e| format!("Failed to canonicalize project path '{}': {}", self.project_dir, e))?
-                    .to_string_lossy()),
-                "capabilities": {
-                    "textDocument": {
-                        "documentSymbol": {
-                            "hierarchicalDocumentSymbolSupport": true
-                        }
+/// Coverage report reason of files without a compile command
+const NO_COMPILE_COMMAND: &str = "no-compile-command";
+
+/// What clangd says about a file without compile command: that it found
+/// no compilation database for the file, and that it builds the file with
+/// the command it calls "clangd fallback"
+const FALLBACK_FLAGS_MESSAGES: &[&str] = &[
+    "Failed to find compilation database for",
+    "with command clangd fallback",
+];
+
+/// Checks whether a notification from clangd says that the file with the
+/// given URI and path is compiled with fallback flags. Messages of the
+/// window have to name the file, diagnostics have to be published for it,
+/// and either has to be one of `FALLBACK_FLAGS_MESSAGES`.
+fn is_fallback_flags_notice(notification: &Value, uri: &str, path: &Path) -> bool {
+    let params = &notification["params"];
+    let path = path.to_string_lossy();
+    let messages: Vec<&str> = match notification["method"].as_str() {
+        Some("window/showMessage") | Some("window/logMessage") => params["message"]
+            .as_str()
+            .filter(|message| message.contains(path.as_ref()) || message.contains(uri))
+            .into_iter()
+            .collect(),
+        Some("textDocument/publishDiagnostics") if params["uri"].as_str() == Some(uri) => params
+            ["diagnostics"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|diagnostic| diagnostic["message"].as_str())
+            .collect(),
+        _ => Vec::new(),
+    };
+    messages.iter().any(|message| {
+        FALLBACK_FLAGS_MESSAGES
+            .iter()
+            .any(|fallback| message.contains(fallback))
+    })
+}
+
+/// Token of the work done progress clangd reports its background index with
+const INDEX_PROGRESS_TOKEN: &str = "backgroundIndexProgress";
+
+/// How long clangd may take to start indexing after a file is opened. If it
+/// has no compilation database it never starts.
+const INDEX_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far clangd got with its background index
+#[derive(Debug, PartialEq, Eq)]
+enum IndexProgress {
+    NotStarted,
+    Unfinished,
+    Done,
+}
+
+/// Follows the `$/progress` notifications of clangd's background index
+/// until it ends, reporting how far it got as the progress mode asks. Gives
+/// up if indexing does not begin within `start_timeout`, or does not end
+/// within `timeout`.
+fn wait_for_index_progress(
+    client: &LspClient,
+    start_timeout: Duration,
+    timeout: Duration,
+    mode: ProgressMode,
+) -> IndexProgress {
+    let json_progress = (mode == ProgressMode::Jsonl).then(|| JsonProgress::new("Indexing"));
+    let started = Instant::now();
+    let mut progress = IndexProgress::NotStarted;
+    loop {
+        let limit = match progress {
+            IndexProgress::NotStarted => start_timeout,
+            _ => timeout,
+        };
+        let remaining = limit.saturating_sub(started.elapsed());
+        let Ok(notification) = client.notifications().recv_timeout(remaining) else {
+            return progress;
+        };
+        let params = &notification["params"];
+        if notification["method"].as_str() != Some("$/progress")
+            || params["token"].as_str() != Some(INDEX_PROGRESS_TOKEN)
+        {
+            continue;
+        }
+        let value = &params["value"];
+        match value["kind"].as_str() {
+            Some("begin") => progress = IndexProgress::Unfinished,
+            Some("report") => {
+                progress = IndexProgress::Unfinished;
+                if let Some(message) = value["message"].as_str() {
+                    match &json_progress {
+                        Some(json_progress) => json_progress.message(message),
+                        None if mode == ProgressMode::Off => {}
+                        None => println!("clangd indexing: {}", message),
                     }
                 }
             }
-        });
+            Some("end") => return IndexProgress::Done,
+            _ => {}
+        }
+    }
+}
 
-        self.send_lsp_request(&mut clangd_stdin, initialize_request)
-            .map_err(|e| format!("Failed to send LSP initialization request: {}", e))?;
+/// Name of the file the caller/callee pairs are written to
+pub const RELATIONS_FILE: &str = "relations.jsonl";
 
-        // Process all source files
-        let total_nr = source_files.len();
-        for (i, source_file) in source_files.into_iter().enumerate() {
-            println!(
-                "Processing file ({i} / {total_nr}): {}",
-                source_file.display()
-            );
-            self.process_file(&source_file, &mut clangd_stdin, &mut clangd_stdout)
-                .map_err(|e| {
-                    format!("Failed to process file '{}': {}", source_file.display(), e)
-                })?;
+/// A call from a chunk to a symbol, which is identified by the canonical
+/// path, start line and name column of its definition until all chunks are
+/// known
+#[derive(Debug)]
+struct Call {
+    caller: String,
+    callee_path: PathBuf,
+    callee_line: usize,
+    callee_character: usize,
+}
+
+/// Resolves the calls to the names of the called chunks and writes one
+/// `{"caller", "callee"}` line per distinct pair. Calls to symbols without
+/// a chunk, e.g. of the standard library, are left out. Returns the number
+/// of pairs written.
+fn write_relations(
+    path: &Path,
+    calls: &[Call],
+    chunks: &HashSet<ChunkKey>,
+) -> Result<usize, Box<dyn Error>> {
+    let names: HashMap<(&Path, usize, usize), &str> = chunks
+        .iter()
+        .map(|(path, line, character, name)| ((path.as_path(), *line, *character), name.as_str()))
+        .collect();
+    let mut pairs = BTreeSet::new();
+    for call in calls {
+        let callee = (
+            call.callee_path.as_path(),
+            call.callee_line,
+            call.callee_character,
+        );
+        if let Some(callee) = names.get(&callee) {
+            pairs.insert((call.caller.as_str(), *callee));
         }
+    }
+    let content: String = pairs
+        .iter()
+        .map(|(caller, callee)| format!("{}\n", json!({ "caller": caller, "callee": callee })))
+        .collect();
+    write_atomic(path, &content)?;
+    Ok(pairs.len())
+}
 
-        // Shutdown clangd
-        let shutdown_request = json!({
-            "jsonrpc": "2.0",
-            "id": 9999,
-            "method": "shutdown",
-            "params": null
-        });
-        self.send_lsp_request(&mut clangd_stdin, shutdown_request)
-            .map_err(|e| format!("Failed to send LSP shutdown request: {}", e))?;
-
-        // Exit clangd
-        let exit_notification = json!({
-            "jsonrpc": "2.0",
-            "method": "exit",
-            "params": null
-        });
-        self.send_lsp_request(&mut clangd_stdin, exit_notification)
-            .map_err(|e| format!("Failed to send LSP exit notification: {}", e))?;
+/// Identifies a symbol across translation units: the canonical path of the
+/// file it is defined in, e.g. a header, its start line, the column of its
+/// name and its qualified name. Symbols sharing a line, like a class and
+/// its inline method, differ in the latter two.
+type ChunkKey = (PathBuf, usize, usize, String);
 
-        Ok(())
+/// Removes the chunks of `source_file` already produced in this run, e.g.
+/// for an inline function of a header reported by several translation
+/// units, and returns how many were dropped. `seen` holds the chunks of the
+/// whole run.
+fn drop_duplicate_chunks(
+    source_file: &Path,
+    chunks: &mut Vec<CodeChunk>,
+    seen: &mut HashSet<ChunkKey>,
+) -> usize {
+    let before = chunks.len();
+    chunks.retain(|chunk| {
+        let character = chunk.name_position.map_or(0, |(_, character)| character);
+        seen.insert((
+            source_file.to_path_buf(),
+            chunk.start_line,
+            character,
+            chunk.name.clone(),
+        ))
+    });
+    before - chunks.len()
+}
+
+/// Asks clangd's call hierarchy for the calls made by every function and
+/// method chunk of a file
+fn outgoing_calls(
+    client: &LspClient,
+    file_uri: &str,
+    chunks: &[CodeChunk],
+) -> Result<Vec<Call>, Box<dyn Error>> {
+    let mut calls = Vec::new();
+    for chunk in chunks {
+        let Some((line, character)) = chunk.name_position else {
+            continue;
+        };
+        if chunk.kind != "function" && chunk.kind != "method" {
+            continue;
+        }
+        let items = client.request(
+            "textDocument/prepareCallHierarchy",
+            json!({
+                "textDocument": { "uri": file_uri },
+                "position": { "line": line, "character": character }
+            }),
+        )?;
+        let Some(item) = items.as_array().and_then(|items| items.first()) else {
+            continue;
+        };
+        let outgoing = client.request("callHierarchy/outgoingCalls", json!({ "item": item }))?;
+        for call in outgoing.as_array().into_iter().flatten() {
+            let to = &call["to"];
+            let (Some(uri), Some(callee_line)) =
+                (to["uri"].as_str(), to["range"]["start"]["line"].as_u64())
+            else {
+                continue;
+            };
+            let callee_character = to["selectionRange"]["start"]["character"]
+                .as_u64()
+                .unwrap_or(0);
+            calls.push(Call {
+                caller: chunk.name.clone(),
+                callee_path: PathBuf::from(uri.strip_prefix("file://").unwrap_or(uri)),
+                callee_line: callee_line as usize,
+                callee_character: callee_character as usize,
+            });
+        }
     }
+    Ok(calls)
+}
 
-    fn send_lsp_request(
-        &self,
-        stdin: &mut std::process::ChildStdin,
-        request: serde_json::Value,
-    ) -> Result<(), Box<dyn Error>> {
-        let request_str = serde_json::to_string(&request)
-            .map_err(|e| format!("Failed to serialize LSP request: {}", e))?;
-        let content_length = request_str.len();
+/// Ends the LSP session and waits for clangd to exit
+fn stop_clangd(mut clangd: Child, client: LspClient) -> Result<(), Box<dyn Error>> {
+    client
+        .request("shutdown", serde_json::Value::Null)
+        .map_err(|e| format!("Failed to shut down LSP session: {}", e))?;
+    client
+        .send_notification("exit", serde_json::Value::Null)
+        .map_err(|e| format!("Failed to send LSP exit notification: {}", e))?;
+    client.join();
+    let _ = clangd.wait();
+    Ok(())
+}
 
-        // Create log entry for request
-        let log_entry = format!(
-            ">>> Request:\nContent-Length: {}\n\n{}\n",
-            content_length, request_str
-        );
-        if let Ok(mut lsp_log) = File::options().append(true).open(&self.lsp_log_file) {
-            write!(lsp_log, "{}", log_entry)
-                .map_err(|e| format!("Failed to write to LSP log file: {}", e))?;
+/// The directory of the chunks of a source file relative to the output
+/// directory: the file's path relative to the project with the extension
+/// joined to the name, e.g. `src/foo_h` for `src/foo.h`, so that every
+/// source file gets its own, also `foo.h` and `foo.cpp`. Files outside the
+/// project are placed by their name alone.
+fn chunk_dir_name(source_file: &Path, project_dir: &Path) -> PathBuf {
+    let relative = source_file.strip_prefix(project_dir).unwrap_or_else(|_| {
+        source_file
+            .file_name()
+            .map(Path::new)
+            .unwrap_or(source_file)
+    });
+    let stem = relative.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match relative.extension() {
+        Some(ext) => format!("{}_{}", stem, ext.to_string_lossy()),
+        None => stem.into_owned(),
+    };
+    relative.with_file_name(name)
+}
+
+/// Git provenance of a source file, recorded in the chunk metadata
+#[derive(Debug, Default)]
+struct GitInfo {
+    commit: Option<String>,
+    blob_sha: Option<String>,
+}
+
+/// Conservative heuristic for test files: `foo_test.cpp`, `foo_tests.cc`,
+/// `test_foo.cpp`, and everything below a directory named `test` or `tests`
+/// (relative to the project directory).
+fn is_test_file(path: &Path, project_dir: &Path) -> bool {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if stem.ends_with("_test") || stem.ends_with("_tests") || stem.starts_with("test_") {
+        return true;
+    }
+
+    let relative = path.strip_prefix(project_dir).unwrap_or(path);
+    match relative.parent() {
+        Some(dir) => dir.components().any(|c| {
+            let c = c.as_os_str().to_string_lossy().to_lowercase();
+            c == "test" || c == "tests"
+        }),
+        None => false,
+    }
+}
+
+/// Directory names below the include directory whose headers are internal
+const PRIVATE_HEADER_DIRS: &[&str] = &["detail", "details", "internal", "impl", "private"];
+
+/// Whether the file is a public header: a header below the include
+/// directory (relative to the project directory) and not in one of the
+/// `PRIVATE_HEADER_DIRS` below it
+fn is_public_header(path: &Path, project_dir: &Path, include_dir: &Path) -> bool {
+    let is_header = path.extension().is_some_and(|ext| {
+        let ext = ext.to_string_lossy().to_lowercase();
+        ext == "h" || ext == "hpp" || ext == "hxx"
+    });
+    let relative = path.strip_prefix(project_dir).unwrap_or(path);
+    let Ok(below_include) = relative.strip_prefix(include_dir) else {
+        return false;
+    };
+    is_header
+        && !below_include.parent().is_some_and(|dir| {
+            dir.components().any(|c| {
+                let c = c.as_os_str().to_string_lossy().to_lowercase();
+                PRIVATE_HEADER_DIRS.contains(&c.as_str())
+            })
+        })
+}
+
+/// Returns true if the path has one of the C++ source or header extensions
+fn is_cpp_source(path: &Path) -> bool {
+    match path.extension() {
+        Some(extension) => {
+            let ext = extension.to_string_lossy().to_lowercase();
+            ext == "cpp"
+                || ext == "cxx"
+                || ext == "cc"
+                || ext == "h"
+                || ext == "hpp"
+                || ext == "hxx"
         }
+        None => false,
+    }
+}
 
-        writeln!(stdin, "Content-Length: {}", content_length)
-            .map_err(|e| format!("Failed to write Content-Length header: {}", e))?;
-        writeln!(stdin).map_err(|e| format!("Failed to write header separator: {}", e))?;
-        write!(stdin, "{}", request_str)
-            .map_err(|e| format!("Failed to write request body: {}", e))?;
-        stdin
-            .flush()
-            .map_err(|e| format!("Failed to flush request: {}", e))?;
+/// Writes name, kind, line range and the first line of the first `n` chunks
+fn print_preview(chunks: &[CodeChunk], n: usize, out: &mut dyn Write) -> io::Result<()> {
+    for chunk in chunks.iter().take(n) {
+        writeln!(
+            out,
+            "  {} ({}, lines {}-{}): {}",
+            chunk.name,
+            chunk.kind,
+            chunk.start_line + 1,
+            chunk.end_line + 1,
+            chunk.content.lines().next().unwrap_or_default().trim()
+        )?;
+    }
+    if chunks.len() > n {
+        writeln!(out, "  ... and {} more", chunks.len() - n)?;
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Converts an LSP character offset (in UTF-16 code units) into a byte
+/// offset into the line, clamped to the end of the line
+fn utf16_to_byte_offset(line: &str, utf16_offset: usize) -> usize {
+    let mut units = 0;
+    for (byte_offset, c) in line.char_indices() {
+        if units >= utf16_offset {
+            return byte_offset;
+        }
+        units += c.len_utf16();
     }
+    line.len()
+}
 
-    fn read_lsp_response(
-        &self,
-        reader: &mut BufReader<std::process::ChildStdout>,
-    ) -> Result<serde_json::Value, Box<dyn Error>> {
-        // Read headers
-        let mut content_length: Option<usize> = None;
-        let mut headers = String::new();
-        loop {
-            let mut line = String::new();
-            reader
-                .read_line(&mut line)
-                .map_err(|e| format!("Failed to read LSP response header: {}", e))?;
-            let line = line.trim();
-
-            headers.push_str(&line);
-            headers.push('\n');
-
-            if line.is_empty() {
-                break; // Headers are done
-            }
-
-            if line.starts_with("Content-Length:") {
-                let len_str = line
-                    .split(':')
-                    .nth(1)
-                    .ok_or("Invalid Content-Length header")?;
-                content_length = Some(len_str.trim().parse().map_err(|e| {
-                    format!(
-                        "Failed to parse Content-Length value '{}': {}",
-                        len_str.trim(),
-                        e
-                    )
-                })?);
-            }
-        }
-
-        // Read content
-        if let Some(length) = content_length {
-            let mut buffer = vec![0; length];
-            reader.read_exact(&mut buffer).map_err(|e| {
-                format!(
-                    "Failed to read LSP response body of length {}: {}",
-                    length, e
-                )
-            })?;
+/// The text of a range on a single line, e.g. the name a `selectionRange`
+/// points at
+fn range_text<'a>(lines: &[&'a str], range: &Range) -> Option<&'a str> {
+    if range.start.line != range.end.line {
+        return None;
+    }
+    let line = lines.get(range.start.line)?;
+    let from = utf16_to_byte_offset(line, range.start.character);
+    let to = utf16_to_byte_offset(line, range.end.character);
+    (from < to)
+        .then(|| line[from..to].trim())
+        .filter(|text| !text.is_empty())
+}
+
+/// Whether a name is an operator overload, e.g. "operator==" or
+/// "operator bool", but not an identifier like "operatorCount"
+fn is_operator(name: &str) -> bool {
+    name.strip_prefix("operator").is_some_and(|rest| {
+        !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') && !rest.trim().is_empty()
+    })
+}
 
-            let response_str = String::from_utf8_lossy(&buffer);
-            let json_value: serde_json::Value = serde_json::from_slice(&buffer)
-                .map_err(|e| format!("Failed to parse LSP response JSON: {}", e))?;
+/// Spells an operator without the whitespace the source may have, e.g.
+/// "operator ==" as "operator==", keeping one space before a type or
+/// keyword as in "operator bool" or "operator new[]"
+fn normalize_operator(name: &str) -> String {
+    let rest = name.trim_start_matches("operator").trim();
+    let words = rest.split_whitespace().collect::<Vec<_>>().join(" ");
+    if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        format!("operator {}", words.replace(" [", "[").replace("[ ]", "[]"))
+    } else {
+        format!("operator{}", words.replace(' ', ""))
+    }
+}
 
-            // Log the response
-            let log_entry = format!("<<< Response:\n{}{}\n", headers, response_str);
-            if let Ok(mut lsp_log) = File::options().append(true).open(&self.lsp_log_file) {
-                write!(lsp_log, "{}", log_entry)
-                    .map_err(|e| format!("Failed to write to LSP log file: {}", e))?;
-            }
+/// The name of a chunk's symbol. The identifier the `selectionRange`
+/// points at is preferred where clangd's name is less reliable: when it is
+/// empty, and for operator overloads, whose name spelling varies.
+fn symbol_name(symbol: &Symbol, lines: &[&str]) -> String {
+    let selected = symbol
+        .selection_range
+        .as_ref()
+        .and_then(|range| range_text(lines, range));
+    match selected {
+        Some(text) if symbol.name.trim().is_empty() => text.to_string(),
+        Some(text) if is_operator(text) => {
+            // Keep the qualifier of out-of-line definitions, e.g. "Foo::"
+            let qualifier = symbol
+                .name
+                .rfind("operator")
+                .map_or("", |i| &symbol.name[..i]);
+            format!("{}{}", qualifier, normalize_operator(text))
+        }
+        _ => symbol.name.clone(),
+    }
+}
 
-            Ok(json_value)
+/// The declaration of a symbol before its body, on one line: its range up
+/// to the first `{` or `;` after the name the `selectionRange` points at.
+/// Searching only after the name skips braces in default template arguments
+/// and attributes before it.
+fn symbol_signature(symbol: &Symbol, lines: &[&str]) -> Option<String> {
+    let range = &symbol.range;
+    let selection = symbol.selection_range.as_ref()?;
+    if selection.end.line < range.start.line || selection.end.line > range.end.line {
+        return None;
+    }
+    let end_line = range.end.line.min(lines.len().checked_sub(1)?);
+    let mut text = String::new();
+    for (line_no, line) in lines
+        .iter()
+        .enumerate()
+        .take(end_line + 1)
+        .skip(range.start.line)
+    {
+        let from = if line_no == range.start.line {
+            utf16_to_byte_offset(line, range.start.character)
         } else {
-            Err("No Content-Length header found".into())
+            0
+        };
+        let to = if line_no == range.end.line {
+            utf16_to_byte_offset(line, range.end.character)
+        } else {
+            line.len()
+        }
+        .max(from);
+        let search_from = match line_no.cmp(&selection.end.line) {
+            std::cmp::Ordering::Less => to,
+            std::cmp::Ordering::Equal => {
+                utf16_to_byte_offset(line, selection.end.character).clamp(from, to)
+            }
+            std::cmp::Ordering::Greater => from,
+        };
+        if let Some(i) = line[search_from..to].find(['{', ';']) {
+            text.push_str(&line[from..search_from + i]);
+            break;
         }
+        text.push_str(&line[from..to]);
+        text.push('\n');
     }
+    let signature = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!signature.is_empty()).then_some(signature)
+}
 
-    fn process_file(
+/// Whether the symbol is defined with its body rather than only declared:
+/// the first `{` or `;` outside of parentheses after the name the
+/// `selectionRange` points at, within the symbol's range, is a `{`. The
+/// range of a declaration ends before its `;`, that of a definition
+/// includes the body.
+fn symbol_has_body(symbol: &Symbol, lines: &[&str]) -> bool {
+    let range = &symbol.range;
+    let Some(selection) = symbol.selection_range.as_ref() else {
+        return false;
+    };
+    let mut depth = 0usize;
+    for (line_no, line) in lines
+        .iter()
+        .enumerate()
+        .take(range.end.line + 1)
+        .skip(selection.end.line)
+    {
+        let from = if line_no == selection.end.line {
+            utf16_to_byte_offset(line, selection.end.character)
+        } else {
+            0
+        };
+        let to = if line_no == range.end.line {
+            utf16_to_byte_offset(line, range.end.character)
+        } else {
+            line.len()
+        }
+        .max(from);
+        for c in line[from..to].chars() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                '{' if depth == 0 => return true,
+                ';' if depth == 0 => return false,
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+/// Whether a namespace symbol is declared inline, i.e. whether its name is
+/// preceded by `inline namespace`, or by `::inline` in a nested namespace
+/// definition. Comments between the start of the symbol's range and its
+/// name are ignored.
+fn is_inline_namespace(symbol: &Symbol, lines: &[&str]) -> bool {
+    let Some(selection) = symbol.selection_range.as_ref() else {
+        return false;
+    };
+    // The last two tokens before the name
+    let mut tokens: [String; 2] = Default::default();
+    let mut push = |token: String| {
+        tokens.swap(0, 1);
+        tokens[1] = token;
+    };
+    let mut in_block_comment = false;
+    for (line_no, line) in lines
+        .iter()
+        .enumerate()
+        .take(selection.start.line + 1)
+        .skip(symbol.range.start.line)
+    {
+        let to = if line_no == selection.start.line {
+            utf16_to_byte_offset(line, selection.start.character)
+        } else {
+            line.len()
+        };
+        let mut chars = line[..to].chars().peekable();
+        let mut word = String::new();
+        while let Some(c) = chars.next() {
+            if in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    in_block_comment = false;
+                }
+                continue;
+            }
+            if c.is_alphanumeric() || c == '_' {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                push(std::mem::take(&mut word));
+            }
+            match (c, chars.peek()) {
+                ('/', Some('/')) => break,
+                ('/', Some('*')) => {
+                    chars.next();
+                    in_block_comment = true;
+                }
+                (':', Some(':')) => {
+                    chars.next();
+                    push("::".to_string());
+                }
+                (c, _) if c.is_whitespace() => {}
+                (c, _) => push(c.to_string()),
+            }
+        }
+        if !word.is_empty() {
+            push(word);
+        }
+    }
+    matches!(
+        (tokens[0].as_str(), tokens[1].as_str()),
+        ("inline", "namespace") | ("::", "inline")
+    )
+}
+
+/// Extends the content of every chunk by up to `context_lines` lines before
+/// and after its symbol, clamped to the file and not reaching into another
+/// chunk, except the ones enclosing it. The span of the symbol itself stays
+/// in `start_line` and `end_line`.
+fn add_context(chunks: &mut [CodeChunk], lines: &[&str], context_lines: usize) {
+    if context_lines == 0 || lines.is_empty() {
+        return;
+    }
+    let spans: Vec<(usize, usize)> = chunks
+        .iter()
+        .map(|chunk| (chunk.start_line, chunk.end_line))
+        .collect();
+    for (i, chunk) in chunks.iter_mut().enumerate() {
+        let (start, end) = spans[i];
+        let mut from = start.saturating_sub(context_lines);
+        let mut to = (end + context_lines).min(lines.len() - 1);
+        for (j, &(other_start, other_end)) in spans.iter().enumerate() {
+            let encloses = other_start <= start && other_end >= end;
+            if j == i || encloses {
+                continue;
+            }
+            if other_end < start {
+                from = from.max(other_end + 1);
+            } else if other_start > end {
+                to = to.min(other_start - 1);
+            }
+        }
+        if from < start || to > end {
+            chunk.content = lines[from..=to].join("\n");
+            chunk.context = Some((from, to));
+        }
+    }
+}
+
+/// Keeps the `max` most significant chunks: functions and methods before
+/// classes before namespaces, larger ones first. Returns the number of
+/// dropped chunks.
+fn limit_chunks(chunks: &mut Vec<CodeChunk>, max: usize) -> usize {
+    if chunks.len() <= max {
+        return 0;
+    }
+    let significance = |chunk: &CodeChunk| match chunk.kind.as_str() {
+        "function" | "method" => 0,
+        "class" => 1,
+        _ => 2,
+    };
+    chunks.sort_by_key(|chunk| {
+        (
+            significance(chunk),
+            std::cmp::Reverse(chunk.end_line - chunk.start_line),
+        )
+    });
+    let dropped = chunks.len() - max;
+    chunks.truncate(max);
+    chunks.sort_by_key(|chunk| chunk.start_line);
+    dropped
+}
+
+/// The warning about a chunked file, if it produced no chunks or clangd
+/// chunked it without a compile command
+fn chunk_warning(file_path: &Path, chunks: usize, no_compile_command: bool) -> Option<String> {
+    if chunks == 0 {
+        Some(format!(
+            "WARN: no chunks found in '{}', check its compile flags",
+            file_path.display()
+        ))
+    } else if no_compile_command {
+        Some(format!(
+            "WARN: clangd has no compile command for '{}', symbols may be missing",
+            file_path.display()
+        ))
+    } else {
+        None
+    }
+}
+
+/// Returns the block of comment lines directly above `start_line`, if any.
+/// Line comments and block comments both count, a blank line ends the block
+/// unless it is inside a block comment. Lines are walked upwards, so a line
+/// ending a block comment makes the lines above part of the block up to the
+/// one starting it, and other lines merely starting with `*` (such as
+/// `*p = 0;`) are code.
+pub fn leading_comment(lines: &[&str], start_line: usize) -> Option<String> {
+    let mut first = start_line;
+    let mut in_block_comment = false;
+    for (i, line) in lines[..start_line].iter().enumerate().rev() {
+        let line = line.trim();
+        if in_block_comment {
+            if let Some(opening) = line.find("/*") {
+                // Code before the comment makes the whole block code
+                if opening > 0 {
+                    break;
+                }
+                in_block_comment = false;
+                first = i;
+            }
+            continue;
+        }
+        if line.starts_with("//") || (line.starts_with("/*") && line.ends_with("*/")) {
+            first = i;
+        } else if line.ends_with("*/") && !line.contains("/*") {
+            in_block_comment = true;
+        } else {
+            break;
+        }
+    }
+    if first == start_line {
+        return None;
+    }
+    Some(lines[first..start_line].join("\n"))
+}
+
+/// Guesses whether the member starting at `member_line` of the class
+/// starting at `class_line` is public. clangd does not report the access of
+/// symbols, so this looks for the last `public:`, `protected:` or `private:`
+/// label at the top level of the class body before the member, falling
+/// back to the default of `class` (private) or `struct` (public). Labels are
+/// only recognized at the start of a line, and braces in comments or string
+/// literals can throw off the nesting.
+fn is_public_member(lines: &[&str], class_line: usize, member_line: usize) -> bool {
+    let mut public = class_key(&lines[class_line..member_line]) != Some("class");
+    let mut depth = 0usize;
+    for line in &lines[class_line..member_line] {
+        if depth == 1 {
+            let line = line.trim_start();
+            for (label, label_public) in
+                [("public", true), ("protected", false), ("private", false)]
+            {
+                if let Some(rest) = line.strip_prefix(label) {
+                    let rest = rest.trim_start();
+                    if rest.starts_with(':') && !rest.starts_with("::") {
+                        public = label_public;
+                    }
+                }
+            }
+        }
+        for c in line.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+    public
+}
+
+/// Returns the `class`, `struct` or `union` keyword a class definition
+/// starting with the given lines is introduced with, after its template
+/// parameters if any
+fn class_key<'a>(lines: &[&'a str]) -> Option<&'a str> {
+    let mut template_depth = 0usize;
+    for line in lines {
+        let mut rest = *line;
+        while !rest.is_empty() {
+            let word_start = rest
+                .find(|c: char| c.is_alphanumeric() || c == '_')
+                .unwrap_or(rest.len());
+            for c in rest[..word_start].chars() {
+                match c {
+                    '<' => template_depth += 1,
+                    '>' => template_depth = template_depth.saturating_sub(1),
+                    '{' => return None,
+                    _ => {}
+                }
+            }
+            rest = &rest[word_start..];
+            let word_end = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let word = &rest[..word_end];
+            if template_depth == 0 && matches!(word, "class" | "struct" | "union") {
+                return Some(word);
+            }
+            rest = &rest[word_end..];
+        }
+    }
+    None
+}
+
+/// Name of anonymous namespaces in qualified chunk names
+const ANONYMOUS_NAMESPACE: &str = "anon";
+
+/// Nesting depth of symbols below which the chunker stops descending, if
+/// not configured. Real code nests a few levels deep; this bounds the
+/// recursion on bogus symbol trees.
+pub const DEFAULT_MAX_SYMBOL_DEPTH: usize = 64;
+
+fn sanitize_name(s: &str) -> String {
+    let mut r = s
+        .replace("::", "_doublecolon_")
+        .replace("<", "_less_")
+        .replace(">", "_greater_")
+        .replace("/", "_slash_");
+    r.truncate(200);
+    r
+}
+
+/// Checks that the server's answer to `initialize` announces the document
+/// symbols the chunks are made of
+fn check_initialize_result(result: &Value) -> Result<(), String> {
+    let capabilities = result
+        .get("capabilities")
+        .filter(|capabilities| capabilities.is_object())
+        .ok_or_else(|| {
+            format!(
+                "Invalid LSP initialize response without capabilities: {}",
+                result
+            )
+        })?;
+    match capabilities.get("documentSymbolProvider") {
+        Some(Value::Bool(true)) | Some(Value::Object(_)) => Ok(()),
+        _ => Err("The LSP server does not provide document symbols, use --no-clangd".to_string()),
+    }
+}
+
+impl Chunker {
+    /// Starts building a chunker with named settings
+    pub fn builder() -> ChunkerBuilder {
+        ChunkerBuilder::default()
+    }
+
+    pub fn new(
+        project_dir: String,
+        output_dir: String,
+        clangd_path: String,
+        git_path: String,
+        lsp_log_file: String,
+        options: ChunkerOptions,
+    ) -> Self {
+        Self {
+            project_dir,
+            output_dir,
+            clangd_path,
+            git_path,
+            lsp_log_file,
+            options,
+        }
+    }
+
+    /// Builds the command line to start clangd with.
+    ///
+    /// clangd always runs with a background index, which it persists in
+    /// `.cache/clangd/index` next to the compilation database, so repeated
+    /// runs only index the files that changed since.
+    fn clangd_command(&self) -> Command {
+        let mut command = Command::new(&self.clangd_path);
+        command
+            .arg(format!("--compile-commands-dir={}", COMPILE_COMMANDS_DIR))
+            .arg("--log=verbose")
+            .arg("--background-index")
+            .args(&self.options.clangd_args);
+        command
+    }
+
+    /// Reads a newline-delimited list of source files from the given file,
+    /// or from stdin if the name is "-". Nonexistent and non-C++ paths are
+    /// skipped with a warning.
+    fn read_file_list(
+        &self,
+        files_from: &str,
+        excluded_dir: Option<&Path>,
+    ) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let list = if files_from == "-" {
+            let mut list = String::new();
+            std::io::stdin()
+                .read_to_string(&mut list)
+                .map_err(|e| format!("Failed to read file list from stdin: {}", e))?;
+            list
+        } else {
+            fs::read_to_string(files_from)
+                .map_err(|e| format!("Failed to read file list '{}': {}", files_from, e))?
+        };
+        Ok(self.listed_files(&list, excluded_dir))
+    }
+
+    /// Checks the paths of a newline-delimited file list, ignoring blank
+    /// lines
+    fn listed_files(&self, list: &str, excluded_dir: Option<&Path>) -> Vec<PathBuf> {
+        let paths = list
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        self.check_listed_files(paths, excluded_dir)
+    }
+
+    /// Reads the source files from the `file` entries of the compilation
+    /// database in the project's build directory, resolving relative paths
+    /// against the entry's `directory`. Files outside the project are
+    /// skipped, and each file is listed once, in the order of the database.
+    fn read_compile_db(&self, excluded_dir: Option<&Path>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let db_path = Path::new(&self.project_dir)
+            .join(COMPILE_COMMANDS_DIR)
+            .join("compile_commands.json");
+        let content = fs::read_to_string(&db_path).map_err(|e| {
+            format!(
+                "Failed to read compilation database '{}': {}",
+                db_path.display(),
+                e
+            )
+        })?;
+        let entries: Vec<CompileCommand> = serde_json::from_str(&content).map_err(|e| {
+            format!(
+                "Failed to parse compilation database '{}': {}",
+                db_path.display(),
+                e
+            )
+        })?;
+
+        let project_dir = fs::canonicalize(&self.project_dir).map_err(|e| {
+            format!(
+                "Failed to canonicalize project path '{}': {}",
+                self.project_dir, e
+            )
+        })?;
+        let mut seen = HashSet::new();
+        let mut outside_project = 0;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = Path::new(&entry.directory).join(entry.file);
+            // Missing files are kept for check_listed_files to warn about
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if !canonical.starts_with(&project_dir) {
+                outside_project += 1;
+            } else if seen.insert(canonical) {
+                paths.push(path);
+            }
+        }
+        if outside_project > 0 {
+            println!(
+                "Skipping {} entries of '{}' outside the project directory",
+                outside_project,
+                db_path.display()
+            );
+        }
+        Ok(self.check_listed_files(paths, excluded_dir))
+    }
+
+    /// Keeps the existing C++ sources of an explicit file list, skipping the
+    /// others with a warning, and applies the test filter and file limit
+    fn check_listed_files(&self, paths: Vec<PathBuf>, excluded_dir: Option<&Path>) -> Vec<PathBuf> {
+        let mut cpp_files = Vec::new();
+        for path in paths {
+            let name = path.display();
+            if !path.is_file() {
+                eprintln!("Warning: skipping '{}': file does not exist", name);
+            } else if !is_cpp_source(&path) {
+                eprintln!("Warning: skipping '{}': not a C++ source file", name);
+            } else if excluded_dir
+                .is_some_and(|dir| fs::canonicalize(&path).is_ok_and(|path| path.starts_with(dir)))
+            {
+                eprintln!("Warning: skipping '{}': inside the output directory", name);
+            } else if self.options.exclude_tests
+                && is_test_file(&path, Path::new(&self.project_dir))
+            {
+                println!("Skipping test file '{}'", name);
+            } else if !self.is_selected_header(&path) {
+                println!("Skipping '{}': not a public header", name);
+            } else {
+                cpp_files.push(path);
+            }
+        }
+
+        if let Some(limit) = self.options.limit_files {
+            cpp_files.truncate(limit);
+        }
+
+        cpp_files
+    }
+
+    /// Checks how the output directory relates to the project directory.
+    /// Fails if the project lies inside the output directory, and returns
+    /// the canonical output directory if it lies inside the project, so that
+    /// generated chunk files can be excluded from the source scan.
+    fn output_dir_in_project(&self) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        // Chunking in memory may leave the output directory uncreated,
+        // then there is nothing to exclude
+        if !Path::new(&self.output_dir).exists() {
+            return Ok(None);
+        }
+        let project_dir = fs::canonicalize(&self.project_dir).map_err(|e| {
+            format!(
+                "Failed to canonicalize project path '{}': {}",
+                self.project_dir, e
+            )
+        })?;
+        let output_dir = fs::canonicalize(&self.output_dir).map_err(|e| {
+            format!(
+                "Failed to canonicalize output path '{}': {}",
+                self.output_dir, e
+            )
+        })?;
+
+        if project_dir.starts_with(&output_dir) {
+            return Err(format!(
+                "Project directory '{}' must not be inside the output directory '{}'",
+                self.project_dir, self.output_dir
+            )
+            .into());
+        }
+        if output_dir.starts_with(&project_dir) {
+            println!(
+                "Output directory '{}' is inside the project directory, excluding it from the source scan",
+                self.output_dir
+            );
+            return Ok(Some(output_dir));
+        }
+        Ok(None)
+    }
+
+    /// Whether the file passes the public header selection, if any
+    fn is_selected_header(&self, path: &Path) -> bool {
+        self.options
+            .only_public_headers
+            .as_deref()
+            .is_none_or(|include_dir| {
+                is_public_header(path, Path::new(&self.project_dir), include_dir)
+            })
+    }
+
+    fn find_cpp_source_files(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let excluded_dir = self.output_dir_in_project()?;
+
+        if let Some(files_from) = &self.options.files_from {
+            return self.read_file_list(files_from, excluded_dir.as_deref());
+        }
+        if self.options.use_compile_db {
+            return self.read_compile_db(excluded_dir.as_deref());
+        }
+
+        let mut cpp_files = Vec::new();
+        let mut excluded_tests = 0;
+        let mut excluded_private = 0;
+
+        // Do not descend into the output directory if it is inside the project
+        let walker = WalkDir::new(&self.project_dir)
+            .into_iter()
+            .filter_entry(|entry| match &excluded_dir {
+                Some(excluded_dir) if entry.file_type().is_dir() => {
+                    fs::canonicalize(entry.path()).map_or(true, |path| path != *excluded_dir)
+                }
+                _ => true,
+            });
+
+        for entry in walker {
+            let entry = entry.map_err(|e| {
+                format!(
+                    "Failed to read directory entry in '{}': {}",
+                    self.project_dir, e
+                )
+            })?;
+            let path = entry.path();
+
+            if path.is_file() && is_cpp_source(path) {
+                if self.options.exclude_tests && is_test_file(path, Path::new(&self.project_dir)) {
+                    excluded_tests += 1;
+                    continue;
+                }
+                if !self.is_selected_header(path) {
+                    excluded_private += 1;
+                    continue;
+                }
+                cpp_files.push(path.to_path_buf());
+            }
+        }
+        if excluded_tests > 0 {
+            println!("Excluded {} test files", excluded_tests);
+        }
+        if excluded_private > 0 {
+            println!(
+                "Excluded {} files that are not public headers",
+                excluded_private
+            );
+        }
+
+        // Sort for determinism, so that a limited run always picks the same files
+        cpp_files.sort();
+        if let Some(limit) = self.options.limit_files {
+            cpp_files.truncate(limit);
+        }
+
+        Ok(cpp_files)
+    }
+
+    fn extract_chunks(
         &self,
         file_path: &Path,
-        clangd_stdin: &mut std::process::ChildStdin,
-        clangd_stdout: &mut BufReader<std::process::ChildStdout>,
+        file_content: &str,
+        symbols: &[Symbol],
+    ) -> Result<Vec<CodeChunk>, Box<dyn Error>> {
+        let mut chunks = Vec::new();
+        let lines: Vec<&str> = file_content.lines().collect();
+
+        // Helper function to process symbols recursively. With `public_only`,
+        // `class_line` is the start line of the class the symbols belong to.
+        // Children are only processed while `depth_left` is not exhausted;
+        // returns whether some were skipped for that reason.
+        fn process_symbols(
+            symbols: &[Symbol],
+            lines: &[&str],
+            chunks: &mut Vec<CodeChunk>,
+            parent: Option<&str>,
+            options: &ChunkerOptions,
+            class_line: Option<usize>,
+            depth_left: usize,
+        ) -> bool {
+            let mut too_deep = false;
+            for symbol in symbols {
+                let kind = match symbol.kind {
+                    SYMBOL_KIND_FUNCTION => "function",
+                    SYMBOL_KIND_METHOD => "method",
+                    SYMBOL_KIND_CLASS => "class",
+                    SYMBOL_KIND_NAMESPACE => "namespace",
+                    // Globals only, not members or locals: their parent, if
+                    // any, was the last namespace chunk of that name
+                    SYMBOL_KIND_VARIABLE | SYMBOL_KIND_CONSTANT
+                        if options.include_globals
+                            && parent.is_none_or(|parent| {
+                                chunks
+                                    .iter()
+                                    .rev()
+                                    .find(|chunk| chunk.name == parent)
+                                    .is_some_and(|chunk| chunk.kind == "namespace")
+                            }) =>
+                    {
+                        "variable"
+                    }
+                    _ => continue, // Skip other symbols like fields
+                };
+
+                let start = &symbol.range.start;
+                let end = &symbol.range.end;
+
+                // Skip only truly invalid ranges: reversed, empty, or
+                // starting beyond the end of the file
+                if start.line > end.line
+                    || (start.line == end.line && start.character >= end.character)
+                    || start.line >= lines.len()
+                {
+                    continue;
+                }
+                let start_line = start.line;
+                if class_line.is_some_and(|class_line| {
+                    class_line < start_line && !is_public_member(lines, class_line, start_line)
+                }) {
+                    continue;
+                }
+                // A range may end after the last line, e.g. at EOF without newline
+                let end_line = end.line.min(lines.len() - 1);
+
+                // Extract the content of the chunk, for symbols on a single
+                // line only the characters covered by the range. A range
+                // clamped to the last line covers the rest of it.
+                let content = if start_line == end_line {
+                    let line = lines[start_line];
+                    let from = utf16_to_byte_offset(line, start.character);
+                    let to = if end.line == start_line {
+                        utf16_to_byte_offset(line, end.character).max(from)
+                    } else {
+                        line.len()
+                    };
+                    line[from..to].to_string()
+                } else {
+                    lines[start_line..=end_line].join("\n")
+                };
+
+                // Anonymous namespaces get a stable placeholder name
+                let is_namespace = symbol.kind == SYMBOL_KIND_NAMESPACE;
+                let name = if is_namespace
+                    && (symbol.name.is_empty() || symbol.name == "(anonymous namespace)")
+                {
+                    ANONYMOUS_NAMESPACE.to_string()
+                } else {
+                    symbol_name(symbol, lines)
+                };
+
+                // Create a unique name for the chunk
+                let chunk_name = if let Some(parent_name) = parent {
+                    format!("{}::{}", parent_name, name)
+                } else {
+                    name
+                };
+
+                chunks.push(CodeChunk {
+                    name: chunk_name.clone(),
+                    content,
+                    start_line,
+                    end_line,
+                    kind: kind.to_string(),
+                    parent: parent.map(|s| s.to_string()),
+                    comment: leading_comment(lines, start_line),
+                    deprecated: symbol.is_deprecated(),
+                    name_position: symbol
+                        .selection_range
+                        .as_ref()
+                        .map(|range| (range.start.line, range.start.character)),
+                    signature: symbol_signature(symbol, lines),
+                    context: None,
+                    has_body: symbol_has_body(symbol, lines),
+                });
+
+                // Process child symbols (like methods within a class). Inline
+                // namespaces are transparent to the qualified names of their
+                // members, clangd does not mark them, so we look at the source.
+                let is_inline = is_namespace && is_inline_namespace(symbol, lines);
+                let child_parent = if is_inline {
+                    parent
+                } else {
+                    Some(chunk_name.as_str())
+                };
+                let child_class_line =
+                    (options.public_only && symbol.kind == SYMBOL_KIND_CLASS).then_some(start_line);
+                if symbol.children.is_empty() {
+                    continue;
+                }
+                if depth_left == 0 {
+                    too_deep = true;
+                    continue;
+                }
+                too_deep |= process_symbols(
+                    &symbol.children,
+                    lines,
+                    chunks,
+                    child_parent,
+                    options,
+                    child_class_line,
+                    depth_left - 1,
+                );
+            }
+            too_deep
+        }
+
+        let max_depth = self
+            .options
+            .max_symbol_depth
+            .unwrap_or(DEFAULT_MAX_SYMBOL_DEPTH);
+        let too_deep = process_symbols(
+            symbols,
+            &lines,
+            &mut chunks,
+            None,
+            &self.options,
+            None,
+            max_depth,
+        );
+        if too_deep {
+            eprintln!(
+                "WARN: symbols of '{}' are nested more than {} levels deep, skipping the deeper ones (see --max-symbol-depth)",
+                file_path.display(),
+                max_depth
+            );
+        }
+        Ok(chunks)
+    }
+
+    /// Runs git in the project directory and returns its trimmed output, or
+    /// None if git fails, e.g. because the project is not a git repository
+    fn git_output(&self, args: &[&str]) -> Option<String> {
+        let output = Command::new(&self.git_path)
+            .arg("-C")
+            .arg(&self.project_dir)
+            .args(args)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (!stdout.is_empty()).then_some(stdout)
+    }
+
+    /// Lays out the chunks of a source file as the chunker writes them: a
+    /// directory per source file with one file per chunk and an index
+    fn chunk_files(&self, source_file: &Path, chunks: &[CodeChunk], git: &GitInfo) -> ChunkFiles {
+        let dir = PathBuf::from(&self.output_dir)
+            .join(chunk_dir_name(source_file, Path::new(&self.project_dir)));
+
+        // Index with metadata about all chunks
+        let mut index = String::new();
+        index.push_str(&format!("Source file: {}\n", source_file.display()));
+        if let Ok(source_path) = source_file.strip_prefix(&self.project_dir) {
+            index.push_str(&format!("Source path: {}\n", source_path.display()));
+        }
+        if let Some(commit) = &git.commit {
+            index.push_str(&format!("Commit: {}\n", commit));
+        }
+        if let Some(blob_sha) = &git.blob_sha {
+            index.push_str(&format!("Blob SHA: {}\n", blob_sha));
+        }
+        if self.options.no_clangd {
+            index.push_str("Chunker: heuristic\n");
+        }
+        index.push_str(&format!("Number of chunks: {}\n", chunks.len()));
+        index.push_str("---\n");
+
+        // Lay out the chunks in line order, so that each one can refer to
+        // its neighbors in the file
+        let mut chunks: Vec<&CodeChunk> = chunks.iter().collect();
+        chunks.sort_by_key(|chunk| chunk.start_line);
+
+        let mut files = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let sanitized_name = sanitize_name(&chunk.name);
+            let chunk_filename = format!(
+                "{:03}_{}_{}_{}.cpp",
+                i + 1,
+                sanitized_name,
+                chunk.kind,
+                chunk.start_line + 1
+            );
+
+            // Keep the comment above the symbol for a separate embedding
+            let comment = chunk
+                .comment
+                .clone()
+                .filter(|_| self.options.extract_comments);
+            files.push(ChunkFile {
+                path: dir.join(&chunk_filename),
+                content: chunk.content.clone(),
+                comment,
+            });
+
+            index.push_str(&format!("Chunk: {}\n", chunk_filename));
+            index.push_str(&format!("  Name: {}\n", chunk.name));
+            index.push_str(&format!("  Kind: {}\n", chunk.kind));
+            // Written before the lines, which the validation checks the
+            // chunk against
+            if let Some((from, to)) = chunk.context {
+                index.push_str(&format!("  Context: {}-{}\n", from + 1, to + 1));
+            }
+            index.push_str(&format!(
+                "  Lines: {}-{}\n",
+                chunk.start_line + 1,
+                chunk.end_line + 1
+            ));
+            if let Some(parent) = &chunk.parent {
+                index.push_str(&format!("  Parent: {}\n", parent));
+            }
+            if let Some(signature) = &chunk.signature {
+                index.push_str(&format!("  Signature: {}\n", signature));
+            }
+            if chunk.deprecated {
+                index.push_str("  Deprecated: true\n");
+            }
+            if chunk.has_body {
+                index.push_str("  Has body: true\n");
+            }
+            if let Some(prev) = i.checked_sub(1).map(|j| chunks[j]) {
+                index.push_str(&format!("  Prev: {}\n", prev.name));
+            }
+            if let Some(next) = chunks.get(i + 1) {
+                index.push_str(&format!("  Next: {}\n", next.name));
+            }
+            index.push_str("---\n");
+        }
+
+        ChunkFiles {
+            source_file: source_file.to_path_buf(),
+            dir,
+            index,
+            files,
+        }
+    }
+
+    /// Runs the chunker, writing the chunk files to the output directory
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        // Create output directory if it doesn't exist
+        fs::create_dir_all(&self.output_dir).map_err(|e| {
+            format!(
+                "Failed to create output directory '{}': {}",
+                self.output_dir, e
+            )
+        })?;
+
+        let mut written_dirs = Vec::new();
+        self.run_each(|files| {
+            write_chunk_files(&files).map_err(|e| {
+                format!(
+                    "Failed to write chunks for '{}': {}",
+                    files.source_file.display(),
+                    e
+                )
+            })?;
+            println!(
+                "Wrote {} chunks for {}",
+                files.files.len(),
+                files.source_file.display()
+            );
+            written_dirs.push(files.dir);
+            Ok(())
+        })?;
+
+        if self.options.link_decl_def {
+            let links = link_declarations(Path::new(&self.output_dir), &written_dirs)?;
+            println!("Linked {} declarations to their definitions", links);
+        }
+
+        if self.options.validate_chunks {
+            self.validate(&written_dirs)?;
+        }
+        Ok(())
+    }
+
+    /// Re-reads the written chunks and checks them against their index
+    fn validate(&self, dirs: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let mut problems = Vec::new();
+        let mut checked = 0;
+        for dir in dirs {
+            checked += validate_chunk_dir(dir, self.options.allow_empty_chunks, &mut problems)?;
+        }
+        if problems.is_empty() {
+            println!("Validated {} chunks", checked);
+            return Ok(());
+        }
+        for problem in &problems {
+            eprintln!("ERROR: {}", problem);
+        }
+        Err(format!(
+            "Validation found {} problems in {} chunks",
+            problems.len(),
+            checked
+        )
+        .into())
+    }
+
+    /// Runs the chunker, handing the chunks of every source file to the sink
+    /// instead of writing them. Only the relations file is still written if
+    /// calls are recorded.
+    pub fn run_each<F>(&self, mut sink: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(ChunkFiles) -> Result<(), Box<dyn Error>>,
+    {
+        // Find all C++ source files in the project
+        let source_files = self.find_cpp_source_files().map_err(|e| {
+            format!(
+                "Failed to scan project directory '{}': {}",
+                self.project_dir, e
+            )
+        })?;
+        println!("Found {} C++ source files", source_files.len());
+
+        // Start clangd unless chunking heuristically
+        let clangd = if self.options.no_clangd {
+            None
+        } else {
+            Some(self.start_clangd()?)
+        };
+        let client = clangd.as_ref().map(|(_, client)| client);
+        if let (Some(client), Some(timeout), Some(first_file)) =
+            (client, self.options.index_timeout, source_files.first())
+        {
+            self.wait_for_background_index(client, first_file, timeout)?;
+        }
+
+        // Determine the commit the chunks are taken from
+        let head_commit = if self.options.record_git {
+            let commit = self.git_output(&["rev-parse", "HEAD"]);
+            if commit.is_none() {
+                println!("Project is not a git repository, not recording git information");
+            }
+            commit
+        } else {
+            None
+        };
+
+        // Process all source files
+        let total_nr = source_files.len();
+        let mut zero_chunk_files = Vec::new();
+        let mut no_compile_command_files = Vec::new();
+        let mut truncated_files = Vec::new();
+        let mut seen_chunks = HashSet::new();
+        let mut duplicates = 0;
+        let mut calls = Vec::new();
+        let json_progress =
+            (self.options.progress == ProgressMode::Jsonl).then(|| JsonProgress::new("Chunking"));
+        for (i, source_file) in source_files.into_iter().enumerate() {
+            match self.options.progress {
+                ProgressMode::Bar | ProgressMode::Plain => println!(
+                    "Processing file ({i} / {total_nr}): {}",
+                    source_file.display()
+                ),
+                ProgressMode::Jsonl | ProgressMode::Off => {}
+            }
+            let stats = self
+                .process_file(
+                    &source_file,
+                    client,
+                    head_commit.as_deref(),
+                    &mut seen_chunks,
+                    &mut sink,
+                )
+                .map_err(|e| {
+                    format!("Failed to process file '{}': {}", source_file.display(), e)
+                })?;
+            self.options.metrics.file_processed(stats.chunks);
+            duplicates += stats.duplicates;
+            calls.extend(stats.calls);
+            if stats.no_compile_command {
+                no_compile_command_files.push(source_file.clone());
+            }
+            if stats.chunks == 0 {
+                zero_chunk_files.push(source_file);
+            } else if stats.truncated {
+                truncated_files.push(source_file);
+            }
+            if let Some(json_progress) = &json_progress {
+                json_progress.report(i as u64 + 1, total_nr as u64);
+            }
+        }
+
+        if duplicates > 0 {
+            println!("Dropped {} duplicate chunks", duplicates);
+        }
+        if self.options.record_calls {
+            fs::create_dir_all(&self.output_dir).map_err(|e| {
+                format!(
+                    "Failed to create output directory '{}': {}",
+                    self.output_dir, e
+                )
+            })?;
+            let relations_path = Path::new(&self.output_dir).join(RELATIONS_FILE);
+            let written = write_relations(&relations_path, &calls, &seen_chunks).map_err(|e| {
+                format!(
+                    "Failed to write relations file '{}': {}",
+                    relations_path.display(),
+                    e
+                )
+            })?;
+            println!(
+                "Wrote {} call relations to {}",
+                written,
+                relations_path.display()
+            );
+        }
+
+        // Files without chunks are often a sign of missing compile flags
+        if !zero_chunk_files.is_empty() {
+            println!(
+                "{} of {} files produced no chunks:",
+                zero_chunk_files.len(),
+                total_nr
+            );
+            for file in &zero_chunk_files {
+                if no_compile_command_files.contains(file) {
+                    println!("  {} ({})", file.display(), NO_COMPILE_COMMAND);
+                } else {
+                    println!("  {}", file.display());
+                }
+            }
+        }
+        if !no_compile_command_files.is_empty() {
+            println!(
+                "{} of {} files have no compile command ({}), clangd used fallback flags:",
+                no_compile_command_files.len(),
+                total_nr,
+                NO_COMPILE_COMMAND
+            );
+            for file in &no_compile_command_files {
+                println!("  {}", file.display());
+            }
+        }
+        if !truncated_files.is_empty() {
+            println!(
+                "{} of {} files were truncated to {} chunks:",
+                truncated_files.len(),
+                total_nr,
+                self.options.max_chunks_per_file.unwrap_or_default()
+            );
+            for file in &truncated_files {
+                println!("  {}", file.display());
+            }
+        }
+
+        if let Some((clangd, client)) = clangd {
+            stop_clangd(clangd, client)?;
+        }
+
+        if let Some(max_percent) = self.options.max_no_compile_command_percent {
+            let percent = 100.0 * no_compile_command_files.len() as f64 / total_nr.max(1) as f64;
+            if percent > max_percent {
+                return Err(format!(
+                    "{:.1}% of the files have no compile command, more than the {}% allowed \
+                     by --strict-coverage, check the compilation database",
+                    percent, max_percent
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits until clangd has built its background index, so that the calls
+    /// and declarations it reports span the whole project. clangd only
+    /// loads the compilation database, and starts indexing, once a file is
+    /// opened, so the given file is opened (and closed again) to trigger it.
+    fn wait_for_background_index(
+        &self,
+        client: &LspClient,
+        file_path: &Path,
+        timeout: Duration,
     ) -> Result<(), Box<dyn Error>> {
-        let file_content = fs::read_to_string(file_path)
+        let file_uri = format!("file://{}", fs::canonicalize(file_path)?.to_string_lossy());
+        let text = fs::read_to_string(file_path)
             .map_err(|e| format!("Failed to read file '{}': {}", file_path.display(), e))?;
-        let file_uri = format!(
-            "file://{}",
-            fs::canonicalize(file_path)
-                .map_err(|e| format!(
-                    "Failed to canonicalize path '{}': {}",
+        client.send_notification(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": file_uri, "languageId": "cpp", "version": 1, "text": text }
+            }),
+        )?;
+        let mode = self.options.progress;
+        match wait_for_index_progress(client, INDEX_START_TIMEOUT, timeout, mode) {
+            IndexProgress::NotStarted => {
+                eprintln!("WARN: clangd did not start a background index, the calls may be incomplete")
+            }
+            IndexProgress::Unfinished => eprintln!(
+                "WARN: clangd did not finish its background index within {}s, the calls may be incomplete",
+                timeout.as_secs()
+            ),
+            IndexProgress::Done => match mode {
+                ProgressMode::Bar | ProgressMode::Plain => {
+                    println!("clangd finished its background index")
+                }
+                ProgressMode::Jsonl | ProgressMode::Off => {}
+            },
+        }
+        client.send_notification(
+            "textDocument/didClose",
+            json!({ "textDocument": { "uri": file_uri } }),
+        )?;
+        Ok(())
+    }
+
+    /// Asks clangd for the symbols of a file and turns them into chunks.
+    /// Also tells whether clangd said it compiles the file with fallback
+    /// flags.
+    fn lsp_chunks(
+        &self,
+        client: &LspClient,
+        file_path: &Path,
+        file_uri: &str,
+        file_content: &str,
+    ) -> Result<(Vec<CodeChunk>, bool), Box<dyn Error>> {
+        // Notifications of earlier files are of no interest, drop them so
+        // they do not pile up
+        client.notifications().try_iter().for_each(drop);
+        let mut fallback_flags = false;
+        let mut observe = |notification: &Value| {
+            fallback_flags |= is_fallback_flags_notice(notification, file_uri, file_path);
+        };
+
+        // Send didOpen notification to tell clangd about the file
+        let did_open_params = json!({
+            "textDocument": {
+                "uri": file_uri,
+                "languageId": "cpp",
+                "version": 1,
+                "text": file_content
+            }
+        });
+        client
+            .send_notification("textDocument/didOpen", did_open_params)
+            .map_err(|e| {
+                format!(
+                    "Failed to send didOpen notification for '{}': {}",
                     file_path.display(),
                     e
-                ))?
-                .to_string_lossy()
-        );
+                )
+            })?;
 
-        // Send didOpen notification to tell clangd about the file
-        let did_open_notification = json!({
-            "jsonrpc": "2.0",
-            "method": "textDocument/didOpen",
-            "params": {
-                "textDocument": {
-                    "uri": file_uri,
-                    "languageId": "cpp",
-                    "version": 1,
-                    "text": file_content
-                }
+        // Symbols requested before clangd parsed the file can be incomplete.
+        // It publishes the diagnostics once parsing is done, so wait for them.
+        if let Some(timeout) = self.options.diagnostics_timeout {
+            if !client.wait_for_notification(
+                "textDocument/publishDiagnostics",
+                file_uri,
+                timeout,
+                &mut observe,
+            ) {
+                eprintln!(
+                    "WARN: clangd published no diagnostics for '{}' within {}s, symbols may be incomplete",
+                    file_path.display(),
+                    timeout.as_secs()
+                );
+            }
+        }
+
+        // Request the symbols in the file and wait for clangd's response
+        let document_symbol_params = json!({
+            "textDocument": {
+                "uri": file_uri
             }
         });
-        self.send_lsp_request(clangd_stdin, did_open_notification)
+        let result = client
+            .request("textDocument/documentSymbol", document_symbol_params)
+            .map_err(|e| {
+                format!(
+                    "Failed to read document symbols for '{}': {}",
+                    file_path.display(),
+                    e
+                )
+            })?;
+        let symbols: Vec<Symbol> = serde_json::from_value(result)
+            .map_err(|e| format!("Failed to parse document symbols from response: {}", e))?;
+        client.notifications().try_iter().for_each(|n| observe(&n));
+
+        // Extract chunks from the file based on the symbols
+        let chunks = self
+            .extract_chunks(file_path, file_content, &symbols)
             .map_err(|e| {
                 format!(
-                    "Failed to send didOpen notification for '{}': {}",
+                    "Failed to extract chunks from '{}': {}",
                     file_path.display(),
                     e
                 )
             })?;
+        Ok((chunks, fallback_flags))
+    }
 
-        // Send document symbol request to get the symbols in the file
-        let document_symbol_request = json!({
-            "jsonrpc": "2.0",
-            "id": 2,
-            "method": "textDocument/documentSymbol",
-            "params": {
-                "textDocument": {
-                    "uri": file_uri
-                }
-            }
-        });
-        self.send_lsp_request(clangd_stdin, document_symbol_request)
+    /// Starts clangd and initializes the LSP session
+    fn start_clangd(&self) -> Result<(Child, LspClient), Box<dyn Error>> {
+        // Open LSP log file
+        let lsp_log = LspLog::new(
+            self.lsp_log_file.clone(),
+            self.options.lsp_log_format,
+            self.options.lsp_log_max_size,
+        )?
+        .shared();
+
+        let mut clangd = self
+            .clangd_command()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
             .map_err(|e| {
                 format!(
-                    "Failed to send document symbol request for '{}': {}",
-                    file_path.display(),
-                    e
+                    "Failed to start clangd process at '{}': {}",
+                    self.clangd_path, e
                 )
             })?;
 
-        // Read and process clangd's response to extract symbols
-        let symbols = self.read_document_symbols(clangd_stdout).map_err(|e| {
+        let clangd_stdin = clangd.stdin.take().expect("Failed to open clangd stdin");
+        let clangd_stdout = clangd.stdout.take().expect("Failed to open clangd stdout");
+        let client = LspClient::new(
+            clangd_stdin,
+            clangd_stdout,
+            lsp_log,
+            self.options.lsp_transport,
+            self.options
+                .lsp_max_response_bytes
+                .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES),
+        );
+
+        self.initialize_session(&client)?;
+
+        Ok((clangd, client))
+    }
+
+    /// Performs the LSP handshake: sends `initialize`, checks the server's
+    /// response and confirms it with the `initialized` notification, before
+    /// which no document may be opened
+    fn initialize_session(&self, client: &LspClient) -> Result<(), Box<dyn Error>> {
+        // Send LSP initialization request and wait until clangd is ready
+        let initialize_params = json!({
+            "processId": std::process::id(),
+            "rootUri": format!("file://{}", fs::canonicalize(&self.project_dir)
+                .map_err(|e| format!("Failed to canonicalize project path '{}': {}", self.project_dir, e))?
+                .to_string_lossy()),
+            "capabilities": {
+                "textDocument": {
+                    "documentSymbol": {
+                        "hierarchicalDocumentSymbolSupport": true
+                    }
+                },
+                // Makes clangd report the progress of its background index
+                "window": {
+                    "workDoneProgress": true
+                }
+            }
+        });
+        // The response must be in before the initialized notification and
+        // any document is opened
+        let initialize_result = client
+            .request("initialize", initialize_params)
+            .map_err(|e| format!("Failed to initialize LSP session: {}", e))?;
+        check_initialize_result(&initialize_result)?;
+        client
+            .send_notification("initialized", json!({}))
+            .map_err(|e| format!("Failed to send LSP initialized notification: {}", e))?;
+
+        Ok(())
+    }
+
+    /// Chunks a single file and returns what was written. Without a client
+    /// the chunks are found heuristically.
+    fn process_file(
+        &self,
+        file_path: &Path,
+        client: Option<&LspClient>,
+        head_commit: Option<&str>,
+        seen_chunks: &mut HashSet<ChunkKey>,
+        sink: &mut dyn FnMut(ChunkFiles) -> Result<(), Box<dyn Error>>,
+    ) -> Result<FileStats, Box<dyn Error>> {
+        let file_content = fs::read_to_string(file_path)
+            .map_err(|e| format!("Failed to read file '{}': {}", file_path.display(), e))?;
+        let canonical_path = fs::canonicalize(file_path).map_err(|e| {
             format!(
-                "Failed to read document symbols for '{}': {}",
+                "Failed to canonicalize path '{}': {}",
                 file_path.display(),
                 e
             )
         })?;
+        let file_uri = format!("file://{}", canonical_path.to_string_lossy());
 
-        // Extract chunks from the file based on the symbols
-        let chunks = self
-            .extract_chunks(file_path, &file_content, &symbols)
-            .map_err(|e| {
+        let (mut chunks, no_compile_command) = match client {
+            Some(client) => self.lsp_chunks(client, file_path, &file_uri, &file_content)?,
+            None => (heuristic::heuristic_chunks(&file_content), false),
+        };
+        let duplicates = drop_duplicate_chunks(&canonical_path, &mut chunks, seen_chunks);
+        let lines: Vec<&str> = file_content.lines().collect();
+        add_context(&mut chunks, &lines, self.options.context_lines);
+
+        // Bound the number of chunks of huge (e.g. generated) files
+        let mut truncated = false;
+        if let Some(max) = self.options.max_chunks_per_file {
+            let dropped = limit_chunks(&mut chunks, max);
+            if dropped > 0 {
+                eprintln!(
+                    "WARN: '{}' has {} chunks, keeping the {} most significant",
+                    file_path.display(),
+                    chunks.len() + dropped,
+                    max
+                );
+                truncated = true;
+            }
+        }
+
+        if let Some(preview) = self.options.preview {
+            print_preview(&chunks, preview, &mut io::stdout())?;
+        }
+
+        // Record where the chunks come from, if the project is a git repository
+        let git = match head_commit {
+            Some(commit) => GitInfo {
+                commit: Some(commit.to_string()),
+                blob_sha: self.git_output(&["hash-object", &canonical_path.to_string_lossy()]),
+            },
+            None => GitInfo::default(),
+        };
+
+        // Hand the chunks over, usually to be written to the output files
+        sink(self.chunk_files(file_path, &chunks, &git))?;
+
+        if let Some(warning) = chunk_warning(file_path, chunks.len(), no_compile_command) {
+            eprintln!("{}", warning);
+        }
+
+        let calls = if let Some(client) = client.filter(|_| self.options.record_calls) {
+            outgoing_calls(client, &file_uri, &chunks).map_err(|e| {
                 format!(
-                    "Failed to extract chunks from '{}': {}",
+                    "Failed to read the calls in '{}': {}",
                     file_path.display(),
                     e
                 )
-            })?;
+            })?
+        } else {
+            Vec::new()
+        };
+
+        Ok(FileStats {
+            chunks: chunks.len(),
+            truncated,
+            duplicates,
+            calls,
+            no_compile_command,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutil::scratch_dir;
+
+    /// Parses a document symbol as clangd sends it
+    fn symbol(name: &str, kind: u8, range: [usize; 4], selection: [usize; 4]) -> Symbol {
+        let position =
+            |line: usize, character: usize| json!({ "line": line, "character": character });
+        serde_json::from_value(json!({
+            "name": name,
+            "kind": kind,
+            "range": { "start": position(range[0], range[1]), "end": position(range[2], range[3]) },
+            "selectionRange": {
+                "start": position(selection[0], selection[1]),
+                "end": position(selection[2], selection[3]),
+            },
+        }))
+        .unwrap()
+    }
+
+    fn test_chunker(project_dir: &Path, output_dir: &Path) -> Chunker {
+        Chunker::builder()
+            .project_dir(project_dir.to_string_lossy())
+            .output_dir(output_dir.to_string_lossy())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn chunk_dirs_are_unique_per_source_file() {
+        let project = Path::new("/project");
+        assert_eq!(
+            chunk_dir_name(Path::new("/project/src/foo.h"), project),
+            PathBuf::from("src/foo_h")
+        );
+        assert_eq!(
+            chunk_dir_name(Path::new("/project/src/foo.cpp"), project),
+            PathBuf::from("src/foo_cpp")
+        );
+        assert_eq!(
+            chunk_dir_name(Path::new("/project/lib/foo.cpp"), project),
+            PathBuf::from("lib/foo_cpp")
+        );
+        assert_eq!(
+            chunk_dir_name(Path::new("/elsewhere/foo.cpp"), project),
+            PathBuf::from("foo_cpp")
+        );
+    }
+
+    #[test]
+    fn has_body_looks_past_the_name() {
+        let lines = vec![
+            "int bar(std::vector<int> v = {});",
+            "int baz(int x) {",
+            "  return x;",
+            "}",
+        ];
+        let declaration = symbol("bar", SYMBOL_KIND_FUNCTION, [0, 0, 0, 32], [0, 4, 0, 7]);
+        let definition = symbol("baz", SYMBOL_KIND_FUNCTION, [1, 0, 3, 1], [1, 4, 1, 7]);
+        assert!(!symbol_has_body(&declaration, &lines));
+        assert!(symbol_has_body(&definition, &lines));
+    }
+
+    #[test]
+    fn public_only_skips_private_and_protected_members() {
+        let content = [
+            "class Account {",
+            "public:",
+            "  int balance() { return b; }",
+            "private:",
+            "  void audit() {}",
+            "protected:",
+            "  void log() {}",
+            "public:",
+            "  void deposit(int x) { b += x; }",
+            "  int b;",
+            "};",
+        ]
+        .join("\n");
+        let lines: Vec<&str> = content.lines().collect();
+        let method = |name: &str, line: usize| {
+            let column = lines[line].find(name).unwrap();
+            let selection = [line, column, line, column + name.len()];
+            symbol(
+                name,
+                SYMBOL_KIND_METHOD,
+                [line, 2, line, lines[line].len()],
+                selection,
+            )
+        };
+        let mut class = symbol("Account", SYMBOL_KIND_CLASS, [0, 0, 10, 1], [0, 6, 0, 13]);
+        class.children = vec![
+            method("balance", 2),
+            method("audit", 4),
+            method("log", 6),
+            method("deposit", 8),
+        ];
+        let symbols = [class];
+        let names = |public_only| {
+            let chunker = Chunker::builder()
+                .project_dir("/project")
+                .output_dir("/chunks")
+                .options(ChunkerOptions {
+                    public_only,
+                    ..Default::default()
+                })
+                .build()
+                .unwrap();
+            chunker
+                .extract_chunks(Path::new("/project/account.h"), &content, &symbols)
+                .unwrap()
+                .into_iter()
+                .map(|chunk| chunk.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(true),
+            ["Account", "Account::balance", "Account::deposit"]
+        );
+        assert_eq!(names(false).len(), 5);
+    }
+
+    #[test]
+    fn one_line_symbols_get_the_characters_of_their_range() {
+        let chunker = test_chunker(Path::new("/project"), Path::new("/chunks"));
+        let path = Path::new("/project/inline.h");
+        let chunk_content = |content: &str, range: [usize; 4], selection: [usize; 4]| {
+            let symbols = [symbol("twice", SYMBOL_KIND_FUNCTION, range, selection)];
+            let chunks = chunker.extract_chunks(path, content, &symbols).unwrap();
+            assert_eq!(chunks.len(), 1);
+            chunks[0].content.clone()
+        };
+
+        let content = "int x = 0; inline int twice(int a) { return 2 * a; } // twice\n";
+        assert_eq!(
+            chunk_content(content, [0, 11, 0, 52], [0, 22, 0, 27]),
+            "inline int twice(int a) { return 2 * a; }"
+        );
+
+        // The range of the last symbol of a file without final newline may
+        // end on the line after the last one
+        let content = "int x = 0;\nint twice(int a) { return 2 * a; }";
+        assert_eq!(
+            chunk_content(content, [1, 0, 2, 0], [1, 4, 1, 9]),
+            "int twice(int a) { return 2 * a; }"
+        );
+    }
+
+    #[test]
+    fn index_entries_match_the_schema() {
+        let project = scratch_dir("index-schema-project");
+        let output = scratch_dir("index-schema-output");
+        let chunker = test_chunker(&project, &output);
+        let chunk = |name: &str, start_line: usize, has_body: bool| CodeChunk {
+            name: name.to_string(),
+            content: String::new(),
+            start_line,
+            end_line: start_line + 2,
+            kind: "function".to_string(),
+            parent: None,
+            comment: None,
+            deprecated: false,
+            name_position: None,
+            signature: None,
+            context: None,
+            has_body,
+        };
+        let full = CodeChunk {
+            parent: Some("calc".to_string()),
+            deprecated: true,
+            signature: Some("int calc::add(int a, int b)".to_string()),
+            context: Some((0, 4)),
+            ..chunk("calc::add", 2, true)
+        };
+        let sources = [
+            ("calc.h", vec![chunk("calc::add", 0, false)]),
+            ("calc.cpp", vec![full, chunk("calc::sub", 6, true)]),
+        ];
+        let mut dirs = Vec::new();
+        for (file_name, chunks) in sources {
+            let files = chunker.chunk_files(&project.join(file_name), &chunks, &GitInfo::default());
+            write_chunk_files(&files).unwrap();
+            dirs.push(files.dir);
+        }
+        assert_eq!(link_declarations(&output, &dirs).unwrap(), 1);
+
+        // Every entry, as the object its lines amount to, matches the
+        // schema, and every property of the schema is written by some entry
+        let schema = &crate::schema::schemas()["chunk_index"];
+        let mut written = HashSet::new();
+        for dir in &dirs {
+            let index = fs::read_to_string(dir.join("_index.txt")).unwrap();
+            let mut entries: Vec<serde_json::Map<String, Value>> = Vec::new();
+            for line in index.lines() {
+                let Some((key, value)) = line.split_once(": ") else {
+                    continue;
+                };
+                let value = match value {
+                    "true" => json!(true),
+                    value => json!(value),
+                };
+                let key = key.trim().to_lowercase().replace(' ', "_");
+                if key == "chunk" {
+                    entries.push(serde_json::Map::new());
+                }
+                // Lines before the first chunk are the header of the file
+                if let Some(entry) = entries.last_mut() {
+                    entry.insert(key, value);
+                }
+            }
+            for entry in entries {
+                written.extend(entry.keys().cloned());
+                crate::schema::check_schema(&Value::Object(entry), schema, "chunk_index");
+            }
+        }
+        for name in schema["properties"].as_object().unwrap().keys() {
+            assert!(written.contains(name), "{} is never written", name);
+        }
+
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn links_header_declaration_to_implementation() {
+        let project = scratch_dir("link-declarations-project");
+        let output = scratch_dir("link-declarations-output");
+        let chunker = test_chunker(&project, &output);
+        let sources = [
+            ("foo.h", "int foo(int x = {});\n", [0, 0, 0, 19]),
+            (
+                "foo.cpp",
+                "int foo(int x) {\n  return x;\n}\n",
+                [0, 0, 2, 1],
+            ),
+        ];
+        let mut dirs = Vec::new();
+        for (file_name, content, range) in sources {
+            let path = project.join(file_name);
+            fs::write(&path, content).unwrap();
+            let symbols = [symbol("foo", SYMBOL_KIND_FUNCTION, range, [0, 4, 0, 7])];
+            let chunks = chunker.extract_chunks(&path, content, &symbols).unwrap();
+            let files = chunker.chunk_files(&path, &chunks, &GitInfo::default());
+            write_chunk_files(&files).unwrap();
+            dirs.push(files.dir);
+        }
+        assert_eq!(dirs, vec![output.join("foo_h"), output.join("foo_cpp")]);
 
-        // Write chunks to output files
-        self.write_chunks(file_path, &chunks).map_err(|e| {
+        assert_eq!(link_declarations(&output, &dirs).unwrap(), 1);
+        let header_index = fs::read_to_string(output.join("foo_h/_index.txt")).unwrap();
+        assert!(header_index.contains("  Declaration of: foo_cpp/001_foo_function_1.cpp\n"));
+        let source_index = fs::read_to_string(output.join("foo_cpp/_index.txt")).unwrap();
+        assert!(source_index.contains("  Definition of: foo_h/001_foo_function_1.cpp\n"));
+
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn fallback_flags_notices_of_the_file() {
+        let uri = "file:///project/src/foo.cpp";
+        let path = Path::new("/project/src/foo.cpp");
+        let log = |message: &str| json!({ "method": "window/logMessage", "params": { "type": 3, "message": message } });
+        let diagnostics = |uri: &str, message: &str| {
+            json!({
+                "method": "textDocument/publishDiagnostics",
+                "params": { "uri": uri, "diagnostics": [{ "message": message }] },
+            })
+        };
+
+        for hit in [
+            log("Failed to find compilation database for /project/src/foo.cpp"),
+            log("ASTWorker building file /project/src/foo.cpp version 1 with command clangd fallback"),
+            diagnostics(uri, "Failed to find compilation database for /project/src/foo.cpp"),
+        ] {
+            assert!(is_fallback_flags_notice(&hit, uri, path), "{}", hit);
+        }
+        for near_miss in [
+            // About another file
+            log("Failed to find compilation database for /project/src/bar.cpp"),
+            diagnostics(
+                "file:///project/src/bar.cpp",
+                "Failed to find compilation database for /project/src/bar.cpp",
+            ),
+            // About the file, but not about its flags
+            log("Indexed /project/src/foo.cpp (fallback symbols: 3)"),
+            diagnostics(uri, "use of undeclared identifier 'fallback'"),
+            diagnostics(
+                uri,
+                "no matching function for call to 'compilation_database'",
+            ),
+        ] {
+            assert!(
+                !is_fallback_flags_notice(&near_miss, uri, path),
+                "{}",
+                near_miss
+            );
+        }
+    }
+
+    #[test]
+    fn compile_db_lists_each_project_file_once() {
+        let project = crate::fsutil::scratch_dir("compile-db");
+        let outside = crate::fsutil::scratch_dir("compile-db-outside");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(project.join("build")).unwrap();
+        fs::write(project.join("src/a.cpp"), "int a() { return 1; }\n").unwrap();
+        fs::write(project.join("src/b.cpp"), "int b() { return 2; }\n").unwrap();
+        fs::write(outside.join("c.cpp"), "int c() { return 3; }\n").unwrap();
+        let build = project.join("build");
+        let compile_db = json!([
+            { "directory": build, "file": "../src/a.cpp" },
+            { "directory": project, "file": "src/a.cpp" },
+            { "directory": build, "file": project.join("src/b.cpp") },
+            { "directory": outside, "file": "c.cpp" },
+        ]);
+        fs::write(build.join("compile_commands.json"), compile_db.to_string()).unwrap();
+
+        let chunker = test_chunker(&project, &project.join("chunks"));
+        let files = chunker.read_compile_db(None).unwrap();
+        assert_eq!(
+            files,
+            vec![build.join("../src/a.cpp"), project.join("src/b.cpp")]
+        );
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn file_list_keeps_only_existing_cpp_sources() {
+        let project = crate::fsutil::scratch_dir("files-from");
+        for name in ["a.cpp", "b.h", "notes.txt"] {
+            fs::write(project.join(name), "int x;\n").unwrap();
+        }
+        let list = ["a.cpp", "bogus.cpp", "b.h", "notes.txt"]
+            .iter()
+            .map(|name| format!("  {}\n\n", project.join(name).display()))
+            .collect::<String>();
+
+        let chunker = test_chunker(&project, &project.join("chunks"));
+        let expected = vec![project.join("a.cpp"), project.join("b.h")];
+        assert_eq!(chunker.listed_files(&list, None), expected);
+        let list_file = project.join("files.txt");
+        fs::write(&list_file, &list).unwrap();
+        assert_eq!(
+            chunker
+                .read_file_list(&list_file.to_string_lossy(), None)
+                .unwrap(),
+            expected
+        );
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[test]
+    fn files_without_chunks_are_warned_about_and_indexed() {
+        let project = crate::fsutil::scratch_dir("zero-chunks");
+        let output = crate::fsutil::scratch_dir("zero-chunks-output");
+        let file = project.join("empty.cpp");
+        fs::write(&file, "// Nothing but a comment\n#include <vector>\n").unwrap();
+
+        let chunker = Chunker::builder()
+            .project_dir(project.to_string_lossy())
+            .output_dir(output.to_string_lossy())
+            .options(ChunkerOptions {
+                no_clangd: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let stats = chunker
+            .process_file(&file, None, None, &mut HashSet::new(), &mut |_| Ok(()))
+            .unwrap();
+        assert_eq!(stats.chunks, 0);
+        assert_eq!(
+            chunk_warning(&file, stats.chunks, false).unwrap(),
             format!(
-                "Failed to write chunks for '{}': {}",
-                file_path.display(),
-                e
+                "WARN: no chunks found in '{}', check its compile flags",
+                file.display()
             )
-        })?;
+        );
+        assert_eq!(chunk_warning(&file, 1, false), None);
 
-        Ok(())
+        chunker.run().unwrap();
+        let dir = output.join("empty_cpp");
+        let index = fs::read_to_string(dir.join("_index.txt")).unwrap();
+        assert!(index.contains("Number of chunks: 0"), "{}", index);
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&output).unwrap();
     }
 
-    fn read_document_symbols(
-        &self,
-        stdout: &mut BufReader<std::process::ChildStdout>,
-    ) -> Result<Vec<Symbol>, Box<dyn Error>> {
-        // Keep reading responses until we get the document symbol response
-        loop {
-            let response = self.read_lsp_response(stdout).map_err(|e| {
-                format!(
-                    "Failed to read LSP response while waiting for document symbols: {}",
-                    e
+    #[test]
+    fn exclude_tests_keeps_only_the_sources() {
+        let project = crate::fsutil::scratch_dir("exclude-tests");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(project.join("tests")).unwrap();
+        for name in [
+            "foo_test.cpp",
+            "test_bar.cc",
+            "src/baz.cpp",
+            "tests/main.cpp",
+        ] {
+            fs::write(project.join(name), "int x;\n").unwrap();
+        }
+        let chunker = |exclude_tests| {
+            Chunker::builder()
+                .project_dir(project.to_string_lossy())
+                .output_dir(project.join("chunks").to_string_lossy())
+                .options(ChunkerOptions {
+                    exclude_tests,
+                    ..Default::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        let mut files = chunker(false).find_cpp_source_files().unwrap();
+        assert_eq!(files.len(), 4);
+        files = chunker(true).find_cpp_source_files().unwrap();
+        assert_eq!(files, [project.join("src/baz.cpp")]);
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[tokio::test]
+    async fn git_commit_and_blob_sha_are_carried_to_the_documents() {
+        use crate::arango_mock::{accept_all, MockArango};
+        use crate::importer::{ImportOptions, Importer};
+        use std::os::unix::fs::PermissionsExt;
+
+        let project = crate::fsutil::scratch_dir("record-git");
+        let output = crate::fsutil::scratch_dir("record-git-chunks");
+        fs::write(
+            project.join("calc.cpp"),
+            "int add(int a, int b) {\n    return a + b;\n}\n",
+        )
+        .unwrap();
+        // Stands in for git, called as `git -C <project> <command> ...`
+        let git = project.join("fake-git");
+        fs::write(
+            &git,
+            "#!/bin/sh\ncase \"$3\" in\n  rev-parse) echo 0123abcd ;;\n  \
+             hash-object) echo 4567cdef ;;\n  *) exit 128 ;;\nesac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&git, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Chunker::new(
+            project.to_string_lossy().into_owned(),
+            output.to_string_lossy().into_owned(),
+            "clangd".to_string(),
+            git.to_string_lossy().into_owned(),
+            "lsp_communication.log".to_string(),
+            ChunkerOptions {
+                no_clangd: true,
+                record_git: true,
+                ..Default::default()
+            },
+        )
+        .run()
+        .unwrap();
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                allow_missing_vectors: true,
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let documents: Vec<Value> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .collect();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0]["commit"], "0123abcd");
+        assert_eq!(documents[0]["blob_sha"], "4567cdef");
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn output_dir_inside_the_project_is_not_scanned() {
+        let project = crate::fsutil::scratch_dir("nested-output");
+        let output = project.join("chunks");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(output.join("src/a_cpp")).unwrap();
+        fs::write(project.join("src/a.cpp"), "int a() { return 1; }\n").unwrap();
+        fs::write(
+            output.join("src/a_cpp/001_a_function_1.cpp"),
+            "int a() { return 1; }\n",
+        )
+        .unwrap();
+
+        let files = test_chunker(&project, &output)
+            .find_cpp_source_files()
+            .unwrap();
+        assert_eq!(files, [project.join("src/a.cpp")]);
+
+        // The other way round nothing could be excluded
+        let error = test_chunker(&project.join("src"), &project)
+            .find_cpp_source_files()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("must not be inside the output directory"));
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[test]
+    fn preview_lists_the_first_chunks() {
+        let source = "int add(int a, int b) {\n    return a + b;\n}\n\n\
+                      int sub(int a, int b) {\n    return a - b;\n}\n\n\
+                      int mul(int a, int b) {\n    return a * b;\n}\n";
+        let chunks = heuristic::heuristic_chunks(source);
+        let mut out = Vec::new();
+        print_preview(&chunks, 2, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().collect::<Vec<_>>(),
+            [
+                "  add (function, lines 1-3): int add(int a, int b) {",
+                "  sub (function, lines 5-7): int sub(int a, int b) {",
+                "  ... and 1 more",
+            ]
+        );
+    }
+
+    #[test]
+    fn clangd_args_are_appended_in_order() {
+        let chunker = Chunker::builder()
+            .project_dir("/project")
+            .output_dir("/chunks")
+            .clangd_path("/opt/llvm/bin/clangd")
+            .options(ChunkerOptions {
+                clangd_args: vec![
+                    "--query-driver=/usr/bin/g++".to_string(),
+                    "--header-insertion=never".to_string(),
+                    "-j=8".to_string(),
+                ],
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let command = chunker.clangd_command();
+        assert_eq!(command.get_program(), "/opt/llvm/bin/clangd");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "--compile-commands-dir=build",
+                "--log=verbose",
+                "--background-index",
+                "--query-driver=/usr/bin/g++",
+                "--header-insertion=never",
+                "-j=8",
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_refer_to_their_neighbors_in_line_order() {
+        let source = "int add(int a, int b) {\n    return a + b;\n}\n\n\
+                      int sub(int a, int b) {\n    return a - b;\n}\n\n\
+                      int mul(int a, int b) {\n    return a * b;\n}\n";
+        let mut chunks = heuristic::heuristic_chunks(source);
+        chunks.reverse();
+        let chunker = test_chunker(Path::new("/project"), Path::new("/chunks"));
+        let files =
+            chunker.chunk_files(Path::new("/project/calc.cpp"), &chunks, &GitInfo::default());
+
+        let entry = |chunk_file: &str| {
+            files
+                .index
+                .split("---\n")
+                .find(|entry| entry.starts_with(&format!("Chunk: {}\n", chunk_file)))
+                .unwrap()
+                .to_string()
+        };
+        let sub = entry("002_sub_function_5.cpp");
+        assert!(sub.contains("  Prev: add\n"), "{}", sub);
+        assert!(sub.contains("  Next: mul\n"), "{}", sub);
+        let add = entry("001_add_function_1.cpp");
+        assert!(
+            !add.contains("Prev:") && add.contains("  Next: sub\n"),
+            "{}",
+            add
+        );
+        let mul = entry("003_mul_function_9.cpp");
+        assert!(
+            mul.contains("  Prev: sub\n") && !mul.contains("Next:"),
+            "{}",
+            mul
+        );
+    }
+
+    #[test]
+    fn max_chunks_per_file_keeps_the_largest_chunks() {
+        let project = crate::fsutil::scratch_dir("max-chunks");
+        let file = project.join("generated.cpp");
+        let mut source = String::new();
+        for (name, body_lines) in [("a", 1), ("b", 4), ("c", 2), ("d", 5), ("e", 1)] {
+            source.push_str(&format!("int {}() {{\n", name));
+            for _ in 0..body_lines {
+                source.push_str("    step();\n");
+            }
+            source.push_str("}\n\n");
+        }
+        fs::write(&file, source).unwrap();
+
+        let chunker = Chunker::builder()
+            .project_dir(project.to_string_lossy())
+            .output_dir(project.join("chunks").to_string_lossy())
+            .options(ChunkerOptions {
+                no_clangd: true,
+                max_chunks_per_file: Some(2),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let mut written = Vec::new();
+        let stats = chunker
+            .process_file(&file, None, None, &mut HashSet::new(), &mut |files| {
+                written.extend(files.files.into_iter().map(|file| file.path));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(stats.chunks, 2);
+        assert!(stats.truncated);
+        let names: Vec<_> = written
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["001_b_function_5.cpp", "002_d_function_17.cpp"]);
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[test]
+    fn validation_flags_chunks_not_matching_their_span() {
+        let output = crate::fsutil::scratch_dir("validate-chunks");
+        let source = "int add(int a, int b) {\n    return a + b;\n}\n\n\
+                      int sub(int a, int b) {\n    return a - b;\n}\n";
+        let chunker = test_chunker(Path::new("/project"), &output);
+        let files = chunker.chunk_files(
+            Path::new("/project/calc.cpp"),
+            &heuristic::heuristic_chunks(source),
+            &GitInfo::default(),
+        );
+        write_chunk_files(&files).unwrap();
+
+        let mut problems = Vec::new();
+        assert_eq!(
+            validate_chunk_dir(&files.dir, false, &mut problems).unwrap(),
+            2
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        let sub = files.dir.join("002_sub_function_5.cpp");
+        fs::write(
+            &sub,
+            "int sub(int a, int b) {\n    return a - b;\n}\n// extra",
+        )
+        .unwrap();
+        assert_eq!(
+            validate_chunk_dir(&files.dir, false, &mut problems).unwrap(),
+            2
+        );
+        assert_eq!(
+            problems,
+            [format!(
+                "'{}' has 4 lines but spans lines 5-7 (3 lines)",
+                sub.display()
+            )]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn only_public_headers_chunks_the_include_tree() {
+        let project = crate::fsutil::scratch_dir("public-headers");
+        let output = crate::fsutil::scratch_dir("public-headers-chunks");
+        for dir in ["include/lib/detail", "src"] {
+            fs::create_dir_all(project.join(dir)).unwrap();
+        }
+        for name in [
+            "include/lib/api.h",
+            "include/lib/detail/impl.h",
+            "src/api.cpp",
+            "src/internal.h",
+        ] {
+            fs::write(project.join(name), "int api();\nint api() { return 1; }\n").unwrap();
+        }
+
+        Chunker::builder()
+            .project_dir(project.to_string_lossy())
+            .output_dir(output.to_string_lossy())
+            .options(ChunkerOptions {
+                no_clangd: true,
+                only_public_headers: Some(PathBuf::from("include")),
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        let chunk_dirs: Vec<String> = WalkDir::new(&output)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name() == "_index.txt")
+            .map(|entry| {
+                let dir = entry
+                    .path()
+                    .parent()
+                    .unwrap()
+                    .strip_prefix(&output)
+                    .unwrap();
+                dir.to_string_lossy().into_owned()
+            })
+            .collect();
+        assert_eq!(chunk_dirs, ["include/lib/api_h"]);
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn names_and_signatures_follow_the_selection_range() {
+        let content = "struct Vec {\n    bool operator == (const Vec& other) const {\n        \
+                       return true;\n    }\n};\nint größe() {\n    return 1;\n}\n";
+        let mut class = symbol("Vec", SYMBOL_KIND_CLASS, [0, 0, 4, 1], [0, 7, 0, 10]);
+        class.children.push(symbol(
+            "operator==",
+            SYMBOL_KIND_METHOD,
+            [1, 4, 3, 5],
+            [1, 9, 1, 20],
+        ));
+        // The selection counts UTF-16 units, the name is left to it
+        let function = symbol("", SYMBOL_KIND_FUNCTION, [5, 0, 7, 1], [5, 4, 5, 9]);
+        let chunker = test_chunker(Path::new("/project"), Path::new("/chunks"));
+        let chunks = chunker
+            .extract_chunks(Path::new("/project/vec.cpp"), content, &[class, function])
+            .unwrap();
+
+        let summary: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.name.as_str(),
+                    chunk.name_position,
+                    chunk.signature.as_deref(),
                 )
-            })?;
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("Vec", Some((0, 7)), Some("struct Vec")),
+                (
+                    "Vec::operator==",
+                    Some((1, 9)),
+                    Some("bool operator == (const Vec& other) const")
+                ),
+                ("größe", Some((5, 4)), Some("int größe()")),
+            ]
+        );
+    }
+
+    #[test]
+    fn context_lines_extend_chunks_up_to_the_file_and_their_neighbors() {
+        let source = "#include <vector>\nusing Vec = std::vector<int>;\n\
+                      int add(int a, int b) {\n    return a + b;\n}\n\n\
+                      int sub(int a, int b) {\n    return a - b;\n}\n";
+        let lines: Vec<&str> = source.lines().collect();
+        let mut chunks = heuristic::heuristic_chunks(source);
+        add_context(&mut chunks, &lines, 2);
+
+        let spans: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.name.as_str(),
+                    chunk.start_line,
+                    chunk.end_line,
+                    chunk.context,
+                )
+            })
+            .collect();
+        // Two lines before add reach the start of the file, after it and
+        // before sub the blank line between them, sub ends with the file
+        assert_eq!(
+            spans,
+            [("add", 2, 4, Some((0, 5))), ("sub", 6, 8, Some((5, 8)))]
+        );
+        assert!(chunks[0]
+            .content
+            .starts_with("#include <vector>\nusing Vec"));
+        assert!(chunks[1].content.ends_with("return a - b;\n}"));
+
+        let mut unchanged = heuristic::heuristic_chunks(source);
+        add_context(&mut unchanged, &lines, 0);
+        assert!(unchanged.iter().all(|chunk| chunk.context.is_none()));
+    }
+
+    #[test]
+    fn include_globals_chunks_file_scope_constants() {
+        let content = "constexpr int kPrimes[] = {2, 3, 5, 7};\n\
+                       struct Table {\n    int size;\n};\n";
+        let symbols = || {
+            let mut class = symbol("Table", SYMBOL_KIND_CLASS, [1, 0, 3, 1], [1, 7, 1, 12]);
+            class.children.push(symbol(
+                "size",
+                SYMBOL_KIND_VARIABLE,
+                [2, 4, 2, 12],
+                [2, 8, 2, 12],
+            ));
+            vec![
+                symbol(
+                    "kPrimes",
+                    SYMBOL_KIND_CONSTANT,
+                    [0, 0, 0, 38],
+                    [0, 14, 0, 21],
+                ),
+                class,
+            ]
+        };
+        let chunks = |include_globals| {
+            Chunker::builder()
+                .project_dir("/project")
+                .output_dir("/chunks")
+                .options(ChunkerOptions {
+                    include_globals,
+                    ..Default::default()
+                })
+                .build()
+                .unwrap()
+                .extract_chunks(Path::new("/project/primes.cpp"), content, &symbols())
+                .unwrap()
+                .into_iter()
+                .map(|chunk| (chunk.name, chunk.kind, chunk.content))
+                .collect::<Vec<_>>()
+        };
+
+        let table = || {
+            (
+                "Table".to_string(),
+                "class".to_string(),
+                "struct Table {\n    int size;\n};".to_string(),
+            )
+        };
+        assert_eq!(chunks(false), [table()]);
+        // Members like Table::size are no globals
+        let primes = (
+            "kPrimes".to_string(),
+            "variable".to_string(),
+            "constexpr int kPrimes[] = {2, 3, 5, 7}".to_string(),
+        );
+        assert_eq!(chunks(true), [primes, table()]);
+    }
+
+    #[test]
+    fn limit_files_chunks_only_the_first_files() {
+        let project = crate::fsutil::scratch_dir("limit-files");
+        let output = crate::fsutil::scratch_dir("limit-files-chunks");
+        for name in ["d", "b", "a", "c"] {
+            fs::write(
+                project.join(format!("{}.cpp", name)),
+                format!("int {}() {{ return 1; }}\n", name),
+            )
+            .unwrap();
+        }
+
+        let chunker = Chunker::builder()
+            .project_dir(project.to_string_lossy())
+            .output_dir(output.to_string_lossy())
+            .options(ChunkerOptions {
+                no_clangd: true,
+                limit_files: Some(2),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        chunker.run().unwrap();
+
+        let mut chunked: Vec<_> = fs::read_dir(&output)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        chunked.sort();
+        assert_eq!(chunked, ["a_cpp", "b_cpp"]);
+        fs::remove_dir_all(&project).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn calls_between_chunks_are_written_as_relations() {
+        let dir = scratch_dir("relations");
+        let source = dir.join("calc.cpp");
+        let chunk = |name: &str, start_line: usize, end_line: usize| CodeChunk {
+            name: name.to_string(),
+            content: String::new(),
+            start_line,
+            end_line,
+            kind: "function".to_string(),
+            parent: None,
+            comment: None,
+            deprecated: false,
+            name_position: Some((start_line, 4)),
+            signature: None,
+            context: None,
+            has_body: true,
+        };
+        // int add(int a, int b) { return a + b; } on line 0 and
+        // int twice(int a) { return add(a, a); } on line 2
+        let mut chunks = vec![chunk("add", 0, 0), chunk("twice", 2, 2)];
+
+        // A server with the call hierarchy of the two functions
+        let (server_in, client_out) = std::io::pipe().unwrap();
+        let (client_in, mut server_out) = std::io::pipe().unwrap();
+        let file_uri = format!("file://{}", source.display());
+        let server = std::thread::spawn({
+            let file_uri = file_uri.clone();
+            move || {
+                use std::io::{BufRead, Write};
+                let mut lines = std::io::BufReader::new(server_in).lines();
+                for _ in 0..4 {
+                    let request: Value =
+                        serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+                    let params = &request["params"];
+                    let result = match request["method"].as_str().unwrap() {
+                        "textDocument/prepareCallHierarchy" => {
+                            let line = &params["position"]["line"];
+                            json!([{ "name": if line == 0 { "add" } else { "twice" } }])
+                        }
+                        _ if params["item"]["name"] == "twice" => json!([{
+                            "to": {
+                                "uri": file_uri,
+                                "range": { "start": { "line": 0, "character": 0 } },
+                                "selectionRange": { "start": { "line": 0, "character": 4 } },
+                            }
+                        }]),
+                        _ => json!([]),
+                    };
+                    let response =
+                        json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+                    writeln!(server_out, "{}", response).unwrap();
+                }
+            }
+        });
+        let log = LspLog::new(String::new(), LspLogFormat::None, None)
+            .unwrap()
+            .shared();
+        let client = LspClient::new(
+            client_out,
+            client_in,
+            log,
+            LspTransport::Ndjson,
+            DEFAULT_MAX_MESSAGE_BYTES,
+        );
+
+        let calls = outgoing_calls(&client, &file_uri, &chunks).unwrap();
+        server.join().unwrap();
+        let mut seen = HashSet::new();
+        drop_duplicate_chunks(&source, &mut chunks, &mut seen);
+        let relations = dir.join(RELATIONS_FILE);
+        assert_eq!(write_relations(&relations, &calls, &seen).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(&relations).unwrap(),
+            "{\"callee\":\"add\",\"caller\":\"twice\"}\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-            // Check if this is the document symbol response (id: 2)
-            if let Some(id) = response.get("id") {
-                if id.as_u64() == Some(2) && response.get("result").is_some() {
-                    return serde_json::from_value(response["result"].clone()).map_err(|e| {
-                        Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            format!("Failed to parse document symbols from response: {}", e),
-                        )) as Box<dyn Error>
-                    });
+    #[test]
+    fn symbols_are_requested_once_the_diagnostics_are_in() {
+        let chunker = Chunker::builder()
+            .project_dir("/project")
+            .output_dir("/chunks")
+            .options(ChunkerOptions {
+                diagnostics_timeout: Some(Duration::from_secs(5)),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let file_path = Path::new("/project/calc.cpp");
+        let file_uri = "file:///project/calc.cpp";
+
+        // The server time-stamps what it receives, and publishes the
+        // diagnostics of the file only a while after it was opened, those of
+        // another file first
+        let (server_in, client_out) = std::io::pipe().unwrap();
+        let (client_in, mut server_out) = std::io::pipe().unwrap();
+        let (received_tx, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            use std::io::BufRead;
+            for line in std::io::BufReader::new(server_in).lines() {
+                let message: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                if received_tx.send((Instant::now(), message)).is_err() {
+                    break;
                 }
             }
+        });
+        let server = std::thread::spawn(move || {
+            use std::io::Write;
+            let (_, did_open) = received.recv().unwrap();
+            assert_eq!(did_open["method"], "textDocument/didOpen");
+            let diagnostics = |uri: &str| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })
+            };
+            writeln!(server_out, "{}", diagnostics("file:///project/other.cpp")).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            let published = Instant::now();
+            writeln!(server_out, "{}", diagnostics(file_uri)).unwrap();
 
-            // For debugging
-            let log_entry = format!(
-                "Got response with id: {:?}, method: {:?}",
-                response.get("id"),
-                response.get("method")
+            let (requested, request) = received.recv().unwrap();
+            assert_eq!(request["method"], "textDocument/documentSymbol");
+            assert!(
+                requested >= published,
+                "symbols requested before the diagnostics"
             );
-            if let Ok(mut lsp_log) = File::options().append(true).open(&self.lsp_log_file) {
-                write!(lsp_log, "{}", log_entry)
-                    .map_err(|e| format!("Failed to write to LSP log file: {}", e))?;
+            let response = json!({ "jsonrpc": "2.0", "id": request["id"], "result": [] });
+            writeln!(server_out, "{}", response).unwrap();
+        });
+        let log = LspLog::new(String::new(), LspLogFormat::None, None)
+            .unwrap()
+            .shared();
+        let client = LspClient::new(
+            client_out,
+            client_in,
+            log,
+            LspTransport::Ndjson,
+            DEFAULT_MAX_MESSAGE_BYTES,
+        );
+
+        let (chunks, _) = chunker
+            .lsp_chunks(&client, file_path, file_uri, "int x;\n")
+            .unwrap();
+        server.join().unwrap();
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn files_are_opened_only_after_the_handshake() {
+        let project = scratch_dir("handshake");
+        let chunker = test_chunker(&project, &project.join("chunks"));
+
+        // The server time-stamps what it receives and answers `initialize`
+        // only after a while
+        let (server_in, client_out) = std::io::pipe().unwrap();
+        let (client_in, mut server_out) = std::io::pipe().unwrap();
+        let (received_tx, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            use std::io::BufRead;
+            for line in std::io::BufReader::new(server_in).lines() {
+                let message: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                if received_tx.send((Instant::now(), message)).is_err() {
+                    break;
+                }
+            }
+        });
+        let server = std::thread::spawn(move || {
+            use std::io::Write;
+            let mut methods = Vec::new();
+            let mut answered = None;
+            while let Ok((at, message)) = received.recv() {
+                let method = message["method"].as_str().unwrap().to_string();
+                let result = match method.as_str() {
+                    "initialize" => {
+                        std::thread::sleep(Duration::from_millis(100));
+                        answered = Some(Instant::now());
+                        json!({ "capabilities": { "documentSymbolProvider": true } })
+                    }
+                    "textDocument/documentSymbol" => json!([]),
+                    _ => {
+                        let answered = answered.expect("message before the initialize response");
+                        assert!(at >= answered, "{} sent before the response", method);
+                        methods.push(method);
+                        continue;
+                    }
+                };
+                methods.push(method);
+                let response = json!({ "jsonrpc": "2.0", "id": message["id"], "result": result });
+                writeln!(server_out, "{}", response).unwrap();
+                if methods.last().unwrap() == "textDocument/documentSymbol" {
+                    break;
+                }
+            }
+            methods
+        });
+        let log = LspLog::new(String::new(), LspLogFormat::None, None)
+            .unwrap()
+            .shared();
+        let client = LspClient::new(
+            client_out,
+            client_in,
+            log,
+            LspTransport::Ndjson,
+            DEFAULT_MAX_MESSAGE_BYTES,
+        );
+
+        chunker.initialize_session(&client).unwrap();
+        let file_path = project.join("calc.cpp");
+        let file_uri = format!("file://{}", file_path.display());
+        chunker
+            .lsp_chunks(&client, &file_path, &file_uri, "int x;\n")
+            .unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "initialize",
+                "initialized",
+                "textDocument/didOpen",
+                "textDocument/documentSymbol"
+            ]
+        );
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[test]
+    fn deeply_nested_symbols_stop_at_the_depth_cap() {
+        // Namespaces nested far deeper than any cap, built bottom up
+        let content = "namespace n {\n}\n";
+        let nested = |levels: usize| {
+            let mut tree = symbol("n", SYMBOL_KIND_NAMESPACE, [0, 0, 1, 1], [0, 10, 0, 11]);
+            for _ in 1..levels {
+                let mut parent = symbol("n", SYMBOL_KIND_NAMESPACE, [0, 0, 1, 1], [0, 10, 0, 11]);
+                parent.children.push(tree);
+                tree = parent;
             }
+            vec![tree]
+        };
+        let path = Path::new("/project/deep.cpp");
+
+        // The top level and as many levels below it as the cap allows
+        for (max_symbol_depth, expected) in [(None, DEFAULT_MAX_SYMBOL_DEPTH + 1), (Some(3), 4)] {
+            let chunker = Chunker::builder()
+                .project_dir("/project")
+                .output_dir("/chunks")
+                .options(ChunkerOptions {
+                    max_symbol_depth,
+                    ..Default::default()
+                })
+                .build()
+                .unwrap();
+            let chunks = chunker
+                .extract_chunks(path, content, &nested(2000))
+                .unwrap();
+            assert_eq!(chunks.len(), expected, "{:?}", max_symbol_depth);
+            let deepest = chunks.last().unwrap();
+            assert_eq!(deepest.name.split("::").count(), expected);
         }
+
+        // Trees within the cap are chunked completely
+        let chunker = test_chunker(Path::new("/project"), Path::new("/chunks"));
+        let chunks = chunker.extract_chunks(path, content, &nested(5)).unwrap();
+        assert_eq!(chunks.len(), 5);
+    }
+
+    #[test]
+    fn index_progress_is_followed_to_its_end() {
+        let client_for = |messages: &[Value]| {
+            let input: String = messages.iter().map(|m| format!("{}\n", m)).collect();
+            let log = LspLog::new(String::new(), LspLogFormat::None, None)
+                .unwrap()
+                .shared();
+            LspClient::new(
+                std::io::sink(),
+                std::io::Cursor::new(input.into_bytes()),
+                log,
+                LspTransport::Ndjson,
+                DEFAULT_MAX_MESSAGE_BYTES,
+            )
+        };
+        let progress = |token: &str, kind: &str| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": "$/progress",
+                "params": { "token": token, "value": { "kind": kind, "message": "1/2" } },
+            })
+        };
+        let short = Duration::from_millis(200);
+
+        let client = client_for(&[
+            json!({ "jsonrpc": "2.0", "id": 0, "method": "window/workDoneProgress/create",
+                    "params": { "token": INDEX_PROGRESS_TOKEN } }),
+            progress(INDEX_PROGRESS_TOKEN, "begin"),
+            progress("other", "end"),
+            progress(INDEX_PROGRESS_TOKEN, "report"),
+            progress(INDEX_PROGRESS_TOKEN, "end"),
+        ]);
+        assert_eq!(
+            wait_for_index_progress(&client, short, short, ProgressMode::Off),
+            IndexProgress::Done
+        );
+
+        let client = client_for(&[
+            progress(INDEX_PROGRESS_TOKEN, "begin"),
+            progress("other", "end"),
+        ]);
+        assert_eq!(
+            wait_for_index_progress(&client, short, short, ProgressMode::Off),
+            IndexProgress::Unfinished
+        );
+
+        let client = client_for(&[progress("other", "begin")]);
+        assert_eq!(
+            wait_for_index_progress(&client, short, short, ProgressMode::Off),
+            IndexProgress::NotStarted
+        );
+    }
+
+    #[test]
+    fn clangd_runs_with_background_index() {
+        let chunker = test_chunker(Path::new("/project"), Path::new("/chunks"));
+        let command = chunker.clangd_command();
+        let args: Vec<_> = command.get_args().collect();
+        assert!(args.contains(&std::ffi::OsStr::new("--background-index")));
+    }
+
+    #[test]
+    fn inline_namespaces_are_told_by_the_tokens_before_the_name() {
+        let is_inline = |source: &str, name: &str| {
+            let lines: Vec<&str> = source.lines().collect();
+            let (line, text) = lines
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, text)| text.contains(&format!("{} {{", name)))
+                .unwrap();
+            let character = text.find(&format!("{} {{", name)).unwrap();
+            let namespace = symbol(
+                name,
+                SYMBOL_KIND_NAMESPACE,
+                [0, 0, lines.len(), 0],
+                [line, character, line, character + name.len()],
+            );
+            is_inline_namespace(&namespace, &lines)
+        };
+
+        assert!(is_inline("inline namespace v1 {\n}", "v1"));
+        assert!(is_inline("namespace A { inline namespace v1 {\n} }", "v1"));
+        assert!(!is_inline("namespace A { inline namespace v1 {\n} }", "A"));
+        assert!(is_inline("inline /* ABI */ namespace v2 {\n}", "v2"));
+        assert!(is_inline("inline\nnamespace v3 {\n}", "v3"));
+        assert!(is_inline("namespace A::inline v4 {\n}", "v4"));
+        assert!(!is_inline(
+            "namespace v5 { // not an inline namespace\n}",
+            "v5"
+        ));
+        assert!(!is_inline(
+            "// inline namespace v6 {\nnamespace v6 {\n}",
+            "v6"
+        ));
+        assert!(!is_inline("/* inline namespace */ namespace v7 {\n}", "v7"));
+    }
+
+    #[test]
+    fn duplicate_chunks_are_dropped_across_the_run() {
+        let chunk = |name: &str, start_line: usize, end_line: usize| CodeChunk {
+            name: name.to_string(),
+            content: String::new(),
+            start_line,
+            end_line,
+            kind: "function".to_string(),
+            parent: None,
+            comment: None,
+            deprecated: false,
+            name_position: None,
+            signature: None,
+            context: None,
+            has_body: true,
+        };
+        let header = Path::new("/project/include/util.h");
+        let mut seen = HashSet::new();
+
+        let mut first = vec![chunk("util::add", 3, 5), chunk("util::sub", 7, 9)];
+        assert_eq!(drop_duplicate_chunks(header, &mut first, &mut seen), 0);
+
+        // The same header reported again, as by another translation unit
+        let mut again = vec![chunk("util::add", 3, 5), chunk("util::mul", 11, 13)];
+        assert_eq!(drop_duplicate_chunks(header, &mut again, &mut seen), 1);
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].name, "util::mul");
+
+        // The same range of another file is another symbol
+        let mut other = vec![chunk("add", 3, 5)];
+        let other_header = Path::new("/project/include/other.h");
+        assert_eq!(
+            drop_duplicate_chunks(other_header, &mut other, &mut seen),
+            0
+        );
+        assert_eq!(seen.len(), 4);
+    }
+
+    #[test]
+    fn symbols_sharing_a_line_are_kept_once_each() {
+        // struct S { int f() { return 1; } }; in a header that two
+        // translation units include
+        let chunk = |name: &str, character: usize| CodeChunk {
+            name: name.to_string(),
+            content: String::new(),
+            start_line: 4,
+            end_line: 4,
+            kind: "function".to_string(),
+            parent: None,
+            comment: None,
+            deprecated: false,
+            name_position: Some((4, character)),
+            signature: None,
+            context: None,
+            has_body: true,
+        };
+        let header = Path::new("/project/include/s.h");
+        let mut seen = HashSet::new();
+        let mut first = vec![chunk("S", 7), chunk("S::f", 15)];
+        assert_eq!(drop_duplicate_chunks(header, &mut first, &mut seen), 0);
+        let names: Vec<&str> = first.iter().map(|chunk| chunk.name.as_str()).collect();
+        assert_eq!(names, ["S", "S::f"]);
+
+        let mut second = vec![chunk("S", 7), chunk("S::f", 15)];
+        assert_eq!(drop_duplicate_chunks(header, &mut second, &mut seen), 2);
+        assert!(second.is_empty());
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn leading_comment_tracks_block_comments() {
+        let comment = |source: &str| {
+            let lines: Vec<&str> = source.lines().collect();
+            leading_comment(&lines, lines.len() - 1)
+        };
+
+        assert_eq!(
+            comment("int x;\n// Adds.\n// Really.\nint add();").as_deref(),
+            Some("// Adds.\n// Really.")
+        );
+        assert_eq!(
+            comment("/**\n * Adds.\n\n * Really.\n */\nint add();").as_deref(),
+            Some("/**\n * Adds.\n\n * Really.\n */")
+        );
+        assert_eq!(
+            comment("// Old.\n\n/* Adds. */\nint add();").as_deref(),
+            Some("/* Adds. */")
+        );
+        assert_eq!(comment("  *p = 0;\nint add();"), None);
+        assert_eq!(
+            comment("int x = 0; /* counter\n   of adds */\nint add();"),
+            None
+        );
+        assert_eq!(comment("x = 1; /* set */\nint add();"), None);
+        assert_eq!(comment("// Adds.\n\nint add();"), None);
+    }
+
+    #[test]
+    fn builder_requires_project_and_output_directories() {
+        let error = |builder: ChunkerBuilder| builder.build().err().unwrap().to_string();
+        assert_eq!(
+            error(Chunker::builder().output_dir("out")),
+            "Chunker needs a project directory"
+        );
+        assert_eq!(
+            error(Chunker::builder().project_dir("").output_dir("out")),
+            "Chunker needs a project directory"
+        );
+        assert_eq!(
+            error(Chunker::builder().project_dir("project")),
+            "Chunker needs an output directory"
+        );
+        assert!(Chunker::builder()
+            .project_dir("project")
+            .output_dir("out")
+            .build()
+            .is_ok());
     }
 }
@@ -0,0 +1,119 @@
+use serde_json::{json, Value};
+use std::error::Error;
+
+use cppembedder::arango::{search_view_name, ArangoArgs, ArangoClient, META_KEY};
+
+/// Arguments of the `cleanup` command
+#[derive(clap::Args, Debug)]
+pub struct CleanupArgs {
+    #[clap(flatten)]
+    arango: ArangoArgs,
+
+    /// Only list the expired collections instead of dropping them
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// Drops the collections of the collection prefix whose `_meta` document,
+/// written by imports with --expires-in-hours, says they expired, together
+/// with their search views. Collections without expiry are kept.
+pub async fn run(args: CleanupArgs) -> Result<(), Box<dyn Error>> {
+    let prefix = args
+        .arango
+        .collection_prefix
+        .as_deref()
+        .ok_or("cleanup needs --collection-prefix to select the collections")?;
+    let prefix = format!("{}_", prefix);
+    let client = args.arango.client();
+
+    let response = client
+        .send(|client, url| {
+            client
+                .get(format!("{}/_api/collection", url))
+                .query(&[("excludeSystem", "true")])
+        })
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to list collections: {}", response.text().await?).into());
+    }
+    let listing: Value = response.json().await?;
+    let mut collections: Vec<&str> = listing["result"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|collection| collection["name"].as_str())
+        .filter(|name| name.starts_with(&prefix))
+        .collect();
+    collections.sort();
+
+    let mut dropped = 0;
+    for collection in collections {
+        let Some(expires_at) = expired_at(&client, collection).await? else {
+            continue;
+        };
+        if args.dry_run {
+            println!("{} expired at {}", collection, expires_at);
+            continue;
+        }
+        drop_collection(&client, collection).await?;
+        println!("Dropped {}, expired at {}", collection, expires_at);
+        dropped += 1;
+    }
+    if !args.dry_run {
+        println!("Dropped {} expired collections", dropped);
+    }
+    Ok(())
+}
+
+/// Returns when the collection expired if its expiry is past, going by the
+/// server's clock
+async fn expired_at(
+    client: &ArangoClient,
+    collection: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let results = client
+        .query(&json!({
+            "query": "FOR doc IN @@chunks FILTER doc._key == @key \
+                      FILTER doc.expires_at != null AND DATE_TIMESTAMP(doc.expires_at) < DATE_NOW() \
+                      RETURN doc.expires_at",
+            "bindVars": { "@chunks": collection, "key": META_KEY },
+        }))
+        .await
+        .map_err(|e| format!("Failed to read the expiry of {}: {}", collection, e))?;
+    Ok(results
+        .first()
+        .and_then(|expires_at| expires_at.as_str())
+        .map(str::to_string))
+}
+
+/// Drops the collection and its search view, if there is one
+pub async fn drop_collection(
+    client: &ArangoClient,
+    collection: &str,
+) -> Result<(), Box<dyn Error>> {
+    let view = search_view_name(collection);
+    let response = client
+        .send(|client, url| client.delete(format!("{}/_api/view/{}", url, view)))
+        .await?;
+    if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+        return Err(format!(
+            "Failed to drop search view {}: {}",
+            view,
+            response.text().await?
+        )
+        .into());
+    }
+
+    let response = client
+        .send(|client, url| client.delete(format!("{}/_api/collection/{}", url, collection)))
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Failed to drop collection {}: {}",
+            collection,
+            response.text().await?
+        )
+        .into());
+    }
+    Ok(())
+}
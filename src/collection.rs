@@ -0,0 +1,96 @@
+use std::error::Error;
+
+/// Maximal length of an ArangoDB collection name in bytes
+const MAX_COLLECTION_NAME_LEN: usize = 256;
+
+/// Checks a collection name against ArangoDB's traditional naming rules:
+/// it must start with a letter and consist of letters, digits, underscores
+/// and dashes only
+pub fn validate_collection_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || name.len() > MAX_COLLECTION_NAME_LEN {
+        return Err(format!(
+            "Invalid collection name '{}': must have between 1 and {} characters",
+            name, MAX_COLLECTION_NAME_LEN
+        )
+        .into());
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(format!(
+            "Invalid collection name '{}': must start with a letter",
+            name
+        )
+        .into());
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '_' && *c != '-')
+    {
+        return Err(format!(
+            "Invalid collection name '{}': character '{}' is not allowed, only letters, digits, '_' and '-'",
+            name, c
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Composes the per-project collection name `<prefix>_<project>_chunks`
+pub fn prefixed_collection_name(
+    prefix: &str,
+    project_name: &str,
+) -> Result<String, Box<dyn Error>> {
+    let name = format!("{}_{}_chunks", prefix, project_name);
+    validate_collection_name(&name)?;
+    Ok(name)
+}
+
+/// Returns the collection to use: the one given explicitly, or else the one
+/// composed from the collection prefix and the project name
+pub fn resolve_collection(
+    collection: Option<String>,
+    prefix: Option<&str>,
+    project_name: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    match (collection, prefix, project_name) {
+        (Some(collection), _, _) => Ok(collection),
+        (None, Some(prefix), Some(project_name)) => prefixed_collection_name(prefix, project_name),
+        (None, Some(_), None) => {
+            Err("A project name is needed to compose the collection name from the prefix".into())
+        }
+        (None, None, _) => {
+            Err("Either a collection name or a collection prefix is required".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_names_are_composed_and_checked() {
+        assert_eq!(
+            prefixed_collection_name("team", "net-lib").unwrap(),
+            "team_net-lib_chunks"
+        );
+        let error = prefixed_collection_name("team", "net lib")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Invalid collection name 'team_net lib_chunks': character ' ' is not allowed, \
+             only letters, digits, '_' and '-'"
+        );
+
+        for (name, message) in [
+            ("", "must have between 1 and 256 characters"),
+            ("_chunks", "must start with a letter"),
+            ("9chunks", "must start with a letter"),
+        ] {
+            let error = validate_collection_name(name).unwrap_err().to_string();
+            assert!(error.ends_with(message), "{}: {}", name, error);
+        }
+        assert!(validate_collection_name(&"c".repeat(256)).is_ok());
+        assert!(validate_collection_name(&"c".repeat(257)).is_err());
+    }
+}
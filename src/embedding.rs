@@ -1,70 +1,1484 @@
-use fastembed::TextEmbedding;
-use indicatif::{ProgressBar, ProgressStyle};
-use serde_json::json;
+use regex::{Captures, Regex};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 use walkdir::WalkDir;
 
-use crate::embedding_common::create_embedder;
+use crate::chunking::ChunkFile;
+use crate::embedding_common::{
+    content_hash, create_embedder, resolve_model_name, with_instruction, EmbedThreads,
+    EmbeddingBackend,
+};
+use crate::fsutil::write_atomic;
+use crate::metrics::Metrics;
+use crate::progress::{Progress, ProgressMode};
+
+/// Name of the file holding all embeddings of a chunk directory in the
+/// per-file layout
+pub const COMBINED_EMBEDDINGS_FILE: &str = "embeddings.json";
+
+/// Directory below the output directory that the embeddings of a new
+/// model are written to, replacing the old ones only once all are done
+pub const EMBEDDING_STAGING_DIR: &str = "_embedding_staging";
+
+/// Marker in the staging directory, written once all embeddings are staged
+const STAGING_COMPLETE_MARKER: &str = "_complete";
+
+/// Whether staged embeddings are complete but not all moved into place,
+/// so that the output directory mixes two model generations
+pub fn staging_pending(output_dir: &Path) -> bool {
+    output_dir
+        .join(EMBEDDING_STAGING_DIR)
+        .join(STAGING_COMPLETE_MARKER)
+        .exists()
+}
+
+/// Where the embedding records of a new model generation are written
+#[derive(Clone, Debug)]
+struct Staging {
+    output_dir: PathBuf,
+    dir: PathBuf,
+}
+
+impl Staging {
+    /// The staged counterpart of a file in the output directory, whose
+    /// directory is created if needed
+    fn path_for(&self, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let relative = path.strip_prefix(&self.output_dir).unwrap_or(path);
+        let staged = self.dir.join(relative);
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                format!(
+                    "Failed to create staging directory '{}': {}",
+                    parent.display(),
+                    e
+                )
+            })?;
+        }
+        Ok(staged)
+    }
+}
+
+/// The vector of a chunk and that of its comment, if it has one
+pub type ChunkVectors = (Vec<f32>, Option<Vec<f32>>);
+
+/// Placeholders the embed template may use
+const TEMPLATE_FIELDS: &[&str] = &[
+    "qualified_name",
+    "name",
+    "kind",
+    "parent",
+    "signature",
+    "comment",
+    "body",
+    "source_file",
+    "source_path",
+];
+
+/// A `{field}` placeholder of the embed template
+fn template_placeholder() -> &'static Regex {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\{([a-z_]+)\}").unwrap())
+}
+
+/// The declaration of the symbol in a chunk: everything before its body or
+/// the end of the declaration, on one line. Used for chunks whose index
+/// entry has no signature located by the chunker.
+fn signature(content: &str) -> String {
+    let end = content.find(['{', ';']).unwrap_or(content.len());
+    content[..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Looks up a field of a chunk entry in the text of an `_index.txt` file
+fn index_field<'a>(index: &'a str, chunk_file: &str, field: &str) -> &'a str {
+    let mut in_entry = false;
+    for line in index.lines() {
+        if let Some(chunk) = line.strip_prefix("Chunk: ") {
+            in_entry = chunk.trim() == chunk_file;
+        } else if in_entry {
+            if let Some(value) = line
+                .strip_prefix("  ")
+                .and_then(|line| line.strip_prefix(field))
+                .and_then(|line| line.strip_prefix(": "))
+            {
+                return value;
+            }
+        } else if let Some(value) = line
+            .strip_prefix(field)
+            .and_then(|line| line.strip_prefix(": "))
+        {
+            // A field of the header, like the source file
+            return value.trim();
+        }
+    }
+    ""
+}
+
+/// Fills the placeholders of the embed template with the chunk's metadata
+/// from the `_index.txt` of its directory. Missing fields render empty.
+fn render_template(
+    template: &str,
+    chunk_file: &str,
+    content: &str,
+    comment: Option<&str>,
+    index: &str,
+) -> String {
+    template_placeholder()
+        .replace_all(template, |caps: &Captures| {
+            let qualified_name = index_field(index, chunk_file, "Name");
+            match &caps[1] {
+                "qualified_name" => qualified_name.to_string(),
+                "name" => qualified_name
+                    .rsplit("::")
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                "kind" => index_field(index, chunk_file, "Kind").to_string(),
+                "parent" => index_field(index, chunk_file, "Parent").to_string(),
+                "signature" => match index_field(index, chunk_file, "Signature") {
+                    "" => signature(content),
+                    signature => signature.to_string(),
+                },
+                "comment" => comment.unwrap_or_default().trim_end().to_string(),
+                "body" => content.to_string(),
+                "source_file" => index_field(index, chunk_file, "Source file").to_string(),
+                "source_path" => index_field(index, chunk_file, "Source path").to_string(),
+                _ => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Checks the fields of an embed template and replaces its `\n` and `\t`
+/// by newline and tab
+pub fn parse_template(template: &str) -> Result<String, Box<dyn Error>> {
+    for caps in template_placeholder().captures_iter(template) {
+        if !TEMPLATE_FIELDS.contains(&&caps[1]) {
+            return Err(format!(
+                "Unknown field {{{}}} in embed template, known fields are: {}",
+                &caps[1],
+                TEMPLATE_FIELDS.join(", ")
+            )
+            .into());
+        }
+    }
+    Ok(template.replace("\\n", "\n").replace("\\t", "\t"))
+}
+
+/// The text embedded for a chunk: its content, or the parsed embed
+/// template filled with its metadata from the text of the `_index.txt`
+/// file, after the source path if `path_prefix` is set
+pub fn assemble_embed_text(
+    template: Option<&str>,
+    path_prefix: bool,
+    chunk_file: &str,
+    content: &str,
+    comment: Option<&str>,
+    index: &str,
+) -> String {
+    let text = match template {
+        Some(template) => render_template(template, chunk_file, content, comment, index),
+        None => content.to_string(),
+    };
+    match index_field(index, chunk_file, "Source path") {
+        source_path if path_prefix && !source_path.is_empty() => {
+            format!("{}\n{}", source_path, text)
+        }
+        _ => text,
+    }
+}
+
+/// Hash of what shapes the vector of a chunk besides its source, taken
+/// from its embedding record: the model, instruction, template and path
+/// prefix. Imports store it with the document as `embed_hash`, so that
+/// `--skip-unchanged` uploads chunks embedded differently again.
+pub fn embedding_settings_hash(record: &Value) -> String {
+    let settings = json!([
+        record["model"],
+        record["instruction"],
+        record["template"],
+        record["path_prefix"]
+    ]);
+    content_hash(&settings.to_string())
+}
+
+/// How embeddings are stored next to the chunks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmbeddingLayout {
+    /// One `<chunk>.embedding.json` file per chunk
+    #[default]
+    PerChunk,
+    /// One `embeddings.json` file per chunk directory, mapping chunk file
+    /// names to their embedding
+    PerFile,
+}
 
 pub struct Embedder {
     output_dir: String,
-    model: TextEmbedding,
+    /// One instance of the model per embedding job, the first one is used
+    /// outside of `run`
+    models: Vec<EmbeddingBackend>,
+    /// Name of the model, recorded with every embedding
+    model_name: String,
+    /// Truncation length the model was created with
+    max_length: Option<usize>,
+    /// ONNX Runtime threads the model was created with
+    threads: EmbedThreads,
+    min_chunk_chars: usize,
+    cache: Option<EmbeddingCache>,
+    multi_vector_lines: Option<usize>,
+    /// Dimension of the first computed embedding, all others must match it
+    dimension: OnceLock<usize>,
+    layout: EmbeddingLayout,
+    progress: ProgressMode,
+    /// Chunk files larger than this many bytes are skipped
+    max_chunk_size: u64,
+    /// Task instruction prepended to every text before embedding it
+    instruction: String,
+    /// Template of the text embedded for a chunk instead of its source
+    template: Option<String>,
+    /// Prepend the source path relative to the project to the embedded text
+    path_prefix: bool,
+    metrics: Arc<Metrics>,
+}
+
+/// What `run` did besides computing embeddings, counted per job
+#[derive(Debug, Default)]
+struct EmbedStats {
+    skipped: usize,
+    too_small: usize,
+    unreadable: usize,
+    /// Recomputed embeddings per model they were made with before
+    other_models: HashMap<String, usize>,
+}
+
+impl EmbedStats {
+    fn add(&mut self, other: EmbedStats) {
+        self.skipped += other.skipped;
+        self.too_small += other.too_small;
+        self.unreadable += other.unreadable;
+        for (model, count) in other.other_models {
+            *self.other_models.entry(model).or_default() += count;
+        }
+    }
+}
+
+/// Default for the largest chunk file the embedder reads
+pub const DEFAULT_MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// State of an existing embedding file compared to its chunk
+enum Freshness {
+    UpToDate,
+    /// Missing, unreadable or computed from other content
+    Stale,
+    /// Computed with another embedding model, whose name is given
+    OtherModel(String),
+}
+
+/// Reads and writes the embedding records of chunks in the configured
+/// layout. In the per-file layout, the combined file of the directory
+/// currently being processed is kept in memory and written once the chunks
+/// of another directory come up, or on `finish`. With staging, records are
+/// read from the output directory but written to the staging directory.
+struct EmbeddingStore {
+    layout: EmbeddingLayout,
+    combined: Option<CombinedEmbeddings>,
+    staging: Option<Staging>,
+}
+
+/// The embedding records of all chunks in one directory
+struct CombinedEmbeddings {
+    dir: PathBuf,
+    records: Map<String, Value>,
+    changed: bool,
+}
+
+impl CombinedEmbeddings {
+    /// Reads the combined file of a directory, a missing or unreadable one
+    /// counts as empty
+    fn load(dir: &Path) -> Self {
+        let records = fs::read_to_string(dir.join(COMBINED_EMBEDDINGS_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Self {
+            dir: dir.to_path_buf(),
+            records,
+            changed: false,
+        }
+    }
+
+    fn save(&self, staging: Option<&Staging>) -> Result<(), Box<dyn Error>> {
+        if !self.changed {
+            return Ok(());
+        }
+        let mut path = self.dir.join(COMBINED_EMBEDDINGS_FILE);
+        if let Some(staging) = staging {
+            path = staging.path_for(&path)?;
+        }
+        write_atomic(&path, serde_json::to_string(&self.records)?).map_err(|e| {
+            format!(
+                "Failed to write embeddings file '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+        Ok(())
+    }
+}
+
+impl EmbeddingStore {
+    fn new(layout: EmbeddingLayout, staging: Option<Staging>) -> Self {
+        Self {
+            layout,
+            combined: None,
+            staging,
+        }
+    }
+
+    /// Returns the combined embeddings of the chunk's directory and the
+    /// chunk's name in it, saving those of the previous directory
+    fn combined_for(
+        &mut self,
+        chunk_path: &Path,
+    ) -> Result<(&mut CombinedEmbeddings, String), Box<dyn Error>> {
+        let dir = chunk_path.parent().unwrap_or(Path::new(""));
+        if self
+            .combined
+            .as_ref()
+            .is_none_or(|combined| combined.dir != dir)
+        {
+            if let Some(combined) = self.combined.take() {
+                combined.save(self.staging.as_ref())?;
+            }
+            self.combined = Some(CombinedEmbeddings::load(dir));
+        }
+        let name = chunk_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        Ok((self.combined.as_mut().unwrap(), name))
+    }
+
+    /// The stored embedding record of a chunk, if there is a readable one
+    fn get(&mut self, chunk_path: &Path) -> Result<Option<Value>, Box<dyn Error>> {
+        match self.layout {
+            EmbeddingLayout::PerChunk => Ok(fs::read_to_string(
+                chunk_path.with_extension("embedding.json"),
+            )
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())),
+            EmbeddingLayout::PerFile => {
+                let (combined, name) = self.combined_for(chunk_path)?;
+                Ok(combined.records.get(&name).cloned())
+            }
+        }
+    }
+
+    fn put(&mut self, chunk_path: &Path, record: Value) -> Result<(), Box<dyn Error>> {
+        match self.layout {
+            EmbeddingLayout::PerChunk => {
+                let mut path = chunk_path.with_extension("embedding.json");
+                if let Some(staging) = &self.staging {
+                    path = staging.path_for(&path)?;
+                }
+                write_atomic(&path, serde_json::to_string_pretty(&record)?)?;
+            }
+            EmbeddingLayout::PerFile => {
+                let (combined, name) = self.combined_for(chunk_path)?;
+                combined.records.insert(name, record);
+                combined.changed = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the pending combined file, if any
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(combined) = self.combined.take() {
+            combined.save(self.staging.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+/// On-disk cache of embeddings keyed by model and content hash, which can be
+/// shared between projects. Unreadable entries are treated as missing.
+struct EmbeddingCache {
+    dir: PathBuf,
+}
+
+impl EmbeddingCache {
+    /// Opens the cache for a model below `cache_dir`. The truncation length
+    /// is part of the key because it changes the resulting vectors.
+    fn new(
+        cache_dir: &str,
+        model_name: &str,
+        max_length: Option<usize>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut model_dir = model_name.replace('/', "_");
+        if let Some(max_length) = max_length {
+            model_dir.push_str(&format!("_len{}", max_length));
+        }
+        let dir = PathBuf::from(cache_dir).join(model_dir);
+        fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Failed to create embedding cache directory '{}': {}",
+                dir.display(),
+                e
+            )
+        })?;
+        Ok(Self { dir })
+    }
+
+    fn get(&self, src_hash: &str) -> Option<Vec<f32>> {
+        let content = fs::read_to_string(self.dir.join(format!("{}.json", src_hash))).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn put(&self, src_hash: &str, v: &[f32]) -> Result<(), Box<dyn Error>> {
+        write_atomic(
+            &self.dir.join(format!("{}.json", src_hash)),
+            serde_json::to_string(v)?,
+        )?;
+        Ok(())
+    }
 }
 
 impl Embedder {
-    pub fn new(output_dir: String, model_name: &str) -> Result<Self, Box<dyn Error>> {
-        let text_embedding = create_embedder(model_name)?;
+    pub fn new(
+        output_dir: String,
+        model_name: &str,
+        min_chunk_chars: usize,
+        max_length: Option<usize>,
+        threads: EmbedThreads,
+        multi_vector_lines: Option<usize>,
+        layout: EmbeddingLayout,
+    ) -> Result<Self, Box<dyn Error>> {
+        let text_embedding = create_embedder(model_name, max_length, threads)?;
         Ok(Self {
             output_dir,
-            model: text_embedding,
+            models: vec![text_embedding],
+            model_name: resolve_model_name(model_name).to_string(),
+            max_length,
+            threads,
+            min_chunk_chars,
+            cache: None,
+            multi_vector_lines,
+            dimension: OnceLock::new(),
+            layout,
+            progress: ProgressMode::default(),
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            instruction: String::new(),
+            template: None,
+            path_prefix: false,
+            metrics: Arc::default(),
         })
     }
 
-    pub fn run(&self) -> Result<(), Box<dyn Error>> {
-        let entries: Vec<_> = WalkDir::new(&self.output_dir)
+    /// Sets how the progress of `run` is reported
+    pub fn with_progress(mut self, progress: ProgressMode) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Uses an embedding cache below `cache_dir`, keyed by model and chunk
+    /// content, which can be shared between projects
+    pub fn with_cache_dir(mut self, cache_dir: &str) -> Result<Self, Box<dyn Error>> {
+        self.cache = Some(EmbeddingCache::new(
+            cache_dir,
+            &self.model_name,
+            self.max_length,
+        )?);
+        Ok(self)
+    }
+
+    /// Sets the size in bytes above which chunk files are skipped
+    pub fn with_max_chunk_size(mut self, max_chunk_size: u64) -> Self {
+        self.max_chunk_size = max_chunk_size;
+        self
+    }
+
+    /// Sets the task instruction prepended to the chunks, for
+    /// instruction-tuned models
+    pub fn with_instruction(mut self, instruction: String) -> Self {
+        self.instruction = instruction;
+        self
+    }
+
+    /// Sets the template of the text embedded for a chunk, e.g.
+    /// `{qualified_name}\n{signature}\n{comment}\n{body}`. `\n` and `\t`
+    /// stand for newline and tab. The stored source is not affected.
+    pub fn with_template(mut self, template: &str) -> Result<Self, Box<dyn Error>> {
+        self.template = Some(parse_template(template)?);
+        Ok(self)
+    }
+
+    /// Prepends the path of the chunk's source file, relative to the
+    /// project, to the embedded text, so that the path contributes to the
+    /// retrieval. The stored source is not affected.
+    pub fn with_path_prefix(mut self, path_prefix: bool) -> Self {
+        self.path_prefix = path_prefix;
+        self
+    }
+
+    /// The `embedding_settings_hash` of the records this embedder writes
+    pub fn settings_hash(&self) -> String {
+        embedding_settings_hash(&json!({
+            "model": self.model_name,
+            "instruction": (!self.instruction.is_empty()).then_some(&self.instruction),
+            "template": self.template,
+            "path_prefix": self.path_prefix.then_some(true),
+        }))
+    }
+
+    /// Sets the number of chunk directories `run` embeds in parallel. Every
+    /// job loads its own instance of the model, so the memory needed for
+    /// the model grows with the number of jobs.
+    pub fn with_jobs(mut self, jobs: usize) -> Result<Self, Box<dyn Error>> {
+        let jobs = jobs.max(1);
+        while self.models.len() < jobs {
+            self.models.push(create_embedder(
+                &self.model_name,
+                self.max_length,
+                self.threads,
+            )?);
+        }
+        self.models.truncate(jobs);
+        Ok(self)
+    }
+
+    /// Sets the counters the computed embeddings are added to
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Returns why a chunk file must not be read, if so: anything but a
+    /// regular file (a FIFO could block forever) or a file above the size
+    /// limit. Checked before reading so a stray file cannot hang or exhaust
+    /// the embedding phase.
+    fn unreadable(&self, path: &Path) -> Option<String> {
+        match fs::metadata(path) {
+            Err(e) => Some(format!("cannot stat: {}", e)),
+            Ok(metadata) if !metadata.is_file() => Some("not a regular file".to_string()),
+            Ok(metadata) if metadata.len() > self.max_chunk_size => Some(format!(
+                "{} bytes, larger than {}",
+                metadata.len(),
+                self.max_chunk_size
+            )),
+            Ok(_) => None,
+        }
+    }
+
+    /// Returns all chunk files below the output directory
+    fn chunk_files(&self) -> Vec<walkdir::DirEntry> {
+        WalkDir::new(&self.output_dir)
             .into_iter()
             .filter_map(|entry| entry.ok())
             .filter(|entry| {
                 entry.file_name().to_string_lossy().ends_with(".cpp")
                     || entry.file_name().to_string_lossy().ends_with(".hpp")
             })
-            .collect();
+            .collect()
+    }
 
-        let pb = ProgressBar::new(entries.len() as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-                )
-                .unwrap()
-                .progress_chars("#>-"),
-        );
+    /// Returns the number of non-blank characters if the chunk is too small
+    /// to be embedded. Blank or tiny chunks only produce meaningless vectors.
+    fn too_small(&self, content: &str) -> Option<usize> {
+        let trimmed_len = content.trim().chars().count();
+        if trimmed_len == 0 || trimmed_len < self.min_chunk_chars {
+            Some(trimmed_len)
+        } else {
+            None
+        }
+    }
 
-        for entry in entries {
-            let file_path = entry.path();
+    /// Makes sure all embeddings of a run have the same dimension, a
+    /// mismatch means the model (or a cache entry) is not what we expect
+    fn check_dimension(&self, v: &[f32]) -> Result<(), Box<dyn Error>> {
+        let dimension = *self.dimension.get_or_init(|| v.len());
+        if dimension != v.len() {
+            return Err(format!(
+                "Embedding dimension changed from {} to {} during the run, check the embedding model",
+                dimension,
+                v.len()
+            )
+            .into());
+        }
+        Ok(())
+    }
 
-            // Read the file content
-            let content = fs::read_to_string(&file_path)?;
+    /// The text embedded for a chunk: its content, or the embed template
+    /// filled with its metadata from the text of the `_index.txt` file,
+    /// after the source path if requested
+    fn embed_text(
+        &self,
+        chunk_path: &Path,
+        content: &str,
+        comment: Option<&str>,
+        index: &str,
+    ) -> String {
+        let chunk_file = chunk_path.file_name().unwrap_or_default().to_string_lossy();
+        assemble_embed_text(
+            self.template.as_deref(),
+            self.path_prefix,
+            &chunk_file,
+            content,
+            comment,
+            index,
+        )
+    }
 
-            // Generate embedding
-            let embedding = self.model.embed(vec![content], None)?;
+    /// Reads the `_index.txt` of a chunk directory if the embed template or
+    /// the path prefix needs it
+    fn read_index(&self, dir: &Path) -> String {
+        if self.template.is_some() || self.path_prefix {
+            fs::read_to_string(dir.join("_index.txt")).unwrap_or_default()
+        } else {
+            String::new()
+        }
+    }
 
-            // Create output path for the embedding JSON
-            let embedding_path = file_path.with_extension("embedding.json");
+    /// Computes the embedding vector for the content of one chunk, or takes
+    /// it from the embedding cache if there is one
+    pub fn embed_content(&self, content: String) -> Result<Vec<f32>, Box<dyn Error>> {
+        self.embed_content_with(&self.models[0], content)
+    }
 
-            // Convert embedding to JSON
-            let json_data = json!({
-                "v": embedding[0]
+    /// Like `embed_content`, computing the vector with the given instance
+    /// of the model
+    fn embed_content_with(
+        &self,
+        model: &EmbeddingBackend,
+        content: String,
+    ) -> Result<Vec<f32>, Box<dyn Error>> {
+        let content = with_instruction(&self.instruction, &content);
+        let src_hash = self.cache.as_ref().map(|_| content_hash(&content));
+        if let (Some(cache), Some(src_hash)) = (&self.cache, &src_hash) {
+            // A cached vector of the wrong dimension is a corrupt entry
+            let cached = cache.get(src_hash).filter(|v| {
+                self.dimension
+                    .get()
+                    .is_none_or(|dimension| *dimension == v.len())
             });
+            if let Some(v) = cached {
+                self.check_dimension(&v)?;
+                self.metrics.embedding_computed();
+                self.metrics.embedding_cached();
+                return Ok(v);
+            }
+        }
 
-            // Write the JSON file
-            fs::write(&embedding_path, serde_json::to_string_pretty(&json_data)?)?;
+        let embedding = model.embed(vec![content], None)?;
+        let v: Vec<f32> = embedding
+            .into_iter()
+            .next()
+            .ok_or("Embedding model returned no vector")?;
+        self.check_dimension(&v)?;
+
+        if let (Some(cache), Some(src_hash)) = (&self.cache, &src_hash) {
+            cache.put(src_hash, &v)?;
+        }
+        self.metrics.embedding_computed();
+        Ok(v)
+    }
+
+    /// Embeds a chunk that was never written to a file, unless it is too
+    /// small or too large to be embedded. `index` is the text of the
+    /// `_index.txt` file its directory would have. Returns the vector of the
+    /// chunk and that of its comment, if the chunker extracted one.
+    pub fn embed_chunk(
+        &self,
+        file: &ChunkFile,
+        index: &str,
+    ) -> Result<Option<ChunkVectors>, Box<dyn Error>> {
+        let content = &file.content;
+        if self.too_small(content).is_some() || content.len() as u64 > self.max_chunk_size {
+            return Ok(None);
+        }
+        let text = self.embed_text(&file.path, content, file.comment.as_deref(), index);
+        let v = self.embed_content(text)?;
+        let comment_v = file
+            .comment
+            .clone()
+            .map(|comment| self.embed_content(comment))
+            .transpose()?;
+        Ok(Some((v, comment_v)))
+    }
+
+    /// Computes one vector per window of `window_lines` lines of the chunk,
+    /// for late-interaction (MaxSim) scoring at query time
+    fn embed_windows(
+        &self,
+        model: &EmbeddingBackend,
+        content: &str,
+        window_lines: usize,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        let lines: Vec<&str> = content.lines().collect();
+        let windows: Vec<String> = lines
+            .chunks(window_lines.max(1))
+            .map(|window| window.join("\n"))
+            .filter(|window| !window.trim().is_empty())
+            .map(|window| with_instruction(&self.instruction, &window))
+            .collect();
+        if windows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let vectors = model.embed(windows, None)?;
+        for v in &vectors {
+            self.check_dimension(v)?;
+        }
+        Ok(vectors)
+    }
+
+    /// Embeds every chunk that is large enough and hands the chunk path, its
+    /// content, the vector and that of its comment file, if any, to `sink`
+    /// instead of writing embedding files. Chunks too small to embed are
+    /// handed over without vectors. Returns the number of chunks embedded.
+    pub fn embed_each<F>(&self, mut sink: F) -> Result<usize, Box<dyn Error>>
+    where
+        F: FnMut(PathBuf, String, Option<ChunkVectors>) -> Result<(), Box<dyn Error>>,
+    {
+        let mut embedded = 0;
+        let mut index: Option<(PathBuf, String)> = None;
+        for entry in self.chunk_files() {
+            let file_path = entry.into_path();
+            if let Some(reason) = self.unreadable(&file_path) {
+                eprintln!(
+                    "Warning: skipping chunk {} ({})",
+                    file_path.display(),
+                    reason
+                );
+                continue;
+            }
+            let content = fs::read_to_string(&file_path)?;
+            if self.too_small(&content).is_some() {
+                sink(file_path, content, None)?;
+                continue;
+            }
+            let dir = file_path.parent().unwrap_or(Path::new(""));
+            if index.as_ref().is_none_or(|(index_dir, _)| index_dir != dir) {
+                index = Some((dir.to_path_buf(), self.read_index(dir)));
+            }
+            let comment = fs::read_to_string(file_path.with_extension("comment")).ok();
+            let text = self.embed_text(
+                &file_path,
+                &content,
+                comment.as_deref(),
+                index.as_ref().map_or("", |(_, index)| index),
+            );
+            let v = self.embed_content(text)?;
+            let comment_v = comment
+                .map(|comment| self.embed_content(comment))
+                .transpose()?;
+            sink(file_path, content, Some((v, comment_v)))?;
+            embedded += 1;
+        }
+        Ok(embedded)
+    }
 
-            pb.inc(1);
+    /// Computes the embeddings of all chunks in the output directory. With
+    /// several jobs, the chunk directories are distributed over them, each
+    /// job embedding whole directories with its own instance of the model.
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        let entries = self.chunk_files();
+
+        let pb = Progress::new(
+            entries.len() as u64,
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+            self.progress,
+            "Embedding",
+        );
+
+        // Embeddings of another model are only replaced once all new ones
+        // are computed, so an interrupted run leaves the old ones intact
+        let staging = self.prepare_staging(&entries)?;
+
+        // A directory is embedded by a single job, so that its combined
+        // embeddings file has a single writer
+        let mut dirs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for entry in entries {
+            let path = entry.into_path();
+            let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            dirs.entry(dir).or_default().push(path);
+        }
+        let queue = Mutex::new(dirs.into_values());
+
+        let results: Vec<Result<EmbedStats, String>> = thread::scope(|scope| {
+            let (queue, pb, staging) = (&queue, &pb, &staging);
+            let jobs: Vec<_> = self
+                .models
+                .iter()
+                .map(|model| {
+                    scope.spawn(move || {
+                        self.embed_dirs(model, queue, staging.clone(), pb)
+                            .map_err(|e| e.to_string())
+                    })
+                })
+                .collect();
+            jobs.into_iter()
+                .map(|job| job.join().expect("embedding job panicked"))
+                .collect()
+        });
+        let mut stats = EmbedStats::default();
+        for result in results {
+            stats.add(result?);
+        }
+        if staging.is_some() {
+            self.swap_in_staged()?;
         }
 
         pb.finish_with_message("Embedding generation complete");
+        for (model, count) in &stats.other_models {
+            println!(
+                "Warning: recomputed {} embeddings made with model '{}' using '{}'",
+                count, model, self.model_name
+            );
+        }
+        if stats.skipped > 0 {
+            println!("Skipped {} unchanged chunks", stats.skipped);
+        }
+        if stats.too_small > 0 {
+            println!(
+                "Skipped {} chunks shorter than {} characters",
+                stats.too_small, self.min_chunk_chars
+            );
+        }
+        if stats.unreadable > 0 {
+            println!(
+                "Warning: skipped {} chunk files that are not regular files or too large",
+                stats.unreadable
+            );
+        }
+        Ok(())
+    }
+
+    /// Cleans up after an interrupted run and decides whether to stage the
+    /// new embeddings: a complete staging directory is swapped in, a
+    /// partial one is discarded. Staging is needed if the existing
+    /// embeddings were computed with another model, going by the first
+    /// record found.
+    fn prepare_staging(
+        &self,
+        entries: &[walkdir::DirEntry],
+    ) -> Result<Option<Staging>, Box<dyn Error>> {
+        let output_dir = Path::new(&self.output_dir);
+        let staging_dir = output_dir.join(EMBEDDING_STAGING_DIR);
+        if staging_pending(output_dir) {
+            println!("Finishing the swap of the embeddings staged by an interrupted run");
+            self.swap_in_staged()?;
+        } else if staging_dir.exists() {
+            println!("Discarding the partial embeddings staged by an interrupted run");
+            fs::remove_dir_all(&staging_dir).map_err(|e| {
+                format!(
+                    "Failed to remove staging directory '{}': {}",
+                    staging_dir.display(),
+                    e
+                )
+            })?;
+        }
+
+        let mut store = EmbeddingStore::new(self.layout, None);
+        let other_model = entries
+            .iter()
+            .find_map(|entry| store.get(entry.path()).ok().flatten())
+            .and_then(|record| record["model"].as_str().map(str::to_string))
+            .filter(|model| *model != self.model_name);
+        Ok(other_model.map(|model| {
+            println!(
+                "Existing embeddings were computed with '{}', staging the new ones in {} until all are done",
+                model,
+                staging_dir.display()
+            );
+            Staging {
+                output_dir: output_dir.to_path_buf(),
+                dir: staging_dir,
+            }
+        }))
+    }
+
+    /// Moves the staged embeddings over the old ones. The staging directory
+    /// is marked complete first, so that the next run finishes an
+    /// interrupted swap instead of discarding it.
+    fn swap_in_staged(&self) -> Result<(), Box<dyn Error>> {
+        let output_dir = Path::new(&self.output_dir);
+        let staging_dir = output_dir.join(EMBEDDING_STAGING_DIR);
+        let marker = staging_dir.join(STAGING_COMPLETE_MARKER);
+        fs::write(&marker, &self.model_name).map_err(|e| {
+            format!(
+                "Failed to mark staged embeddings complete in '{}': {}",
+                marker.display(),
+                e
+            )
+        })?;
+        for entry in WalkDir::new(&staging_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && entry.path() != marker)
+        {
+            let relative = entry.path().strip_prefix(&staging_dir)?;
+            let target = output_dir.join(relative);
+            fs::rename(entry.path(), &target).map_err(|e| {
+                format!(
+                    "Failed to move staged embeddings to '{}': {}",
+                    target.display(),
+                    e
+                )
+            })?;
+        }
+        fs::remove_dir_all(&staging_dir).map_err(|e| {
+            format!(
+                "Failed to remove staging directory '{}': {}",
+                staging_dir.display(),
+                e
+            )
+        })?;
         Ok(())
     }
+
+    /// Embeds the chunks of the directories taken from the queue until it
+    /// is empty, using the given instance of the model
+    fn embed_dirs(
+        &self,
+        model: &EmbeddingBackend,
+        queue: &Mutex<impl Iterator<Item = Vec<PathBuf>>>,
+        staging: Option<Staging>,
+        pb: &Progress,
+    ) -> Result<EmbedStats, Box<dyn Error>> {
+        let mut stats = EmbedStats::default();
+        let mut store = EmbeddingStore::new(self.layout, staging);
+        loop {
+            let Some(files) = queue.lock().unwrap().next() else {
+                break;
+            };
+            let index = match files.first().and_then(|file| file.parent()) {
+                Some(dir) => self.read_index(dir),
+                None => String::new(),
+            };
+            for file_path in &files {
+                self.embed_file(model, file_path, &index, &mut store, &mut stats, pb)?;
+                pb.inc(1);
+            }
+        }
+        store.finish()?;
+        Ok(stats)
+    }
+
+    /// Computes and stores the embeddings of one chunk file, unless it is
+    /// skipped
+    fn embed_file(
+        &self,
+        model: &EmbeddingBackend,
+        file_path: &Path,
+        index: &str,
+        store: &mut EmbeddingStore,
+        stats: &mut EmbedStats,
+        pb: &Progress,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(reason) = self.unreadable(file_path) {
+            pb.println(format!(
+                "Warning: skipping chunk {} ({})",
+                file_path.display(),
+                reason
+            ));
+            stats.unreadable += 1;
+            return Ok(());
+        }
+
+        // Read the file content
+        let content = fs::read_to_string(file_path)?;
+
+        // Never embed blank or tiny chunks, their vectors are meaningless
+        if let Some(trimmed_len) = self.too_small(&content) {
+            pb.println(format!(
+                "Skipping chunk {} ({} non-blank characters)",
+                file_path.display(),
+                trimmed_len
+            ));
+            stats.too_small += 1;
+            return Ok(());
+        }
+
+        let src_hash = content_hash(&content);
+
+        // The comment above the symbol, if the chunker extracted it
+        let comment = fs::read_to_string(file_path.with_extension("comment")).ok();
+
+        // The text actually embedded, which may hold more than the chunk
+        let text = self.embed_text(file_path, &content, comment.as_deref(), index);
+        let text_hash = content_hash(&with_instruction(&self.instruction, &text));
+
+        // Skip the chunk if the embedded text did not change since the last
+        // run, but recompute embeddings of another model
+        let comment_hash = comment.as_deref().map(content_hash);
+        match self.freshness(store.get(file_path)?, &text_hash, comment_hash.as_deref()) {
+            Freshness::UpToDate => {
+                stats.skipped += 1;
+                return Ok(());
+            }
+            Freshness::OtherModel(other) => *stats.other_models.entry(other).or_default() += 1,
+            Freshness::Stale => {}
+        }
+
+        // Generate the multi-vector embedding if requested
+        let multi_vector = match self.multi_vector_lines {
+            Some(window_lines) => Some(self.embed_windows(model, &content, window_lines)?),
+            None => None,
+        };
+
+        // Generate embedding
+        let embedding = self.embed_content_with(model, text)?;
+
+        // Convert embedding to JSON
+        let mut json_data = json!({
+            "v": embedding,
+            "src_hash": src_hash,
+            "text_hash": text_hash,
+            "model": self.model_name,
+            "dim": embedding.len()
+        });
+        if !self.instruction.is_empty() {
+            json_data["instruction"] = json!(self.instruction);
+        }
+        if let Some(template) = &self.template {
+            json_data["template"] = json!(template);
+        }
+        if self.path_prefix {
+            json_data["path_prefix"] = json!(true);
+        }
+        if let Some(multi_vector) = multi_vector {
+            json_data["mv"] = json!(multi_vector);
+        }
+        if let Some(comment) = comment {
+            json_data["comment_v"] = json!(self.embed_content_with(model, comment)?);
+            json_data["comment_hash"] = json!(comment_hash);
+        }
+
+        // Store the embedding
+        store.put(file_path, json_data)
+    }
+
+    /// Checks whether an existing embedding record was computed from the
+    /// text with the given hash, i.e. the chunk as assembled by template,
+    /// path prefix and instruction, by the same model, and contains the
+    /// multi-vector embeddings if needed and the embedding of the comment
+    /// with the given hash, if any. Records without a model predate
+    /// recording it and are assumed to match.
+    fn freshness(
+        &self,
+        existing: Option<Value>,
+        text_hash: &str,
+        comment_hash: Option<&str>,
+    ) -> Freshness {
+        let Some(json) = existing else {
+            return Freshness::Stale;
+        };
+        if let Some(model) = json["model"].as_str() {
+            if model != self.model_name {
+                return Freshness::OtherModel(model.to_string());
+            }
+        }
+        if json["text_hash"].as_str() == Some(text_hash)
+            && json["instruction"].as_str().unwrap_or_default() == self.instruction
+            && json["template"].as_str() == self.template.as_deref()
+            && json["path_prefix"].as_bool().unwrap_or(false) == self.path_prefix
+            && (self.multi_vector_lines.is_none() || json.get("mv").is_some())
+            && json["comment_hash"].as_str() == comment_hash
+            && (comment_hash.is_none() || json.get("comment_v").is_some())
+        {
+            Freshness::UpToDate
+        } else {
+            Freshness::Stale
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedding_common::enable_test_embedder;
+    use crate::fsutil::scratch_dir;
+
+    fn test_embedder(output: &Path) -> Embedder {
+        enable_test_embedder();
+        Embedder::new(
+            output.to_string_lossy().into_owned(),
+            "test/deterministic-8",
+            1,
+            None,
+            EmbedThreads::default(),
+            None,
+            EmbeddingLayout::default(),
+        )
+        .unwrap()
+    }
+
+    /// Number of vectors the embedder computed so far
+    fn computed(embedder: &Embedder) -> u64 {
+        embedder.metrics.snapshot()["embeddings_computed"]
+            .as_u64()
+            .unwrap()
+    }
+
+    #[test]
+    fn unchanged_chunks_are_skipped_and_modified_ones_recomputed() {
+        let output = scratch_dir("unchanged-chunks");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let add = dir.join("001_add_function_1.cpp");
+        let sub = dir.join("002_sub_function_5.cpp");
+        fs::write(&add, "int add(int a, int b) { return a + b; }").unwrap();
+        fs::write(&sub, "int sub(int a, int b) { return a - b; }").unwrap();
+
+        let embedder = test_embedder(&output);
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), 2);
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), 2);
+
+        fs::write(&sub, "int sub(int a, int b) { return b - a; }").unwrap();
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), 3);
+        let record: Value = serde_json::from_str(
+            &fs::read_to_string(sub.with_extension("embedding.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            record["src_hash"],
+            content_hash("int sub(int a, int b) { return b - a; }")
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn template_assembles_the_embedded_text() {
+        let output = scratch_dir("embed-template");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let chunk = dir.join("001_add_function_2.cpp");
+        let body = "int add(int a, int b) { return a + b; }";
+        fs::write(&chunk, body).unwrap();
+        fs::write(chunk.with_extension("comment"), "// Adds two numbers.\n").unwrap();
+        let index = |name: &str| {
+            format!(
+                "Source file: calc.cpp\n---\nChunk: 001_add_function_2.cpp\n  Name: {}\n  \
+                 Signature: int add(int a, int b)\n---\n",
+                name
+            )
+        };
+        fs::write(dir.join("_index.txt"), index("calc::add")).unwrap();
+
+        let embedder = test_embedder(&output)
+            .with_template("{qualified_name}\\n{signature}\\n{comment}\\n{body}")
+            .unwrap();
+        assert_eq!(
+            embedder.embed_text(
+                &chunk,
+                body,
+                Some("// Adds two numbers.\n"),
+                &index("calc::add")
+            ),
+            format!(
+                "calc::add\nint add(int a, int b)\n// Adds two numbers.\n{}",
+                body
+            )
+        );
+
+        // Renaming the symbol changes the embedded text, not the body
+        embedder.run().unwrap();
+        let computed_before = computed(&embedder);
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), computed_before);
+        fs::write(dir.join("_index.txt"), index("calc::sum")).unwrap();
+        embedder.run().unwrap();
+        assert!(computed(&embedder) > computed_before);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn edited_comments_are_re_embedded() {
+        let output = scratch_dir("edited-comment");
+        let chunk = output.join("calc_cpp/001_add_function_2.cpp");
+        fs::create_dir_all(chunk.parent().unwrap()).unwrap();
+        fs::write(&chunk, "int add(int a, int b) { return a + b; }").unwrap();
+        let comment_v = |comment: &str| {
+            fs::write(chunk.with_extension("comment"), comment).unwrap();
+            test_embedder(&output).run().unwrap();
+            let record: Value = serde_json::from_str(
+                &fs::read_to_string(chunk.with_extension("embedding.json")).unwrap(),
+            )
+            .unwrap();
+            record["comment_v"].clone()
+        };
+
+        let adds = comment_v("// Adds two numbers.\n");
+        let sums = comment_v("// Returns the sum of a and b.\n");
+        assert!(adds.is_array());
+        assert_ne!(adds, sums);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn settings_hash_matches_the_written_records() {
+        let output = scratch_dir("settings-hash");
+        let chunk = output.join("calc_cpp/001_add_function_1.cpp");
+        fs::create_dir_all(chunk.parent().unwrap()).unwrap();
+        fs::write(&chunk, "int add(int a, int b) { return a + b; }").unwrap();
+
+        for instruction in ["", "Represent this code: "] {
+            let embedder = test_embedder(&output).with_instruction(instruction.to_string());
+            embedder.run().unwrap();
+            let record: Value = serde_json::from_str(
+                &fs::read_to_string(chunk.with_extension("embedding.json")).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(embedding_settings_hash(&record), embedder.settings_hash());
+        }
+        assert_ne!(
+            test_embedder(&output).settings_hash(),
+            test_embedder(&output)
+                .with_path_prefix(true)
+                .settings_hash()
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn every_chunk_is_embedded_by_parallel_jobs() {
+        let output = scratch_dir("embed-jobs");
+        let mut chunks = Vec::new();
+        for file in ["add", "sub", "mul", "div", "mod"] {
+            for i in 0..3 {
+                let chunk = output.join(format!("{}_cpp/{:03}_f{}_function_1.cpp", file, i, i));
+                fs::create_dir_all(chunk.parent().unwrap()).unwrap();
+                fs::write(&chunk, format!("int {}{}(int a, int b);", file, i)).unwrap();
+                chunks.push(chunk);
+            }
+        }
+
+        let embedder = test_embedder(&output).with_jobs(2).unwrap();
+        assert_eq!(embedder.models.len(), 2);
+        embedder.run().unwrap();
+        for chunk in &chunks {
+            assert!(
+                chunk.with_extension("embedding.json").is_file(),
+                "{}",
+                chunk.display()
+            );
+        }
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn embeddings_of_another_model_are_recomputed() {
+        let output = scratch_dir("other-model");
+        let chunk = output.join("calc_cpp/001_add_function_1.cpp");
+        fs::create_dir_all(chunk.parent().unwrap()).unwrap();
+        fs::write(&chunk, "int add(int a, int b) { return a + b; }").unwrap();
+        let record_path = chunk.with_extension("embedding.json");
+
+        let embedder = test_embedder(&output);
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), 1);
+        let mut record: Value =
+            serde_json::from_str(&fs::read_to_string(&record_path).unwrap()).unwrap();
+        record["model"] = json!("other/model");
+        fs::write(&record_path, record.to_string()).unwrap();
+
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), 2);
+        let record: Value =
+            serde_json::from_str(&fs::read_to_string(&record_path).unwrap()).unwrap();
+        assert_eq!(record["model"], "test/deterministic-8");
+        assert!(!output.join(EMBEDDING_STAGING_DIR).exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn interrupted_re_embedding_leaves_the_old_embeddings_intact() {
+        let output = scratch_dir("interrupted-staging");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let add = dir.join("001_add_function_1.cpp");
+        let sub = dir.join("002_sub_function_5.cpp");
+        fs::write(&add, "int add(int a, int b) { return a + b; }").unwrap();
+        fs::write(&sub, "int sub(int a, int b) { return a - b; }").unwrap();
+        let model_of = |chunk: &Path| {
+            let record: Value = serde_json::from_str(
+                &fs::read_to_string(chunk.with_extension("embedding.json")).unwrap(),
+            )
+            .unwrap();
+            record["model"].as_str().unwrap().to_string()
+        };
+
+        let embedder = test_embedder(&output);
+        embedder.run().unwrap();
+        for chunk in [&add, &sub] {
+            let path = chunk.with_extension("embedding.json");
+            let mut record: Value =
+                serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            record["model"] = json!("other/model");
+            fs::write(&path, record.to_string()).unwrap();
+        }
+
+        // A run stopped after staging the first new record
+        let staging = embedder
+            .prepare_staging(&embedder.chunk_files())
+            .unwrap()
+            .unwrap();
+        let mut store = EmbeddingStore::new(embedder.layout, Some(staging));
+        store
+            .put(&add, json!({ "model": "test/deterministic-8" }))
+            .unwrap();
+        store.finish().unwrap();
+        let staged = output.join(EMBEDDING_STAGING_DIR).join("calc_cpp");
+        assert!(staged.join("001_add_function_1.embedding.json").is_file());
+        assert_eq!(model_of(&add), "other/model");
+        assert_eq!(model_of(&sub), "other/model");
+
+        // The next run discards the partial staging and replaces all
+        embedder.run().unwrap();
+        assert_eq!(model_of(&add), "test/deterministic-8");
+        assert_eq!(model_of(&sub), "test/deterministic-8");
+        assert!(!output.join(EMBEDDING_STAGING_DIR).exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn identical_chunks_of_another_project_come_from_the_cache() {
+        let cache = scratch_dir("embed-cache");
+        let cache_dir = cache.to_string_lossy().into_owned();
+        let cached = |embedder: &Embedder| {
+            embedder.metrics.snapshot()["embeddings_cached"]
+                .as_u64()
+                .unwrap()
+        };
+        let vendored = "int add(int a, int b) { return a + b; }";
+
+        let first = scratch_dir("embed-cache-first");
+        let chunk = first.join("calc_cpp/001_add_function_1.cpp");
+        fs::create_dir_all(chunk.parent().unwrap()).unwrap();
+        fs::write(&chunk, vendored).unwrap();
+        let embedder = test_embedder(&first).with_cache_dir(&cache_dir).unwrap();
+        embedder.run().unwrap();
+        assert_eq!((computed(&embedder), cached(&embedder)), (1, 0));
+
+        let second = scratch_dir("embed-cache-second");
+        let dir = second.join("vendor_calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("001_add_function_3.cpp"), vendored).unwrap();
+        fs::write(
+            dir.join("002_sub_function_7.cpp"),
+            "int sub(int a, int b) { return a - b; }",
+        )
+        .unwrap();
+        let embedder = test_embedder(&second).with_cache_dir(&cache_dir).unwrap();
+        embedder.run().unwrap();
+        assert_eq!((computed(&embedder), cached(&embedder)), (2, 1));
+        for dir in [cache, first, second] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_dimension_change_during_the_run_is_an_error() {
+        let output = scratch_dir("dimension-drift");
+        let cache = output.join("cache");
+        let add = "int add(int a, int b) { return a + b; }";
+        // Directories are embedded in order, the first chunk's vector comes
+        // from a cache entry of another model variant
+        for (dir, content) in [("a_cpp", add), ("b_cpp", "int sub(int a, int b);")] {
+            let chunk = output.join(dir).join("001_f_function_1.cpp");
+            fs::create_dir_all(chunk.parent().unwrap()).unwrap();
+            fs::write(&chunk, content).unwrap();
+        }
+        let embedder = test_embedder(&output)
+            .with_cache_dir(&cache.to_string_lossy())
+            .unwrap();
+        let entry = cache
+            .join("test_deterministic-8")
+            .join(format!("{}.json", content_hash(add)));
+        fs::write(&entry, "[0.5, 0.5, 0.5, 0.5]").unwrap();
+
+        let error = embedder.run().unwrap_err().to_string();
+        assert!(error.contains("dimension changed from 4 to 8"), "{}", error);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn oversized_chunks_are_skipped() {
+        let output = scratch_dir("max-chunk-size");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let small = dir.join("001_add_function_1.cpp");
+        let large = dir.join("002_table_variable_5.cpp");
+        fs::write(&small, "int add(int a, int b) { return a + b; }").unwrap();
+        fs::write(&large, format!("int table[] = {{{}}};", "1, ".repeat(100))).unwrap();
+
+        let embedder = test_embedder(&output).with_max_chunk_size(100);
+        embedder.run().unwrap();
+        assert_eq!(computed(&embedder), 1);
+        assert!(small.with_extension("embedding.json").is_file());
+        assert!(!large.with_extension("embedding.json").exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn instruction_is_prepended_to_the_embedded_text() {
+        let output = scratch_dir("embed-instruction");
+        let text = "int add(int a, int b) { return a + b; }";
+        let expected = EmbeddingBackend::Deterministic(8)
+            .embed(vec![format!("Represent this code: {}", text)], None)
+            .unwrap();
+        let embedder = test_embedder(&output);
+        assert_ne!(
+            embedder.embed_content(text.to_string()).unwrap(),
+            expected[0]
+        );
+        let embedder = embedder.with_instruction("Represent this code: ".to_string());
+        assert_eq!(
+            embedder.embed_content(text.to_string()).unwrap(),
+            expected[0]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn path_prefix_puts_the_relative_source_path_before_the_chunk() {
+        let output = scratch_dir("path-prefix");
+        let dir = output.join("src_net_tcp_socket_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let chunk = dir.join("001_connect_function_12.cpp");
+        let body = "int connect(Socket& socket);";
+        fs::write(&chunk, body).unwrap();
+        fs::write(
+            dir.join("_index.txt"),
+            "Source file: /project/src/net/tcp_socket.cpp\n---\n\
+             Chunk: 001_connect_function_12.cpp\n  Name: connect\n  \
+             Source path: src/net/tcp_socket.cpp\n---\n",
+        )
+        .unwrap();
+
+        let embedder = test_embedder(&output).with_path_prefix(true);
+        embedder.run().unwrap();
+        let record: Value = serde_json::from_str(
+            &fs::read_to_string(chunk.with_extension("embedding.json")).unwrap(),
+        )
+        .unwrap();
+        let expected = EmbeddingBackend::Deterministic(8)
+            .embed(vec![format!("src/net/tcp_socket.cpp\n{}", body)], None)
+            .unwrap();
+        assert_eq!(record["v"], json!(expected[0]));
+        assert_eq!(record["path_prefix"], true);
+        fs::remove_dir_all(&output).unwrap();
+    }
 }
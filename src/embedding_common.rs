@@ -1,29 +1,495 @@
 use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
 use std::error::Error;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Once, OnceLock};
+use std::thread::available_parallelism;
+use std::time::Duration;
 
-pub fn create_embedder(model_name: &str) -> Result<TextEmbedding, Box<dyn Error>> {
-    // Parse the model name into an EmbeddingModel enum
+/// Model name selecting the recommended model, `DEFAULT_MODEL`
+pub const AUTO_MODEL: &str = "auto";
+
+/// The model `auto` stands for, trained on code
+pub const DEFAULT_MODEL: &str = "jinaai/jina-embeddings-v2-base-code";
+
+/// Prefix of the names of the deterministic test embedder, followed by the
+/// dimension of its vectors, e.g. `test/deterministic-8`
+pub const TEST_MODEL_PREFIX: &str = "test/deterministic-";
+
+/// Environment variable that has to be set to 1 to allow the test embedder,
+/// so that it cannot be picked by accident in production
+pub const TEST_MODEL_ENV: &str = "CPPEMBEDDER_TEST_EMBEDDER";
+
+/// Allows the test embedder for the rest of the process by setting
+/// `TEST_MODEL_ENV`. The environment is shared by all threads, so it is set
+/// only once, not again by every test running in parallel.
+pub fn enable_test_embedder() {
+    static ENABLED: Once = Once::new();
+    ENABLED.call_once(|| std::env::set_var(TEST_MODEL_ENV, "1"));
+}
+
+/// Resolves `auto` to the recommended model, other names are kept
+pub fn resolve_model_name(model_name: &str) -> &str {
+    if model_name == AUTO_MODEL {
+        DEFAULT_MODEL
+    } else {
+        model_name
+    }
+}
+
+/// The line logged when `auto` picks the model, None for other names
+pub fn model_choice_notice(model_name: &str) -> Option<String> {
+    (model_name == AUTO_MODEL).then(|| {
+        format!(
+            "Embedding model auto: using {}",
+            resolve_model_name(model_name)
+        )
+    })
+}
+
+/// Maps a model name to the fastembed model and the maximal number of tokens
+/// the model can process (its hard context limit)
+pub fn parse_model(model_name: &str) -> Result<(EmbeddingModel, usize), Box<dyn Error>> {
     let model = match model_name {
-        "BAAI/bge-small-en-v1.5" => EmbeddingModel::BGESmallENV15,
-        "BAAI/bge-base-en-v1.5" => EmbeddingModel::BGEBaseENV15,
-        "BAAI/bge-large-en-v1.5" => EmbeddingModel::BGELargeENV15,
-        "sentence-transformers/all-MiniLM-L6-v2" => EmbeddingModel::AllMiniLML6V2,
-        "sentence-transformers/all-MiniLM-L12-v2" => EmbeddingModel::AllMiniLML12V2,
-        "sentence-transformers/paraphrase-MiniLM-L6-v2" => EmbeddingModel::ParaphraseMLMiniLML12V2,
-        "sentence-transformers/paraphrase-mpnet-base-v2" => EmbeddingModel::ParaphraseMLMpnetBaseV2,
-        "nomic-ai/nomic-embed-text-v1" => EmbeddingModel::NomicEmbedTextV1,
-        "nomic-ai/nomic-embed-text-v1.5" => EmbeddingModel::NomicEmbedTextV15,
-        "intfloat/multilingual-e5-small" => EmbeddingModel::MultilingualE5Small,
-        "intfloat/multilingual-e5-base" => EmbeddingModel::MultilingualE5Base,
-        "intfloat/multilingual-e5-large" => EmbeddingModel::MultilingualE5Large,
-        "mixedbread-ai/mxbai-embed-large-v1" => EmbeddingModel::MxbaiEmbedLargeV1,
-        "Alibaba-NLP/gte-base-en-v1.5" => EmbeddingModel::GTEBaseENV15,
-        "Alibaba-NLP/gte-large-en-v1.5" => EmbeddingModel::GTELargeENV15,
-        "Qdrant/clip-ViT-B-32-text" => EmbeddingModel::ClipVitB32,
-        "jinaai/jina-embeddings-v2-base-code" => EmbeddingModel::JinaEmbeddingsV2BaseCode,
+        "BAAI/bge-small-en-v1.5" => (EmbeddingModel::BGESmallENV15, 512),
+        "BAAI/bge-base-en-v1.5" => (EmbeddingModel::BGEBaseENV15, 512),
+        "BAAI/bge-large-en-v1.5" => (EmbeddingModel::BGELargeENV15, 512),
+        "sentence-transformers/all-MiniLM-L6-v2" => (EmbeddingModel::AllMiniLML6V2, 512),
+        "sentence-transformers/all-MiniLM-L12-v2" => (EmbeddingModel::AllMiniLML12V2, 512),
+        "sentence-transformers/paraphrase-MiniLM-L6-v2" => (EmbeddingModel::ParaphraseMLMiniLML12V2, 512),
+        "sentence-transformers/paraphrase-mpnet-base-v2" => (EmbeddingModel::ParaphraseMLMpnetBaseV2, 512),
+        "nomic-ai/nomic-embed-text-v1" => (EmbeddingModel::NomicEmbedTextV1, 8192),
+        "nomic-ai/nomic-embed-text-v1.5" => (EmbeddingModel::NomicEmbedTextV15, 8192),
+        "intfloat/multilingual-e5-small" => (EmbeddingModel::MultilingualE5Small, 512),
+        "intfloat/multilingual-e5-base" => (EmbeddingModel::MultilingualE5Base, 512),
+        "intfloat/multilingual-e5-large" => (EmbeddingModel::MultilingualE5Large, 512),
+        "mixedbread-ai/mxbai-embed-large-v1" => (EmbeddingModel::MxbaiEmbedLargeV1, 512),
+        "Alibaba-NLP/gte-base-en-v1.5" => (EmbeddingModel::GTEBaseENV15, 8192),
+        "Alibaba-NLP/gte-large-en-v1.5" => (EmbeddingModel::GTELargeENV15, 8192),
+        "Qdrant/clip-ViT-B-32-text" => (EmbeddingModel::ClipVitB32, 77),
+        "jinaai/jina-embeddings-v2-base-code" => (EmbeddingModel::JinaEmbeddingsV2BaseCode, 8192),
         _ => return Err(format!("Unsupported embedding model: {}", model_name).into()),
     };
+    Ok(model)
+}
+
+/// An embedding model: a fastembed model, or the deterministic test embedder
+/// that hashes the words of a text into a few dimensions without network
+/// access or ONNX Runtime
+pub enum EmbeddingBackend {
+    Model(Box<TextEmbedding>),
+    Deterministic(usize),
+}
+
+impl EmbeddingBackend {
+    /// Computes one vector per text
+    pub fn embed<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        match self {
+            EmbeddingBackend::Model(model) => Ok(model.embed(texts, batch_size)?),
+            EmbeddingBackend::Deterministic(dimension) => Ok(texts
+                .iter()
+                .map(|text| deterministic_vector(text.as_ref(), *dimension))
+                .collect()),
+        }
+    }
+}
+
+/// Parses the dimension out of the name of the test embedder, None for
+/// other names. Fails unless the test embedder is enabled by
+/// `TEST_MODEL_ENV`.
+fn test_model_dimension(model_name: &str) -> Result<Option<usize>, Box<dyn Error>> {
+    let Some(dimension) = model_name.strip_prefix(TEST_MODEL_PREFIX) else {
+        return Ok(None);
+    };
+    if std::env::var(TEST_MODEL_ENV).as_deref() != Ok("1") {
+        return Err(format!(
+            "The test embedder {} is only available with {}=1",
+            model_name, TEST_MODEL_ENV
+        )
+        .into());
+    }
+    match dimension.parse() {
+        Ok(dimension) if dimension > 0 => Ok(Some(dimension)),
+        _ => Err(format!("Invalid dimension of the test embedder: {}", model_name).into()),
+    }
+}
+
+/// The vector of the test embedder: every word is hashed into one of the
+/// dimensions, so texts sharing words are similar, and the result is
+/// normalized. Texts without words get a vector from the hash of the text.
+fn deterministic_vector(text: &str, dimension: usize) -> Vec<f32> {
+    let fnv = |text: &str| {
+        text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    };
+    let mut v = vec![0.0f32; dimension];
+    let words = text
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty());
+    for word in words {
+        let hash = fnv(&word.to_lowercase());
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        v[(hash % dimension as u64) as usize] += sign;
+    }
+    if v.iter().all(|x| *x == 0.0) {
+        v[(fnv(text) % dimension as u64) as usize] = 1.0;
+    }
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    v.iter().map(|x| x / norm).collect()
+}
+
+/// Thread counts of ONNX Runtime running the embedding model, given as
+/// `INTRA` or `INTRA,INTER` on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbedThreads {
+    /// Threads computing a single operator, e.g. a matrix product
+    pub intra: usize,
+    /// Threads running independent operators of the graph in parallel
+    pub inter: usize,
+}
+
+impl Default for EmbedThreads {
+    /// One intra-op thread per available core, as fastembed would use
+    fn default() -> Self {
+        Self {
+            intra: available_parallelism().map_or(1, |n| n.get()),
+            inter: 1,
+        }
+    }
+}
+
+impl FromStr for EmbedThreads {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let count = |count: &str| match count.trim().parse::<i64>() {
+            Ok(n) if n > 0 => Ok(n as usize),
+            Ok(n) => Err(format!("thread count must be at least 1, got {}", n)),
+            Err(_) => Err(format!("thread count must be a number, got '{}'", count)),
+        };
+        match spec.split_once(',') {
+            Some((intra, inter)) => Ok(Self {
+                intra: count(intra)?,
+                inter: count(inter)?,
+            }),
+            None => Ok(Self {
+                intra: count(spec)?,
+                inter: 1,
+            }),
+        }
+    }
+}
+
+impl Display for EmbedThreads {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.intra, self.inter)
+    }
+}
+
+/// The thread counts ONNX Runtime was set up with, fixed by the first model
+/// loaded in the process
+static EMBED_THREADS: OnceLock<EmbedThreads> = OnceLock::new();
+
+/// Records `threads` as the thread counts of the process, which is only
+/// possible once. Returns whether ONNX Runtime still has to be set up.
+fn claim_embed_threads(threads: EmbedThreads) -> Result<bool, Box<dyn Error>> {
+    let mut claimed = false;
+    let current = EMBED_THREADS.get_or_init(|| {
+        claimed = true;
+        threads
+    });
+    if *current != threads {
+        return Err(format!(
+            "Embedding threads are already set to {}, cannot change them to {}",
+            current, threads
+        )
+        .into());
+    }
+    Ok(claimed)
+}
+
+/// Sets up the global thread pools of ONNX Runtime with `threads`. The
+/// sessions fastembed creates then use these pools instead of their own,
+/// which fastembed would size by the number of cores.
+fn init_thread_pool(threads: EmbedThreads) -> Result<(), Box<dyn Error>> {
+    if !claim_embed_threads(threads)? {
+        return Ok(());
+    }
+    let pool = ort::environment::GlobalThreadPoolOptions::default()
+        .with_intra_threads(threads.intra)?
+        .with_inter_threads(threads.inter)?;
+    ort::init().with_global_thread_pool(pool).commit()?;
+    Ok(())
+}
+
+/// Options to load a fastembed model with. `max_length` overrides the
+/// number of tokens after which input is truncated and may not exceed the
+/// model's limit.
+fn init_options(
+    model_name: &str,
+    max_length: Option<usize>,
+) -> Result<InitOptions, Box<dyn Error>> {
+    // Parse the model name into an EmbeddingModel enum
+    let (model, model_max_length) = parse_model(model_name)?;
+    let mut options = InitOptions::new(model).with_show_download_progress(true);
+    if let Some(max_length) = max_length {
+        if max_length == 0 || max_length > model_max_length {
+            return Err(format!(
+                "Truncation length {} is not supported by {}, which accepts at most {} tokens",
+                max_length, model_name, model_max_length
+            )
+            .into());
+        }
+        options = options.with_max_length(max_length);
+    }
+    Ok(options)
+}
+
+/// Creates the embedding model. `max_length` overrides the number of tokens
+/// after which input is truncated and may not exceed the model's limit.
+/// Failed downloads of the model files are retried a few times. All models
+/// of a process share the ONNX Runtime threads, so `threads` has to be the
+/// same for every model.
+pub fn create_embedder(
+    model_name: &str,
+    max_length: Option<usize>,
+    threads: EmbedThreads,
+) -> Result<EmbeddingBackend, Box<dyn Error>> {
+    if let Some(dimension) = test_model_dimension(model_name)? {
+        eprintln!("WARN: using the deterministic test embedder, the vectors carry no meaning");
+        return Ok(EmbeddingBackend::Deterministic(dimension));
+    }
+    if let Some(notice) = model_choice_notice(model_name) {
+        eprintln!("{}", notice);
+    }
+    let model_name = resolve_model_name(model_name);
+    let options = init_options(model_name, max_length)?;
+    init_thread_pool(threads)
+        .map_err(|e| format!("Failed to set up {} embedding threads: {}", threads, e))?;
+    init_with_retries(
+        || TextEmbedding::try_new(options.clone()),
+        MODEL_INIT_RETRY_DELAY,
+    )
+    .map(|model| EmbeddingBackend::Model(Box::new(model)))
+    .map_err(|e| format!("Failed to load embedding model {}: {}", model_name, e).into())
+}
+
+/// Number of attempts to load the embedding model if its download fails
+const MODEL_INIT_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of loading the model, doubled every time
+const MODEL_INIT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Parts of error messages hinting at unreadable cached model files
+const CORRUPT_MODEL_HINTS: &[&str] = &["protobuf", "onnx", "parse", "invalid", "unexpected end"];
+
+/// Why loading the embedding model failed, going by the error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InitFailure {
+    /// The model files do not exist on the model hub
+    NotFound,
+    /// The cached files cannot be read, e.g. after an interrupted download
+    Corrupt,
+    /// Anything else, most likely a network problem, which is retried
+    Download,
+}
+
+impl InitFailure {
+    fn classify(message: &str) -> Self {
+        let message = message.to_lowercase();
+        if message.contains("not found") || message.contains("404") {
+            InitFailure::NotFound
+        } else if CORRUPT_MODEL_HINTS
+            .iter()
+            .any(|hint| message.contains(hint))
+        {
+            InitFailure::Corrupt
+        } else {
+            InitFailure::Download
+        }
+    }
+
+    /// What the user can do about the failure
+    fn advice(self) -> &'static str {
+        match self {
+            InitFailure::NotFound => "the model files were not found on the model hub",
+            InitFailure::Corrupt => {
+                "the cached model files look corrupt, remove the model's directory from the \
+                 fastembed cache (`.fastembed_cache` or $FASTEMBED_CACHE_PATH) to download \
+                 them again"
+            }
+            InitFailure::Download => "downloading the model files failed, check the network",
+        }
+    }
+}
+
+/// Calls `init` until it succeeds, retrying only failures that look like
+/// download problems, up to `MODEL_INIT_ATTEMPTS` times with a doubling
+/// delay. The error names the kind of failure and what to do about it.
+fn init_with_retries<T, E: Display>(
+    mut init: impl FnMut() -> Result<T, E>,
+    mut delay: Duration,
+) -> Result<T, String> {
+    let mut attempt = 1;
+    loop {
+        let error = match init() {
+            Ok(model) => return Ok(model),
+            Err(error) => error.to_string(),
+        };
+        let failure = InitFailure::classify(&error);
+        if failure != InitFailure::Download || attempt == MODEL_INIT_ATTEMPTS {
+            return Err(format!("{} ({})", failure.advice(), error));
+        }
+        eprintln!(
+            "WARN: loading the embedding model failed (attempt {} of {}), retrying in {}s: {}",
+            attempt,
+            MODEL_INIT_ATTEMPTS,
+            delay.as_secs(),
+            error
+        );
+        std::thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Prepends the task instruction of instruction-tuned models to a text. The
+/// instruction is used verbatim, so it has to bring its own separator.
+pub fn with_instruction(instruction: &str, text: &str) -> String {
+    if instruction.is_empty() {
+        text.to_string()
+    } else {
+        format!("{}{}", instruction, text)
+    }
+}
+
+/// Computes a stable 64 bit FNV-1a hash of the content, rendered as hex.
+/// Used to detect whether a chunk changed since its embedding was computed.
+pub fn content_hash(content: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in content.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Cosine similarity of two vectors, 0 if one of them is all zeros
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_resolves_to_the_default_model() {
+        assert_eq!(resolve_model_name(AUTO_MODEL), DEFAULT_MODEL);
+        assert_eq!(DEFAULT_MODEL, "jinaai/jina-embeddings-v2-base-code");
+        assert_eq!(
+            resolve_model_name("BAAI/bge-small-en-v1.5"),
+            "BAAI/bge-small-en-v1.5"
+        );
+        assert_eq!(
+            model_choice_notice(AUTO_MODEL).as_deref(),
+            Some("Embedding model auto: using jinaai/jina-embeddings-v2-base-code")
+        );
+        assert_eq!(model_choice_notice(DEFAULT_MODEL), None);
+    }
+
+    #[test]
+    fn embed_threads_are_parsed() {
+        assert_eq!(
+            "8".parse::<EmbedThreads>(),
+            Ok(EmbedThreads { intra: 8, inter: 1 })
+        );
+        assert_eq!(
+            "6, 2".parse::<EmbedThreads>(),
+            Ok(EmbedThreads { intra: 6, inter: 2 })
+        );
+        assert!(EmbedThreads::default().intra >= 1);
+        for (spec, message) in [
+            ("0", "at least 1, got 0"),
+            ("-2", "at least 1, got -2"),
+            ("4,0", "at least 1, got 0"),
+            ("many", "must be a number, got 'many'"),
+            ("", "must be a number"),
+        ] {
+            let error = spec.parse::<EmbedThreads>().unwrap_err();
+            assert!(error.contains(message), "{}: {}", spec, error);
+        }
+    }
+
+    #[test]
+    fn embed_threads_are_fixed_by_the_first_model() {
+        let threads = EmbedThreads { intra: 3, inter: 2 };
+        assert!(claim_embed_threads(threads).unwrap());
+        assert_eq!(EMBED_THREADS.get(), Some(&threads));
+        assert!(!claim_embed_threads(threads).unwrap());
+        let error = claim_embed_threads(EmbedThreads { intra: 4, inter: 2 }).unwrap_err();
+        let error = error.to_string();
+        assert!(error.contains("already set to 3,2"), "{}", error);
+    }
+
+    #[test]
+    fn only_download_failures_are_retried() {
+        let mut attempts = 0;
+        let model = init_with_retries(
+            || {
+                attempts += 1;
+                match attempts {
+                    1 => Err("connection reset by peer"),
+                    _ => Ok("model"),
+                }
+            },
+            Duration::ZERO,
+        );
+        assert_eq!(model, Ok("model"));
+        assert_eq!(attempts, 2);
+
+        let mut attempts = 0;
+        let error = init_with_retries(
+            || -> Result<(), _> {
+                attempts += 1;
+                Err("model.onnx: 404 Not Found")
+            },
+            Duration::ZERO,
+        )
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+        assert!(
+            error.starts_with("the model files were not found on the model hub"),
+            "{}",
+            error
+        );
+        assert!(error.contains("404 Not Found"), "{}", error);
+    }
+
+    #[test]
+    fn truncation_length_is_applied_up_to_the_model_maximum() {
+        let options = init_options("BAAI/bge-small-en-v1.5", Some(256)).unwrap();
+        assert_eq!(options.max_length, 256);
+        let options = init_options("BAAI/bge-small-en-v1.5", None).unwrap();
+        assert_eq!(options.max_length, InitOptions::default().max_length);
 
-    let options = InitOptions::new(model).with_show_download_progress(true);
-    TextEmbedding::try_new(options).map_err(|e| e.into())
-} 
\ No newline at end of file
+        for max_length in [0, 513] {
+            let error = init_options("BAAI/bge-small-en-v1.5", Some(max_length))
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("accepts at most 512 tokens"), "{}", error);
+        }
+        assert!(init_options("nomic-ai/nomic-embed-text-v1.5", Some(4096)).is_ok());
+    }
+}
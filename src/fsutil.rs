@@ -0,0 +1,74 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Returns the temporary file next to `path` that `write_atomic` writes to
+/// first. It is unique per process, so concurrent writers do not collide.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Writes a file such that readers never see partial content: the content
+/// goes to a temporary file in the same directory, which is then renamed
+/// over the target.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let temp = temp_path(path);
+    if let Err(e) = fs::write(&temp, contents).and_then(|_| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Creates an empty directory for a test under the system's temporary
+/// directory, unique per test name and process
+#[cfg(test)]
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cppembedder-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("failed to create scratch directory");
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn readers_never_see_partial_files() {
+        let dir = scratch_dir("write-atomic");
+        let path = dir.join("001_add_function_1.embedding.json");
+        let contents = ["a".repeat(1 << 20), "b".repeat(1 << 20)];
+        write_atomic(&path, &contents[0]).unwrap();
+
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..20 {
+                    write_atomic(&path, &contents[i % 2]).unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            });
+            while !done.load(Ordering::SeqCst) {
+                let read = fs::read_to_string(&path).unwrap();
+                assert!(
+                    contents.contains(&read),
+                    "partial file of {} bytes",
+                    read.len()
+                );
+            }
+        });
+
+        // A failed write leaves neither the temporary file nor changes behind
+        let target = dir.join("occupied");
+        fs::create_dir_all(target.join("inside")).unwrap();
+        assert!(write_atomic(&target, "new").is_err());
+        assert!(!temp_path(&target).exists());
+        assert!(target.join("inside").is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use crate::chunking::{leading_comment, CodeChunk};
+
+/// Keywords that open a braced block but never a function body
+const CONTROL_KEYWORDS: &[&str] = &["if", "for", "while", "switch", "catch", "do", "else"];
+
+/// What a `{` opened, as far as the heuristic can tell
+#[derive(Debug)]
+enum Block {
+    /// A namespace, transparent for chunking but part of qualified names
+    Namespace(String),
+    /// A class, struct or union body with its name
+    Class(String, usize),
+    /// A function body with its (possibly qualified) name
+    Function(String, usize),
+    /// Anything else: initializers, control flow, lambdas, ...
+    Other,
+}
+
+/// Replaces comments, string and character literals and preprocessor lines
+/// by spaces, keeping the line structure, so that braces and parentheses in
+/// them do not confuse the block matching
+fn strip_noise(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut in_block_comment = false;
+    for line in content.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut code = String::with_capacity(line.len());
+        if !in_block_comment && line.trim_start().starts_with('#') {
+            lines.push(String::new());
+            continue;
+        }
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if in_block_comment {
+                if c == '*' && next == Some('/') {
+                    in_block_comment = false;
+                    i += 1;
+                }
+                code.push(' ');
+            } else if c == '/' && next == Some('/') {
+                break;
+            } else if c == '/' && next == Some('*') {
+                in_block_comment = true;
+                code.push(' ');
+                i += 1;
+            } else if c == '"' || c == '\'' {
+                // Skip to the closing quote, honoring escapes
+                code.push(' ');
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                code.push(' ');
+            } else {
+                code.push(c);
+            }
+            i += 1;
+        }
+        lines.push(code);
+    }
+    lines
+}
+
+/// The last identifier (with `::` qualification and `~`/`operator`) before
+/// the first parenthesis of a function head, if the head looks like one
+fn function_name(head: &str) -> Option<String> {
+    let paren = head.find('(')?;
+    if head.contains('=') && !head.contains("operator") {
+        return None;
+    }
+    let name: String = head[..paren]
+        .trim_end()
+        .chars()
+        .rev()
+        .take_while(|c| c.is_alphanumeric() || matches!(c, '_' | ':' | '~'))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    let name = name.trim_start_matches(':');
+    if name.is_empty() || CONTROL_KEYWORDS.contains(&name) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// The name of a class head like `template <...> class Foo : public Bar`
+fn class_name(head: &str) -> Option<String> {
+    let mut words = head.split(|c: char| !c.is_alphanumeric() && c != '_');
+    words.find(|word| matches!(*word, "class" | "struct" | "union"))?;
+    words
+        .find(|word| !word.is_empty() && *word != "final" && !word.starts_with("alignas"))
+        .map(|name| name.to_string())
+}
+
+/// Classifies the block opened after the given head at the top level, in a
+/// namespace or in a class body
+fn classify(head: &str, start_line: usize) -> Block {
+    let head = head.trim();
+    let first_word = head.split_whitespace().next().unwrap_or_default();
+    if first_word == "namespace" || head.starts_with("inline namespace") {
+        let name = head
+            .split_whitespace()
+            .skip_while(|word| *word != "namespace")
+            .nth(1)
+            .unwrap_or_default();
+        return Block::Namespace(name.to_string());
+    }
+    // `extern "C"` blocks, the string literal is stripped already
+    if first_word == "extern" && !head.contains('(') {
+        return Block::Namespace(String::new());
+    }
+    if head.split_whitespace().any(|word| word == "enum") {
+        return Block::Other;
+    }
+    if !head.contains('(') {
+        if let Some(name) = class_name(head) {
+            return Block::Class(name, start_line);
+        }
+    }
+    match function_name(head) {
+        Some(name) => Block::Function(name, start_line),
+        None => Block::Other,
+    }
+}
+
+/// Splits a C++ file into function and class chunks by matching braces,
+/// without clangd. Much less accurate than the symbols clangd reports:
+/// macros, unusual formatting and code in strings can throw it off.
+pub fn heuristic_chunks(content: &str) -> Vec<CodeChunk> {
+    let lines: Vec<&str> = content.lines().collect();
+    let code = strip_noise(content);
+
+    let mut chunks = Vec::new();
+    let mut stack: Vec<Block> = Vec::new();
+    // Text since the last `;`, `{` or `}` and the line it starts on
+    let mut head = String::new();
+    let mut head_line = None;
+
+    for (line_nr, line) in code.iter().enumerate() {
+        for c in line.chars() {
+            match c {
+                '{' => {
+                    let at_declaration_level = stack
+                        .iter()
+                        .all(|block| matches!(block, Block::Namespace(_) | Block::Class(..)));
+                    let block = if at_declaration_level {
+                        classify(&head, head_line.unwrap_or(line_nr))
+                    } else {
+                        Block::Other
+                    };
+                    stack.push(block);
+                    head.clear();
+                    head_line = None;
+                }
+                '}' => {
+                    let parent = qualified_parent(&stack[..stack.len().saturating_sub(1)]);
+                    let in_class = matches!(
+                        stack.len().checked_sub(2).map(|i| &stack[i]),
+                        Some(Block::Class(..))
+                    );
+                    match stack.pop() {
+                        Some(Block::Function(name, start_line)) => {
+                            let kind = if in_class || name.contains("::") {
+                                "method"
+                            } else {
+                                "function"
+                            };
+                            chunks
+                                .push(make_chunk(&lines, name, kind, parent, start_line, line_nr));
+                        }
+                        Some(Block::Class(name, start_line)) => {
+                            chunks.push(make_chunk(
+                                &lines, name, "class", parent, start_line, line_nr,
+                            ));
+                        }
+                        _ => {}
+                    }
+                    head.clear();
+                    head_line = None;
+                }
+                ';' => {
+                    head.clear();
+                    head_line = None;
+                }
+                _ => {
+                    if head_line.is_none() && !c.is_whitespace() {
+                        head_line = Some(line_nr);
+                    }
+                    head.push(c);
+                    // Access specifiers do not belong to the next member
+                    if matches!(head.trim(), "public:" | "protected:" | "private:") {
+                        head.clear();
+                        head_line = None;
+                    }
+                }
+            }
+        }
+        head.push(' ');
+    }
+    chunks
+}
+
+/// The qualified name of the enclosing namespaces and classes, if any
+fn qualified_parent(stack: &[Block]) -> Option<String> {
+    let names: Vec<&str> = stack
+        .iter()
+        .filter_map(|block| match block {
+            Block::Namespace(name) | Block::Class(name, _) if !name.is_empty() => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+    (!names.is_empty()).then(|| names.join("::"))
+}
+
+fn make_chunk(
+    lines: &[&str],
+    name: String,
+    kind: &str,
+    parent: Option<String>,
+    start_line: usize,
+    end_line: usize,
+) -> CodeChunk {
+    let end_line = end_line.min(lines.len().saturating_sub(1));
+    let name = match &parent {
+        Some(parent) => format!("{}::{}", parent, name),
+        None => name,
+    };
+    CodeChunk {
+        name,
+        content: lines[start_line..=end_line].join("\n"),
+        start_line,
+        end_line,
+        kind: kind.to_string(),
+        parent,
+        comment: leading_comment(lines, start_line),
+        deprecated: false,
+        name_position: None,
+        signature: None,
+        context: None,
+        // Chunks are only found by their braces
+        has_body: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn functions_of_a_simple_file_are_found() {
+        let content = r#"#include <string>
+
+namespace calc {
+
+// Adds two numbers.
+int add(int a, int b) {
+    if (a < 0) { return b; }
+    return a + b;
+}
+
+class Counter {
+public:
+    void bump() { count_ += 1; }
+private:
+    int count_ = 0;
+};
+
+}  // namespace calc
+
+const char* greeting() {
+    return "{ not a block";
+}
+"#;
+        let chunks = heuristic_chunks(content);
+        let found: Vec<(&str, &str, usize, usize)> = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.name.as_str(),
+                    chunk.kind.as_str(),
+                    chunk.start_line,
+                    chunk.end_line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("calc::add", "function", 5, 8),
+                ("calc::Counter::bump", "method", 12, 12),
+                ("calc::Counter", "class", 10, 15),
+                ("greeting", "function", 19, 21),
+            ]
+        );
+        assert_eq!(chunks[0].parent.as_deref(), Some("calc"));
+        assert_eq!(chunks[0].comment.as_deref(), Some("// Adds two numbers."));
+        assert_eq!(
+            chunks[3].content,
+            "const char* greeting() {\n    return \"{ not a block\";\n}"
+        );
+    }
+}
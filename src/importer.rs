@@ -1,30 +1,670 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::Client;
-use serde_json::{json, Value};
+use futures_util::StreamExt;
+use regex::Regex;
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::OnceCell;
 use walkdir::WalkDir;
 
+use cppembedder::arango::{search_view_name, ArangoClient, META_KEY, NAME_ANALYZER};
+use cppembedder::collection::validate_collection_name;
+use cppembedder::query::HEADER_EXTENSIONS;
+
+use crate::chunking::RELATIONS_FILE;
+use crate::embedding::{
+    embedding_settings_hash, staging_pending, ChunkVectors, COMBINED_EMBEDDINGS_FILE,
+};
+use crate::embedding_common::content_hash;
+use crate::metrics::Metrics;
+use crate::progress::{Progress, ProgressMode};
+use crate::src_codec::{compress_src, GZIP_ENCODING};
+
 const BATCH_SIZE: usize = 100;
 
+/// What ArangoDB should do when a document with the same key already exists
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Fail the import with a unique constraint violation
+    #[default]
+    Error,
+    /// Replace the existing document
+    Replace,
+    /// Merge the new attributes into the existing document
+    Update,
+    /// Keep the existing document
+    Ignore,
+}
+
+/// Settings controlling how documents are written to ArangoDB
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    /// Wait until the documents are synced to disk
+    pub wait_for_sync: bool,
+    /// What to do with documents whose key already exists
+    pub on_conflict: OnConflict,
+    /// Store the `src` field gzip compressed and base64 encoded
+    pub compress_src: bool,
+    /// Import chunks without embedding too, without the `v` field, so that
+    /// the vectors can be added by a later import
+    pub allow_missing_vectors: bool,
+    /// Store at most this many characters of each chunk's source, the
+    /// embedding is computed from all of it
+    pub max_src_chars: Option<usize>,
+    /// Only import chunks whose qualified name matches this expression
+    pub name_filter: Option<Regex>,
+    /// Delete documents of the imported source files whose chunks were not
+    /// produced in this run
+    pub prune: bool,
+    /// Skip documents recorded as imported by an interrupted earlier run
+    pub resume: bool,
+    /// Skip documents whose stored `src_hash` matches the chunk content
+    pub skip_unchanged: bool,
+    /// Create the analyzer and ArangoSearch view for the name search
+    pub create_search_view: bool,
+    /// Leave out chunks of header declarations whose definition is chunked
+    /// from an implementation file, see `preferred_chunks`
+    pub dedup_headers: bool,
+    /// The chunks are streamed from memory and never written, so nothing
+    /// is read from or written to the output directory
+    pub in_memory: bool,
+    /// Drop a vector index whose dimension differs from the embeddings'
+    /// instead of refusing to import
+    pub reindex: bool,
+    /// Edge collection to fill with the call relations recorded by the
+    /// chunker, between the imported documents
+    pub edge_collection: Option<String>,
+    /// Send the documents as newline-delimited JSON to the bulk import API,
+    /// this many per request, instead of batches to the document API
+    pub ndjson_segment_size: Option<usize>,
+    /// Import each document into `<collection>_<kind>` by the kind of its
+    /// symbol, creating those collections as needed
+    pub split_by_kind: bool,
+    /// Stamp the collection with a `_meta` document saying it expires this
+    /// many hours after the import, for the `cleanup` command
+    pub expires_in_hours: Option<u64>,
+    /// Fail instead of warning if there are no chunk files to import
+    pub strict: bool,
+    /// How to report the progress of the import
+    pub progress: ProgressMode,
+    /// Counters of the run, updated after every batch
+    pub metrics: Arc<Metrics>,
+}
+
 pub struct Importer {
     output_dir: String,
-    client: Client,
-    endpoint: String,
-    username: String,
-    password: String,
-    database: String,
+    client: ArangoClient,
     collection: String,
+    options: ImportOptions,
+    /// Set once the vector index of the collection was checked against the
+    /// dimension of the imported embeddings
+    vector_index_checked: OnceCell<()>,
+    /// Per-kind collections created so far with `split_by_kind`, and
+    /// whether their vector index was checked
+    kind_collections: Mutex<HashMap<String, bool>>,
+}
+
+/// A chunk together with its embedding, as produced by the streaming pipeline
+pub struct EmbeddedChunk {
+    pub path: PathBuf,
+    pub content: String,
+    /// The vector of the chunk and that of the comment above the symbol, if
+    /// extracted. None for chunks that were too small to embed, which are
+    /// only imported with `allow_missing_vectors`.
+    pub vectors: Option<ChunkVectors>,
+    /// `embedding_settings_hash` of the embedder that computed the vectors
+    pub embed_hash: String,
+    /// Content of the `_index.txt` of the chunk's directory if the chunk
+    /// was never written, otherwise the index is read from the directory
+    pub index: Option<Arc<str>>,
+}
+
+/// Metadata from the `_index.txt` file the chunker writes next to the chunk files
+#[derive(Debug, Default)]
+struct ChunkIndex {
+    /// Qualified symbol name per chunk file name
+    names: HashMap<String, String>,
+    /// Kind of symbol per chunk file name
+    kinds: HashMap<String, String>,
+    /// Declaration of the symbol before its body per chunk file name
+    signatures: HashMap<String, String>,
+    /// Name of the preceding chunk in the source file, per chunk file name
+    prev: HashMap<String, String>,
+    /// Name of the following chunk in the source file, per chunk file name
+    next: HashMap<String, String>,
+    /// Chunk files of symbols clangd tagged deprecated
+    deprecated: HashSet<String>,
+    /// Relative path of the definition, per chunk file of a declaration
+    declaration_of: HashMap<String, String>,
+    /// Relative path of the declaration, per chunk file of a definition
+    definition_of: HashMap<String, String>,
+    source_file: Option<String>,
+    /// Path of the source file relative to the project
+    source_path: Option<String>,
+    commit: Option<String>,
+    blob_sha: Option<String>,
+    /// Whether the chunks were found heuristically instead of by clangd
+    heuristic: bool,
+}
+
+/// Chunk metadata per chunk directory, read lazily
+type ChunkIndexes = HashMap<PathBuf, ChunkIndex>;
+
+/// Combined embedding files of the per-file layout per chunk directory,
+/// read lazily
+type CombinedEmbeddings = HashMap<PathBuf, Map<String, Value>>;
+
+/// Document keys and source files produced in a run, to prune the others
+#[derive(Debug, Default)]
+struct SeenChunks {
+    keys: HashSet<String>,
+    source_files: HashSet<String>,
+    /// Keys of the documents in the collection after the run, by name, for
+    /// the edges between them
+    names: HashMap<String, Vec<String>>,
+}
+
+impl SeenChunks {
+    fn add(&mut self, document: &Document) {
+        self.keys.insert(document.key.clone());
+        if let Some(source_file) = &document.source_file {
+            self.source_files.insert(source_file.clone());
+        }
+    }
+
+    /// Records a document that is (or already was) in the collection
+    fn add_imported(&mut self, document: &Document) {
+        self.names
+            .entry(document.name.clone())
+            .or_default()
+            .push(document.key.clone());
+    }
 }
 
 #[derive(Debug)]
 struct Document {
+    key: String,
     name: String,
+    /// Kind of the symbol, if the chunk index has it
+    kind: Option<String>,
+    chunk_file: String,
     v: Vec<f32>,
     src: String,
+    src_encoding: Option<&'static str>,
+    /// Hash of the uncompressed source, to detect unchanged chunks
+    src_hash: String,
+    /// Length of the source in characters if `src` holds only its start
+    src_truncated_from: Option<usize>,
+    source_file: Option<String>,
+    /// Path of the source file relative to the project
+    source_path: Option<String>,
+    commit: Option<String>,
+    blob_sha: Option<String>,
+    /// Multi-vector embedding for late-interaction scoring, if computed
+    mv: Option<Value>,
+    prev_chunk: Option<String>,
+    next_chunk: Option<String>,
+    /// Embedding of the comment above the symbol, if extracted
+    comment_v: Option<Value>,
+    deprecated: bool,
+    /// Found by the brace matching heuristic, so of lower quality
+    heuristic: bool,
+    /// Key of the definition if this chunk is a declaration
+    declaration_of: Option<String>,
+    /// Key of the declaration if this chunk is a definition
+    definition_of: Option<String>,
+    /// Embedding model and instruction, as recorded with the embedding
+    model: Option<String>,
+    instruction: Option<String>,
+    /// Hash of the embedding settings, see `embedding_settings_hash`
+    embed_hash: Option<String>,
+}
+
+/// The `src_hash` and `embed_hash` of the stored documents by key
+type StoredHashes = HashMap<String, (String, Option<String>)>;
+
+/// Whether the collection holds the document as it would be imported now:
+/// with the same source, embedded with the same settings
+fn is_unchanged(stored_hashes: &StoredHashes, document: &Document) -> bool {
+    stored_hashes
+        .get(&document.key)
+        .is_some_and(|(src_hash, embed_hash)| {
+            *src_hash == document.src_hash
+                && document.embed_hash.is_some()
+                && *embed_hash == document.embed_hash
+        })
+}
+
+/// The metadata of a written chunk, read from the index of its directory
+/// on first use
+fn lookup_chunk_index<'a>(
+    chunk_indexes: &'a mut ChunkIndexes,
+    chunk_path: &Path,
+) -> &'a ChunkIndex {
+    let dir = chunk_path.parent().unwrap_or(Path::new(""));
+    chunk_indexes
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| read_chunk_index(dir))
+}
+
+/// Reads the `_index.txt` file in a chunk directory
+fn read_chunk_index(dir: &Path) -> ChunkIndex {
+    match fs::read_to_string(dir.join("_index.txt")) {
+        Ok(index) => parse_chunk_index(&index),
+        Err(_) => ChunkIndex::default(),
+    }
+}
+
+/// Parses the content of an `_index.txt` file
+fn parse_chunk_index(index: &str) -> ChunkIndex {
+    let mut chunk_index = ChunkIndex::default();
+    let mut current_chunk: Option<&str> = None;
+    for line in index.lines() {
+        if let Some(chunk_file) = line.strip_prefix("Chunk: ") {
+            current_chunk = Some(chunk_file.trim());
+        } else if let Some(name) = line.strip_prefix("  Name: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .names
+                    .insert(chunk_file.to_string(), name.to_string());
+            }
+        } else if let Some(kind) = line.strip_prefix("  Kind: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .kinds
+                    .insert(chunk_file.to_string(), kind.to_string());
+            }
+        } else if let Some(signature) = line.strip_prefix("  Signature: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .signatures
+                    .insert(chunk_file.to_string(), signature.to_string());
+            }
+        } else if let Some(prev) = line.strip_prefix("  Prev: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .prev
+                    .insert(chunk_file.to_string(), prev.to_string());
+            }
+        } else if let Some(next) = line.strip_prefix("  Next: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .next
+                    .insert(chunk_file.to_string(), next.to_string());
+            }
+        } else if let Some(definition) = line.strip_prefix("  Declaration of: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .declaration_of
+                    .insert(chunk_file.to_string(), definition.to_string());
+            }
+        } else if let Some(declaration) = line.strip_prefix("  Definition of: ") {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index
+                    .definition_of
+                    .insert(chunk_file.to_string(), declaration.to_string());
+            }
+        } else if line == "  Deprecated: true" {
+            if let Some(chunk_file) = current_chunk {
+                chunk_index.deprecated.insert(chunk_file.to_string());
+            }
+        } else if let Some(source_file) = line.strip_prefix("Source file: ") {
+            chunk_index.source_file = Some(source_file.trim().to_string());
+        } else if let Some(source_path) = line.strip_prefix("Source path: ") {
+            chunk_index.source_path = Some(source_path.trim().to_string());
+        } else if let Some(commit) = line.strip_prefix("Commit: ") {
+            chunk_index.commit = Some(commit.trim().to_string());
+        } else if let Some(blob_sha) = line.strip_prefix("Blob SHA: ") {
+            chunk_index.blob_sha = Some(blob_sha.trim().to_string());
+        } else if line == "Chunker: heuristic" {
+            chunk_index.heuristic = true;
+        }
+    }
+    chunk_index
+}
+
+/// The key of the offending document in an error detail of the bulk
+/// import API, e.g. "at position 3: creating document failed with error
+/// 'unique constraint violated', offending document: {"_key":"a",...}"
+fn offending_key(detail: &str) -> Option<String> {
+    let (_, document) = detail.split_once("offending document: ")?;
+    let document = serde_json::Deserializer::from_str(document)
+        .into_iter::<Value>()
+        .next()?
+        .ok()?;
+    document["_key"].as_str().map(str::to_string)
+}
+
+/// Name of the checkpoint file in the output directory
+const CHECKPOINT_FILE: &str = "_imported_keys.log";
+
+/// Log of the keys of all documents imported so far, one per line, so that
+/// an interrupted import can be resumed. Keys are appended once ArangoDB
+/// confirmed their documents, also those of a batch in which others
+/// failed, and the log is removed when the import completes.
+struct Checkpoint {
+    path: PathBuf,
+    /// Missing if no checkpoint is kept
+    file: Option<File>,
+    keys: HashSet<String>,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint, keeping the keys of an earlier run if resuming
+    /// and starting afresh otherwise
+    fn open(output_dir: &str, resume: bool) -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(output_dir).join(CHECKPOINT_FILE);
+        let keys = if resume {
+            fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.to_string())
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to open checkpoint '{}': {}", path.display(), e))?;
+        if !resume {
+            file.set_len(0)?;
+        }
+        Ok(Self {
+            path,
+            file: Some(file),
+            keys,
+        })
+    }
+
+    /// A checkpoint that records nothing, for imports that cannot resume
+    fn disabled() -> Self {
+        Self {
+            path: PathBuf::new(),
+            file: None,
+            keys: HashSet::new(),
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Records the keys of imported documents
+    fn record<'a>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let mut lines = String::new();
+        for key in keys {
+            lines.push_str(key);
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| {
+                format!(
+                    "Failed to write checkpoint '{}': {}",
+                    self.path.display(),
+                    e
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Removes the log after a complete import
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(|e| {
+            format!(
+                "Failed to remove checkpoint '{}': {}",
+                self.path.display(),
+                e
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// The parameter list of a function signature, normalized so that a
+/// declaration and its definition agree: without default arguments and
+/// with whitespace collapsed. It is the first parenthesized group after the
+/// last component of the name, so "int Foo::bar(int x = 1) const" with the
+/// name "Foo::bar" gives "(int x)". None if the signature has no such group.
+fn parameter_list(name: &str, signature: &str) -> Option<String> {
+    let short_name = name.rsplit("::").next().unwrap_or(name);
+    let after_name = signature.find(short_name)? + short_name.len();
+    let open = after_name + signature[after_name..].find('(')?;
+    let mut depth = 0;
+    let mut params = vec![String::new()];
+    let mut in_default = false;
+    for c in signature[open + 1..].chars() {
+        match c {
+            '(' | '<' | '[' | '{' => depth += 1,
+            ')' if depth == 0 => {
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|param| param.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .filter(|param| !param.is_empty())
+                    .collect();
+                return Some(format!("({})", params.join(", ")));
+            }
+            ')' | '>' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                params.push(String::new());
+                in_default = false;
+                continue;
+            }
+            '=' if depth == 0 => in_default = true,
+            _ => {}
+        }
+        if !in_default {
+            params.last_mut()?.push(c);
+        }
+    }
+    None
+}
+
+/// Chooses the chunk files to import if header declarations are collapsed
+/// into their definitions: the chunk of a header is left out if a chunk of
+/// an implementation file has the same qualified name and parameter list.
+/// Overloads thus stay apart, and chunks without signature, like classes
+/// and namespaces, are all kept, as are chunks of two headers or of two
+/// implementation files. Returns the chunk files to import.
+fn preferred_chunks(output_dir: &str) -> HashSet<PathBuf> {
+    let mut chunk_indexes = ChunkIndexes::new();
+    let mut chosen = HashSet::new();
+    // Chunk files of headers and the keys of implementation chunks
+    let mut header_chunks: Vec<(String, PathBuf)> = Vec::new();
+    let mut implemented: HashSet<String> = HashSet::new();
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().ends_with(".cpp"))
+    {
+        let path = entry.path();
+        let chunk_index = lookup_chunk_index(&mut chunk_indexes, path);
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        // Chunks without name or signature are never collapsed
+        let key = chunk_index.names.get(&file_name).and_then(|name| {
+            let signature = chunk_index.signatures.get(&file_name)?;
+            Some(format!("{}{}", name, parameter_list(name, signature)?))
+        });
+        let Some(key) = key else {
+            chosen.insert(path.to_path_buf());
+            continue;
+        };
+        let is_header = chunk_index
+            .source_file
+            .as_deref()
+            .is_some_and(|source_file| {
+                Path::new(source_file).extension().is_some_and(|ext| {
+                    HEADER_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+                })
+            });
+        if is_header {
+            header_chunks.push((key, path.to_path_buf()));
+        } else {
+            implemented.insert(key);
+            chosen.insert(path.to_path_buf());
+        }
+    }
+    chosen.extend(
+        header_chunks
+            .into_iter()
+            .filter(|(key, _)| !implemented.contains(key))
+            .map(|(_, path)| path),
+    );
+    chosen
+}
+
+/// Reads the embedding record of a chunk, from its own embedding file or
+/// from the combined file of its directory
+fn read_embedding(
+    file_path: &Path,
+    combined: &mut CombinedEmbeddings,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    let json_file_path = file_path.with_extension("embedding.json");
+    if json_file_path.exists() {
+        let json_content = fs::read_to_string(&json_file_path)?;
+        return Ok(Some(serde_json::from_str(&json_content)?));
+    }
+
+    let dir = file_path.parent().unwrap_or(Path::new(""));
+    if !combined.contains_key(dir) {
+        let path = dir.join(COMBINED_EMBEDDINGS_FILE);
+        let records = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| {
+                format!(
+                    "Failed to parse embeddings file '{}': {}",
+                    path.display(),
+                    e
+                )
+            })?,
+            Err(_) => Map::new(),
+        };
+        combined.insert(dir.to_path_buf(), records);
+    }
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(combined[dir].get(name.as_ref()).cloned())
+}
+
+/// Builds a document key from the chunk path relative to the output directory.
+/// Characters not allowed in ArangoDB keys are replaced by underscores.
+fn make_key(relative_path: &Path) -> String {
+    let mut key: String = relative_path
+        .with_extension("")
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_-:.@()+,=;$!*'%".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    key.truncate(254);
+    key
+}
+
+/// Builds an `Importer` with named setters, so that the connection
+/// settings cannot be mixed up. All of them are required, the password may
+/// be empty.
+#[derive(Default)]
+pub struct ImporterBuilder {
+    output_dir: Option<String>,
+    endpoint: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    database: Option<String>,
+    collection: Option<String>,
+    options: ImportOptions,
+}
+
+impl ImporterBuilder {
+    pub fn output_dir(mut self, output_dir: impl Into<String>) -> Self {
+        self.output_dir = Some(output_dir.into());
+        self
+    }
+
+    /// ArangoDB endpoint URL, or several separated by commas
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn database(mut self, database: impl Into<String>) -> Self {
+        self.database = Some(database.into());
+        self
+    }
+
+    pub fn collection(mut self, collection: impl Into<String>) -> Self {
+        self.collection = Some(collection.into());
+        self
+    }
+
+    pub fn options(mut self, options: ImportOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Checks that all settings are given, non-empty except for the
+    /// password, and that the collection name is valid
+    pub fn build(self) -> Result<Importer, Box<dyn Error>> {
+        let required = |value: Option<String>, what: &str| {
+            value
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| format!("Importer needs {}", what))
+        };
+        let output_dir = required(self.output_dir, "an output directory")?;
+        let endpoint = required(self.endpoint, "an ArangoDB endpoint")?;
+        let username = required(self.username, "an ArangoDB username")?;
+        let password = self.password.ok_or("Importer needs an ArangoDB password")?;
+        let database = required(self.database, "an ArangoDB database")?;
+        let collection = required(self.collection, "an ArangoDB collection")?;
+        validate_collection_name(&collection)?;
+        Ok(Importer::new(
+            output_dir,
+            endpoint,
+            username,
+            password,
+            database,
+            collection,
+            self.options,
+        ))
+    }
 }
 
 impl Importer {
+    /// Starts building an importer with named settings
+    pub fn builder() -> ImporterBuilder {
+        ImporterBuilder::default()
+    }
+
     pub fn new(
         output_dir: String,
         endpoint: String,
@@ -32,60 +672,163 @@ impl Importer {
         password: String,
         database: String,
         collection: String,
+        options: ImportOptions,
     ) -> Self {
         Self {
             output_dir,
-            client: Client::new(),
-            endpoint,
-            username,
-            password,
-            database,
+            client: ArangoClient::new(endpoint, username, password, database),
             collection,
+            options,
+            vector_index_checked: OnceCell::new(),
+            kind_collections: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Builds the document API URL of a collection below the database URL,
+    /// including the query parameters for durability and conflict handling
+    fn document_url(&self, database_url: &str, collection: &str) -> String {
+        let mut params = Vec::new();
+        if self.options.wait_for_sync {
+            params.push("waitForSync=true");
+        }
+        match self.options.on_conflict {
+            OnConflict::Error => {}
+            OnConflict::Replace => params.push("overwriteMode=replace"),
+            OnConflict::Update => params.push("overwriteMode=update"),
+            OnConflict::Ignore => params.push("overwriteMode=ignore"),
+        }
+
+        let mut url = format!("{}/_api/document/{}", database_url, collection);
+        if !params.is_empty() {
+            url.push('?');
+            url.push_str(&params.join("&"));
+        }
+        url
+    }
+
     pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        if staging_pending(Path::new(&self.output_dir)) {
+            return Err(format!(
+                "The embeddings in '{}' are being swapped to a new model, run the embedding \
+                 phase again to finish the swap before importing",
+                self.output_dir
+            )
+            .into());
+        }
+        if self.options.create_search_view {
+            self.create_search_view().await?;
+        }
+        if let Some(hours) = self.options.expires_in_hours {
+            self.stamp_expiry(hours).await?;
+        }
+
         // Read all JSON files from the output directory recursively
         let entries: Vec<_> = WalkDir::new(&self.output_dir)
             .into_iter()
             .filter_map(|e| e.ok())
             .filter(|e| e.file_name().to_string_lossy().ends_with(".cpp"))
             .collect();
+        if entries.is_empty() {
+            return self.report_no_chunks();
+        }
 
-        let pb = ProgressBar::new(entries.len() as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%)")
-                .unwrap()
-                .progress_chars("#>-"),
+        let pb = Progress::new(
+            entries.len() as u64,
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%)",
+            self.options.progress,
+            "Importing",
         );
 
-        let mut current_batch = Vec::with_capacity(BATCH_SIZE);
+        let batch_size = self.batch_size();
+        let mut current_batch = Vec::with_capacity(batch_size);
+        let mut chunk_indexes = ChunkIndexes::new();
+        let mut combined = CombinedEmbeddings::new();
+        let mut seen = SeenChunks::default();
+        let mut checkpoint = Checkpoint::open(&self.output_dir, self.options.resume)?;
+        let stored_hashes = self.stored_hashes().await?;
+        let preferred = self.preferred_chunks();
+        let mut imported = 0;
+        let mut filtered = 0;
+        let mut resumed = 0;
+        let mut unchanged = 0;
+        let mut collapsed = 0;
+        let mut without_embedding = 0;
+        let mut outdated = 0;
 
         for entry in entries {
             let file_path = entry.path();
-            let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
-            let json_file_path = file_path.with_extension("embedding.json");
+            let content = fs::read_to_string(file_path)?;
+            let src_hash = content_hash(&content);
 
-            let content = fs::read_to_string(&file_path)?;
-            let json_content = fs::read_to_string(&json_file_path)?;
-            let json: Value = serde_json::from_str(&json_content)?;
+            // Every chunk file counts as seen for --prune, also the ones
+            // that are not imported for lack of an embedding
+            let chunk_index = lookup_chunk_index(&mut chunk_indexes, file_path);
+            let mut document = self.make_document(file_path, content, Vec::new(), chunk_index)?;
+            seen.add(&document);
 
-            let document = Document {
-                name: file_name.to_string(),
-                v: json["v"]
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|v| v.as_f64().unwrap() as f32)
-                    .collect(),
-                src: content,
+            // Chunks that were too small to embed, or were not embedded
+            // yet, have no embedding. One computed from another version of
+            // the chunk does not count.
+            let json = match read_embedding(file_path, &mut combined)? {
+                Some(json)
+                    if json["src_hash"]
+                        .as_str()
+                        .is_some_and(|hash| hash != src_hash) =>
+                {
+                    outdated += 1;
+                    None
+                }
+                json => json,
+            };
+            let json = match json {
+                Some(json) => json,
+                None if self.options.allow_missing_vectors => Value::Null,
+                None => {
+                    without_embedding += 1;
+                    pb.inc(1);
+                    continue;
+                }
             };
 
+            document.v = json["v"]
+                .as_array()
+                .map(|v| v.iter().map(|v| v.as_f64().unwrap() as f32).collect())
+                .unwrap_or_default();
+            if !self.matches_name_filter(&document) {
+                filtered += 1;
+                pb.inc(1);
+                continue;
+            }
+            if preferred
+                .as_ref()
+                .is_some_and(|preferred| !preferred.contains(file_path))
+            {
+                collapsed += 1;
+                pb.inc(1);
+                continue;
+            }
+            seen.add_imported(&document);
+            if checkpoint.contains(&document.key) {
+                resumed += 1;
+                pb.inc(1);
+                continue;
+            }
+            document.mv = json.get("mv").cloned();
+            document.comment_v = json.get("comment_v").cloned();
+            document.model = json["model"].as_str().map(str::to_string);
+            document.instruction = json["instruction"].as_str().map(str::to_string);
+            document.embed_hash = (!json.is_null()).then(|| embedding_settings_hash(&json));
+            if is_unchanged(&stored_hashes, &document) {
+                unchanged += 1;
+                pb.inc(1);
+                continue;
+            }
             current_batch.push(document);
 
-            if current_batch.len() >= BATCH_SIZE {
-                self.import_batch(&current_batch).await?;
+            if current_batch.len() >= batch_size {
+                self.import_documents(&current_batch, imported, &mut checkpoint)
+                    .await?;
+                imported += current_batch.len();
                 current_batch.clear();
             }
 
@@ -94,36 +837,856 @@ impl Importer {
 
         // Import any remaining documents
         if !current_batch.is_empty() {
-            self.import_batch(&current_batch).await?;
+            self.import_documents(&current_batch, imported, &mut checkpoint)
+                .await?;
+            imported += current_batch.len();
         }
 
         pb.finish_with_message("Import completed");
+        if self.options.name_filter.is_some() {
+            println!(
+                "Imported {} documents, {} did not match the name filter",
+                imported, filtered
+            );
+        }
+        if resumed > 0 {
+            println!(
+                "Skipped {} documents imported before the interruption",
+                resumed
+            );
+        }
+        if unchanged > 0 {
+            println!("Skipped {} unchanged documents", unchanged);
+        }
+        if without_embedding > 0 {
+            println!(
+                "Skipped {} chunks without an up-to-date embedding, too small to embed or not \
+                 embedded yet (import them without vector with --allow-missing-vectors)",
+                without_embedding
+            );
+        }
+        if outdated > 0 {
+            println!(
+                "Ignored {} embeddings computed from an older version of their chunk, embed again \
+                 to update them",
+                outdated
+            );
+        }
+        if collapsed > 0 {
+            println!(
+                "Skipped {} header declarations of functions defined in implementation files",
+                collapsed
+            );
+        }
+        if self.options.prune {
+            self.prune(&seen).await?;
+        }
+        if let Some(edge_collection) = &self.options.edge_collection {
+            self.import_edges(edge_collection, &seen.names).await?;
+        }
+        checkpoint.finish()?;
         Ok(())
     }
 
-    async fn import_batch(&self, documents: &[Document]) -> Result<(), Box<dyn Error>> {
-        let url = format!(
-            "{}/_db/{}/_api/document/{}",
-            self.endpoint, self.database, self.collection
+    /// Reports that there were no chunks to import, which usually means
+    /// that something went wrong before: a warning, or an error if strict
+    pub fn report_no_chunks(&self) -> Result<(), Box<dyn Error>> {
+        let message = format!(
+            "No chunks to import; did the chunker skip every file, or is '{}' the wrong \
+             output directory?",
+            self.output_dir
         );
+        if self.options.strict {
+            return Err(message.into());
+        }
+        eprintln!("WARN: {}", message);
+        Ok(())
+    }
 
-        let documents_json: Vec<Value> = documents
-            .iter()
-            .map(|doc| {
-                json!({
-                    "name": doc.name,
-                    "v": doc.v,
-                    "src": doc.src,
+    /// Imports chunks as they arrive on the channel, in batches, until the
+    /// sender is dropped. Returns the number of imported documents.
+    pub async fn import_stream(
+        &self,
+        mut chunks: Receiver<EmbeddedChunk>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let batch_size = self.batch_size();
+        let mut current_batch = Vec::with_capacity(batch_size);
+        let mut chunk_indexes = ChunkIndexes::new();
+        let mut in_memory_indexes: HashMap<usize, (Arc<str>, ChunkIndex)> = HashMap::new();
+        let mut seen = SeenChunks::default();
+        let mut checkpoint = if self.options.in_memory {
+            Checkpoint::disabled()
+        } else {
+            Checkpoint::open(&self.output_dir, self.options.resume)?
+        };
+        let stored_hashes = self.stored_hashes().await?;
+        let preferred = self.preferred_chunks();
+        let mut imported = 0;
+        if self.options.create_search_view {
+            self.create_search_view().await?;
+        }
+        if let Some(hours) = self.options.expires_in_hours {
+            self.stamp_expiry(hours).await?;
+        }
+
+        while let Some(chunk) = chunks.recv().await {
+            let chunk_index = match &chunk.index {
+                // Chunks that were never written bring the index of their
+                // source file, parsed once per index rather than per
+                // directory. The index is kept alive with its parse, so its
+                // address is not reused for another one.
+                Some(index) => {
+                    &in_memory_indexes
+                        .entry(Arc::as_ptr(index) as *const u8 as usize)
+                        .or_insert_with(|| (index.clone(), parse_chunk_index(index)))
+                        .1
+                }
+                None => lookup_chunk_index(&mut chunk_indexes, &chunk.path),
+            };
+            let (v, comment_v) = chunk.vectors.unzip();
+            let mut document = self.make_document(
+                &chunk.path,
+                chunk.content,
+                v.unwrap_or_default(),
+                chunk_index,
+            )?;
+            seen.add(&document);
+            if document.v.is_empty() && !self.options.allow_missing_vectors {
+                continue;
+            }
+            if !document.v.is_empty() {
+                document.embed_hash = Some(chunk.embed_hash);
+            }
+            document.comment_v = comment_v.flatten().map(|comment_v| json!(comment_v));
+            if !self.matches_name_filter(&document)
+                || preferred
+                    .as_ref()
+                    .is_some_and(|preferred| !preferred.contains(&chunk.path))
+            {
+                continue;
+            }
+            seen.add_imported(&document);
+            if checkpoint.contains(&document.key) || is_unchanged(&stored_hashes, &document) {
+                continue;
+            }
+            current_batch.push(document);
+
+            if current_batch.len() >= batch_size {
+                self.import_documents(&current_batch, imported, &mut checkpoint)
+                    .await?;
+                imported += current_batch.len();
+                current_batch.clear();
+            }
+        }
+
+        // Import any remaining documents
+        if !current_batch.is_empty() {
+            self.import_documents(&current_batch, imported, &mut checkpoint)
+                .await?;
+            imported += current_batch.len();
+        }
+
+        if self.options.prune {
+            self.prune(&seen).await?;
+        }
+        if let Some(edge_collection) = &self.options.edge_collection {
+            self.import_edges(edge_collection, &seen.names).await?;
+        }
+        checkpoint.finish()?;
+        Ok(imported)
+    }
+
+    /// Creates the analyzer for names and an ArangoSearch view indexing the
+    /// `name` field with it and `src` with the English text analyzer. Both
+    /// are kept if they exist already.
+    async fn create_search_view(&self) -> Result<(), Box<dyn Error>> {
+        let analyzer = json!({
+            "name": NAME_ANALYZER,
+            "type": "text",
+            "properties": {
+                "locale": "en",
+                "case": "lower",
+                "accent": false,
+                "stemming": false,
+                "stopwords": [],
+                "edgeNgram": { "min": 2, "max": 32, "preserveOriginal": true }
+            },
+            "features": ["frequency", "norm", "position"]
+        });
+        self.create_if_missing("analyzer", &analyzer).await?;
+
+        let view_name = search_view_name(&self.collection);
+        let view = json!({
+            "name": view_name,
+            "type": "arangosearch",
+            "links": {
+                self.collection.clone(): {
+                    "fields": {
+                        "name": { "analyzers": [NAME_ANALYZER] },
+                        "src": { "analyzers": ["text_en"] }
+                    }
+                }
+            }
+        });
+        self.create_if_missing("view", &view).await?;
+        println!("Search view {} is ready", view_name);
+        Ok(())
+    }
+
+    /// Imports the call relations written by the chunker as edges between
+    /// the documents of caller and callee into the edge collection, which
+    /// is created if needed. Edge keys are derived from the documents they
+    /// connect, so importing again replaces the edges. Relations of names
+    /// with several documents, e.g. overloads, are left out.
+    async fn import_edges(
+        &self,
+        edge_collection: &str,
+        names: &HashMap<String, Vec<String>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let relations_path = Path::new(&self.output_dir).join(RELATIONS_FILE);
+        let relations = fs::read_to_string(&relations_path).map_err(|e| {
+            format!(
+                "Failed to read relations file '{}', chunk with --record-calls: {}",
+                relations_path.display(),
+                e
+            )
+        })?;
+        self.create_if_missing("collection", &json!({ "name": edge_collection, "type": 3 }))
+            .await?;
+
+        let key_of = |name: &str| match names.get(name).map(Vec::as_slice) {
+            Some([key]) => Some(key.as_str()),
+            _ => None,
+        };
+        let mut edges = Vec::new();
+        let mut unresolved = 0;
+        for line in relations.lines().filter(|line| !line.trim().is_empty()) {
+            let relation: Value = serde_json::from_str(line).map_err(|e| {
+                format!(
+                    "Invalid line in relations file '{}': {}",
+                    relations_path.display(),
+                    e
+                )
+            })?;
+            let from = relation["caller"].as_str().and_then(key_of);
+            let to = relation["callee"].as_str().and_then(key_of);
+            let (Some(from), Some(to)) = (from, to) else {
+                unresolved += 1;
+                continue;
+            };
+            edges.push(json!({
+                "_key": content_hash(&format!("{}->{}", from, to)),
+                "_from": format!("{}/{}", self.collection, from),
+                "_to": format!("{}/{}", self.collection, to),
+                "type": "calls"
+            }));
+        }
+
+        for batch in edges.chunks(BATCH_SIZE) {
+            let response = self
+                .client
+                .send(|client, url| {
+                    client
+                        .post(format!(
+                            "{}/_api/document/{}?overwriteMode=replace",
+                            url, edge_collection
+                        ))
+                        .json(batch)
                 })
+                .await?;
+            if !response.status().is_success() {
+                let error_text = response.text().await?;
+                return Err(format!("Failed to import edges: {}", error_text).into());
+            }
+            let results: Value = response.json().await?;
+            if let Some(error) = results
+                .as_array()
+                .and_then(|results| results.iter().find(|r| r["error"].as_bool() == Some(true)))
+            {
+                return Err(format!(
+                    "Failed to import edges, first error: {}",
+                    error["errorMessage"].as_str().unwrap_or("unknown error")
+                )
+                .into());
+            }
+        }
+
+        println!(
+            "Imported {} call edges into {}",
+            edges.len(),
+            edge_collection
+        );
+        if unresolved > 0 {
+            println!(
+                "Skipped {} relations whose chunks were not imported or are ambiguous",
+                unresolved
+            );
+        }
+        Ok(())
+    }
+
+    /// Makes sure no vector index of the collection expects embeddings of
+    /// another dimension, as left behind by a different embedding model.
+    /// Such an index is dropped with `reindex`, otherwise importing fails.
+    async fn check_vector_index(
+        &self,
+        collection: &str,
+        dimension: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .client
+            .send(|client, url| {
+                client
+                    .get(format!("{}/_api/index", url))
+                    .query(&[("collection", collection)])
+            })
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            // The collection does not exist yet, so neither does an index
+            return Ok(());
+        }
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!(
+                "Failed to read the indexes of {}: {}",
+                collection, error_text
+            )
+            .into());
+        }
+        let result: Value = response.json().await?;
+        let indexes = result["indexes"].as_array().cloned().unwrap_or_default();
+        for index in indexes.iter().filter(|index| index["type"] == "vector") {
+            let Some(index_dimension) = index["params"]["dimension"].as_u64() else {
+                continue;
+            };
+            if index_dimension == dimension as u64 {
+                continue;
+            }
+            let id = index["id"].as_str().unwrap_or_default();
+            if !self.options.reindex {
+                return Err(format!(
+                    "Vector index '{}' of collection {} has dimension {}, but the embeddings have \
+                     dimension {}; the embedding model probably changed. Pass --reindex to drop the \
+                     index, re-import all documents with --on-conflict replace and create the index \
+                     again, or import into another collection",
+                    id, collection, index_dimension, dimension
+                )
+                .into());
+            }
+            let response = self
+                .client
+                .send(|client, url| client.delete(format!("{}/_api/index/{}", url, id)))
+                .await?;
+            if !response.status().is_success() {
+                let error_text = response.text().await?;
+                return Err(format!("Failed to drop vector index '{}': {}", id, error_text).into());
+            }
+            println!(
+                "Dropped vector index '{}' of dimension {}, create it again with dimension {} after the import",
+                id, index_dimension, dimension
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes the `_meta` document of the collection with the time of the
+    /// import and when the collection expires, taken from the server's
+    /// clock. Imports into the collection later move the expiry.
+    async fn stamp_expiry(&self, hours: u64) -> Result<(), Box<dyn Error>> {
+        self.client
+            .query(&json!({
+                "query": "LET now = DATE_NOW() \
+                          UPSERT { _key: @key } \
+                          INSERT { _key: @key, created_at: DATE_ISO8601(now), \
+                                   expires_at: DATE_ISO8601(DATE_ADD(now, @hours, 'hours')) } \
+                          UPDATE { expires_at: DATE_ISO8601(DATE_ADD(now, @hours, 'hours')) } \
+                          IN @@chunks",
+                "bindVars": { "@chunks": self.collection, "key": META_KEY, "hours": hours },
+            }))
+            .await
+            .map_err(|e| format!("Failed to stamp the expiry of {}: {}", self.collection, e))?;
+        Ok(())
+    }
+
+    /// Posts the definition to `/_api/<api>`, treating a conflict with an
+    /// existing object of the same name as success
+    async fn create_if_missing(&self, api: &str, definition: &Value) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .client
+            .send(|client, url| {
+                client
+                    .post(format!("{}/_api/{}", url, api))
+                    .json(definition)
+            })
+            .await?;
+        if response.status().is_success() || response.status() == StatusCode::CONFLICT {
+            return Ok(());
+        }
+        let error_text = response.text().await?;
+        Err(format!(
+            "Failed to create {} '{}': {}",
+            api, definition["name"], error_text
+        )
+        .into())
+    }
+
+    /// Takes the chunks from memory only, see `ImportOptions::in_memory`
+    pub fn in_memory(mut self) -> Self {
+        self.options.in_memory = true;
+        self
+    }
+
+    /// The chunk files to import if header declarations are collapsed
+    fn preferred_chunks(&self) -> Option<HashSet<PathBuf>> {
+        self.options
+            .dedup_headers
+            .then(|| preferred_chunks(&self.output_dir))
+    }
+
+    /// Returns the `src_hash` and `embed_hash` of every document in the
+    /// collection by key if unchanged documents are to be skipped, an empty
+    /// map otherwise
+    async fn stored_hashes(&self) -> Result<StoredHashes, Box<dyn Error>> {
+        if !self.options.skip_unchanged {
+            return Ok(HashMap::new());
+        }
+        let results = self
+            .client
+            .query(&json!({
+                "query": "FOR doc IN @@chunks FILTER doc.src_hash != null AND doc.v != null RETURN [doc._key, doc.src_hash, doc.embed_hash]",
+                "bindVars": { "@chunks": self.collection },
+                "batchSize": 10000
+            }))
+            .await
+            .map_err(|e| format!("Failed to read the stored content hashes: {}", e))?;
+        Ok(results
+            .iter()
+            .filter_map(|hashes| {
+                let key = hashes[0].as_str()?.to_string();
+                let src_hash = hashes[1].as_str()?.to_string();
+                Some((key, (src_hash, hashes[2].as_str().map(str::to_string))))
+            })
+            .collect())
+    }
+
+    /// Deletes all documents of the source files seen in this run whose keys
+    /// were not produced in this run, i.e. chunks of symbols that no longer
+    /// exist. Documents of other source files are left alone.
+    async fn prune(&self, seen: &SeenChunks) -> Result<(), Box<dyn Error>> {
+        if seen.source_files.is_empty() {
+            return Ok(());
+        }
+
+        let query_body = json!({
+            "query": "FOR doc IN @@chunks \
+                      FILTER doc.source_file IN @source_files AND doc._key NOT IN @keys \
+                      REMOVE doc IN @@chunks \
+                      COLLECT WITH COUNT INTO removed \
+                      RETURN removed",
+            "bindVars": {
+                "@chunks": self.collection,
+                "source_files": seen.source_files,
+                "keys": seen.keys
+            }
+        });
+
+        let response = self
+            .client
+            .send(|client, url| {
+                client
+                    .post(format!("{}/_api/cursor", url))
+                    .json(&query_body)
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("Failed to prune stale documents: {}", error_text).into());
+        }
+
+        let result: Value = response.json().await?;
+        let removed = result["result"][0].as_u64().unwrap_or(0);
+        println!("Pruned {} stale documents", removed);
+        Ok(())
+    }
+
+    fn matches_name_filter(&self, document: &Document) -> bool {
+        match &self.options.name_filter {
+            Some(filter) => filter.is_match(&document.name),
+            None => true,
+        }
+    }
+
+    /// Builds the document for a chunk file from its content and embedding
+    fn make_document(
+        &self,
+        file_path: &Path,
+        mut content: String,
+        v: Vec<f32>,
+        chunk_index: &ChunkIndex,
+    ) -> Result<Document, Box<dyn Error>> {
+        let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
+
+        // Look up the qualified symbol name in the chunk metadata, falling
+        // back to the file name if there is no index entry for it
+        let name = chunk_index
+            .names
+            .get(&file_name)
+            .cloned()
+            .unwrap_or_else(|| file_name.clone());
+        let relative_path = file_path
+            .strip_prefix(&self.output_dir)
+            .unwrap_or(file_path);
+
+        let src_hash = content_hash(&content);
+        let src_length = content.chars().count();
+        let src_truncated_from = match self.options.max_src_chars {
+            Some(max_src_chars) if src_length > max_src_chars => {
+                content = content.chars().take(max_src_chars).collect();
+                Some(src_length)
+            }
+            _ => None,
+        };
+        let (src, src_encoding) = if self.options.compress_src {
+            (compress_src(&content)?, Some(GZIP_ENCODING))
+        } else {
+            (content, None)
+        };
+
+        Ok(Document {
+            key: make_key(relative_path),
+            name,
+            kind: chunk_index.kinds.get(&file_name).cloned(),
+            chunk_file: file_name.clone(),
+            v,
+            src,
+            src_encoding,
+            src_hash,
+            src_truncated_from,
+            source_file: chunk_index.source_file.clone(),
+            source_path: chunk_index.source_path.clone(),
+            commit: chunk_index.commit.clone(),
+            blob_sha: chunk_index.blob_sha.clone(),
+            mv: None,
+            prev_chunk: chunk_index.prev.get(&file_name).cloned(),
+            next_chunk: chunk_index.next.get(&file_name).cloned(),
+            comment_v: None,
+            deprecated: chunk_index.deprecated.contains(&file_name),
+            heuristic: chunk_index.heuristic,
+            declaration_of: chunk_index
+                .declaration_of
+                .get(&file_name)
+                .map(|path| make_key(Path::new(path))),
+            definition_of: chunk_index
+                .definition_of
+                .get(&file_name)
+                .map(|path| make_key(Path::new(path))),
+            model: None,
+            instruction: None,
+            embed_hash: None,
+        })
+    }
+
+    /// Number of documents sent per request
+    fn batch_size(&self) -> usize {
+        self.options
+            .ndjson_segment_size
+            .unwrap_or(BATCH_SIZE)
+            .max(1)
+    }
+
+    /// Imports documents with the configured API and records the imported
+    /// ones in the checkpoint, also if some of them fail. `confirmed` is the
+    /// number of documents imported before, for the error message.
+    async fn import_documents(
+        &self,
+        documents: &[Document],
+        confirmed: usize,
+        checkpoint: &mut Checkpoint,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.options.split_by_kind {
+            if let Some(document) = documents.iter().find(|document| !document.v.is_empty()) {
+                self.vector_index_checked
+                    .get_or_try_init(|| self.check_vector_index(&self.collection, document.v.len()))
+                    .await?;
+            }
+            let documents: Vec<&Document> = documents.iter().collect();
+            return self
+                .import_into(&self.collection, &documents, confirmed, checkpoint)
+                .await;
+        }
+
+        // Route every document to the collection of its kind
+        let mut by_collection: BTreeMap<String, Vec<&Document>> = BTreeMap::new();
+        for document in documents {
+            by_collection
+                .entry(self.kind_collection(document))
+                .or_default()
+                .push(document);
+        }
+        for (collection, documents) in by_collection {
+            self.prepare_kind_collection(&collection, &documents)
+                .await?;
+            self.import_into(&collection, &documents, confirmed, checkpoint)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The collection of a document with `split_by_kind`, documents of
+    /// unknown kind stay in the main collection
+    fn kind_collection(&self, document: &Document) -> String {
+        match &document.kind {
+            Some(kind) => format!("{}_{}", self.collection, kind),
+            None => self.collection.clone(),
+        }
+    }
+
+    /// Creates a per-kind collection when it is first used and checks its
+    /// vector index once documents with vectors arrive
+    async fn prepare_kind_collection(
+        &self,
+        collection: &str,
+        documents: &[&Document],
+    ) -> Result<(), Box<dyn Error>> {
+        let checked = self
+            .kind_collections
+            .lock()
+            .unwrap()
+            .get(collection)
+            .copied();
+        if checked.is_none() {
+            self.create_if_missing("collection", &json!({ "name": collection }))
+                .await?;
+        }
+        let mut checked = checked.unwrap_or(false);
+        if !checked {
+            if let Some(document) = documents.iter().find(|document| !document.v.is_empty()) {
+                self.check_vector_index(collection, document.v.len())
+                    .await?;
+                checked = true;
+            }
+        }
+        self.kind_collections
+            .lock()
+            .unwrap()
+            .insert(collection.to_string(), checked);
+        Ok(())
+    }
+
+    /// Imports documents into one collection with the configured API
+    async fn import_into(
+        &self,
+        collection: &str,
+        documents: &[&Document],
+        confirmed: usize,
+        checkpoint: &mut Checkpoint,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.options.ndjson_segment_size.is_none() {
+            return self.import_batch(collection, documents, checkpoint).await;
+        }
+        self.import_ndjson(collection, documents, checkpoint)
+            .await
+            .map_err(|e| {
+                format!(
+                    "{} ({} documents were imported before, continue with --resume)",
+                    e, confirmed
+                )
+                .into()
+            })
+    }
+
+    /// Builds the ArangoDB document of a chunk
+    fn document_json(doc: &Document) -> Value {
+        let mut json = json!({
+            "_key": doc.key,
+            "name": doc.name,
+            "chunk_file": doc.chunk_file,
+            "src": doc.src,
+            "src_hash": doc.src_hash,
+        });
+        // Chunks imported without embedding get their vector later
+        if !doc.v.is_empty() {
+            json["v"] = json!(doc.v);
+        }
+        if let Some(encoding) = doc.src_encoding {
+            json["src_encoding"] = json!(encoding);
+        }
+        if let Some(source_file) = &doc.source_file {
+            json["source_file"] = json!(source_file);
+            if let Some(ext) = Path::new(source_file).extension() {
+                json["source_ext"] = json!(ext.to_string_lossy().to_lowercase());
+            }
+        }
+        if let Some(source_path) = &doc.source_path {
+            json["source_path"] = json!(source_path);
+        }
+        if let Some(commit) = &doc.commit {
+            json["commit"] = json!(commit);
+        }
+        if let Some(blob_sha) = &doc.blob_sha {
+            json["blob_sha"] = json!(blob_sha);
+        }
+        if let Some(mv) = &doc.mv {
+            json["mv"] = mv.clone();
+        }
+        if let Some(comment_v) = &doc.comment_v {
+            json["comment_v"] = comment_v.clone();
+        }
+        if let Some(prev_chunk) = &doc.prev_chunk {
+            json["prev_chunk"] = json!(prev_chunk);
+        }
+        if let Some(next_chunk) = &doc.next_chunk {
+            json["next_chunk"] = json!(next_chunk);
+        }
+        if doc.deprecated {
+            json["deprecated"] = json!(true);
+        }
+        if doc.heuristic {
+            json["chunker"] = json!("heuristic");
+        }
+        if let Some(declaration_of) = &doc.declaration_of {
+            json["declaration_of"] = json!(declaration_of);
+        }
+        if let Some(definition_of) = &doc.definition_of {
+            json["definition_of"] = json!(definition_of);
+        }
+        if let Some(src_length) = doc.src_truncated_from {
+            json["src_truncated"] = json!(true);
+            json["src_length"] = json!(src_length);
+        }
+        if let Some(model) = &doc.model {
+            json["model"] = json!(model);
+        }
+        if let Some(instruction) = &doc.instruction {
+            json["instruction"] = json!(instruction);
+        }
+        if let Some(embed_hash) = &doc.embed_hash {
+            json["embed_hash"] = json!(embed_hash);
+        }
+        json
+    }
+
+    /// Imports a segment of documents through the bulk import API, as one
+    /// JSON document per line. The body is streamed, each document is
+    /// serialized when it is sent, and sent again from the start if the
+    /// connection fails over to another endpoint. The documents ArangoDB
+    /// confirms are recorded in the checkpoint, also if others fail; the
+    /// failed ones are told by the offending documents of the error details.
+    async fn import_ndjson(
+        &self,
+        collection: &str,
+        documents: &[&Document],
+        checkpoint: &mut Checkpoint,
+    ) -> Result<(), Box<dyn Error>> {
+        let documents_json: Arc<Vec<Value>> = Arc::new(
+            documents
+                .iter()
+                .map(|document| Self::document_json(document))
+                .collect(),
+        );
+        let on_duplicate = match self.options.on_conflict {
+            OnConflict::Error => "error",
+            OnConflict::Replace => "replace",
+            OnConflict::Update => "update",
+            OnConflict::Ignore => "ignore",
+        };
+        let wait_for_sync = self.options.wait_for_sync.to_string();
+        let sent_bytes = Arc::new(AtomicUsize::new(0));
+        let response = self
+            .client
+            .send(|client, url| {
+                let documents_json = documents_json.clone();
+                let sent_bytes = sent_bytes.clone();
+                sent_bytes.store(0, Ordering::Relaxed);
+                let lines = futures_util::stream::iter(0..documents_json.len()).map(move |i| {
+                    let mut line = serde_json::to_vec(&documents_json[i])?;
+                    line.push(b'\n');
+                    sent_bytes.fetch_add(line.len(), Ordering::Relaxed);
+                    Ok::<_, serde_json::Error>(line)
+                });
+                client
+                    .post(format!("{}/_api/import", url))
+                    .query(&[
+                        ("collection", collection),
+                        ("type", "documents"),
+                        ("details", "true"),
+                        ("onDuplicate", on_duplicate),
+                        ("waitForSync", wait_for_sync.as_str()),
+                    ])
+                    .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+                    .body(reqwest::Body::wrap_stream(lines))
             })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("Failed to import segment: {}", error_text).into());
+        }
+
+        let result: Value = response.json().await?;
+        let errors = result["errors"].as_u64().unwrap_or(0) as usize;
+        let details: Vec<&str> = result["details"]
+            .as_array()
+            .map(|details| details.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let failed: HashSet<String> = details
+            .iter()
+            .filter_map(|detail| offending_key(detail))
+            .collect();
+        let first_error = details.first().copied().unwrap_or("unknown error");
+        if failed.len() < errors {
+            // Without knowing which documents failed, none can be recorded
+            return Err(format!(
+                "Failed to import {} documents of segment, not all of them are named in the \
+                 details, first error: {}",
+                errors, first_error
+            )
+            .into());
+        }
+        checkpoint.record(
+            documents
+                .iter()
+                .map(|document| document.key.as_str())
+                .filter(|key| !failed.contains(*key)),
+        )?;
+        self.options.metrics.batch_imported(
+            documents.len() - errors.min(documents.len()),
+            sent_bytes.load(Ordering::Relaxed),
+        );
+        if errors > 0 {
+            return Err(format!(
+                "Failed to import {} documents of segment, first error: {}",
+                errors, first_error
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Imports a batch of documents through the document API and records
+    /// the ones that were created in the checkpoint, also if others fail
+    async fn import_batch(
+        &self,
+        collection: &str,
+        documents: &[&Document],
+        checkpoint: &mut Checkpoint,
+    ) -> Result<(), Box<dyn Error>> {
+        let documents_json: Vec<Value> = documents
+            .iter()
+            .map(|document| Self::document_json(document))
             .collect();
 
+        // Serialized once, so that the uploaded bytes can be counted
+        let body = serde_json::to_vec(&documents_json)?;
         let response = self
             .client
-            .post(&url)
-            .basic_auth(&self.username, Some(&self.password))
-            .json(&documents_json)
-            .send()
+            .send(|client, url| {
+                client
+                    .post(self.document_url(url, collection))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone())
+            })
             .await?;
 
         if !response.status().is_success() {
@@ -131,7 +1694,1298 @@ impl Importer {
             return Err(format!("Failed to import batch: {}", error_text).into());
         }
 
+        // The batch as a whole succeeds even if single documents fail, the
+        // results are in the order of the documents
+        let results: Value = response.json().await?;
+        let results = results.as_array().cloned().unwrap_or_default();
+        let is_error = |result: &Value| result["error"].as_bool() == Some(true);
+        let errors: Vec<&Value> = results.iter().filter(|result| is_error(result)).collect();
+        if results.len() == documents.len() {
+            checkpoint.record(
+                documents
+                    .iter()
+                    .zip(&results)
+                    .filter(|(_, result)| !is_error(result))
+                    .map(|(document, _)| document.key.as_str()),
+            )?;
+        }
+        self.options
+            .metrics
+            .batch_imported(documents.len() - errors.len(), body.len());
+        if let Some(first) = errors.first() {
+            return Err(format!(
+                "Failed to import {} documents of batch, first error: {}",
+                errors.len(),
+                first["errorMessage"].as_str().unwrap_or("unknown error")
+            )
+            .into());
+        }
+
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arango_mock::{accept_all, MockArango};
+    use crate::embedding::{Embedder, EmbeddingLayout};
+    use crate::embedding_common::enable_test_embedder;
+    use crate::fsutil::scratch_dir;
+    use std::sync::atomic::AtomicBool;
+
+    /// Writes a chunk directory with an index listing the chunks, given as
+    /// file name, qualified name and signature
+    fn write_chunk_dir(dir: &Path, source_file: &str, chunks: &[(&str, &str, Option<&str>)]) {
+        fs::create_dir_all(dir).unwrap();
+        let mut index = format!("Source file: {}\n---\n", source_file);
+        for (file_name, name, signature) in chunks {
+            fs::write(dir.join(file_name), "// chunk\n").unwrap();
+            index.push_str(&format!("Chunk: {}\n  Name: {}\n", file_name, name));
+            if let Some(signature) = signature {
+                index.push_str(&format!("  Signature: {}\n", signature));
+            }
+            index.push_str("---\n");
+        }
+        fs::write(dir.join("_index.txt"), index).unwrap();
+    }
+
+    fn chosen_files(output_dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = preferred_chunks(output_dir.to_str().unwrap())
+            .iter()
+            .map(|path| {
+                path.strip_prefix(output_dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parameter_list_ignores_defaults_and_qualifiers() {
+        assert_eq!(
+            parameter_list(
+                "Foo::bar",
+                "int Foo::bar(int x = 1, std::map<int, int> m) const"
+            ),
+            Some("(int x, std::map<int, int> m)".to_string())
+        );
+        assert_eq!(
+            parameter_list(
+                "bar",
+                "virtual int bar(int  x,\tstd::map<int, int> m = {}) const"
+            ),
+            Some("(int x, std::map<int, int> m)".to_string())
+        );
+        assert_eq!(parameter_list("f", "void f()"), Some("()".to_string()));
+        assert_eq!(parameter_list("Foo", "class Foo"), None);
+    }
+
+    #[test]
+    fn dedup_headers_prefers_the_implementation() {
+        let output = scratch_dir("dedup-headers");
+        write_chunk_dir(
+            &output.join("foo_h"),
+            "/project/foo.h",
+            &[
+                ("001_foo_function_3.cpp", "foo", Some("int foo(int x = 0)")),
+                ("002_foo_function_4.cpp", "foo", Some("int foo(double x)")),
+                ("003_ns_namespace_1.cpp", "ns", None),
+            ],
+        );
+        write_chunk_dir(
+            &output.join("foo_cpp"),
+            "/project/foo.cpp",
+            &[
+                ("001_ns_namespace_1.cpp", "ns", None),
+                ("002_foo_function_3.cpp", "foo", Some("int foo(int x)")),
+            ],
+        );
+
+        // The header's declaration of the implemented overload is left out,
+        // the other overload and the namespaces are kept
+        assert_eq!(
+            chosen_files(&output),
+            vec![
+                "foo_cpp/001_ns_namespace_1.cpp",
+                "foo_cpp/002_foo_function_3.cpp",
+                "foo_h/002_foo_function_4.cpp",
+                "foo_h/003_ns_namespace_1.cpp",
+            ]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn dedup_headers_keeps_chunks_of_the_same_kind_of_file() {
+        let output = scratch_dir("dedup-headers-same-kind");
+        write_chunk_dir(
+            &output.join("a_cpp"),
+            "/project/a.cpp",
+            &[(
+                "001_helper_function_1.cpp",
+                "helper",
+                Some("static int helper()"),
+            )],
+        );
+        write_chunk_dir(
+            &output.join("b_cpp"),
+            "/project/b.cpp",
+            &[(
+                "001_helper_function_1.cpp",
+                "helper",
+                Some("static int helper()"),
+            )],
+        );
+
+        assert_eq!(
+            chosen_files(&output),
+            vec![
+                "a_cpp/001_helper_function_1.cpp",
+                "b_cpp/001_helper_function_1.cpp"
+            ]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn offending_key_is_read_from_the_details() {
+        assert_eq!(
+            offending_key(
+                "at position 3: creating document failed with error 'unique constraint \
+                 violated', offending document: {\"_key\":\"a_b\",\"name\":\"f\"}"
+            ),
+            Some("a_b".to_string())
+        );
+        assert_eq!(offending_key("at position 3: invalid JSON"), None);
+    }
+
+    #[tokio::test]
+    async fn resume_after_a_partly_imported_segment() {
+        let output = scratch_dir("ndjson-resume");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..10 {
+            let chunk = dir.join(format!("{:03}_f{}_function_1.cpp", i, i));
+            fs::write(chunk, format!("int f{}();", i)).unwrap();
+        }
+
+        // A database in which storing f7 fails once, and documents that
+        // exist already are rejected as with --on-conflict error
+        let stored = Arc::new(Mutex::new(HashSet::new()));
+        let duplicates = Arc::new(AtomicUsize::new(0));
+        let fail_f7 = Arc::new(AtomicBool::new(true));
+        let arango = MockArango::start({
+            let stored = stored.clone();
+            let duplicates = duplicates.clone();
+            let fail_f7 = fail_f7.clone();
+            move |request| {
+                if request.path != "/_api/import" {
+                    return accept_all(request);
+                }
+                let documents = request.ndjson();
+                let mut stored = stored.lock().unwrap();
+                let mut details = Vec::new();
+                for (position, document) in documents.iter().enumerate() {
+                    let key = document["_key"].as_str().unwrap().to_string();
+                    let error = if document["src"] == "int f7();" && fail_f7.load(Ordering::SeqCst)
+                    {
+                        "write-write conflict"
+                    } else if stored.contains(&key) {
+                        duplicates.fetch_add(1, Ordering::SeqCst);
+                        "unique constraint violated"
+                    } else {
+                        stored.insert(key);
+                        continue;
+                    };
+                    details.push(format!(
+                        "at position {}: creating document failed with error '{}', \
+                         offending document: {}",
+                        position, error, document
+                    ));
+                }
+                let errors = details.len();
+                let created = documents.len() - errors;
+                (
+                    201,
+                    json!({ "created": created, "errors": errors, "details": details }),
+                )
+            }
+        })
+        .await;
+        let importer = |resume| {
+            Importer::builder()
+                .output_dir(output.to_string_lossy())
+                .endpoint(&arango.endpoint)
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    allow_missing_vectors: true,
+                    ndjson_segment_size: Some(4),
+                    resume,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        let error = importer(false).run().await.unwrap_err();
+        assert!(
+            error.to_string().contains("write-write conflict"),
+            "{}",
+            error
+        );
+        assert!(stored.lock().unwrap().len() < 10);
+
+        // The documents of the failed segment that made it in are not sent
+        // again, so resuming does not run into them
+        fail_f7.store(false, Ordering::SeqCst);
+        importer(true).run().await.unwrap();
+        assert_eq!(stored.lock().unwrap().len(), 10);
+        assert_eq!(duplicates.load(Ordering::SeqCst), 0);
+        assert!(!output.join(CHECKPOINT_FILE).exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn documents_are_named_by_their_qualified_symbol() {
+        let output = scratch_dir("qualified-names");
+        write_chunk_dir(
+            &output.join("math_cpp"),
+            "math.cpp",
+            &[
+                (
+                    "000_add_function_3.cpp",
+                    "math::add",
+                    Some("int add(int, int)"),
+                ),
+                ("001_sub_function_8.cpp", "math::sub", None),
+            ],
+        );
+        let arango = MockArango::start(accept_all).await;
+
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                allow_missing_vectors: true,
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+        let mut documents: Vec<(String, String)> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .map(|document| {
+                (
+                    document["name"].as_str().unwrap().to_string(),
+                    document["chunk_file"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        documents.sort();
+        assert_eq!(
+            documents,
+            vec![
+                (
+                    "math::add".to_string(),
+                    "000_add_function_3.cpp".to_string()
+                ),
+                (
+                    "math::sub".to_string(),
+                    "001_sub_function_8.cpp".to_string()
+                ),
+            ]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn vector_index_of_another_dimension_is_reported() {
+        let arango = MockArango::start(|request| {
+            if request.method == "GET" && request.path == "/_api/index" {
+                let index = json!({
+                    "id": "chunks/42",
+                    "type": "vector",
+                    "params": { "dimension": 384 },
+                });
+                (
+                    200,
+                    json!({ "indexes": [{ "id": "chunks/0", "type": "primary" }, index] }),
+                )
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+        let importer = |reindex| {
+            Importer::builder()
+                .output_dir("/chunks")
+                .endpoint(&arango.endpoint)
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    reindex,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        // Vectors of the index's dimension are fine
+        importer(false)
+            .check_vector_index("chunks", 384)
+            .await
+            .unwrap();
+
+        let error = importer(false)
+            .check_vector_index("chunks", 768)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'chunks/42'"), "{}", error);
+        assert!(error.contains("has dimension 384"), "{}", error);
+        assert!(error.contains("dimension 768"), "{}", error);
+        assert!(error.contains("--reindex"), "{}", error);
+        let dropped = |arango: &MockArango| {
+            arango.requests().iter().any(|request| {
+                request.method == "DELETE" && request.path == "/_api/index/chunks/42"
+            })
+        };
+        assert!(!dropped(&arango));
+
+        // With --reindex the index is dropped instead
+        importer(true)
+            .check_vector_index("chunks", 768)
+            .await
+            .unwrap();
+        assert!(dropped(&arango));
+    }
+
+    #[tokio::test]
+    async fn calls_are_imported_as_edges_between_the_documents() {
+        let output = scratch_dir("import-edges");
+        fs::write(
+            output.join(RELATIONS_FILE),
+            "{\"callee\":\"math::add\",\"caller\":\"math::twice\"}\n\
+             {\"callee\":\"math::scale\",\"caller\":\"math::twice\"}\n",
+        )
+        .unwrap();
+        let arango = MockArango::start(accept_all).await;
+        let importer = Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .build()
+            .unwrap();
+        // math::scale is overloaded, so its calls cannot be resolved
+        let names: HashMap<String, Vec<String>> = [
+            ("math::add", vec!["math_cpp_add"]),
+            ("math::twice", vec!["math_cpp_twice"]),
+            ("math::scale", vec!["math_cpp_scale_1", "math_cpp_scale_2"]),
+        ]
+        .into_iter()
+        .map(|(name, keys)| {
+            (
+                name.to_string(),
+                keys.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+
+        importer.import_edges("calls", &names).await.unwrap();
+
+        let edges: Vec<Value> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.method == "POST" && request.path == "/_api/document/calls")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            edges,
+            [json!({
+                "_key": content_hash("math_cpp_twice->math_cpp_add"),
+                "_from": "chunks/math_cpp_twice",
+                "_to": "chunks/math_cpp_add",
+                "type": "calls",
+            })]
+        );
+        crate::schema::check_schema(&edges[0], &crate::schema::schemas()["edge"], "edge");
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn split_by_kind_imports_into_the_collection_of_each_kind() {
+        let output = scratch_dir("split-by-kind");
+        let dir = output.join("shapes_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let mut index = "Source file: shapes.cpp\n---\n".to_string();
+        for (file_name, name, kind) in [
+            ("000_area_function_1.cpp", "area", Some("function")),
+            ("001_Circle_class_5.cpp", "Circle", Some("class")),
+            ("002_Circle_r_method_7.cpp", "Circle::r", Some("method")),
+            ("003_pi_10.cpp", "pi", None),
+        ] {
+            fs::write(dir.join(file_name), "// chunk\n").unwrap();
+            index.push_str(&format!("Chunk: {}\n  Name: {}\n", file_name, name));
+            if let Some(kind) = kind {
+                index.push_str(&format!("  Kind: {}\n", kind));
+            }
+            index.push_str("---\n");
+        }
+        fs::write(dir.join("_index.txt"), index).unwrap();
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("shapes")
+            .options(ImportOptions {
+                allow_missing_vectors: true,
+                split_by_kind: true,
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let requests = arango.requests();
+        let imported = |collection: &str| -> Vec<String> {
+            let path = format!("/_api/document/{}", collection);
+            requests
+                .iter()
+                .filter(|request| request.method == "POST" && request.path == path)
+                .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+                .map(|document| document["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(imported("shapes_function"), ["area"]);
+        assert_eq!(imported("shapes_class"), ["Circle"]);
+        assert_eq!(imported("shapes_method"), ["Circle::r"]);
+        // Documents of unknown kind stay in the main collection
+        assert_eq!(imported("shapes"), ["pi"]);
+        let mut created: Vec<String> = requests
+            .iter()
+            .filter(|request| request.method == "POST" && request.path == "/_api/collection")
+            .map(|request| request.json()["name"].as_str().unwrap().to_string())
+            .collect();
+        created.sort();
+        created.dedup();
+        assert!(
+            ["shapes_class", "shapes_function", "shapes_method"]
+                .iter()
+                .all(|name| created.iter().any(|created| created == name)),
+            "{:?}",
+            created
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_sources_are_truncated_by_characters() {
+        let output = scratch_dir("max-src-chars");
+        let dir = output.join("text_cpp");
+        let long = "// Größe: 1\nint size() { return 1; }\n";
+        let short = "int one;\n";
+        write_chunk_dir(
+            &dir,
+            "text.cpp",
+            &[
+                ("000_size_function_1.cpp", "size", None),
+                ("001_one_2.cpp", "one", None),
+            ],
+        );
+        fs::write(dir.join("000_size_function_1.cpp"), long).unwrap();
+        fs::write(dir.join("001_one_2.cpp"), short).unwrap();
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                allow_missing_vectors: true,
+                max_src_chars: Some(12),
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let documents: Vec<Value> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .collect();
+        let document = |name: &str| {
+            documents
+                .iter()
+                .find(|document| document["name"] == name)
+                .unwrap()
+        };
+
+        // The hash is that of the whole source, so unchanged chunks are
+        // still recognized
+        let size = document("size");
+        assert_eq!(size["src"], "// Größe: 1\n");
+        assert_eq!(size["src_truncated"], true);
+        assert_eq!(size["src_length"], long.chars().count());
+        assert_eq!(size["src_hash"], json!(content_hash(long)));
+
+        let one = document("one");
+        assert_eq!(one["src"], short);
+        assert!(one.get("src_truncated").is_none());
+        assert!(one.get("src_length").is_none());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn name_filter_imports_only_the_matching_chunks() {
+        let output = scratch_dir("name-filter");
+        write_chunk_dir(
+            &output.join("parser_cpp"),
+            "parser.cpp",
+            &[
+                ("000_parse_method_3.cpp", "Parser::parse", None),
+                ("001_lex_function_9.cpp", "lex", None),
+                ("002_Token_class_20.cpp", "Token", None),
+            ],
+        );
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                allow_missing_vectors: true,
+                name_filter: Some(Regex::new("^Parser::").unwrap()),
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let imported: Vec<Value> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .map(|document| document["name"].clone())
+            .collect();
+        assert_eq!(imported, [json!("Parser::parse")]);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn document_url_carries_durability_and_conflict_handling() {
+        let url = |wait_for_sync, on_conflict| {
+            Importer::builder()
+                .output_dir("/chunks")
+                .endpoint("http://localhost:8529")
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    wait_for_sync,
+                    on_conflict,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+                .document_url("http://db", "chunks")
+        };
+        for (wait_for_sync, on_conflict, expected) in [
+            (false, OnConflict::Error, "http://db/_api/document/chunks"),
+            (
+                false,
+                OnConflict::Replace,
+                "http://db/_api/document/chunks?overwriteMode=replace",
+            ),
+            (
+                false,
+                OnConflict::Update,
+                "http://db/_api/document/chunks?overwriteMode=update",
+            ),
+            (
+                false,
+                OnConflict::Ignore,
+                "http://db/_api/document/chunks?overwriteMode=ignore",
+            ),
+            (
+                true,
+                OnConflict::Error,
+                "http://db/_api/document/chunks?waitForSync=true",
+            ),
+            (
+                true,
+                OnConflict::Replace,
+                "http://db/_api/document/chunks?waitForSync=true&overwriteMode=replace",
+            ),
+            (
+                true,
+                OnConflict::Update,
+                "http://db/_api/document/chunks?waitForSync=true&overwriteMode=update",
+            ),
+            (
+                true,
+                OnConflict::Ignore,
+                "http://db/_api/document/chunks?waitForSync=true&overwriteMode=ignore",
+            ),
+        ] {
+            assert_eq!(url(wait_for_sync, on_conflict), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_view_indexes_names_with_the_name_analyzer() {
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir("/chunks")
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .build()
+            .unwrap()
+            .create_search_view()
+            .await
+            .unwrap();
+
+        let requests = arango.requests();
+        let created = |api: &str| {
+            let path = format!("/_api/{}", api);
+            requests
+                .iter()
+                .find(|request| request.method == "POST" && request.path == path)
+                .unwrap_or_else(|| panic!("no {} created", api))
+                .json()
+        };
+        let analyzer = created("analyzer");
+        assert_eq!(analyzer["name"], NAME_ANALYZER);
+        assert_eq!(
+            analyzer["properties"]["edgeNgram"]["preserveOriginal"],
+            true
+        );
+        let view = created("view");
+        assert_eq!(view["name"], search_view_name("chunks"));
+        assert_eq!(view["type"], "arangosearch");
+        let fields = &view["links"]["chunks"]["fields"];
+        assert_eq!(fields["name"]["analyzers"], json!([NAME_ANALYZER]));
+        assert_eq!(fields["src"]["analyzers"], json!(["text_en"]));
+    }
+
+    #[tokio::test]
+    async fn prune_removes_the_chunks_gone_from_the_source() {
+        let output = scratch_dir("prune");
+        let dir = output.join("math_cpp");
+        let add = ("000_add_function_3.cpp", "math::add", None);
+        let sub = ("001_sub_function_8.cpp", "math::sub", None);
+        let arango = MockArango::start(accept_all).await;
+        let import = |prune| {
+            Importer::builder()
+                .output_dir(output.to_string_lossy())
+                .endpoint(&arango.endpoint)
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    allow_missing_vectors: true,
+                    prune,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        write_chunk_dir(&dir, "math.cpp", &[add, sub]);
+        import(false).run().await.unwrap();
+
+        // math::sub was deleted from the source and is chunked no more
+        fs::remove_dir_all(&dir).unwrap();
+        write_chunk_dir(&dir, "math.cpp", &[add]);
+        import(true).run().await.unwrap();
+
+        let removals: Vec<Value> = arango
+            .requests()
+            .iter()
+            .map(|request| request.json())
+            .filter(|body| {
+                body["query"]
+                    .as_str()
+                    .is_some_and(|query| query.contains("REMOVE"))
+            })
+            .collect();
+        assert_eq!(removals.len(), 1);
+        let bind_vars = &removals[0]["bindVars"];
+        assert_eq!(bind_vars["source_files"], json!(["math.cpp"]));
+        assert_eq!(
+            bind_vars["keys"],
+            json!([make_key(Path::new("math_cpp/000_add_function_3.cpp"))])
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_the_chunks_without_embedding() {
+        let output = scratch_dir("prune-unembedded");
+        let dir = output.join("math_cpp");
+        write_chunk_dir(
+            &dir,
+            "math.cpp",
+            &[
+                ("000_add_function_3.cpp", "math::add", None),
+                ("001_sub_function_8.cpp", "math::sub", None),
+            ],
+        );
+        // Only math::add is embedded, math::sub is not (yet)
+        let record = json!({ "v": [1.0, 0.0], "model": "test" });
+        fs::write(
+            dir.join("000_add_function_3.embedding.json"),
+            record.to_string(),
+        )
+        .unwrap();
+        let arango = MockArango::start(accept_all).await;
+
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                prune: true,
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let removal = arango
+            .requests()
+            .iter()
+            .map(|request| request.json())
+            .find(|body| {
+                body["query"]
+                    .as_str()
+                    .is_some_and(|query| query.contains("REMOVE"))
+            })
+            .unwrap();
+        let mut kept: Vec<String> =
+            serde_json::from_value(removal["bindVars"]["keys"].clone()).unwrap();
+        kept.sort();
+        assert_eq!(
+            kept,
+            [
+                make_key(Path::new("math_cpp/000_add_function_3.cpp")),
+                make_key(Path::new("math_cpp/001_sub_function_8.cpp")),
+            ]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_after_a_crash_skips_the_confirmed_batches() {
+        let output = scratch_dir("batch-resume");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let total = BATCH_SIZE + BATCH_SIZE / 2;
+        for i in 0..total {
+            let chunk = dir.join(format!("{:03}_f{}_function_1.cpp", i, i));
+            fs::write(chunk, format!("int f{}();", i)).unwrap();
+        }
+
+        // The server goes away after the first batch
+        let crashed = Arc::new(AtomicBool::new(false));
+        let arango = MockArango::start({
+            let crashed = crashed.clone();
+            move |request| {
+                if request.path != "/_api/document/chunks" {
+                    accept_all(request)
+                } else if crashed.swap(true, Ordering::SeqCst) {
+                    (
+                        400,
+                        json!({ "error": true, "errorMessage": "shutting down" }),
+                    )
+                } else {
+                    accept_all(request)
+                }
+            }
+        })
+        .await;
+        let importer = |resume| {
+            Importer::builder()
+                .output_dir(output.to_string_lossy())
+                .endpoint(&arango.endpoint)
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    allow_missing_vectors: true,
+                    resume,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+        };
+        let sent_keys = || -> Vec<Vec<String>> {
+            arango
+                .requests()
+                .iter()
+                .filter(|request| request.path == "/_api/document/chunks")
+                .map(|request| {
+                    request
+                        .json()
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|document| document["_key"].as_str().unwrap().to_string())
+                        .collect()
+                })
+                .collect()
+        };
+
+        importer(false).run().await.unwrap_err();
+        let confirmed: HashSet<String> = sent_keys()[0].iter().cloned().collect();
+        assert_eq!(confirmed.len(), BATCH_SIZE);
+
+        crashed.store(false, Ordering::SeqCst);
+        importer(true).run().await.unwrap();
+        let batches = sent_keys();
+        let resumed: Vec<&String> = batches[2..].iter().flatten().collect();
+        assert_eq!(resumed.len(), total - BATCH_SIZE);
+        assert!(resumed.iter().all(|key| !confirmed.contains(*key)));
+        assert!(!output.join(CHECKPOINT_FILE).exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_output_dir_is_reported() {
+        let output = scratch_dir("empty-output");
+        let arango = MockArango::start(accept_all).await;
+        let importer = |strict| {
+            Importer::builder()
+                .output_dir(output.to_string_lossy())
+                .endpoint(&arango.endpoint)
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    strict,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        let error = importer(true).run().await.unwrap_err();
+        assert!(
+            error.to_string().starts_with("No chunks to import"),
+            "{}",
+            error
+        );
+        // Without --strict it is only a warning, and nothing is sent
+        importer(false).run().await.unwrap();
+        assert!(arango
+            .requests()
+            .iter()
+            .all(|request| !request.path.starts_with("/_api/document")
+                && request.path != "/_api/import"));
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skip_unchanged_compares_the_embedding_settings() {
+        let output = scratch_dir("skip-unchanged");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        let record = json!({ "v": [1.0, 0.0], "model": "new-model" });
+        let mut stored = Vec::new();
+        for i in 0..3 {
+            let chunk = dir.join(format!("{:03}_f{}_function_1.cpp", i, i));
+            let src = format!("int f{}();", i);
+            fs::write(&chunk, &src).unwrap();
+            fs::write(chunk.with_extension("embedding.json"), record.to_string()).unwrap();
+            let key = make_key(chunk.strip_prefix(&output).unwrap());
+            // f0 is stored as it would be imported, f1 was embedded by
+            // another model and f2 is new
+            let model = if i == 0 { "new-model" } else { "old-model" };
+            let embed_hash = embedding_settings_hash(&json!({ "model": model }));
+            if i < 2 {
+                stored.push(json!([key, content_hash(&src), embed_hash]));
+            }
+        }
+        let arango = MockArango::start(move |request| {
+            if request.path == "/_api/cursor" {
+                (201, json!({ "result": stored, "hasMore": false }))
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                skip_unchanged: true,
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+        let mut imported: Vec<String> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .map(|document| document["src"].as_str().unwrap().to_string())
+            .collect();
+        imported.sort();
+        assert_eq!(imported, vec!["int f1();", "int f2();"]);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks_without_current_embedding_are_not_imported_with_vector() {
+        let output = scratch_dir("outdated-embeddings");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..3 {
+            let chunk = dir.join(format!("{:03}_f{}_function_1.cpp", i, i));
+            let src = format!("int f{}();", i);
+            fs::write(&chunk, &src).unwrap();
+            // f0 is embedded, f1 was embedded before it changed and f2 not
+            let src_hash = match i {
+                0 => content_hash(&src),
+                1 => content_hash("int f1(int);"),
+                _ => continue,
+            };
+            let record = json!({ "v": [1.0, 0.0], "src_hash": src_hash, "model": "m" });
+            fs::write(chunk.with_extension("embedding.json"), record.to_string()).unwrap();
+        }
+        let arango = MockArango::start(accept_all).await;
+        let import = |allow_missing_vectors| {
+            Importer::builder()
+                .output_dir(output.to_string_lossy())
+                .endpoint(&arango.endpoint)
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+                .options(ImportOptions {
+                    allow_missing_vectors,
+                    on_conflict: OnConflict::Replace,
+                    ..ImportOptions::default()
+                })
+                .build()
+                .unwrap()
+        };
+        // The sources of the imported documents, marked if they have a vector
+        let imported = || {
+            let mut imported: Vec<String> = arango
+                .requests()
+                .iter()
+                .filter(|request| request.path == "/_api/document/chunks")
+                .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+                .map(|document| {
+                    let vector = if document["v"].is_array() {
+                        " with v"
+                    } else {
+                        ""
+                    };
+                    format!("{}{}", document["src"].as_str().unwrap(), vector)
+                })
+                .collect();
+            imported.sort();
+            imported
+        };
+
+        import(false).run().await.unwrap();
+        assert_eq!(imported(), vec!["int f0(); with v"]);
+        import(true).run().await.unwrap();
+        assert_eq!(
+            imported(),
+            vec![
+                "int f0(); with v",
+                "int f0(); with v",
+                "int f1();",
+                "int f2();"
+            ]
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn documents_match_the_schema() {
+        let schema = &crate::schema::schemas()["document"];
+        let minimal = || Document {
+            key: "src_foo_cpp_f_cpp".to_string(),
+            name: "f".to_string(),
+            kind: None,
+            chunk_file: "f.cpp".to_string(),
+            v: Vec::new(),
+            src: "int f();".to_string(),
+            src_encoding: None,
+            src_hash: content_hash("int f();"),
+            src_truncated_from: None,
+            source_file: None,
+            source_path: None,
+            commit: None,
+            blob_sha: None,
+            mv: None,
+            prev_chunk: None,
+            next_chunk: None,
+            comment_v: None,
+            deprecated: false,
+            heuristic: false,
+            declaration_of: None,
+            definition_of: None,
+            model: None,
+            instruction: None,
+            embed_hash: None,
+        };
+        let full = Document {
+            kind: Some("function".to_string()),
+            v: vec![0.5, -0.25],
+            src: compress_src("int f();").unwrap(),
+            src_encoding: Some(GZIP_ENCODING),
+            src_truncated_from: Some(120),
+            source_file: Some("/project/src/foo.cpp".to_string()),
+            source_path: Some("src/foo.cpp".to_string()),
+            commit: Some("0123abcd".to_string()),
+            blob_sha: Some("4567cdef".to_string()),
+            mv: Some(json!([[0.5, -0.25], [0.125, 1.0]])),
+            prev_chunk: Some("e.cpp".to_string()),
+            next_chunk: Some("g.cpp".to_string()),
+            comment_v: Some(json!([0.75, 0.0])),
+            deprecated: true,
+            heuristic: true,
+            declaration_of: Some("src_foo_h_f_h".to_string()),
+            definition_of: Some("src_foo_h_f_h".to_string()),
+            model: Some("test/deterministic-8".to_string()),
+            instruction: Some("Represent the code".to_string()),
+            embed_hash: Some(content_hash("settings")),
+            ..minimal()
+        };
+        for document in [&minimal(), &full] {
+            crate::schema::check_schema(&Importer::document_json(document), schema, "document");
+        }
+        // Every property of the schema is written by some document
+        let written = Importer::document_json(&full);
+        for name in schema["properties"].as_object().unwrap().keys() {
+            assert!(written.get(name).is_some(), "{} is never written", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn per_file_embeddings_are_written_once_per_directory_and_imported() {
+        let output = scratch_dir("per-file-layout");
+        for (dir, source_file) in [("calc_cpp", "calc.cpp"), ("parser_cpp", "parser.cpp")] {
+            write_chunk_dir(
+                &output.join(dir),
+                source_file,
+                &[
+                    ("000_add_function_1.cpp", "add", None),
+                    ("001_sub_function_5.cpp", "sub", None),
+                ],
+            );
+        }
+        enable_test_embedder();
+        Embedder::new(
+            output.to_string_lossy().into_owned(),
+            "test/deterministic-8",
+            1,
+            None,
+            Default::default(),
+            None,
+            EmbeddingLayout::PerFile,
+        )
+        .unwrap()
+        .run()
+        .unwrap();
+
+        let mut json_files: Vec<String> = WalkDir::new(&output)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".json"))
+            .map(|entry| {
+                let path = entry.path().strip_prefix(&output).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        json_files.sort();
+        assert_eq!(
+            json_files,
+            ["calc_cpp/embeddings.json", "parser_cpp/embeddings.json"]
+        );
+        let combined: Value = serde_json::from_str(
+            &fs::read_to_string(output.join("calc_cpp").join(COMBINED_EMBEDDINGS_FILE)).unwrap(),
+        )
+        .unwrap();
+        let add_v = combined["000_add_function_1.cpp"]["v"].clone();
+        assert_eq!(add_v.as_array().unwrap().len(), 8);
+
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions::default())
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let imported: Vec<Value> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .collect();
+        assert_eq!(imported.len(), 4);
+        assert!(imported.iter().all(|document| document["v"].is_array()));
+        let add = imported
+            .iter()
+            .find(|document| document["source_file"] == "calc.cpp" && document["name"] == "add")
+            .unwrap();
+        assert_eq!(add["v"], add_v);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn builder_names_the_first_missing_setting() {
+        let error = |builder: ImporterBuilder| builder.build().err().unwrap().to_string();
+        let complete = || {
+            Importer::builder()
+                .output_dir("out")
+                .endpoint("http://localhost:8529")
+                .username("root")
+                .password("")
+                .database("test")
+                .collection("chunks")
+        };
+        assert_eq!(
+            error(Importer::builder()),
+            "Importer needs an output directory"
+        );
+        assert_eq!(
+            error(complete().endpoint("")),
+            "Importer needs an ArangoDB endpoint"
+        );
+        assert_eq!(
+            error(
+                Importer::builder()
+                    .output_dir("out")
+                    .endpoint("http://localhost:8529")
+            ),
+            "Importer needs an ArangoDB username"
+        );
+        assert_eq!(
+            error(complete().collection("")),
+            "Importer needs an ArangoDB collection"
+        );
+        // An empty password is allowed, a missing one is not
+        assert!(complete().build().is_ok());
+        let mut no_password = complete();
+        no_password.password = None;
+        assert_eq!(error(no_password), "Importer needs an ArangoDB password");
+    }
+
+    #[tokio::test]
+    async fn chunks_without_embedding_files_are_imported_without_vectors() {
+        let output = scratch_dir("no-embeddings");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("000_add_function_1.cpp"), "int add(int a, int b);").unwrap();
+        fs::write(dir.join("001_sub_function_2.cpp"), "int sub(int a, int b);").unwrap();
+        let arango = MockArango::start(accept_all).await;
+        Importer::builder()
+            .output_dir(output.to_string_lossy())
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .options(ImportOptions {
+                allow_missing_vectors: true,
+                ..ImportOptions::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+
+        let documents: Vec<Value> = arango
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .collect();
+        let mut sources: Vec<&str> = documents
+            .iter()
+            .map(|document| document["src"].as_str().unwrap())
+            .collect();
+        sources.sort();
+        assert_eq!(
+            sources,
+            vec!["int add(int a, int b);", "int sub(int a, int b);"]
+        );
+        for document in &documents {
+            assert!(!document["v"].is_array(), "unexpected vector: {}", document);
+            assert!(document["name"].is_string());
+        }
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
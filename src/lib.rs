@@ -1 +1,7 @@
-pub mod embedding_common; 
\ No newline at end of file
+pub mod arango;
+#[cfg(test)]
+pub mod arango_mock;
+pub mod collection;
+pub mod embedding_common;
+pub mod query;
+pub mod src_codec;
@@ -0,0 +1,755 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::lsp_log::SharedLspLog;
+
+/// The LSP base protocol separates header lines with CRLF and ends the
+/// header part with an empty line
+const HEADER_LINE_END: &str = "\r\n";
+
+/// Default for the largest message body accepted from the server
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 256 * 1024 * 1024;
+
+/// How messages are framed on the byte streams to and from the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LspTransport {
+    /// The LSP base protocol: a Content-Length header before every body
+    #[default]
+    ContentLength,
+    /// One JSON message per line, for symbol providers other than clangd
+    Ndjson,
+}
+
+type PendingMap = Arc<Mutex<HashMap<u64, Sender<Value>>>>;
+
+/// A minimal LSP client speaking JSON-RPC over a pair of byte streams.
+///
+/// Every request gets its own id. A background reader thread dispatches
+/// responses to the request waiting for that id, and forwards notifications
+/// from the server to a channel, so that several requests can be outstanding
+/// at the same time and notifications may arrive interleaved with responses.
+///
+/// Outgoing messages are queued for a background writer thread. Neither the
+/// caller nor the reader thread ever block on a full pipe to the server, so
+/// the server can always make progress writing its output, no matter how
+/// many notifications it sends before a response.
+pub struct LspClient {
+    outgoing: Option<Sender<Value>>,
+    next_id: AtomicU64,
+    pending: PendingMap,
+    waiting: Mutex<HashMap<u64, Receiver<Value>>>,
+    notifications: Receiver<Value>,
+    log: SharedLspLog,
+    /// Why the reader thread stopped early, reported to waiting requests
+    reader_error: Arc<Mutex<Option<String>>>,
+    reader: Option<JoinHandle<()>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Writes a single JSON-RPC message, with its Content-Length header or
+/// followed by a newline depending on the transport. The length counts the
+/// bytes of the UTF-8 encoded body.
+fn write_message(
+    writer: &mut dyn Write,
+    message: &Value,
+    log: &SharedLspLog,
+    transport: LspTransport,
+) -> Result<(), Box<dyn Error>> {
+    // Compact JSON escapes all newlines inside strings, so it is one line
+    let message_str = serde_json::to_string(message)
+        .map_err(|e| format!("Failed to serialize LSP request: {}", e))?;
+    let content_length = message_str.len();
+
+    log.lock().unwrap().sent(&message_str)?;
+
+    match transport {
+        LspTransport::ContentLength => {
+            write!(
+                writer,
+                "Content-Length: {}{}{}",
+                content_length, HEADER_LINE_END, HEADER_LINE_END
+            )
+            .map_err(|e| format!("Failed to write Content-Length header: {}", e))?;
+            write!(writer, "{}", message_str)
+        }
+        LspTransport::Ndjson => writeln!(writer, "{}", message_str),
+    }
+    .map_err(|e| format!("Failed to write request body: {}", e))?;
+    writer
+        .flush()
+        .map_err(|e| format!("Failed to flush request: {}", e))?;
+
+    Ok(())
+}
+
+/// Reads a single JSON-RPC message framed as the transport demands.
+/// Returns `Ok(None)` on end of stream.
+fn read_message(
+    reader: &mut dyn BufRead,
+    log: &SharedLspLog,
+    transport: LspTransport,
+    max_bytes: usize,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    match transport {
+        LspTransport::ContentLength => read_framed_message(reader, log, max_bytes),
+        LspTransport::Ndjson => read_line_message(reader, log, max_bytes),
+    }
+}
+
+/// Parses an LSP header line and returns the value of a Content-Length
+/// header, or None for any other header. Names are case-insensitive and
+/// whitespace around name and value is ignored, as in HTTP; only the first
+/// colon separates them.
+fn parse_content_length(line: &str) -> Result<Option<usize>, String> {
+    let Some((name, value)) = line.split_once(':') else {
+        return Ok(None);
+    };
+    if !name.trim().eq_ignore_ascii_case("Content-Length") {
+        return Ok(None);
+    }
+    let value = value.trim();
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| format!("Failed to parse Content-Length value '{}': {}", value, e))
+}
+
+/// Reads a message with Content-Length header. Header lines may end with CRLF (as the spec demands) or a bare LF, and
+/// header names are matched case-insensitively. Other headers (such as
+/// Content-Type), lines without a colon and blank lines before the first
+/// header are ignored. Bodies longer than `max_bytes` are rejected before
+/// allocating a buffer for them, so that a bogus Content-Length cannot
+/// exhaust the memory.
+fn read_framed_message(
+    reader: &mut dyn BufRead,
+    log: &SharedLspLog,
+    max_bytes: usize,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    // Read headers
+    let mut content_length: Option<usize> = None;
+    let mut headers = String::new();
+    loop {
+        let mut line = String::new();
+        let n = reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read LSP response header: {}", e))?;
+        if n == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if line.trim().is_empty() {
+            if headers.is_empty() {
+                continue; // Stray line break between messages
+            }
+            headers.push('\n');
+            break; // Headers are done
+        }
+        headers.push_str(line);
+        headers.push('\n');
+
+        if let Some(length) = parse_content_length(line)? {
+            if let Some(previous) = content_length.filter(|previous| *previous != length) {
+                return Err(format!(
+                    "Conflicting Content-Length headers: {} and {}",
+                    previous, length
+                )
+                .into());
+            }
+            content_length = Some(length);
+        }
+    }
+
+    // Read content
+    let length = content_length.ok_or("No Content-Length header found")?;
+    if length > max_bytes {
+        return Err(format!(
+            "LSP response of {} bytes exceeds the limit of {} bytes, see --lsp-max-response-bytes",
+            length, max_bytes
+        )
+        .into());
+    }
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer).map_err(|e| {
+        format!(
+            "Failed to read LSP response body of length {}: {}",
+            length, e
+        )
+    })?;
+
+    let json_value: Value = serde_json::from_slice(&buffer)
+        .map_err(|e| format!("Failed to parse LSP response JSON: {}", e))?;
+
+    // Log the response
+    log.lock()
+        .unwrap()
+        .received(&headers, &String::from_utf8_lossy(&buffer))?;
+
+    Ok(Some(json_value))
+}
+
+/// Reads a message on a line of its own, skipping blank lines. Lines
+/// longer than `max_bytes` are rejected without reading them completely.
+fn read_line_message(
+    reader: &mut dyn BufRead,
+    log: &SharedLspLog,
+    max_bytes: usize,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    let mut line = String::new();
+    while line.trim().is_empty() {
+        line.clear();
+        let n = (&mut *reader)
+            .take(max_bytes as u64 + 1)
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read LSP message line: {}", e))?;
+        if n == 0 {
+            return Ok(None);
+        }
+        if n > max_bytes && !line.ends_with('\n') {
+            return Err(format!(
+                "LSP message line exceeds the limit of {} bytes, see --lsp-max-response-bytes",
+                max_bytes
+            )
+            .into());
+        }
+    }
+
+    let body = line.trim_end_matches(['\r', '\n']);
+    let json_value: Value = serde_json::from_str(body)
+        .map_err(|e| format!("Failed to parse LSP response JSON: {}", e))?;
+    log.lock().unwrap().received("", body)?;
+
+    Ok(Some(json_value))
+}
+
+impl LspClient {
+    /// Creates a client talking to a server through `writer` (the server's
+    /// stdin) and `reader` (the server's stdout), and starts the reader thread.
+    /// Messages are framed as `transport` demands, and messages from the
+    /// server longer than `max_message_bytes` end the session.
+    pub fn new<W, R>(
+        writer: W,
+        reader: R,
+        log: SharedLspLog,
+        transport: LspTransport,
+        max_message_bytes: usize,
+    ) -> Self
+    where
+        W: Write + Send + 'static,
+        R: Read + Send + 'static,
+    {
+        let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
+        let (notification_tx, notifications) = mpsc::channel();
+        let (outgoing, outgoing_rx) = mpsc::channel();
+
+        let writer = {
+            let pending = Arc::clone(&pending);
+            let log = Arc::clone(&log);
+            thread::spawn(move || {
+                let mut writer = writer;
+                Self::write_loop(&mut writer, &outgoing_rx, &pending, &log, transport);
+            })
+        };
+
+        let reader_error = Arc::new(Mutex::new(None));
+        let reader = {
+            let outgoing = outgoing.clone();
+            let pending = Arc::clone(&pending);
+            let log = Arc::clone(&log);
+            let reader_error = Arc::clone(&reader_error);
+            thread::spawn(move || {
+                let mut reader = BufReader::new(reader);
+                if let Err(e) = Self::dispatch_loop(
+                    &mut reader,
+                    &outgoing,
+                    &pending,
+                    &notification_tx,
+                    &log,
+                    transport,
+                    max_message_bytes,
+                ) {
+                    *reader_error.lock().unwrap() = Some(e);
+                }
+                // Wake up everybody still waiting, their requests will never
+                // be answered
+                pending.lock().unwrap().clear();
+            })
+        };
+
+        Self {
+            outgoing: Some(outgoing),
+            next_id: AtomicU64::new(1),
+            pending,
+            waiting: Mutex::new(HashMap::new()),
+            notifications,
+            log,
+            reader_error,
+            reader: Some(reader),
+            writer: Some(writer),
+        }
+    }
+
+    /// Writes queued messages until all senders are gone. If writing fails,
+    /// the server is unreachable and all outstanding requests are failed.
+    fn write_loop(
+        writer: &mut dyn Write,
+        outgoing: &Receiver<Value>,
+        pending: &PendingMap,
+        log: &SharedLspLog,
+        transport: LspTransport,
+    ) {
+        for message in outgoing {
+            if let Err(e) = write_message(writer, &message, log, transport) {
+                let _ = log
+                    .lock()
+                    .unwrap()
+                    .info(&format!("LSP writer error: {}", e));
+                pending.lock().unwrap().clear();
+                break;
+            }
+        }
+    }
+
+    /// Queues a message for the writer thread
+    fn send(&self, message: Value) -> Result<(), Box<dyn Error>> {
+        self.outgoing
+            .as_ref()
+            .ok_or("LSP client is shut down")?
+            .send(message)
+            .map_err(|_| {
+                "Failed to send LSP message, the connection to the server is closed".into()
+            })
+    }
+
+    /// Reads messages until the stream ends and routes each one: responses go
+    /// to the request waiting for their id, notifications to the channel, and
+    /// requests from the server are answered with a null result. Returns the
+    /// error that stopped the reading early, if any.
+    fn dispatch_loop(
+        reader: &mut dyn BufRead,
+        outgoing: &Sender<Value>,
+        pending: &PendingMap,
+        notifications: &Sender<Value>,
+        log: &SharedLspLog,
+        transport: LspTransport,
+        max_message_bytes: usize,
+    ) -> Result<(), String> {
+        loop {
+            let message = match read_message(reader, log, transport, max_message_bytes) {
+                Ok(Some(message)) => message,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    let _ = log
+                        .lock()
+                        .unwrap()
+                        .info(&format!("LSP reader error: {}", e));
+                    return Err(e.to_string());
+                }
+            };
+
+            let id = message.get("id").cloned();
+            let has_method = message.get("method").is_some();
+            match (id, has_method) {
+                // Response to one of our requests
+                (Some(id), false) => {
+                    let sender = id
+                        .as_u64()
+                        .and_then(|id| pending.lock().unwrap().remove(&id));
+                    match sender {
+                        Some(sender) => {
+                            let _ = sender.send(message);
+                        }
+                        None => {
+                            let _ = log
+                                .lock()
+                                .unwrap()
+                                .info(&format!("Got response for unknown request id: {}", id));
+                        }
+                    }
+                }
+                // Request from the server, e.g. window/workDoneProgress/create
+                (Some(id), true) => {
+                    let _ = notifications.send(message.clone());
+                    let reply = json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "result": null
+                    });
+                    let _ = outgoing.send(reply);
+                }
+                // Notification from the server
+                (None, _) => {
+                    let _ = notifications.send(message);
+                }
+            }
+        }
+    }
+
+    /// Sends a request and returns the id it was sent with. The response can
+    /// be collected later with `wait_for_response`.
+    pub fn send_request(&self, method: &str, params: Value) -> Result<u64, Box<dyn Error>> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        self.waiting.lock().unwrap().insert(id, rx);
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params
+        });
+        if let Err(e) = self.send(request) {
+            self.pending.lock().unwrap().remove(&id);
+            self.waiting.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// Sends a notification, which has no id and gets no response
+    pub fn send_notification(&self, method: &str, params: Value) -> Result<(), Box<dyn Error>> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        });
+        self.send(notification)
+    }
+
+    /// Blocks until the response for the request with the given id arrives
+    /// and returns its `result`, or an error if the server answered with one.
+    pub fn wait_for_response(&self, id: u64) -> Result<Value, Box<dyn Error>> {
+        let rx = self
+            .waiting
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or_else(|| format!("No outstanding LSP request with id {}", id))?;
+        let mut response =
+            rx.recv()
+                .map_err(|_| match self.reader_error.lock().unwrap().as_ref() {
+                    Some(error) => format!("LSP request {} failed: {}", id, error),
+                    None => format!(
+                        "LSP server closed the connection before answering request {}",
+                        id
+                    ),
+                })?;
+
+        if let Some(error) = response.get("error") {
+            return Err(format!("LSP request {} failed: {}", id, error).into());
+        }
+        Ok(response
+            .get_mut("result")
+            .map(Value::take)
+            .unwrap_or(Value::Null))
+    }
+
+    /// Sends a request and waits for its result
+    pub fn request(&self, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+        let id = self.send_request(method, params)?;
+        self.wait_for_response(id)
+    }
+
+    /// Notifications (and requests) the server sent us so far
+    pub fn notifications(&self) -> &Receiver<Value> {
+        &self.notifications
+    }
+
+    /// Blocks until the server sends a notification with the given method
+    /// for the document with the given URI, handing every notification
+    /// received meanwhile to `observe` before dropping it. Returns false if
+    /// none arrived within the timeout.
+    pub fn wait_for_notification(
+        &self,
+        method: &str,
+        uri: &str,
+        timeout: Duration,
+        mut observe: impl FnMut(&Value),
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(notification) = self.notifications.recv_timeout(remaining) else {
+                return false;
+            };
+            observe(&notification);
+            if notification["method"].as_str() == Some(method)
+                && notification["params"]["uri"].as_str() == Some(uri)
+            {
+                return true;
+            }
+        }
+    }
+
+    /// Waits for the reader thread to finish, which happens once the server
+    /// closes its output stream, and for the writer thread to write all
+    /// queued messages
+    pub fn join(mut self) {
+        self.outgoing.take();
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        let _ = self.log.lock().unwrap().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp_log::{LspLog, LspLogFormat};
+
+    fn no_log() -> SharedLspLog {
+        LspLog::new(String::new(), LspLogFormat::None, None)
+            .unwrap()
+            .shared()
+    }
+
+    #[test]
+    fn content_length_is_parsed_from_any_header_form() {
+        for line in [
+            "Content-Length: 42",
+            "content-length:42",
+            "CONTENT-LENGTH :\t42  ",
+            "  Content-Length: 42\r",
+        ] {
+            assert_eq!(parse_content_length(line), Ok(Some(42)), "{:?}", line);
+        }
+        for line in [
+            "Content-Type: application/vscode-jsonrpc; charset=utf-8",
+            "X-Note: Content-Length: 7",
+            "no header at all",
+            "",
+        ] {
+            assert_eq!(parse_content_length(line), Ok(None), "{:?}", line);
+        }
+        let error = parse_content_length("Content-Length: lots").unwrap_err();
+        assert!(error.contains("'lots'"), "{}", error);
+    }
+
+    #[test]
+    fn framed_messages_skip_other_headers() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":"a:b"}"#;
+        let input = format!(
+            "\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\
+             content-length:  {}\n\
+             X-Trace: on\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut reader = std::io::Cursor::new(input.into_bytes());
+        let message = read_framed_message(&mut reader, &no_log(), DEFAULT_MAX_MESSAGE_BYTES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message["result"], "a:b");
+    }
+
+    #[test]
+    fn responses_are_routed_by_id() {
+        let (server_in, client_out) = std::io::pipe().unwrap();
+        let (client_in, mut server_out) = std::io::pipe().unwrap();
+        let transport = LspTransport::ContentLength;
+        let client = LspClient::new(
+            client_out,
+            client_in,
+            no_log(),
+            transport,
+            DEFAULT_MAX_MESSAGE_BYTES,
+        );
+
+        // Answers both requests in reverse order, with a request of its own
+        // in between, and returns what the client answered to that
+        let server = thread::spawn(move || {
+            let log = no_log();
+            let mut server_in = BufReader::new(server_in);
+            let mut read = || {
+                read_message(&mut server_in, &log, transport, DEFAULT_MAX_MESSAGE_BYTES)
+                    .unwrap()
+                    .unwrap()
+            };
+            let first = read();
+            let second = read();
+            let mut write = |message: Value| {
+                write_message(&mut server_out, &message, &log, transport).unwrap();
+            };
+            write(json!({ "jsonrpc": "2.0", "id": second["id"], "result": second["method"] }));
+            write(json!({ "jsonrpc": "2.0", "id": 0, "method": "window/workDoneProgress/create" }));
+            write(json!({ "jsonrpc": "2.0", "id": first["id"], "result": first["method"] }));
+            read()
+        });
+
+        let first = client.send_request("first", json!({})).unwrap();
+        let second = client.send_request("second", json!({})).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(client.wait_for_response(first).unwrap(), json!("first"));
+        assert_eq!(client.wait_for_response(second).unwrap(), json!("second"));
+
+        let reply = server.join().unwrap();
+        assert_eq!(reply["id"], json!(0));
+        assert!(reply.get("result").is_some());
+        let request = client.notifications().recv().unwrap();
+        assert_eq!(request["method"], "window/workDoneProgress/create");
+    }
+
+    #[test]
+    fn notifications_flooding_in_before_the_response_do_not_block() {
+        let (server_in, client_out) = std::io::pipe().unwrap();
+        let (client_in, mut server_out) = std::io::pipe().unwrap();
+        let transport = LspTransport::ContentLength;
+        let client = LspClient::new(
+            client_out,
+            client_in,
+            no_log(),
+            transport,
+            DEFAULT_MAX_MESSAGE_BYTES,
+        );
+
+        // Like clangd publishing diagnostics while a large file is opened:
+        // the server writes far more than a pipe buffer holds before it
+        // reads anything, then answers the request
+        const FLOOD: usize = 2000;
+        let server = thread::spawn(move || {
+            let log = no_log();
+            let padding = "x".repeat(1000);
+            for i in 0..FLOOD {
+                let notification = json!({
+                    "jsonrpc": "2.0",
+                    "method": "$/progress",
+                    "params": { "token": i, "value": padding },
+                });
+                write_message(&mut server_out, &notification, &log, transport).unwrap();
+            }
+            let mut server_in = BufReader::new(server_in);
+            let mut read = || {
+                read_message(&mut server_in, &log, transport, DEFAULT_MAX_MESSAGE_BYTES)
+                    .unwrap()
+                    .unwrap()
+            };
+            assert_eq!(read()["method"], "textDocument/didOpen");
+            let request = read();
+            let response = json!({ "jsonrpc": "2.0", "id": request["id"], "result": "done" });
+            write_message(&mut server_out, &response, &log, transport).unwrap();
+        });
+
+        // A request that cannot be answered in time means a deadlock
+        let (done_tx, done) = mpsc::channel();
+        thread::spawn(move || {
+            let text = "int x;\n".repeat(200_000);
+            client
+                .send_notification("textDocument/didOpen", json!({ "text": text }))
+                .unwrap();
+            let result = client.request("textDocument/documentSymbol", json!({}));
+            let notifications = client.notifications().try_iter().count();
+            done_tx
+                .send((result.map_err(|e| e.to_string()), notifications))
+                .unwrap();
+        });
+        let (result, notifications) = done
+            .recv_timeout(Duration::from_secs(30))
+            .expect("client and server deadlocked");
+        assert_eq!(result, Ok(json!("done")));
+        assert_eq!(notifications, FLOOD);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected_before_reading_them() {
+        let input = "Content-Length: 18446744073709551615\r\n\r\n{}";
+        let mut reader = std::io::Cursor::new(input.as_bytes());
+        let error = read_framed_message(&mut reader, &no_log(), 1024)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("of 18446744073709551615 bytes exceeds the limit of 1024 bytes"),
+            "{}",
+            error
+        );
+
+        let line = format!("{{\"result\":\"{}\"}}\n", "x".repeat(2048));
+        let mut reader = std::io::Cursor::new(line.as_bytes());
+        let error = read_line_message(&mut reader, &no_log(), 1024)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("exceeds the limit of 1024 bytes"),
+            "{}",
+            error
+        );
+        // Only the limit and the byte after it were read
+        assert_eq!(reader.position(), 1025);
+    }
+
+    #[test]
+    fn ndjson_messages_round_trip() {
+        let log = no_log();
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen",
+                    "params": { "text": "int x;\n// größer\r\nint y;\n" } }),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": null }),
+        ];
+        let mut output = Vec::new();
+        for message in &messages {
+            write_message(&mut output, message, &log, LspTransport::Ndjson).unwrap();
+        }
+        // One line per message, newlines in strings are escaped
+        let text = String::from_utf8(output).unwrap();
+        assert_eq!(text.lines().count(), messages.len());
+
+        // Blank lines between messages are skipped
+        let input = text.replacen('\n', "\n\n", 1);
+        let mut reader = std::io::Cursor::new(input.into_bytes());
+        for message in &messages {
+            let read = read_message(
+                &mut reader,
+                &log,
+                LspTransport::Ndjson,
+                DEFAULT_MAX_MESSAGE_BYTES,
+            )
+            .unwrap();
+            assert_eq!(read.as_ref(), Some(message));
+        }
+        let end = read_message(
+            &mut reader,
+            &log,
+            LspTransport::Ndjson,
+            DEFAULT_MAX_MESSAGE_BYTES,
+        );
+        assert!(end.unwrap().is_none());
+    }
+
+    #[test]
+    fn headers_are_written_with_crlf_and_read_with_either_line_end() {
+        let log = no_log();
+        let message = json!({ "jsonrpc": "2.0", "id": 7, "result": "größer" });
+        let body = serde_json::to_string(&message).unwrap();
+        let mut output = Vec::new();
+        write_message(&mut output, &message, &log, LspTransport::ContentLength).unwrap();
+        // The length counts bytes, not characters
+        let expected = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        for line_end in ["\r\n", "\n"] {
+            let input = format!(
+                "Content-Length: {}{}{}{}",
+                body.len(),
+                line_end,
+                line_end,
+                body
+            );
+            let mut reader = std::io::Cursor::new(input.into_bytes());
+            let read = read_framed_message(&mut reader, &log, DEFAULT_MAX_MESSAGE_BYTES).unwrap();
+            assert_eq!(read, Some(message.clone()), "{:?}", line_end);
+        }
+    }
+}
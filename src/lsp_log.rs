@@ -0,0 +1,191 @@
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+/// Format of the LSP communication log
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LspLogFormat {
+    /// Do not write a log at all
+    None,
+    /// Human readable, with the raw headers and messages
+    #[default]
+    Text,
+    /// One JSON record per line
+    Jsonl,
+}
+
+/// Log of the LSP communication, written through a single buffered writer.
+/// If a maximal size is configured, the log is rotated once it would grow
+/// beyond it: the current file is renamed to `<path>.1` and a new one started.
+pub struct LspLog {
+    path: String,
+    format: LspLogFormat,
+    max_size: Option<u64>,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+}
+
+/// The log shared between the LSP client and its reader thread
+pub type SharedLspLog = Arc<Mutex<LspLog>>;
+
+impl LspLog {
+    /// Creates (or truncates) the log file, unless logging is disabled
+    pub fn new(
+        path: String,
+        format: LspLogFormat,
+        max_size: Option<u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let writer = match format {
+            LspLogFormat::None => None,
+            _ => Some(Self::create(&path)?),
+        };
+        Ok(Self {
+            path,
+            format,
+            max_size,
+            writer,
+            size: 0,
+        })
+    }
+
+    pub fn shared(self) -> SharedLspLog {
+        Arc::new(Mutex::new(self))
+    }
+
+    fn create(path: &str) -> Result<BufWriter<File>, Box<dyn Error>> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create LSP log file '{}': {}", path, e))?;
+        Ok(BufWriter::new(file))
+    }
+
+    /// Logs a message we sent to the server
+    pub fn sent(&mut self, body: &str) -> Result<(), Box<dyn Error>> {
+        let entry = match self.format {
+            LspLogFormat::None => return Ok(()),
+            LspLogFormat::Text => {
+                format!(">>> Request:\nContent-Length: {}\n\n{}\n", body.len(), body)
+            }
+            LspLogFormat::Jsonl => Self::json_record("sent", body),
+        };
+        self.write(&entry)
+    }
+
+    /// Logs a message we received from the server
+    pub fn received(&mut self, headers: &str, body: &str) -> Result<(), Box<dyn Error>> {
+        let entry = match self.format {
+            LspLogFormat::None => return Ok(()),
+            LspLogFormat::Text => format!("<<< Response:\n{}{}\n", headers, body),
+            LspLogFormat::Jsonl => Self::json_record("received", body),
+        };
+        self.write(&entry)
+    }
+
+    /// Logs a note of our own, e.g. about an unexpected message
+    pub fn info(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let entry = match self.format {
+            LspLogFormat::None => return Ok(()),
+            LspLogFormat::Text => format!("{}\n", text),
+            LspLogFormat::Jsonl => format!("{}\n", json!({ "direction": "info", "message": text })),
+        };
+        self.write(&entry)
+    }
+
+    fn json_record(direction: &str, body: &str) -> String {
+        let message = serde_json::from_str::<Value>(body).unwrap_or_else(|_| json!(body));
+        format!(
+            "{}\n",
+            json!({ "direction": direction, "message": message })
+        )
+    }
+
+    fn write(&mut self, entry: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + entry.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        if let Some(writer) = &mut self.writer {
+            writer
+                .write_all(entry.as_bytes())
+                .map_err(|e| format!("Failed to write to LSP log file: {}", e))?;
+            self.size += entry.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(mut writer) = self.writer.take() {
+            writer
+                .flush()
+                .map_err(|e| format!("Failed to flush LSP log file: {}", e))?;
+        }
+        let rotated = format!("{}.1", self.path);
+        fs::rename(&self.path, &rotated).map_err(|e| {
+            format!(
+                "Failed to rotate LSP log file '{}' to '{}': {}",
+                self.path, rotated, e
+            )
+        })?;
+        self.writer = Some(Self::create(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = &mut self.writer {
+            writer
+                .flush()
+                .map_err(|e| format!("Failed to flush LSP log file: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutil::scratch_dir;
+
+    /// Logs a request, a response, a note and a body that is no JSON
+    fn log_session(log: &mut LspLog) {
+        log.sent(r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#)
+            .unwrap();
+        log.received(
+            "Content-Length: 38\r\n\r\n",
+            r#"{"jsonrpc":"2.0","id":1,"result":{}}"#,
+        )
+        .unwrap();
+        log.info("Unexpected message").unwrap();
+        log.received("", "not json").unwrap();
+        log.flush().unwrap();
+    }
+
+    #[test]
+    fn none_writes_no_file_and_jsonl_writes_json_lines() {
+        let dir = scratch_dir("lsp-log");
+        let path = dir.join("lsp.log");
+        let path_str = path.to_string_lossy().into_owned();
+
+        let mut log = LspLog::new(path_str.clone(), LspLogFormat::None, None).unwrap();
+        log_session(&mut log);
+        assert!(!path.exists());
+
+        let mut log = LspLog::new(path_str, LspLogFormat::Jsonl, None).unwrap();
+        log_session(&mut log);
+        let records: Vec<Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let directions: Vec<&str> = records
+            .iter()
+            .map(|record| record["direction"].as_str().unwrap())
+            .collect();
+        assert_eq!(directions, ["sent", "received", "info", "received"]);
+        assert_eq!(records[0]["message"]["method"], "initialize");
+        assert_eq!(records[3]["message"], "not json");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
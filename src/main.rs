@@ -1,91 +1,1563 @@
-use clap::Parser;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use cppembedder::arango::ArangoArgs;
+use cppembedder::query::QueryArgs;
+use embedding_common::EmbedThreads;
 use std::error::Error;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
+#[cfg(test)]
+mod arango_mock;
+mod backfill;
 mod chunking;
+mod cleanup;
 mod embedding;
 mod embedding_common;
+mod fsutil;
+mod heuristic;
 mod importer;
+mod lsp;
+mod lsp_log;
+mod metrics;
+mod pipeline;
+mod progress;
+mod schema;
+mod selftest;
+mod src_codec;
 
-/// Program to chunk C++ source files based on function/class/method boundaries using clangd
+/// Program to chunk C++ source files based on function/class/method boundaries
+/// using clangd, embed the chunks and import them into ArangoDB
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
-struct Args {
-    /// Root directory of the C++ project
-    #[clap(short, long)]
-    project_dir: String,
+struct Cli {
+    /// Do not show progress bars or the per-file progress of the chunker
+    #[clap(long, global = true)]
+    no_progress: bool,
 
+    /// Suppress all progress output (same as --no-progress)
+    #[clap(long, global = true)]
+    quiet: bool,
+
+    /// How progress is shown: bars on a terminal and plain lines otherwise
+    /// (auto), or JSON lines on stderr with phase, done, total and rate
+    /// (jsonl), for log parsers
+    #[clap(long, global = true, value_enum, default_value = "auto")]
+    progress_format: progress::ProgressFormat,
+
+    /// Print the effective arguments of the command, defaults included and
+    /// passwords redacted, as JSON and exit
+    #[clap(long, global = true)]
+    print_config: bool,
+
+    /// Write counters and throughput of the run as JSON to this file, in
+    /// regular intervals and once more at the end
+    #[clap(long, global = true)]
+    metrics_file: Option<String>,
+
+    /// Seconds between two writes of the metrics file
+    #[clap(long, global = true, default_value_t = 10)]
+    metrics_interval: u64,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Split the source files of a project into chunks
+    Chunk {
+        /// Root directory of the C++ project
+        #[clap(short, long)]
+        project_dir: String,
+        #[clap(flatten)]
+        output: OutputArgs,
+        #[clap(flatten)]
+        chunk: ChunkArgs,
+    },
+    /// Compute the embeddings of the chunks in the output directory
+    Embed {
+        #[clap(flatten)]
+        output: OutputArgs,
+        #[clap(flatten)]
+        embed: EmbedArgs,
+    },
+    /// Import the chunks and their embeddings into ArangoDB
+    Import {
+        #[clap(flatten)]
+        output: OutputArgs,
+        #[clap(flatten)]
+        arango: ArangoArgs,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Compute and store the vectors of documents imported without one
+    Backfill(backfill::BackfillArgs),
+    /// Drop the collections of the collection prefix whose expiry, set by
+    /// imports with --expires-in-hours, is past
+    Cleanup(cleanup::CleanupArgs),
+    /// Search the imported chunks with a natural language query
+    Query(QueryArgs),
+    /// Chunk, embed and import in one go
+    All(Box<AllArgs>),
+    /// Run the whole pipeline on a small bundled C++ file and check that a
+    /// known query finds the expected function. Imports into a collection
+    /// of its own, which is dropped at the end.
+    SelfTest(SelfTestArgs),
+    /// Print the JSON Schemas of the chunk index entries and of the
+    /// documents imported into ArangoDB
+    Schema,
+}
+
+/// The directory the chunks and embeddings are written to and read from
+#[derive(clap::Args, Debug)]
+struct OutputArgs {
     /// Output directory for the chunked files
     #[clap(short, long, default_value = "chunked_output")]
     output_dir: String,
+}
 
+/// Options of the chunking step
+#[derive(clap::Args, Debug)]
+struct ChunkArgs {
     /// Path to clangd executable
     #[clap(short, long, default_value = "clangd")]
     clangd_path: String,
 
-    /// Name of the embedding model to use (e.g. "BAAI/bge-small-en-v1.5")
-    #[clap(short, long)]
+    /// Path to git executable, used with --record-git
+    #[clap(long, default_value = "git")]
+    git_path: String,
+
+    /// Path to the LSP communication log file
+    #[clap(long, default_value = "lsp_communication.log")]
+    lsp_log_file: String,
+
+    /// Format of the LSP communication log
+    #[clap(long, value_enum, default_value = "text")]
+    lsp_log: lsp_log::LspLogFormat,
+
+    /// Rotate the LSP log once it grows beyond this many bytes
+    #[clap(long)]
+    lsp_log_max_size: Option<u64>,
+
+    /// Reject responses from clangd larger than this many bytes, to fail
+    /// clearly on a bogus Content-Length
+    #[clap(long, default_value_t = lsp::DEFAULT_MAX_MESSAGE_BYTES)]
+    lsp_max_response_bytes: usize,
+
+    /// Do not chunk symbols nested deeper than this (e.g. classes in
+    /// classes in namespaces), warning about the file, to stay safe on
+    /// bogus symbol trees from clangd
+    #[clap(long, default_value_t = chunking::DEFAULT_MAX_SYMBOL_DEPTH)]
+    max_symbol_depth: usize,
+
+    /// Framing of the LSP messages. clangd needs content-length, ndjson (one
+    /// JSON message per line) is for other symbol providers given with
+    /// --clangd-path
+    #[clap(long, value_enum, default_value = "content-length")]
+    lsp_transport: lsp::LspTransport,
+
+    /// Only process the first N source files found (sorted by path), for quick test runs
+    #[clap(long)]
+    limit_files: Option<usize>,
+
+    /// Read the list of source files to process from this file ("-" for stdin)
+    /// instead of scanning the project directory
+    #[clap(long)]
+    files_from: Option<String>,
+
+    /// Take the source files from the `file` entries of
+    /// `build/compile_commands.json` in the project directory instead of
+    /// scanning it, skipping entries outside the project
+    #[clap(long, conflicts_with = "files_from")]
+    use_compile_db: bool,
+
+    /// Skip test files: `*_test.*`, `*_tests.*`, `test_*.*` and everything
+    /// below `test/` or `tests/` directories
+    #[clap(long)]
+    exclude_tests: bool,
+
+    /// Only chunk the public headers, for an index of the API: headers
+    /// below the `--public-include-dir`, except those in `detail/`,
+    /// `details/`, `internal/`, `impl/` or `private/` directories
+    #[clap(long)]
+    only_public_headers: bool,
+
+    /// Directory of the public headers, relative to the project directory
+    #[clap(long, default_value = "include")]
+    public_include_dir: String,
+
+    /// Record the HEAD commit and each file's git blob SHA with the chunks
+    #[clap(long)]
+    record_git: bool,
+
+    /// Keep at most N chunks per file (functions and methods first, then
+    /// the largest), to bound the output for huge generated files
+    #[clap(long)]
+    max_chunks_per_file: Option<usize>,
+
+    /// Also extract the comment block above each symbol, so that it is
+    /// embedded into a separate `comment_v` field
+    #[clap(long)]
+    embed_comments: bool,
+
+    /// Print name, kind, line range and first line of the first N chunks of
+    /// every processed file
+    #[clap(long)]
+    preview: Option<usize>,
+
+    /// Additional argument for clangd (e.g. "--query-driver=/usr/bin/g++"),
+    /// can be given multiple times
+    #[clap(long = "clangd-arg", allow_hyphen_values = true)]
+    clangd_args: Vec<String>,
+
+    /// Seconds to wait for clangd to finish parsing a file (signalled by its
+    /// diagnostics) before requesting the symbols
+    #[clap(long, default_value_t = 30)]
+    diagnostics_timeout: u64,
+
+    /// Request the symbols right away instead of waiting for the diagnostics
+    #[clap(long)]
+    no_wait_for_diagnostics: bool,
+
+    /// Seconds to wait for clangd to build its background index, which it
+    /// keeps in `.cache/clangd/index` next to the compilation database,
+    /// before chunking
+    #[clap(long, default_value_t = 600)]
+    index_timeout: u64,
+
+    /// Start chunking right away instead of waiting for the background index
+    #[clap(long)]
+    no_wait_for_index: bool,
+
+    /// Fail the run if more than --coverage-threshold percent of the files
+    /// have no compile command, i.e. clangd parsed them with fallback flags
+    #[clap(long, conflicts_with = "no_clangd")]
+    strict_coverage: bool,
+
+    /// Percentage of files without a compile command tolerated by
+    /// --strict-coverage
+    #[clap(long, default_value_t = 10.0, requires = "strict_coverage")]
+    coverage_threshold: f64,
+
+    /// Record which function chunks call which others, using clangd's call
+    /// hierarchy, in `relations.jsonl` in the output directory
+    #[clap(long)]
+    record_calls: bool,
+
+    /// Do not use clangd but split the files with a brace matching
+    /// heuristic, which gives rougher chunks marked as heuristic
+    #[clap(long, conflicts_with = "record_calls")]
+    no_clangd: bool,
+
+    /// After chunking, re-read every written chunk and check that it is
+    /// valid UTF-8, not empty and spans as many lines as its index entry
+    /// says. Problems are reported as errors.
+    #[clap(long)]
+    validate_chunks: bool,
+
+    /// Do not report empty chunks in the validation
+    #[clap(long, requires = "validate_chunks")]
+    allow_empty_chunks: bool,
+
+    /// Skip protected and private class members. clangd does not report the
+    /// access of symbols, so it is guessed from the `public:`/`private:`/
+    /// `protected:` labels in the class source; members defined outside
+    /// their class are always kept.
+    #[clap(long, conflicts_with = "no_clangd")]
+    public_only: bool,
+
+    /// Also chunk variables and constants at file or namespace scope, e.g.
+    /// `constexpr` tables, as chunks of kind `variable`
+    #[clap(long, conflicts_with = "no_clangd")]
+    include_globals: bool,
+
+    /// Link the declaration of each function, e.g. in a header, to its
+    /// definition by qualified name, recording `declaration_of` and
+    /// `definition_of` with the chunks. Overloads are not linked.
+    #[clap(long)]
+    link_decl_def: bool,
+
+    /// Include up to N lines before and after each symbol in its chunk, and
+    /// so in its embedding and stored source, e.g. preceding `using`
+    /// declarations. Context stops at other symbols; the index keeps the
+    /// symbol's own lines.
+    #[clap(long, default_value_t = 0)]
+    chunk_context_lines: usize,
+}
+
+/// Options of the embedding step
+#[derive(clap::Args, Debug)]
+struct EmbedArgs {
+    /// Name of the embedding model to use (e.g. "BAAI/bge-small-en-v1.5"),
+    /// "auto" picks jinaai/jina-embeddings-v2-base-code, which is trained
+    /// on code
+    #[clap(short, long, default_value = embedding_common::AUTO_MODEL)]
     embedding_model: String,
 
+    /// Skip chunks with fewer non-blank characters than this when embedding
+    #[clap(long, default_value_t = 10)]
+    min_chunk_chars: usize,
+
+    /// Number of tokens after which chunks are truncated before embedding,
+    /// overriding the model default (up to the model's maximum)
+    #[clap(long)]
+    tokenizer_truncation_length: Option<usize>,
+
+    /// How to store the embeddings: one file per chunk, or one combined file
+    /// per source file
+    #[clap(long, value_enum, default_value = "per-chunk")]
+    embedding_layout: embedding::EmbeddingLayout,
+
+    /// Directory of an embedding cache keyed by model and chunk content,
+    /// which can be shared between projects (e.g. "~/.cache/cppembedder")
+    #[clap(long)]
+    embed_cache_dir: Option<String>,
+
+    /// Additionally store one vector per window of this many lines of each
+    /// chunk, for late-interaction (MaxSim) scoring in queries
+    #[clap(long)]
+    multi_vector_lines: Option<usize>,
+
+    /// Task instruction prepended to every chunk before embedding it, for
+    /// instruction-tuned models (e.g. "passage: ")
+    #[clap(long, default_value = "")]
+    embed_instruction: String,
+
+    /// Template of the text embedded for each chunk, assembled from its
+    /// metadata, e.g. "{qualified_name}\n{signature}\n{comment}\n{body}".
+    /// Fields: qualified_name, name, kind, parent, signature, comment,
+    /// body, source_file and source_path; missing ones render empty. The stored source is
+    /// not affected.
+    #[clap(long)]
+    embed_template: Option<String>,
+
+    /// Prepend the path of each chunk's source file, relative to the
+    /// project, to the embedded text (e.g. "src/net/tcp_socket.cpp"), so
+    /// that paths help find code. The stored source is not affected.
+    #[clap(long)]
+    embed_path: bool,
+
+    /// Skip chunk files larger than this many bytes with a warning
+    #[clap(long, default_value_t = embedding::DEFAULT_MAX_CHUNK_SIZE)]
+    max_chunk_size: u64,
+
+    /// Number of chunk directories embedded in parallel. Every job loads
+    /// its own instance of the model, multiplying the memory it needs.
+    #[clap(long, default_value_t = 1)]
+    embed_jobs: usize,
+
+    /// Threads of ONNX Runtime computing the embeddings, as INTRA or
+    /// INTRA,INTER: intra-op threads work on a single operator, inter-op
+    /// threads run independent operators in parallel. Shared by all
+    /// embedding jobs. Defaults to one intra-op thread per core.
+    #[clap(long, default_value_t = EmbedThreads::default())]
+    embed_threads: EmbedThreads,
+}
+
+/// Options of the import step
+#[derive(clap::Args, Debug)]
+struct ImportArgs {
+    /// Wait until imported documents are synced to disk before returning
+    #[clap(long)]
+    arango_wait_for_sync: bool,
+
+    /// What to do when a document with the same key already exists
+    #[clap(long, value_enum, default_value = "error")]
+    on_conflict: importer::OnConflict,
+
+    /// Store the source of each chunk gzip compressed and base64 encoded
+    #[clap(long)]
+    compress_src: bool,
+
+    /// Import chunks that have no embedding (e.g. chunked with
+    /// --skip-embeddings) without vector, for name and text search. Import
+    /// again with --on-conflict replace once they are embedded; with
+    /// --skip-unchanged, documents without vector are not skipped.
+    #[clap(long)]
+    allow_missing_vectors: bool,
+
+    /// Store at most this many characters of each chunk's source, marking
+    /// shortened ones with `src_truncated` and their `src_length`. The
+    /// embedding is still computed from the whole chunk.
+    #[clap(long)]
+    max_src_chars: Option<usize>,
+
+    /// Only import chunks whose qualified name matches this regular expression
+    #[clap(long)]
+    import_name_filter: Option<regex::Regex>,
+
+    /// Delete documents of the imported source files whose chunks were not
+    /// produced by this run, e.g. because the function was removed
+    #[clap(long)]
+    prune: bool,
+
+    /// Continue an interrupted import, skipping the documents it already
+    /// imported successfully
+    #[clap(long)]
+    resume: bool,
+
+    /// Do not upload chunks that are unchanged since they were imported:
+    /// with the same source and embedded with the same model, instruction,
+    /// template and path prefix, going by the `src_hash` and `embed_hash`
+    /// stored with each document
+    #[clap(long)]
+    skip_unchanged: bool,
+
+    /// Create an ArangoSearch view over the names and sources, needed by the
+    /// `--name-search` of queries
+    #[clap(long)]
+    create_search_view: bool,
+
+    /// Leave out the chunks of header declarations whose definition, with
+    /// the same qualified name and parameter list, is chunked from an
+    /// implementation file
+    #[clap(long)]
+    dedup_headers: bool,
+
+    /// Drop a vector index of the collection whose dimension differs from
+    /// the embeddings' (e.g. after changing the embedding model) instead of
+    /// refusing to import. Combine with --on-conflict replace and create
+    /// the index again afterwards.
+    #[clap(long)]
+    reindex: bool,
+
+    /// Import the call relations recorded by `--record-calls` into this edge
+    /// collection, connecting the documents of caller and callee for graph
+    /// traversals. The collection is created if it does not exist.
+    #[clap(long)]
+    edge_collection: Option<String>,
+
+    /// Send the documents as newline-delimited JSON to ArangoDB's bulk
+    /// import API, in segments of `--ndjson-segment-size` documents, which
+    /// is faster for large collections. An interrupted import reports how
+    /// many documents made it and can be continued with `--resume`.
+    #[clap(long)]
+    ndjson_import: bool,
+
+    /// Number of documents per request of the NDJSON import
+    #[clap(long, default_value_t = 10_000, requires = "ndjson_import")]
+    ndjson_segment_size: usize,
+
+    /// Import functions, methods, classes, namespaces and variables into
+    /// separate collections named `<collection>_<kind>`, created as
+    /// needed. Query them with `query --kind`.
+    #[clap(
+        long,
+        conflicts_with_all = ["prune", "skip_unchanged", "create_search_view", "edge_collection"]
+    )]
+    split_by_kind: bool,
+
+    /// Record in a `_meta` document of the collection that it expires this
+    /// many hours after the import, e.g. for indexes of pull requests. The
+    /// `cleanup` command drops expired collections.
+    #[clap(long, conflicts_with = "split_by_kind")]
+    expires_in_hours: Option<u64>,
+
+    /// Fail if the output directory has no chunk files to import, instead
+    /// of warning and importing nothing
+    #[clap(long)]
+    strict: bool,
+}
+
+/// Arguments of the `all` command, running the three steps in a row
+#[derive(clap::Args, Debug)]
+struct AllArgs {
+    /// Root directory of the C++ project, not needed with --skip-chunking
+    #[clap(short, long, required_unless_present = "skip_chunking")]
+    project_dir: Option<String>,
+    #[clap(flatten)]
+    output: OutputArgs,
+    #[clap(flatten)]
+    chunk: ChunkArgs,
+    #[clap(flatten)]
+    embed: EmbedArgs,
+    #[clap(flatten)]
+    arango: ArangoArgs,
+    #[clap(flatten)]
+    import: ImportArgs,
+
     /// Skip the chunking step and assume it has already been done
-    #[clap(short, long)]
+    #[clap(long)]
     skip_chunking: bool,
 
     /// Skip the embeddings computation step
-    #[clap(short, long)]
+    #[clap(long)]
     skip_embeddings: bool,
 
-    /// ArangoDB endpoint URL (e.g. "http://localhost:8529")
-    #[clap(short, long)]
-    arango_endpoint: String,
+    /// Embed and import the chunks in a single streaming pass, without
+    /// writing embedding files
+    #[clap(long, conflicts_with = "skip_embeddings")]
+    streaming: bool,
+
+    /// Chunk, embed and import in a single pass in memory, without writing
+    /// chunk or embedding files to the output directory
+    #[clap(
+        long,
+        conflicts_with_all = ["skip_chunking", "skip_embeddings", "streaming", "resume", "dedup_headers", "validate_chunks", "link_decl_def", "edge_collection"]
+    )]
+    no_intermediate_files: bool,
+}
+
+/// Arguments of the `self-test` command
+#[derive(clap::Args, Debug)]
+struct SelfTestArgs {
+    /// Path to clangd executable
+    #[clap(short, long, default_value = "clangd")]
+    clangd_path: String,
+
+    /// Additional argument for clangd (e.g. "--query-driver=/usr/bin/g++"),
+    /// can be given multiple times
+    #[clap(long = "clangd-arg", allow_hyphen_values = true)]
+    clangd_args: Vec<String>,
+
+    /// Split the fixture with the brace matching heuristic instead of clangd
+    #[clap(long)]
+    no_clangd: bool,
+
+    #[clap(flatten)]
+    embed: EmbedArgs,
+
+    /// Test without any outside dependency: chunk heuristically, embed with
+    /// the deterministic test embedder and rank the chunks locally instead
+    /// of in ArangoDB
+    #[clap(long)]
+    mock: bool,
+
+    /// ArangoDB endpoint URL (e.g. "http://localhost:8529"), or several
+    /// separated by commas. The self-test imports into a collection of its
+    /// own, which it drops at the end.
+    #[clap(long, required_unless_present = "mock")]
+    arango_endpoint: Option<String>,
 
     /// ArangoDB username
-    #[clap(short, long)]
-    arango_username: String,
+    #[clap(long, required_unless_present = "mock")]
+    arango_username: Option<String>,
 
     /// ArangoDB password
-    #[clap(short, long)]
-    arango_password: String,
+    #[clap(long, required_unless_present = "mock")]
+    arango_password: Option<String>,
 
     /// ArangoDB database name
-    #[clap(short, long)]
-    arango_database: String,
+    #[clap(long, required_unless_present = "mock")]
+    arango_database: Option<String>,
+}
 
-    /// ArangoDB collection name
-    #[clap(short, long)]
-    arango_collection: String,
+/// The phases of a run. A failure in a phase terminates the process with
+/// the phase's exit code, so that scripts can tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Chunking,
+    Embedding,
+    Import,
+    Query,
+    Config,
+}
 
-    /// Path to the LSP communication log file
-    #[clap(long, default_value = "lsp_communication.log")]
-    lsp_log_file: String,
+impl Phase {
+    fn exit_code(self) -> u8 {
+        match self {
+            Phase::Chunking => 2,
+            Phase::Embedding => 3,
+            Phase::Import => 4,
+            Phase::Config => 5,
+            Phase::Query => 6,
+        }
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Chunking => "chunking",
+            Phase::Embedding => "embedding",
+            Phase::Import => "import",
+            Phase::Query => "query",
+            Phase::Config => "configuration",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An error together with the phase it occurred in
+#[derive(Debug)]
+struct PhaseError {
+    phase: Phase,
+    error: Box<dyn Error>,
+}
+
+/// Returns a closure tagging an error with the given phase, for `map_err`
+fn in_phase(phase: Phase) -> impl FnOnce(Box<dyn Error>) -> PhaseError {
+    move |error| PhaseError { phase, error }
+}
+
+/// Maps the outcome of a run to the process exit code
+fn exit_code(result: &Result<(), PhaseError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => ExitCode::from(e.phase.exit_code()),
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if cli.print_config {
+        println!("{:#}", effective_config(&matches));
+        return ExitCode::SUCCESS;
+    }
+    let result = run(cli).await;
+    if let Err(e) = &result {
+        eprintln!("Error during {}: {}", e.phase, e.error);
+    }
+    exit_code(&result)
+}
+
+/// The values of all arguments of the command line and its subcommand as
+/// JSON, defaults included. Passwords are redacted.
+fn effective_config(matches: &ArgMatches) -> serde_json::Value {
+    let command = Cli::command();
+    let mut config = arg_values(&command, matches);
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        config.insert("command".to_string(), serde_json::json!(name));
+        if let Some(subcommand) = command.find_subcommand(name) {
+            config.extend(arg_values(subcommand, sub_matches));
+        }
+    }
+    serde_json::Value::Object(config)
+}
+
+/// The values of the arguments of one command, by argument id
+fn arg_values(
+    command: &clap::Command,
+    matches: &ArgMatches,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut values = serde_json::Map::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let value = match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => serde_json::json!(matches.get_flag(id)),
+            ArgAction::Set | ArgAction::Append => {
+                let Ok(Some(raw)) = matches.try_get_raw(id) else {
+                    continue;
+                };
+                let raw: Vec<String> = raw.map(|v| v.to_string_lossy().into_owned()).collect();
+                if id.contains("password") {
+                    serde_json::json!("<redacted>")
+                } else if matches!(arg.get_action(), ArgAction::Append) {
+                    serde_json::json!(raw)
+                } else {
+                    serde_json::json!(raw.join(" "))
+                }
+            }
+            _ => continue,
+        };
+        values.insert(id.to_string(), value);
+    }
+    values
+}
+
+/// Name of the project, the last component of the project directory
+fn project_name(project_dir: &str) -> Option<String> {
+    let path = Path::new(project_dir);
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+impl ChunkArgs {
+    fn chunker(
+        self,
+        project_dir: String,
+        output_dir: String,
+        progress: progress::ProgressMode,
+        metrics: &Arc<metrics::Metrics>,
+    ) -> chunking::Chunker {
+        chunking::Chunker::new(
+            project_dir,
+            output_dir,
+            self.clangd_path,
+            self.git_path,
+            self.lsp_log_file,
+            chunking::ChunkerOptions {
+                limit_files: self.limit_files,
+                files_from: self.files_from,
+                exclude_tests: self.exclude_tests,
+                only_public_headers: self
+                    .only_public_headers
+                    .then(|| self.public_include_dir.into()),
+                record_git: self.record_git,
+                lsp_log_format: self.lsp_log,
+                lsp_log_max_size: self.lsp_log_max_size,
+                lsp_max_response_bytes: Some(self.lsp_max_response_bytes),
+                max_symbol_depth: Some(self.max_symbol_depth),
+                lsp_transport: self.lsp_transport,
+                preview: self.preview,
+                clangd_args: self.clangd_args,
+                use_compile_db: self.use_compile_db,
+                max_chunks_per_file: self.max_chunks_per_file,
+                progress,
+                metrics: metrics.clone(),
+                extract_comments: self.embed_comments,
+                diagnostics_timeout: (!self.no_wait_for_diagnostics)
+                    .then(|| Duration::from_secs(self.diagnostics_timeout)),
+                index_timeout: (!self.no_wait_for_index)
+                    .then(|| Duration::from_secs(self.index_timeout)),
+                max_no_compile_command_percent: self
+                    .strict_coverage
+                    .then_some(self.coverage_threshold),
+                record_calls: self.record_calls,
+                no_clangd: self.no_clangd,
+                validate_chunks: self.validate_chunks,
+                allow_empty_chunks: self.allow_empty_chunks,
+                public_only: self.public_only,
+                include_globals: self.include_globals,
+                link_decl_def: self.link_decl_def,
+                context_lines: self.chunk_context_lines,
+            },
+        )
+    }
+}
+
+impl EmbedArgs {
+    fn embedder(
+        &self,
+        output_dir: String,
+        metrics: &Arc<metrics::Metrics>,
+    ) -> Result<embedding::Embedder, Box<dyn Error>> {
+        embedding::Embedder::new(
+            output_dir,
+            &self.embedding_model,
+            self.min_chunk_chars,
+            self.tokenizer_truncation_length,
+            self.embed_threads,
+            self.multi_vector_lines,
+            self.embedding_layout,
+        )
+        .and_then(|embedder| match &self.embed_cache_dir {
+            Some(cache_dir) => embedder.with_cache_dir(cache_dir),
+            None => Ok(embedder),
+        })
+        .map(|embedder| {
+            embedder
+                .with_max_chunk_size(self.max_chunk_size)
+                .with_instruction(self.embed_instruction.clone())
+                .with_path_prefix(self.embed_path)
+                .with_metrics(metrics.clone())
+        })
+        .and_then(|embedder| match &self.embed_template {
+            Some(template) => embedder.with_template(template),
+            None => Ok(embedder),
+        })
+    }
+}
+
+impl ImportArgs {
+    fn importer(
+        self,
+        output_dir: String,
+        arango: &ArangoArgs,
+        collection: String,
+        progress: progress::ProgressMode,
+        metrics: &Arc<metrics::Metrics>,
+    ) -> importer::Importer {
+        importer::Importer::new(
+            output_dir,
+            arango.arango_endpoint.clone(),
+            arango.arango_username.clone(),
+            arango.arango_password.clone(),
+            arango.arango_database.clone(),
+            collection,
+            importer::ImportOptions {
+                wait_for_sync: self.arango_wait_for_sync,
+                on_conflict: self.on_conflict,
+                compress_src: self.compress_src,
+                max_src_chars: self.max_src_chars,
+                allow_missing_vectors: self.allow_missing_vectors,
+                name_filter: self.import_name_filter,
+                prune: self.prune,
+                resume: self.resume,
+                skip_unchanged: self.skip_unchanged,
+                create_search_view: self.create_search_view,
+                dedup_headers: self.dedup_headers,
+                edge_collection: self.edge_collection,
+                reindex: self.reindex,
+                in_memory: false,
+                ndjson_segment_size: self.ndjson_import.then_some(self.ndjson_segment_size),
+                split_by_kind: self.split_by_kind,
+                expires_in_hours: self.expires_in_hours,
+                strict: self.strict,
+                progress,
+                metrics: metrics.clone(),
+            },
+        )
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), PhaseError> {
+    let progress =
+        progress::ProgressMode::detect(cli.quiet || cli.no_progress, cli.progress_format);
+    let metrics = Arc::new(metrics::Metrics::default());
+    let _metrics_writer = cli.metrics_file.map(|path| {
+        metrics::MetricsWriter::start(
+            metrics.clone(),
+            path.into(),
+            Duration::from_secs(cli.metrics_interval),
+        )
+    });
+
+    let result = match cli.command {
+        Command::Chunk {
+            project_dir,
+            output,
+            chunk,
+        } => chunk
+            .chunker(project_dir, output.output_dir, progress, &metrics)
+            .run()
+            .map_err(in_phase(Phase::Chunking)),
+        Command::Embed { output, embed } => embed
+            .embedder(output.output_dir, &metrics)
+            .and_then(|embedder| embedder.with_jobs(embed.embed_jobs))
+            .map_err(in_phase(Phase::Config))
+            .and_then(|embedder| {
+                embedder
+                    .with_progress(progress)
+                    .run()
+                    .map_err(in_phase(Phase::Embedding))
+            }),
+        Command::Import {
+            output,
+            arango,
+            import,
+        } => match arango.collection(None).map_err(in_phase(Phase::Config)) {
+            Ok(collection) => import
+                .importer(output.output_dir, &arango, collection, progress, &metrics)
+                .run()
+                .await
+                .map_err(in_phase(Phase::Import)),
+            Err(e) => Err(e),
+        },
+        Command::Backfill(args) => backfill::run(args)
+            .await
+            .map_err(in_phase(Phase::Embedding)),
+        Command::Cleanup(args) => cleanup::run(args).await.map_err(in_phase(Phase::Import)),
+        Command::Query(args) => cppembedder::query::run(args)
+            .await
+            .map_err(in_phase(Phase::Query)),
+        Command::All(args) => run_all(*args, progress, &metrics).await,
+        Command::SelfTest(args) => selftest::run(args).await,
+        Command::Schema => {
+            println!("{:#}", schema::schemas());
+            Ok(())
+        }
+    };
+    if result.is_err() {
+        metrics.error();
+    }
+    result
+}
+
+/// Runs chunking, embedding and import in a row, skipping what was requested
+async fn run_all(
+    args: AllArgs,
+    progress: progress::ProgressMode,
+    metrics: &Arc<metrics::Metrics>,
+) -> Result<(), PhaseError> {
+    let output_dir = args.output.output_dir;
+    let collection = args
+        .arango
+        .collection(
+            args.project_dir
+                .as_deref()
+                .and_then(project_name)
+                .as_deref(),
+        )
+        .map_err(in_phase(Phase::Config))?;
+    // Only optional with --skip-chunking, which chunking in memory conflicts with
+    let project_dir = || {
+        args.project_dir.clone().ok_or_else(|| PhaseError {
+            phase: Phase::Config,
+            error: "--project-dir is required unless --skip-chunking is given".into(),
+        })
+    };
+
+    // Never touch the chunk files if requested
+    if args.no_intermediate_files {
+        let chunker = args
+            .chunk
+            .chunker(project_dir()?, output_dir.clone(), progress, metrics);
+        let embedder = args
+            .embed
+            .embedder(output_dir.clone(), metrics)
+            .map_err(in_phase(Phase::Config))?;
+        let importer = args
+            .import
+            .importer(output_dir, &args.arango, collection, progress, metrics)
+            .in_memory();
+        pipeline::run_in_memory(chunker, embedder, importer)
+            .await
+            .map_err(in_phase(Phase::Import))?;
+        return Ok(());
+    }
 
     // Create and run the chunker only if not skipped
     if !args.skip_chunking {
-        let chunker =
-            chunking::Chunker::new(args.project_dir, args.output_dir.clone(), args.clangd_path, args.lsp_log_file.clone());
-        chunker.run()?;
+        let chunker = args
+            .chunk
+            .chunker(project_dir()?, output_dir.clone(), progress, metrics);
+        chunker.run().map_err(in_phase(Phase::Chunking))?;
+    }
+
+    // Create the importer
+    let importer = args.import.importer(
+        output_dir.clone(),
+        &args.arango,
+        collection,
+        progress,
+        metrics,
+    );
+
+    // Embed and import in one pass if requested
+    if args.streaming {
+        let embedder = args
+            .embed
+            .embedder(output_dir, metrics)
+            .map_err(in_phase(Phase::Config))?;
+        pipeline::run_streaming(embedder, importer)
+            .await
+            .map_err(in_phase(Phase::Import))?;
+        return Ok(());
     }
 
     // Create and run the embedder only if not skipped
     if !args.skip_embeddings {
-        let embedder = embedding::Embedder::new(args.output_dir.clone(), &args.embedding_model)?;
-        embedder.run()?;
-    }
-
-    // Create and run the importer
-    let importer = importer::Importer::new(
-        args.output_dir,
-        args.arango_endpoint,
-        args.arango_username,
-        args.arango_password,
-        args.arango_database,
-        args.arango_collection,
-    );
-    importer.run().await?;
+        let embedder = args
+            .embed
+            .embedder(output_dir, metrics)
+            .and_then(|embedder| embedder.with_jobs(args.embed.embed_jobs))
+            .map_err(in_phase(Phase::Config))?
+            .with_progress(progress);
+        embedder.run().map_err(in_phase(Phase::Embedding))?;
+    }
+
+    // Run the importer
+    importer.run().await.map_err(in_phase(Phase::Import))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arango_mock::{accept_all, MockArango};
+    use crate::fsutil::scratch_dir;
+    use std::fs;
+
+    /// Parses a command line, program name excluded
+    fn parse<S: AsRef<str>>(args: &[S]) -> Result<Cli, clap::Error> {
+        let args = args.iter().map(|arg| arg.as_ref());
+        Cli::try_parse_from(std::iter::once("cppembedder").chain(args))
+    }
+
+    #[tokio::test]
+    async fn all_without_intermediate_files_imports_every_chunk() {
+        embedding_common::enable_test_embedder();
+        let project = scratch_dir("no-intermediate-files");
+        let source = "int add(int a, int b) {\n  return a + b;\n}\n\n\
+                      int sub(int a, int b) {\n  return a - b;\n}\n";
+        fs::create_dir_all(project.join("lib")).unwrap();
+        fs::write(project.join("calc.cpp"), source).unwrap();
+        fs::write(project.join("lib/calc.cpp"), source).unwrap();
+        let output = project.join("chunks");
+        let mock = MockArango::start(accept_all).await;
+
+        let mut args: Vec<String> = [
+            "--quiet",
+            "all",
+            "--project-dir",
+            &project.to_string_lossy(),
+            "--output-dir",
+            &output.to_string_lossy(),
+            "--no-clangd",
+            "--embedding-model",
+            "test/deterministic-8",
+            "--no-intermediate-files",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(mock.args("chunks"));
+        run(parse(&args).unwrap()).await.unwrap();
+
+        // Both files of the same name reach the importer with all chunks,
+        // and nothing was written
+        let mut imported: Vec<String> = mock
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/document/chunks")
+            .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+            .map(|document| {
+                format!(
+                    "{} {}",
+                    document["source_path"].as_str().unwrap(),
+                    document["name"].as_str().unwrap()
+                )
+            })
+            .collect();
+        imported.sort();
+        assert_eq!(
+            imported,
+            vec![
+                "calc.cpp add",
+                "calc.cpp sub",
+                "lib/calc.cpp add",
+                "lib/calc.cpp sub"
+            ]
+        );
+        assert!(!output.exists());
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    /// A search result as the query of `query` returns it
+    fn search_result(key: &str, source_file: &str, line: usize, score: f64) -> serde_json::Value {
+        serde_json::json!({
+            "doc": {
+                "_key": key,
+                "name": key,
+                "kind": "function",
+                "source_file": source_file,
+                "chunk_file": format!("000_{}_function_{}.cpp", key, line),
+            },
+            "score": score,
+        })
+    }
+
+    /// Runs a batch query of one query against `mock` and returns the keys
+    /// of its results in the order written
+    async fn query_result_keys(mock: &MockArango, name: &str, extra: &[&str]) -> Vec<String> {
+        embedding_common::enable_test_embedder();
+        let dir = scratch_dir(name);
+        let queries = dir.join("queries.txt");
+        let output = dir.join("results.jsonl");
+        fs::write(&queries, "add two numbers\n").unwrap();
+        let mut args: Vec<String> = [
+            "query",
+            "--queries-file",
+            &queries.to_string_lossy(),
+            "--output",
+            &output.to_string_lossy(),
+            "--embedding-model",
+            "test/deterministic-8",
+        ]
+        .iter()
+        .chain(extra)
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(mock.args("chunks"));
+        run(parse(&args).unwrap()).await.unwrap();
+
+        let line: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        line["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| record["key"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn batch_queries_write_one_line_per_query_in_order() {
+        embedding_common::enable_test_embedder();
+        let mock = MockArango::start(|request| {
+            if request.path == "/_api/cursor" {
+                let results = [search_result("add", "calc.cpp", 1, 0.9)];
+                (
+                    201,
+                    serde_json::json!({ "result": results, "hasMore": false }),
+                )
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+        let dir = scratch_dir("batch-queries");
+        let queries = dir.join("queries.txt");
+        let output = dir.join("results.jsonl");
+        fs::write(&queries, "add two numbers\n\n  parse the config  \n").unwrap();
+        let mut args: Vec<String> = [
+            "query",
+            "--queries-file",
+            &queries.to_string_lossy(),
+            "--output",
+            &output.to_string_lossy(),
+            "--embedding-model",
+            "test/deterministic-8",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(mock.args("chunks"));
+        run(parse(&args).unwrap()).await.unwrap();
+
+        // Blank lines are skipped, every query is searched once
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let queried: Vec<&str> = lines
+            .iter()
+            .map(|line| line["query"].as_str().unwrap())
+            .collect();
+        assert_eq!(queried, ["add two numbers", "parse the config"]);
+        for line in &lines {
+            assert_eq!(line["results"][0]["key"], "add");
+            assert_eq!(line["results"][0]["score"], 0.9);
+        }
+        let searches = mock
+            .requests()
+            .iter()
+            .filter(|request| request.path == "/_api/cursor")
+            .count();
+        assert_eq!(searches, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn query_collects_every_page_of_the_cursor() {
+        let mock =
+            MockArango::start(
+                |request| match (request.method.as_str(), request.path.as_str()) {
+                    ("POST", "/_api/cursor") => (
+                        201,
+                        serde_json::json!({
+                            "result": [
+                                search_result("first", "a.cpp", 1, 0.5),
+                                search_result("second", "a.cpp", 9, 0.9),
+                            ],
+                            "hasMore": true,
+                            "id": "4711",
+                        }),
+                    ),
+                    ("PUT", "/_api/cursor/4711") => (
+                        200,
+                        serde_json::json!({
+                            "result": [
+                                search_result("third", "b.cpp", 1, 0.7),
+                                search_result("fourth", "b.cpp", 5, 0.8),
+                            ],
+                            "hasMore": false,
+                        }),
+                    ),
+                    _ => accept_all(request),
+                },
+            )
+            .await;
+
+        let keys = query_result_keys(&mock, "query-pages", &[]).await;
+        assert_eq!(keys, ["second", "fourth", "third", "first"]);
+        assert!(mock
+            .requests()
+            .iter()
+            .any(|request| request.method == "PUT" && request.path == "/_api/cursor/4711"));
+    }
+
+    #[tokio::test]
+    async fn query_sorted_by_path_keeps_the_best_results() {
+        let mock = MockArango::start(|request| {
+            if request.path == "/_api/cursor" {
+                let results = [
+                    search_result("deposit", "b.cpp", 5, 0.9),
+                    search_result("withdraw", "a.cpp", 9, 0.8),
+                    search_result("balance", "a.cpp", 1, 0.7),
+                    search_result("audit", "a.cpp", 3, 0.1),
+                ];
+                (
+                    201,
+                    serde_json::json!({ "result": results, "hasMore": false }),
+                )
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+
+        let extra = ["--limit", "3", "--sort-by", "path"];
+        let keys = query_result_keys(&mock, "query-sort-by-path", &extra).await;
+        assert_eq!(keys, ["balance", "withdraw", "deposit"]);
+    }
+
+    #[tokio::test]
+    async fn failures_exit_with_the_code_of_their_phase() {
+        let failed = |phase| exit_code(&Err(in_phase(phase)("failed".into())));
+        assert_eq!(exit_code(&Ok(())), ExitCode::SUCCESS);
+        assert_eq!(failed(Phase::Chunking), ExitCode::from(2));
+        assert_eq!(failed(Phase::Embedding), ExitCode::from(3));
+        assert_eq!(failed(Phase::Import), ExitCode::from(4));
+        assert_eq!(failed(Phase::Config), ExitCode::from(5));
+        assert_eq!(failed(Phase::Query), ExitCode::from(6));
+
+        // An unknown model is a configuration error
+        let output = scratch_dir("unknown-model");
+        let args = [
+            "embed",
+            "--output-dir",
+            &output.to_string_lossy(),
+            "--embedding-model",
+            "no/such-model",
+        ];
+        let result = run(parse(&args).unwrap()).await;
+        assert_eq!(result.as_ref().unwrap_err().phase, Phase::Config);
+        assert_eq!(exit_code(&result), ExitCode::from(5));
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mock_self_test_passes() {
+        run(parse(&["self-test", "--mock"]).unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn self_test_drops_its_collection() {
+        embedding_common::enable_test_embedder();
+        let mock = MockArango::start(|request| {
+            if request.path == "/_api/cursor"
+                && request.json()["query"]
+                    .as_str()
+                    .is_some_and(|query| query.contains("COSINE_SIMILARITY"))
+            {
+                (
+                    201,
+                    serde_json::json!({ "result": ["crc32"], "hasMore": false }),
+                )
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+        let mut args = vec![
+            "self-test".to_string(),
+            "--no-clangd".to_string(),
+            "--embedding-model".to_string(),
+            "test/deterministic-64".to_string(),
+        ];
+        // The collection is the self-test's own
+        args.extend(mock.args("chunks").into_iter().take(8));
+        run(parse(&args).unwrap()).await.unwrap();
+
+        let requests = mock.requests();
+        let imported: Vec<&str> = requests
+            .iter()
+            .filter_map(|request| request.path.strip_prefix("/_api/document/"))
+            .collect();
+        assert!(!imported.is_empty());
+        assert!(imported
+            .iter()
+            .all(|collection| collection.starts_with("cppembedder_selftest_")));
+        let dropped = format!("/_api/collection/{}", imported[0]);
+        assert!(requests
+            .iter()
+            .any(|request| request.method == "DELETE" && request.path == dropped));
+    }
+
+    #[test]
+    fn self_test_needs_arango_unless_mocked() {
+        assert!(parse(&["self-test"]).is_err());
+        assert!(parse(&["self-test", "--mock"]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn streamed_chunks_get_the_vectors_of_their_comments() {
+        embedding_common::enable_test_embedder();
+        for mode in ["--streaming", "--no-intermediate-files"] {
+            let project = scratch_dir(&format!("streamed-comments{}", mode));
+            let source = "// Adds two numbers.\nint add(int a, int b) {\n  return a + b;\n}\n\n\
+                          int sub(int a, int b) {\n  return a - b;\n}\n";
+            fs::write(project.join("calc.cpp"), source).unwrap();
+            let output = project.join("chunks");
+            let mock = MockArango::start(accept_all).await;
+
+            let mut args: Vec<String> = [
+                "--quiet",
+                "all",
+                "--project-dir",
+                &project.to_string_lossy(),
+                "--output-dir",
+                &output.to_string_lossy(),
+                "--no-clangd",
+                "--embedding-model",
+                "test/deterministic-8",
+                "--embed-comments",
+                mode,
+            ]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+            args.extend(mock.args("chunks"));
+            run(parse(&args).unwrap()).await.unwrap();
+
+            let documents: Vec<serde_json::Value> = mock
+                .requests()
+                .iter()
+                .filter(|request| request.path == "/_api/document/chunks")
+                .flat_map(|request| request.json().as_array().cloned().unwrap_or_default())
+                .collect();
+            let comment_v = |name: &str| {
+                let document = documents
+                    .iter()
+                    .find(|document| document["name"] == name)
+                    .unwrap_or_else(|| panic!("{} not imported {}", name, mode));
+                document["comment_v"].as_array().map(Vec::len)
+            };
+            assert_eq!(comment_v("add"), Some(8), "{}", mode);
+            assert_eq!(comment_v("sub"), None, "{}", mode);
+            fs::remove_dir_all(&project).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn backfill_patches_the_vectors_of_the_assembled_text() {
+        embedding_common::enable_test_embedder();
+        let src = "int add(int a, int b) {\n  return a + b;\n}\n";
+        let mock = MockArango::start(move |request| {
+            let query = request.json()["query"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            match request.method.as_str() {
+                "POST" if query.contains("RETURN doc._key") => (
+                    201,
+                    serde_json::json!({ "result": ["add", "sub"], "hasMore": false }),
+                ),
+                "POST" => (
+                    201,
+                    serde_json::json!({
+                        "result": [
+                            {
+                                "_key": "add",
+                                "name": "calc::add",
+                                "chunk_file": "add.cpp",
+                                "src": src,
+                                "source_file": "calc.cpp",
+                                "source_path": "src/calc.cpp",
+                            },
+                            {
+                                "_key": "sub",
+                                "name": "calc::sub",
+                                "chunk_file": "sub.cpp",
+                                "src": "int sub(int a, int b) {",
+                                "src_truncated": true,
+                                "src_length": 400,
+                            },
+                        ],
+                        "hasMore": false,
+                    }),
+                ),
+                "PATCH" => (202, serde_json::json!([{ "_key": "add" }])),
+                _ => accept_all(request),
+            }
+        })
+        .await;
+
+        let mut args: Vec<String> = [
+            "backfill",
+            "--embedding-model",
+            "test/deterministic-8",
+            "--embed-instruction",
+            "passage: ",
+            "--embed-template",
+            "{qualified_name}\\n{body}",
+            "--embed-path",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(mock.args("chunks"));
+        run(parse(&args).unwrap()).await.unwrap();
+
+        // Only the document with its whole source is patched, with the
+        // vector of the text `embed` would have embedded
+        let patches: Vec<serde_json::Value> = mock
+            .requests()
+            .iter()
+            .filter(|request| request.method == "PATCH")
+            .map(|request| request.json())
+            .collect();
+        assert_eq!(patches.len(), 1);
+        let patch = &patches[0][0];
+        assert_eq!(patches[0].as_array().unwrap().len(), 1);
+        assert_eq!(patch["_key"], "add");
+        let text = format!("passage: src/calc.cpp\ncalc::add\n{}", src);
+        let model = embedding_common::create_embedder(
+            "test/deterministic-8",
+            None,
+            EmbedThreads::default(),
+        )
+        .unwrap();
+        let expected = model.embed(vec![text], None).unwrap().remove(0);
+        assert_eq!(patch["v"], serde_json::json!(expected));
+        assert_eq!(patch["model"], "test/deterministic-8");
+        assert_eq!(patch["instruction"], "passage: ");
+        let embed_hash = embedding::embedding_settings_hash(&serde_json::json!({
+            "model": "test/deterministic-8",
+            "instruction": "passage: ",
+            "template": "{qualified_name}\n{body}",
+            "path_prefix": true,
+        }));
+        assert_eq!(patch["embed_hash"], serde_json::json!(embed_hash));
+    }
+
+    #[tokio::test]
+    async fn cleanup_drops_only_expired_collections() {
+        let mock = MockArango::start(|request| match request.path.as_str() {
+            "/_api/collection" if request.method == "GET" => (
+                200,
+                serde_json::json!({ "result": [
+                    { "name": "tmp_expired_chunks" },
+                    { "name": "tmp_current_chunks" },
+                    { "name": "other_expired_chunks" },
+                ] }),
+            ),
+            "/_api/cursor" => {
+                let collection = request.json()["bindVars"]["@chunks"].clone();
+                let result = if collection == "tmp_expired_chunks" {
+                    serde_json::json!(["2020-01-01T00:00:00Z"])
+                } else {
+                    serde_json::json!([])
+                };
+                (
+                    201,
+                    serde_json::json!({ "result": result, "hasMore": false }),
+                )
+            }
+            _ => accept_all(request),
+        })
+        .await;
+        let mut args = vec!["cleanup".to_string()];
+        args.extend(mock.args("unused").into_iter().take(8));
+        args.extend(["--collection-prefix".to_string(), "tmp".to_string()]);
+        run(parse(&args).unwrap()).await.unwrap();
+
+        let deleted: Vec<String> = mock
+            .requests()
+            .iter()
+            .filter(|request| request.method == "DELETE")
+            .map(|request| request.path.clone())
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![
+                "/_api/view/tmp_expired_chunks_search",
+                "/_api/collection/tmp_expired_chunks"
+            ]
+        );
+    }
+
+    #[test]
+    fn subcommands_take_only_their_arguments() {
+        let arango = [
+            "--arango-endpoint",
+            "http://localhost:8529",
+            "--arango-username",
+            "root",
+            "--arango-password",
+            "",
+            "--arango-database",
+            "test",
+            "--arango-collection",
+            "chunks",
+        ];
+        let with_arango = |args: &[&str]| {
+            let mut args: Vec<&str> = args.to_vec();
+            args.extend(arango);
+            parse(&args)
+        };
+
+        assert!(parse(&["chunk", "-p", "project", "-o", "out", "-c", "clangd"]).is_ok());
+        assert!(parse(&["chunk"]).is_err());
+        assert!(parse(&["chunk", "-p", "project", "-e", "auto"]).is_err());
+
+        assert!(parse(&["embed", "-o", "out", "-e", "auto"]).is_ok());
+        assert!(parse(&["embed", "-p", "project"]).is_err());
+
+        assert!(with_arango(&["import", "-o", "out"]).is_ok());
+        assert!(parse(&["import", "-o", "out"]).is_err());
+        assert!(with_arango(&["import", "-e", "auto"]).is_err());
+
+        assert!(with_arango(&["query", "-q", "parse the config"]).is_ok());
+        assert!(with_arango(&["query"]).is_err());
+        assert!(with_arango(&["query", "-q", "parse", "-p", "project"]).is_err());
+
+        assert!(with_arango(&["all", "-p", "project", "-e", "auto"]).is_ok());
+        assert!(with_arango(&["all", "--skip-chunking"]).is_ok());
+        assert!(with_arango(&["all"]).is_err());
+        assert!(parse(&["all", "-p", "project"]).is_err());
+
+        assert!(with_arango(&["backfill"]).is_ok());
+        assert!(with_arango(&["backfill", "-p", "project"]).is_err());
+
+        assert!(with_arango(&["cleanup", "--dry-run"]).is_ok());
+        assert!(with_arango(&["cleanup", "-o", "out"]).is_err());
+
+        assert!(parse(&["schema"]).is_ok());
+        assert!(parse(&["schema", "-o", "out"]).is_err());
+    }
+
+    #[test]
+    fn all_without_chunking_needs_no_project() {
+        let Cli { command, .. } = parse(&[
+            "all",
+            "--skip-chunking",
+            "--arango-endpoint",
+            "http://localhost:8529",
+            "--arango-username",
+            "root",
+            "--arango-password",
+            "",
+            "--arango-database",
+            "test",
+            "--arango-collection",
+            "chunks",
+        ])
+        .unwrap();
+        let Command::All(args) = command else {
+            panic!("parsed as {:?}", command);
+        };
+        assert!(args.skip_chunking);
+        assert_eq!(args.project_dir, None);
+    }
+
+    #[test]
+    fn effective_config_shows_overrides_and_redacts_the_password() {
+        let matches = Cli::command()
+            .try_get_matches_from([
+                "cppembedder",
+                "--print-config",
+                "import",
+                "-o",
+                "chunks-out",
+                "--arango-endpoint",
+                "http://db:8529",
+                "--arango-username",
+                "root",
+                "--arango-password",
+                "secret",
+                "--arango-database",
+                "code",
+                "--arango-collection",
+                "my_chunks",
+            ])
+            .unwrap();
+        let config = effective_config(&matches);
+        assert_eq!(config["print_config"], true);
+        assert_eq!(config["command"], "import");
+        assert_eq!(config["output_dir"], "chunks-out");
+        assert_eq!(config["arango_collection"], "my_chunks");
+        assert_eq!(config["arango_password"], "<redacted>");
+        assert!(!config.to_string().contains("secret"));
+    }
+}
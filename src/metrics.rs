@@ -0,0 +1,206 @@
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::fsutil::write_atomic;
+
+/// Counters of a run, updated by all phases. Counting is cheap, so the
+/// phases always count and only a `MetricsWriter` makes the numbers visible.
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    files_processed: AtomicU64,
+    chunks_written: AtomicU64,
+    embeddings_computed: AtomicU64,
+    embeddings_cached: AtomicU64,
+    documents_imported: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            files_processed: AtomicU64::new(0),
+            chunks_written: AtomicU64::new(0),
+            embeddings_computed: AtomicU64::new(0),
+            embeddings_cached: AtomicU64::new(0),
+            documents_imported: AtomicU64::new(0),
+            bytes_uploaded: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// A source file was chunked, producing the given number of chunks
+    pub fn file_processed(&self, chunks: usize) {
+        self.files_processed.fetch_add(1, Ordering::Relaxed);
+        self.chunks_written
+            .fetch_add(chunks as u64, Ordering::Relaxed);
+    }
+
+    /// A chunk embedding was computed (or taken from the cache)
+    pub fn embedding_computed(&self) {
+        self.embeddings_computed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A chunk embedding was taken from the cache, counted in addition to
+    /// `embedding_computed`
+    pub fn embedding_cached(&self) {
+        self.embeddings_cached.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A batch of documents was uploaded
+    pub fn batch_imported(&self, documents: usize, bytes: usize) {
+        self.documents_imported
+            .fetch_add(documents as u64, Ordering::Relaxed);
+        self.bytes_uploaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A phase failed
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The current counters, with the throughput since the start of the run
+    pub fn snapshot(&self) -> Value {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let per_second = |count: u64| {
+            if elapsed > 0.0 {
+                count as f64 / elapsed
+            } else {
+                0.0
+            }
+        };
+        let embeddings = self.embeddings_computed.load(Ordering::Relaxed);
+        let documents = self.documents_imported.load(Ordering::Relaxed);
+        json!({
+            "elapsed_secs": elapsed,
+            "files_processed": self.files_processed.load(Ordering::Relaxed),
+            "chunks_written": self.chunks_written.load(Ordering::Relaxed),
+            "embeddings_computed": embeddings,
+            "embeddings_cached": self.embeddings_cached.load(Ordering::Relaxed),
+            "documents_imported": documents,
+            "bytes_uploaded": self.bytes_uploaded.load(Ordering::Relaxed),
+            "errors": self.errors.load(Ordering::Relaxed),
+            "embeddings_per_sec": per_second(embeddings),
+            "documents_per_sec": per_second(documents),
+        })
+    }
+}
+
+/// Writes the metrics as JSON to a file in regular intervals on a
+/// background thread, and a last time when dropped
+pub struct MetricsWriter {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsWriter {
+    pub fn start(metrics: Arc<Metrics>, path: PathBuf, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || loop {
+                // Sleep in small steps, so that dropping the writer is quick
+                let deadline = Instant::now() + interval;
+                while Instant::now() < deadline && !stop.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(100).min(interval));
+                }
+                let content = format!("{:#}\n", metrics.snapshot());
+                if let Err(e) = write_atomic(&path, content) {
+                    eprintln!(
+                        "WARN: failed to write metrics file '{}': {}",
+                        path.display(),
+                        e
+                    );
+                }
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+            })
+        };
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for MetricsWriter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutil::scratch_dir;
+    use std::fs;
+
+    fn read_metrics(path: &std::path::Path) -> Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn writer_writes_snapshots_periodically_and_on_drop() {
+        let dir = scratch_dir("metrics-writer");
+        let path = dir.join("metrics.json");
+        let metrics = Arc::new(Metrics::default());
+        metrics.file_processed(3);
+        metrics.embedding_computed();
+
+        let writer = MetricsWriter::start(metrics.clone(), path.clone(), Duration::from_millis(50));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !path.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let first = read_metrics(&path);
+        assert_eq!(first["files_processed"], 1);
+        assert_eq!(first["chunks_written"], 3);
+
+        metrics.file_processed(2);
+        metrics.embedding_computed();
+        metrics.embedding_cached();
+        metrics.batch_imported(2, 100);
+        metrics.error();
+        drop(writer);
+
+        let last = read_metrics(&path);
+        for counter in [
+            "files_processed",
+            "chunks_written",
+            "embeddings_computed",
+            "documents_imported",
+            "bytes_uploaded",
+            "errors",
+        ] {
+            assert!(
+                last[counter].as_u64() >= first[counter].as_u64(),
+                "{}",
+                counter
+            );
+        }
+        assert_eq!(last["files_processed"], 2);
+        assert_eq!(last["chunks_written"], 5);
+        assert_eq!(last["embeddings_computed"], 2);
+        assert_eq!(last["embeddings_cached"], 1);
+        assert_eq!(last["documents_imported"], 2);
+        assert_eq!(last["bytes_uploaded"], 100);
+        assert_eq!(last["errors"], 1);
+        assert!(last["elapsed_secs"].as_f64().unwrap() >= first["elapsed_secs"].as_f64().unwrap());
+        let leftovers: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(leftovers.len(), 1, "temporary files left behind");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
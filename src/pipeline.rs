@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::chunking::Chunker;
+use crate::embedding::Embedder;
+use crate::importer::{EmbeddedChunk, Importer};
+
+/// Number of embedded chunks that may wait for import before embedding pauses
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Embeds all chunks in the output directory and imports them in a single
+/// pass, without writing embedding files. Embedding is CPU-bound and runs on
+/// a blocking thread, handing each chunk over a bounded channel to the
+/// importer, so that it overlaps with the HTTP import.
+pub async fn run_streaming(embedder: Embedder, importer: Importer) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+    let producer = tokio::task::spawn_blocking(move || {
+        let embed_hash = embedder.settings_hash();
+        embedder
+            .embed_each(|path, content, vectors| {
+                sender
+                    .blocking_send(EmbeddedChunk {
+                        path,
+                        content,
+                        vectors,
+                        embed_hash: embed_hash.clone(),
+                        index: None,
+                    })
+                    .map_err(|_| "Importer stopped receiving chunks".into())
+            })
+            .map_err(|e| e.to_string())
+    });
+
+    // If the import fails, the receiver is dropped and the producer stops
+    // for lack of a receiver, so the import error is the cause to report
+    let (imported, embedded) = tokio::join!(importer.import_stream(receiver), producer);
+    let imported = imported?;
+    let embedded = embedded.map_err(|e| format!("Embedding task failed: {}", e))??;
+    if embedded == 0 {
+        importer.report_no_chunks()?;
+    }
+
+    println!(
+        "Embedded {} chunks and imported {} documents",
+        embedded, imported
+    );
+    Ok(())
+}
+
+/// Chunks, embeds and imports in a single pass without writing chunk or
+/// embedding files. Chunking and embedding share a blocking thread, the
+/// importer receives the chunks over a bounded channel as in
+/// `run_streaming`.
+pub async fn run_in_memory(
+    chunker: Chunker,
+    embedder: Embedder,
+    importer: Importer,
+) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+    let producer = tokio::task::spawn_blocking(move || {
+        let mut embedded = 0;
+        let embed_hash = embedder.settings_hash();
+        chunker
+            .run_each(|files| {
+                let index: Arc<str> = files.index.into();
+                for file in files.files {
+                    // Chunks without vectors are sent too, for --prune
+                    let vectors = embedder.embed_chunk(&file, &index)?;
+                    embedded += usize::from(vectors.is_some());
+                    sender
+                        .blocking_send(EmbeddedChunk {
+                            path: file.path,
+                            content: file.content,
+                            vectors,
+                            embed_hash: embed_hash.clone(),
+                            index: Some(index.clone()),
+                        })
+                        .map_err(|_| "Importer stopped receiving chunks")?;
+                }
+                Ok(())
+            })
+            .map(|_| embedded)
+            .map_err(|e| e.to_string())
+    });
+
+    // If the import fails, the receiver is dropped and the producer stops,
+    // so the import error is the cause to report
+    let (imported, embedded) = tokio::join!(importer.import_stream(receiver), producer);
+    let imported = imported?;
+    let embedded = embedded.map_err(|e| format!("Chunking and embedding task failed: {}", e))??;
+    if embedded == 0 {
+        importer.report_no_chunks()?;
+    }
+
+    println!(
+        "Embedded {} chunks and imported {} documents",
+        embedded, imported
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arango_mock::{accept_all, MockArango};
+    use crate::embedding::EmbeddingLayout;
+    use crate::embedding_common::{enable_test_embedder, EmbedThreads};
+    use crate::fsutil::scratch_dir;
+    use serde_json::json;
+    use std::fs;
+
+    #[tokio::test]
+    async fn streaming_reports_the_import_error() {
+        enable_test_embedder();
+        let output = scratch_dir("streaming-import-error");
+        let dir = output.join("calc_cpp");
+        fs::create_dir_all(&dir).unwrap();
+        // More chunks than the channel holds, so that embedding notices that
+        // the import stopped
+        for i in 0..CHANNEL_CAPACITY * 2 {
+            fs::write(
+                dir.join(format!("{:03}_f{}_function_1.cpp", i, i)),
+                format!("int f{}() {{ return {}; }}", i, i),
+            )
+            .unwrap();
+        }
+        let arango = MockArango::start(|request| {
+            if request.path.starts_with("/_api/document/") {
+                let error = json!({ "error": true, "errorMessage": "database is read-only" });
+                (403, error)
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+
+        let output_dir = output.to_string_lossy().into_owned();
+        let embedder = Embedder::new(
+            output_dir.clone(),
+            "test/deterministic-8",
+            1,
+            None,
+            EmbedThreads::default(),
+            None,
+            EmbeddingLayout::default(),
+        )
+        .unwrap();
+        let importer = Importer::builder()
+            .output_dir(output_dir)
+            .endpoint(&arango.endpoint)
+            .username("root")
+            .password("")
+            .database("test")
+            .collection("chunks")
+            .build()
+            .unwrap();
+        let error = run_streaming(embedder, importer).await.unwrap_err();
+        assert!(
+            error.to_string().contains("database is read-only"),
+            "{}",
+            error
+        );
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
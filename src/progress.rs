@@ -0,0 +1,243 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Least time between two JSON progress lines of a phase
+const JSON_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where plain and JSON progress lines are written, stderr unless a test
+/// captures them
+type ProgressOutput = Arc<Mutex<dyn Write + Send>>;
+
+fn stderr_output() -> ProgressOutput {
+    Arc::new(Mutex::new(io::stderr()))
+}
+
+/// Progress output chosen on the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// Bars on a terminal, plain lines otherwise
+    #[default]
+    Auto,
+    Bar,
+    Plain,
+    /// JSON lines on stderr, for log parsers
+    Jsonl,
+}
+
+/// How the phases report their progress
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Interactive progress bars on stderr
+    #[default]
+    Bar,
+    /// A plain log line every 10% of the work, for logs of CI runs and pipes
+    Plain,
+    /// A JSON line with phase, done, total and rate at most every second
+    Jsonl,
+    /// No progress output at all
+    Off,
+}
+
+impl ProgressMode {
+    /// The mode of the given format, unless progress output is disabled.
+    /// The automatic format shows bars if stderr is a terminal and plain
+    /// lines otherwise.
+    pub fn detect(disabled: bool, format: ProgressFormat) -> Self {
+        match format {
+            _ if disabled => ProgressMode::Off,
+            ProgressFormat::Auto if io::stderr().is_terminal() => ProgressMode::Bar,
+            ProgressFormat::Auto | ProgressFormat::Plain => ProgressMode::Plain,
+            ProgressFormat::Bar => ProgressMode::Bar,
+            ProgressFormat::Jsonl => ProgressMode::Jsonl,
+        }
+    }
+}
+
+/// Progress of a phase as JSON lines on stderr, like
+/// `{"phase":"embedding","done":120,"total":4000,"rate":35.2}`, with the
+/// rate in items per second. Lines are written at most every second and
+/// once the phase is done.
+pub struct JsonProgress {
+    phase: String,
+    start: Instant,
+    last_report: Mutex<Option<Instant>>,
+    out: ProgressOutput,
+}
+
+impl JsonProgress {
+    pub fn new(phase: &str) -> Self {
+        Self {
+            phase: phase.to_lowercase(),
+            start: Instant::now(),
+            last_report: Mutex::new(None),
+            out: stderr_output(),
+        }
+    }
+
+    pub fn report(&self, done: u64, total: u64) {
+        let now = Instant::now();
+        let mut last_report = self.last_report.lock().unwrap();
+        let due = last_report.is_none_or(|last| now - last >= JSON_REPORT_INTERVAL);
+        if !due && done < total {
+            return;
+        }
+        *last_report = Some(now);
+        let elapsed = (now - self.start).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            done as f64 / elapsed
+        } else {
+            0.0
+        };
+        let line = json!({
+            "phase": self.phase,
+            "done": done,
+            "total": total,
+            "rate": (rate * 10.0).round() / 10.0
+        });
+        let _ = writeln!(self.out.lock().unwrap(), "{}", line);
+    }
+
+    /// Writes a status message of the phase that has no item count, like
+    /// `{"phase":"indexing","message":"12/40"}`
+    pub fn message(&self, message: &str) {
+        let line = json!({ "phase": self.phase, "message": message });
+        let _ = writeln!(self.out.lock().unwrap(), "{}", line);
+    }
+}
+
+/// Progress of a phase over a known number of items, reported according to
+/// the progress mode
+pub struct Progress {
+    bar: ProgressBar,
+    mode: ProgressMode,
+    label: &'static str,
+    json: Option<JsonProgress>,
+    out: ProgressOutput,
+}
+
+impl Progress {
+    pub fn new(len: u64, template: &str, mode: ProgressMode, label: &'static str) -> Self {
+        let bar = match mode {
+            ProgressMode::Bar => {
+                let bar = ProgressBar::new(len);
+                bar.set_style(
+                    ProgressStyle::default_bar()
+                        .template(template)
+                        .unwrap()
+                        .progress_chars("#>-"),
+                );
+                bar
+            }
+            // A hidden bar still counts, we report from its position
+            ProgressMode::Plain | ProgressMode::Jsonl | ProgressMode::Off => ProgressBar::hidden(),
+        };
+        bar.set_length(len);
+        let json = (mode == ProgressMode::Jsonl).then(|| JsonProgress::new(label));
+        Self {
+            bar,
+            mode,
+            label,
+            json,
+            out: stderr_output(),
+        }
+    }
+
+    /// Writes the plain and JSON progress lines to `out` instead of stderr
+    #[cfg(test)]
+    fn with_output(mut self, out: ProgressOutput) -> Self {
+        if let Some(json) = &mut self.json {
+            json.out = out.clone();
+        }
+        self.out = out;
+        self
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.bar.inc(delta);
+        if self.mode == ProgressMode::Plain {
+            let len = self.bar.length().unwrap_or(0);
+            let step = (len / 10).max(1);
+            let pos = self.bar.position();
+            if pos.is_multiple_of(step) || pos == len {
+                let _ = writeln!(self.out.lock().unwrap(), "{}: {}/{}", self.label, pos, len);
+            }
+        }
+        if let Some(json) = &self.json {
+            json.report(self.bar.position(), self.bar.length().unwrap_or(0));
+        }
+    }
+
+    /// Prints a message without disturbing the progress bar
+    pub fn println(&self, message: String) {
+        match self.mode {
+            ProgressMode::Bar => self.bar.println(message),
+            ProgressMode::Plain | ProgressMode::Jsonl | ProgressMode::Off => {
+                println!("{}", message)
+            }
+        }
+    }
+
+    pub fn finish_with_message(&self, message: &'static str) {
+        self.bar.finish_with_message(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = "{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len}";
+
+    /// Runs a phase of `len` items in the given mode and returns what it
+    /// wrote
+    fn run_phase(mode: ProgressMode, len: u64) -> String {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let progress = Progress::new(len, TEMPLATE, mode, "Embedding").with_output(out.clone());
+        for _ in 0..len {
+            progress.inc(1);
+        }
+        progress.finish_with_message("done");
+        let written = out.lock().unwrap().clone();
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn quiet_and_plain_progress_write_no_bar_control_characters() {
+        assert_eq!(
+            ProgressMode::detect(true, ProgressFormat::Bar),
+            ProgressMode::Off
+        );
+        assert_eq!(
+            ProgressMode::detect(false, ProgressFormat::Plain),
+            ProgressMode::Plain
+        );
+
+        assert_eq!(run_phase(ProgressMode::Off, 20), "");
+        let plain = run_phase(ProgressMode::Plain, 20);
+        assert!(!plain.contains(['\x1b', '\r']), "{:?}", plain);
+        let lines: Vec<&str> = plain.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "Embedding: 2/20");
+        assert_eq!(lines[9], "Embedding: 20/20");
+    }
+
+    #[test]
+    fn json_progress_lines_parse() {
+        let written = run_phase(ProgressMode::Jsonl, 20);
+        assert!(!written.contains(['\x1b', '\r']), "{:?}", written);
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // The first item is reported at once, the last once the phase is done
+        assert!(lines.len() >= 2);
+        assert_eq!(lines[0]["phase"], "embedding");
+        assert_eq!(lines[0]["done"], 1);
+        let last = lines.last().unwrap();
+        assert_eq!((&last["done"], &last["total"]), (&json!(20), &json!(20)));
+        assert!(last["rate"].is_number());
+    }
+}
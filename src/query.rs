@@ -0,0 +1,1359 @@
+use clap::Parser;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::arango::{search_view_name, ArangoArgs, ArangoClient, META_KEY, NAME_ANALYZER};
+use crate::embedding_common::{
+    cosine_similarity, create_embedder, with_instruction, EmbedThreads, EmbeddingBackend,
+    AUTO_MODEL,
+};
+use crate::src_codec::document_src;
+
+/// Program to query the codebase using semantic search
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+pub struct QueryArgs {
+    /// The query to search for. Given several times, the further ones are
+    /// paraphrases whose results are merged by reciprocal rank fusion.
+    #[clap(short, long, required_unless_present_any = ["queries_file", "like_key", "name_search", "info"])]
+    query: Vec<String>,
+
+    /// Expand the query with paraphrases generated by this command. It gets
+    /// `{"query"}` as JSON on stdin and prints a JSON array of strings. If
+    /// it fails, only the given queries are searched.
+    #[clap(long, requires = "query")]
+    expand_cmd: Option<String>,
+
+    /// Constant k of the reciprocal rank fusion of query variants: every
+    /// result scores the sum of 1 / (k + rank) over the variants
+    #[clap(long, default_value_t = 60.0)]
+    rrf_k: f64,
+
+    /// Look up chunks whose name has a word starting with this text, or
+    /// whose source contains it, in the search view created by the import
+    /// with `--create-search-view`, instead of searching semantically
+    #[clap(long, conflicts_with_all = ["query", "queries_file", "like_key"])]
+    name_search: Option<String>,
+
+    /// Print what the collection holds instead of searching: the number of
+    /// documents, the embedding models and dimensions they were imported
+    /// with, the vector indexes and whether the search view exists
+    #[clap(long, conflicts_with_all = ["query", "queries_file", "like_key", "name_search"])]
+    info: bool,
+
+    /// Search for chunks similar to the stored chunk with this document key,
+    /// using its vector instead of embedding a query
+    #[clap(long, conflicts_with_all = ["query", "queries_file"])]
+    like_key: Option<String>,
+
+    /// Run every line of this file as a separate query and write the results
+    /// as JSON lines, one object per query
+    #[clap(long, conflicts_with = "query")]
+    queries_file: Option<String>,
+
+    /// File to write the JSON lines of a batch query to (default: stdout)
+    #[clap(long, requires = "queries_file")]
+    output: Option<String>,
+
+    /// Name of the embedding model to use (e.g. "BAAI/bge-small-en-v1.5"),
+    /// "auto" picks jinaai/jina-embeddings-v2-base-code, which is trained
+    /// on code
+    #[clap(short, long, default_value = AUTO_MODEL)]
+    embedding_model: String,
+
+    #[clap(flatten)]
+    arango: ArangoArgs,
+
+    /// Fail instead of falling back to an exact (slow) cosine similarity in
+    /// AQL if the server does not support APPROX_NEAR_COSINE
+    #[clap(long)]
+    no_exact_fallback: bool,
+
+    /// Search the collection of this kind of symbol (function, method,
+    /// class, namespace or variable), as imported with --split-by-kind
+    #[clap(long, value_parser = ["function", "method", "class", "namespace", "variable"])]
+    kind: Option<String>,
+
+    /// The collection resolved from the collection options
+    #[clap(skip)]
+    collection: String,
+
+    /// Task instruction prepended to the query before embedding it, for
+    /// instruction-tuned models (e.g. "query: "), the counterpart of
+    /// `--embed-instruction`
+    #[clap(long, default_value = "")]
+    query_instruction: String,
+
+    /// Number of tokens after which the query is truncated (must match the
+    /// value used when embedding the chunks)
+    #[clap(long)]
+    tokenizer_truncation_length: Option<usize>,
+
+    /// Threads of ONNX Runtime embedding the query, as INTRA or
+    /// INTRA,INTER. Defaults to one intra-op thread per core.
+    #[clap(long, default_value_t = EmbedThreads::default())]
+    embed_threads: EmbedThreads,
+
+    /// Maximum number of results to return
+    #[clap(long, default_value_t = 10)]
+    limit: usize,
+
+    /// Weight of the keyword boost added to the vector score for query terms
+    /// found in the document name or source (0 disables hybrid scoring)
+    #[clap(long, default_value_t = 0.0)]
+    keyword_weight: f64,
+
+    /// Print the source code of each result
+    #[clap(long)]
+    show_src: bool,
+
+    /// Rerank the best vector matches by late-interaction (MaxSim) scoring
+    /// against their multi-vector embeddings
+    #[clap(long)]
+    max_sim: bool,
+
+    /// Blend the score with the similarity of the query to the comment above
+    /// each symbol (0 uses the code only, 1 the comment only), for chunks
+    /// imported with `--embed-comments`
+    #[clap(long, default_value_t = 0.0)]
+    comment_weight: f64,
+
+    /// Subtracted from the score of chunks taken from header files, to
+    /// prefer implementations over declarations
+    #[clap(long, default_value_t = 0.0)]
+    header_penalty: f64,
+
+    /// Leave out chunks of symbols clangd marked as deprecated
+    #[clap(long)]
+    exclude_deprecated: bool,
+
+    /// Rerank the best matches with this command (e.g. a cross-encoder). It
+    /// gets `{"query", "candidates": [{"key", "name", "score", "src"}]}` as
+    /// JSON on stdin and prints a JSON array of `{"key", "score"}` in the
+    /// final order. If it fails, the vector order is kept.
+    #[clap(long)]
+    rerank_cmd: Option<String>,
+
+    /// Add the parts each result's score is made of (vector, keyword,
+    /// MaxSim, comment, header and rerank scores) to the output
+    #[clap(long)]
+    explain: bool,
+
+    /// How to calibrate the printed scores: raw scores, scaled to 0..1
+    /// over the returned results, or mapped through a sigmoid. The raw
+    /// score is kept next to a calibrated one.
+    #[clap(long, value_enum, default_value = "raw")]
+    score_scale: ScoreScale,
+
+    /// Raw score the sigmoid maps to 0.5
+    #[clap(long, default_value_t = 0.5)]
+    sigmoid_center: f64,
+
+    /// Steepness of the sigmoid around its center
+    #[clap(long, default_value_t = 10.0)]
+    sigmoid_steepness: f64,
+
+    /// Order of the printed results. The best results by score are selected
+    /// either way, path and kind only reorder them.
+    #[clap(long, value_enum, default_value = "score")]
+    sort_by: SortBy,
+}
+
+/// How the scores of the results are calibrated before printing them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScoreScale {
+    /// The scores as computed
+    #[default]
+    Raw,
+    /// Linearly scaled so that the best result gets 1 and the worst 0
+    Minmax,
+    /// `1 / (1 + exp(-steepness * (score - center)))`
+    Sigmoid,
+}
+
+/// Order of the results in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortBy {
+    /// Best score first
+    #[default]
+    Score,
+    /// By source file, then by line within the file
+    Path,
+    /// By kind of symbol, best score first within a kind
+    Kind,
+}
+
+/// Extensions of C++ header files, as stored in the `source_ext` field
+pub const HEADER_EXTENSIONS: &[&str] = &["h", "hh", "hpp", "hxx"];
+
+/// Splits the query into lowercase keyword terms for the hybrid score
+fn keyword_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|t| t.len() >= 2)
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// Builds the AQL query. Without a keyword weight this is a plain vector
+/// search. Otherwise the best vector matches are rescored by adding the
+/// keyword score (1 per term found in the name, 0.5 per term found only in
+/// the source, averaged over all terms) times the keyword weight. Keyword
+/// matching does not see compressed sources.
+fn build_query(args: &QueryArgs, query: &str, query_embedding: &[f32], limit: usize) -> Value {
+    // Filtered before the limit, so that the limit counts the results kept
+    let filter = if args.exclude_deprecated {
+        "FILTER doc.deprecated != true "
+    } else {
+        ""
+    };
+    let terms = keyword_terms(query);
+    if args.keyword_weight == 0.0 || terms.is_empty() {
+        return json!({
+            "query": format!("FOR doc IN @@chunks {}LET score = APPROX_NEAR_COSINE(doc.v, @query) SORT score DESC LIMIT @limit RETURN {{doc, score}}", filter),
+            "bindVars": {
+                "@chunks": args.collection,
+                "query": query_embedding,
+                "limit": limit
+            }
+        });
+    }
+
+    json!({
+        "query": format!("FOR doc IN @@chunks {}\
+                  LET vector_score = APPROX_NEAR_COSINE(doc.v, @query) \
+                  SORT vector_score DESC LIMIT @candidates \
+                  LET keyword_score = SUM(FOR t IN @terms RETURN CONTAINS(LOWER(doc.name), t) ? 1 : (CONTAINS(LOWER(doc.src), t) ? 0.5 : 0)) / LENGTH(@terms) \
+                  LET score = vector_score + @keyword_weight * keyword_score \
+                  SORT score DESC LIMIT @limit \
+                  RETURN {{doc, score, vector_score, keyword_score}}", filter),
+        "bindVars": {
+            "@chunks": args.collection,
+            "query": query_embedding,
+            "terms": terms,
+            "keyword_weight": args.keyword_weight,
+            "candidates": limit * 10,
+            "limit": limit
+        }
+    })
+}
+
+/// Approximate vector similarity as used by `build_query`
+const APPROX_COSINE: &str = "APPROX_NEAR_COSINE(doc.v, @query)";
+
+/// Exact cosine similarity computed in AQL, for servers without vector
+/// index support
+const EXACT_COSINE: &str = "(SUM(FOR i IN 0..LENGTH(@query) - 1 RETURN doc.v[i] * @query[i]) \
+                            / (SQRT(SUM(FOR x IN doc.v RETURN x * x)) * SQRT(SUM(FOR x IN @query RETURN x * x))))";
+
+/// Checks whether a query failed because APPROX_NEAR_COSINE is unknown to
+/// the server (older versions or vector indexes not enabled)
+fn is_approx_unavailable(error: &str) -> bool {
+    error.contains("APPROX_NEAR_COSINE")
+        && (error.contains("unknown function") || error.contains("1540"))
+}
+
+/// Rewrites a query built by `build_query` to compute the cosine similarity
+/// exactly instead of using APPROX_NEAR_COSINE
+fn exact_fallback(query_body: &Value) -> Value {
+    let mut exact = query_body.clone();
+    if let Some(query) = query_body["query"].as_str() {
+        exact["query"] = json!(query.replace(APPROX_COSINE, EXACT_COSINE));
+    }
+    exact
+}
+
+/// Records a part of the score in the result's `explain` object, if
+/// scores are explained
+fn explain(item: &mut Value, part: &str, value: Value) {
+    if let Some(explain) = item.get_mut("explain").and_then(Value::as_object_mut) {
+        explain.insert(part.to_string(), value);
+    }
+}
+
+/// Late-interaction (ColBERT-style) MaxSim score: for every query vector the
+/// best cosine similarity among the document vectors, summed up
+fn max_sim(query: &[Vec<f32>], document: &[Vec<f32>]) -> f32 {
+    query
+        .iter()
+        .map(|q| {
+            document
+                .iter()
+                .map(|d| cosine_similarity(q, d))
+                .fold(0.0, f32::max)
+        })
+        .sum()
+}
+
+/// For every query vector the index of the document vector it matches best
+/// in the MaxSim score, to explain a result
+fn max_sim_matches(query: &[Vec<f32>], document: &[Vec<f32>]) -> Vec<usize> {
+    query
+        .iter()
+        .map(|q| {
+            document
+                .iter()
+                .map(|d| cosine_similarity(q, d))
+                .enumerate()
+                .fold((0, f32::MIN), |best, (i, score)| {
+                    if score > best.1 {
+                        (i, score)
+                    } else {
+                        best
+                    }
+                })
+                .0
+        })
+        .collect()
+}
+
+/// Replaces the score of each result by the MaxSim score of the query
+/// against the document's multi-vector embedding, falling back to its single
+/// vector for documents without one
+fn rerank_max_sim(results: &mut [Value], query_embedding: &[Vec<f32>]) {
+    for item in results.iter_mut() {
+        let doc = &item["doc"];
+        let vectors: Vec<Vec<f32>> = serde_json::from_value(doc["mv"].clone())
+            .or_else(|_| serde_json::from_value(doc["v"].clone()).map(|v| vec![v]))
+            .unwrap_or_default();
+        let score = max_sim(query_embedding, &vectors);
+        let matches = max_sim_matches(query_embedding, &vectors);
+        item["score"] = json!(score);
+        explain(item, "max_sim", json!(score));
+        explain(item, "max_sim_windows", json!(matches));
+    }
+}
+
+/// Blends the score of each result with the similarity of the query to the
+/// document's comment embedding. Documents without one keep their score.
+fn blend_comment_score(results: &mut [Value], query_embedding: &[f32], weight: f64) {
+    for item in results.iter_mut() {
+        let Ok(comment_v) = serde_json::from_value::<Vec<f32>>(item["doc"]["comment_v"].clone())
+        else {
+            continue;
+        };
+        let score = item["score"].as_f64().unwrap_or(0.0);
+        let comment_score = cosine_similarity(query_embedding, &comment_v) as f64;
+        item["score"] = json!((1.0 - weight) * score + weight * comment_score);
+        explain(item, "comment_score", json!(comment_score));
+    }
+}
+
+/// Subtracts the penalty from the score of all results taken from headers
+fn apply_header_penalty(results: &mut [Value], penalty: f64) {
+    for item in results.iter_mut() {
+        let is_header = item["doc"]["source_ext"]
+            .as_str()
+            .is_some_and(|ext| HEADER_EXTENSIONS.contains(&ext));
+        if is_header {
+            let score = item["score"].as_f64().unwrap_or(0.0);
+            item["score"] = json!(score - penalty);
+            explain(item, "header_penalty", json!(penalty));
+        }
+    }
+}
+
+/// Replaces the score of each result by its calibrated score, keeping the
+/// original one as `raw_score`. Min-max scaling gives all results 1 if
+/// their scores are equal.
+fn calibrate_scores(results: &mut [Value], scale: ScoreScale, center: f64, steepness: f64) {
+    let scores: Vec<f64> = results
+        .iter()
+        .map(|item| item["score"].as_f64().unwrap_or(0.0))
+        .collect();
+    let min = scores.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    for (item, score) in results.iter_mut().zip(scores) {
+        let calibrated = match scale {
+            ScoreScale::Raw => return,
+            ScoreScale::Minmax if max > min => (score - min) / (max - min),
+            ScoreScale::Minmax => 1.0,
+            ScoreScale::Sigmoid => 1.0 / (1.0 + (-steepness * (score - center)).exp()),
+        };
+        item["raw_score"] = json!(score);
+        item["score"] = json!(calibrated);
+    }
+}
+
+/// Kind and first line of a result's chunk, as encoded in the chunk file
+/// name `NNN_name_kind_line.cpp`
+fn chunk_kind_and_line(item: &Value) -> (&str, usize) {
+    let stem = item["doc"]["chunk_file"]
+        .as_str()
+        .unwrap_or_default()
+        .trim_end_matches(".cpp");
+    let mut parts = stem.rsplitn(3, '_');
+    let line = parts.next().and_then(|line| line.parse().ok());
+    let kind = parts.next();
+    match (kind, line) {
+        (Some(kind), Some(line)) => (kind, line),
+        _ => ("", 0),
+    }
+}
+
+/// Reorders the results, which come sorted by score, for the output
+fn sort_results(results: &mut [Value], sort_by: SortBy) {
+    match sort_by {
+        SortBy::Score => {}
+        SortBy::Path => results.sort_by(|a, b| {
+            let a_file = a["doc"]["source_file"].as_str().unwrap_or_default();
+            let b_file = b["doc"]["source_file"].as_str().unwrap_or_default();
+            a_file
+                .cmp(b_file)
+                .then_with(|| chunk_kind_and_line(a).1.cmp(&chunk_kind_and_line(b).1))
+        }),
+        SortBy::Kind => {
+            results.sort_by(|a, b| chunk_kind_and_line(a).0.cmp(chunk_kind_and_line(b).0))
+        }
+    }
+}
+
+/// Runs a helper command with JSON on stdin and parses the JSON it prints.
+/// `what` names the command in errors.
+async fn run_json_cmd<T: serde::de::DeserializeOwned>(
+    command: &str,
+    what: &str,
+    input: &Value,
+) -> Result<T, Box<dyn Error>> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| format!("Empty {} command", what))?;
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start {} command '{}': {}", what, command, e))?;
+    // The input is written by a task of its own while the output is read,
+    // a command writing before it read all its input would block otherwise
+    let stdin = child.stdin.take();
+    let body = serde_json::to_vec(input)?;
+    let writer = tokio::spawn(async move {
+        match stdin {
+            Some(mut stdin) => stdin.write_all(&body).await,
+            None => Ok(()),
+        }
+    });
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(format!("The {} command exited with {}", what, output.status).into());
+    }
+    writer
+        .await?
+        .map_err(|e| format!("Failed to write the input of the {} command: {}", what, e))?;
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Invalid output of {} command: {}", what, e).into())
+}
+
+/// Runs the expand command on the query and returns the paraphrases it
+/// generated, leaving out blank ones and the query itself
+async fn run_expand_cmd(command: &str, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let paraphrases: Vec<String> =
+        run_json_cmd(command, "expand", &json!({ "query": query })).await?;
+    Ok(paraphrases
+        .into_iter()
+        .filter(|paraphrase| !paraphrase.trim().is_empty() && paraphrase != query)
+        .collect())
+}
+
+/// Merges the results of several query variants by reciprocal rank fusion:
+/// every document scores the sum of 1 / (k + rank) over the result lists it
+/// is in, ranks starting at 1. Keeps the best `limit` documents.
+fn reciprocal_rank_fusion(lists: Vec<Vec<Value>>, k: f64, limit: usize) -> Vec<Value> {
+    let mut fused: Vec<(f64, Value)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for list in lists {
+        for (rank, item) in list.into_iter().enumerate() {
+            let key = item["doc"]["_key"].as_str().unwrap_or_default().to_string();
+            let contribution = 1.0 / (k + rank as f64 + 1.0);
+            match positions.get(&key) {
+                Some(&position) => fused[position].0 += contribution,
+                None => {
+                    positions.insert(key, fused.len());
+                    fused.push((contribution, item));
+                }
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    fused.truncate(limit);
+    fused
+        .into_iter()
+        .map(|(score, mut item)| {
+            item["score"] = json!(score);
+            explain(&mut item, "rrf_score", json!(score));
+            item
+        })
+        .collect()
+}
+
+/// Runs the rerank command on the candidates and returns their keys and
+/// scores in the order it chose
+async fn run_rerank_cmd(
+    command: &str,
+    query: &str,
+    results: &[Value],
+) -> Result<Vec<(String, f64)>, Box<dyn Error>> {
+    let candidates = results
+        .iter()
+        .map(|item| {
+            let doc = &item["doc"];
+            Ok(json!({
+                "key": doc["_key"],
+                "name": doc["name"],
+                "score": item["score"],
+                "src": document_src(doc)?,
+            }))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let input = json!({ "query": query, "candidates": candidates });
+    let ranked: Vec<Value> = run_json_cmd(command, "rerank", &input).await?;
+    ranked
+        .iter()
+        .map(|entry| {
+            let key = entry["key"]
+                .as_str()
+                .ok_or("Rerank command returned an entry without key")?;
+            Ok((key.to_string(), entry["score"].as_f64().unwrap_or(0.0)))
+        })
+        .collect()
+}
+
+/// Orders the results as ranked by the rerank command, with its scores.
+/// Results it left out are dropped.
+fn apply_rerank(results: Vec<Value>, ranked: &[(String, f64)]) -> Vec<Value> {
+    let mut by_key: HashMap<String, Value> = results
+        .into_iter()
+        .filter_map(|item| Some((item["doc"]["_key"].as_str()?.to_string(), item)))
+        .collect();
+    ranked
+        .iter()
+        .filter_map(|(key, score)| {
+            let mut item = by_key.remove(key)?;
+            item["score"] = json!(score);
+            explain(&mut item, "rerank_score", json!(score));
+            Some(item)
+        })
+        .collect()
+}
+
+/// Reads the queries of a batch, one per line, skipping blank lines
+fn read_queries(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read queries file '{}': {}", path, e))?;
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Runs the search for one query and returns the best results, rescored
+/// and sorted
+async fn search(
+    args: &QueryArgs,
+    client: &ArangoClient,
+    query: &str,
+    query_embedding: &[Vec<f32>],
+) -> Result<Vec<Value>, Box<dyn Error>> {
+    // Prepare the AQL query, fetching more candidates if we rescore them
+    let fetch_limit = if args.max_sim
+        || args.header_penalty != 0.0
+        || args.comment_weight != 0.0
+        || args.rerank_cmd.is_some()
+    {
+        args.limit * 10
+    } else {
+        args.limit
+    };
+    // The seed document of a "more like this" search is its own best match
+    let fetch_limit = fetch_limit + usize::from(args.like_key.is_some());
+    let query_body = build_query(args, query, &query_embedding[0], fetch_limit);
+
+    // Send the query to ArangoDB and collect all result batches, computing
+    // the similarity exactly if the server has no vector search
+    let mut results = match client.query(&query_body).await {
+        Ok(results) => results,
+        Err(e) if !args.no_exact_fallback && is_approx_unavailable(&e.to_string()) => {
+            eprintln!(
+                "Warning: APPROX_NEAR_COSINE is not available on this server, \
+                 falling back to exact cosine similarity, which is much slower"
+            );
+            client.query(&exact_fallback(&query_body)).await?
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(like_key) = &args.like_key {
+        results.retain(|item| item["doc"]["_key"].as_str() != Some(like_key.as_str()));
+    }
+    if args.explain {
+        for item in results.iter_mut() {
+            let vector_score = item.get("vector_score").unwrap_or(&item["score"]).clone();
+            let mut parts = json!({ "vector_score": vector_score });
+            if let Some(keyword_score) = item.get("keyword_score") {
+                parts["keyword_score"] = keyword_score.clone();
+            }
+            item["explain"] = parts;
+        }
+    }
+
+    // Adjust the scores and keep the best results
+    if args.max_sim {
+        rerank_max_sim(&mut results, query_embedding);
+    }
+    if args.comment_weight != 0.0 {
+        blend_comment_score(&mut results, &query_embedding[0], args.comment_weight);
+    }
+    if args.header_penalty != 0.0 {
+        apply_header_penalty(&mut results, args.header_penalty);
+    }
+    results.sort_by(|a, b| {
+        let a = a["score"].as_f64().unwrap_or(f64::MIN);
+        let b = b["score"].as_f64().unwrap_or(f64::MIN);
+        b.total_cmp(&a)
+    });
+    if let Some(command) = &args.rerank_cmd {
+        match run_rerank_cmd(command, query, &results).await {
+            Ok(ranked) => results = apply_rerank(results, &ranked),
+            Err(e) => eprintln!("Warning: {}, keeping the vector order", e),
+        }
+    }
+    results.truncate(args.limit);
+    for item in results.iter_mut() {
+        let score = item["score"].clone();
+        explain(item, "score", score);
+    }
+    Ok(results)
+}
+
+/// Calibrates the scores of the final results and puts them in the order
+/// of the output
+fn finish_results(args: &QueryArgs, results: &mut [Value]) {
+    calibrate_scores(
+        results,
+        args.score_scale,
+        args.sigmoid_center,
+        args.sigmoid_steepness,
+    );
+    sort_results(results, args.sort_by);
+}
+
+/// Converts a search result to the record written in batch mode, leaving
+/// out the vectors
+fn result_record(args: &QueryArgs, item: &Value) -> Result<Value, Box<dyn Error>> {
+    let doc = &item["doc"];
+    let mut record = json!({
+        "key": doc["_key"],
+        "name": doc["name"],
+        "score": item["score"],
+        "chunk_file": doc["chunk_file"],
+        "source_file": doc["source_file"],
+        "prev_chunk": doc["prev_chunk"],
+        "next_chunk": doc["next_chunk"],
+    });
+    if let Some(raw_score) = item.get("raw_score") {
+        record["raw_score"] = raw_score.clone();
+    }
+    if args.show_src {
+        record["src"] = json!(document_src(doc)?);
+        if doc["src_truncated"].as_bool() == Some(true) {
+            record["src_truncated"] = json!(true);
+            record["src_length"] = doc["src_length"].clone();
+        }
+    }
+    if let Some(explain) = item.get("explain") {
+        record["explain"] = explain.clone();
+    }
+    Ok(record)
+}
+
+/// Embeds all queries of the file in one batch, runs them one after the
+/// other and writes one JSON line per query
+async fn run_batch(
+    args: &QueryArgs,
+    client: &ArangoClient,
+    embedder: &EmbeddingBackend,
+    queries_file: &str,
+) -> Result<(), Box<dyn Error>> {
+    let queries = read_queries(queries_file)?;
+    let instructed = queries
+        .iter()
+        .map(|query| with_instruction(&args.query_instruction, query))
+        .collect();
+    let embeddings = embedder.embed(instructed, None)?;
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| {
+                format!("Failed to create output file '{}': {}", path, e)
+            })?))
+        }
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    for (query, embedding) in queries.iter().zip(embeddings) {
+        let mut results = search(args, client, query, &[embedding]).await?;
+        finish_results(args, &mut results);
+        let records = results
+            .iter()
+            .map(|item| result_record(args, item))
+            .collect::<Result<Vec<_>, _>>()?;
+        writeln!(out, "{}", json!({ "query": query, "results": records }))?;
+    }
+    out.flush()?;
+
+    if let Some(path) = &args.output {
+        eprintln!("Wrote results of {} queries to {}", queries.len(), path);
+    }
+    Ok(())
+}
+
+/// Builds the AQL query of the name search. Names match if one of their
+/// words starts with the text (the analyzer indexes all prefixes), sources
+/// if they contain it as a phrase; name matches weigh more.
+fn build_name_search(args: &QueryArgs, text: &str) -> Value {
+    json!({
+        "query": "FOR doc IN @@view \
+                  SEARCH BOOST(ANALYZER(doc.name == LOWER(@text), @analyzer), 2) \
+                      OR ANALYZER(PHRASE(doc.src, @text), \"text_en\") \
+                  LET score = BM25(doc) \
+                  SORT score DESC LIMIT @limit \
+                  RETURN {doc, score}",
+        "bindVars": {
+            "@view": search_view_name(&args.collection),
+            "text": text,
+            "analyzer": NAME_ANALYZER,
+            "limit": args.limit
+        }
+    })
+}
+
+/// Writes the summary of the collection for `--info`. Documents imported
+/// without the embedding record (e.g. streamed) have no model.
+async fn print_info(
+    args: &QueryArgs,
+    client: &ArangoClient,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let summary = client
+        .query(&json!({
+            "query": "LET meta = FIRST(FOR doc IN @@chunks FILTER doc._key == @meta RETURN doc) \
+                      RETURN { \
+                          count: LENGTH(@@chunks) - (meta == null ? 0 : 1), \
+                          embeddings: (FOR doc IN @@chunks FILTER doc._key != @meta \
+                              COLLECT model = doc.model, dimension = LENGTH(doc.v), \
+                                      instruction = doc.instruction WITH COUNT INTO documents \
+                              RETURN {model, dimension, instruction, documents}), \
+                          expires_at: meta.expires_at \
+                      }",
+            "bindVars": { "@chunks": args.collection, "meta": META_KEY }
+        }))
+        .await
+        .map_err(|e| format!("Failed to read collection {}: {}", args.collection, e))?;
+    let summary = summary.first().cloned().unwrap_or_default();
+
+    writeln!(out, "Collection: {}", args.collection)?;
+    writeln!(out, "Documents: {}", summary["count"].as_u64().unwrap_or(0))?;
+    if let Some(expires_at) = summary["expires_at"].as_str() {
+        writeln!(out, "Expires: {}", expires_at)?;
+    }
+    writeln!(out, "Embeddings:")?;
+    for group in summary["embeddings"].as_array().into_iter().flatten() {
+        let mut line = format!(
+            "  {}, dimension {}",
+            group["model"].as_str().unwrap_or("unknown model"),
+            group["dimension"].as_u64().unwrap_or(0)
+        );
+        if let Some(instruction) = group["instruction"].as_str() {
+            line.push_str(&format!(", instruction {:?}", instruction));
+        }
+        writeln!(
+            out,
+            "{}: {} documents",
+            line,
+            group["documents"].as_u64().unwrap_or(0)
+        )?;
+    }
+
+    let response = client
+        .send(|client, url| {
+            client
+                .get(format!("{}/_api/index", url))
+                .query(&[("collection", &args.collection)])
+        })
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to read the indexes: {}", response.text().await?).into());
+    }
+    let indexes: Value = response.json().await?;
+    let vector_indexes: Vec<&Value> = indexes["indexes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|index| index["type"] == "vector")
+        .collect();
+    if vector_indexes.is_empty() {
+        writeln!(
+            out,
+            "Vector index: none, queries compute the similarity exactly"
+        )?;
+    }
+    for index in vector_indexes {
+        writeln!(
+            out,
+            "Vector index: {} (dimension {}, metric {})",
+            index["id"].as_str().unwrap_or_default(),
+            index["params"]["dimension"],
+            index["params"]["metric"].as_str().unwrap_or("unknown")
+        )?;
+    }
+
+    let view = search_view_name(&args.collection);
+    let response = client
+        .send(|client, url| client.get(format!("{}/_api/view/{}", url, view)))
+        .await?;
+    let status = if response.status().is_success() {
+        "present"
+    } else {
+        "missing, import with --create-search-view for --name-search"
+    };
+    writeln!(out, "Search view {}: {}", view, status)?;
+    Ok(())
+}
+
+/// Runs the search described by the arguments and prints the results
+pub async fn run(mut args: QueryArgs) -> Result<(), Box<dyn Error>> {
+    args.collection = args.arango.collection(None)?;
+    if let Some(kind) = &args.kind {
+        args.collection = format!("{}_{}", args.collection, kind);
+    }
+    let client = args.arango.client();
+
+    if args.info {
+        return print_info(&args, &client, &mut io::stdout()).await;
+    }
+
+    if let Some(text) = &args.name_search {
+        let mut results = client.query(&build_name_search(&args, text)).await?;
+        finish_results(&args, &mut results);
+        return print_results(&args, &results);
+    }
+
+    let (queries, query_embeddings) = if let Some(like_key) = &args.like_key {
+        // Take the vector of the seed document
+        let doc = client
+            .get_document(&args.collection, like_key)
+            .await?
+            .ok_or_else(|| format!("No document with key '{}'", like_key))?;
+        let v: Vec<f32> = serde_json::from_value(doc["v"].clone())
+            .map_err(|e| format!("Document '{}' has no valid vector: {}", like_key, e))?;
+        println!(
+            "Searching for chunks like {}",
+            doc["name"].as_str().unwrap_or(like_key)
+        );
+        (vec![String::new()], vec![v])
+    } else {
+        // Create the embedder
+        let embedder = create_embedder(
+            &args.embedding_model,
+            args.tokenizer_truncation_length,
+            args.embed_threads,
+        )?;
+
+        if let Some(queries_file) = &args.queries_file {
+            return run_batch(&args, &client, &embedder, queries_file).await;
+        }
+        let mut queries = args.query.clone();
+        if let (Some(command), Some(query)) = (&args.expand_cmd, queries.first()) {
+            match run_expand_cmd(command, query).await {
+                Ok(paraphrases) => queries.extend(paraphrases),
+                Err(e) => eprintln!("Warning: {}, searching without expansion", e),
+            }
+        }
+
+        // Generate embeddings for the query and its variants
+        let query_embeddings = embedder.embed(
+            queries
+                .iter()
+                .map(|query| with_instruction(&args.query_instruction, query))
+                .collect(),
+            None,
+        )?;
+        println!(
+            "Generated embedding for query with dimension: {}",
+            query_embeddings[0].len()
+        );
+        if queries.len() > 1 {
+            println!("Searching for {} query variants", queries.len());
+        }
+        (queries, query_embeddings)
+    };
+
+    // Every variant is searched on its own, their results are fused
+    let mut lists = Vec::with_capacity(queries.len());
+    for (query, embedding) in queries.iter().zip(query_embeddings) {
+        lists.push(search(&args, &client, query, &[embedding]).await?);
+    }
+    let mut results = if lists.len() == 1 {
+        lists.remove(0)
+    } else {
+        reciprocal_rank_fusion(lists, args.rrf_k, args.limit)
+    };
+    finish_results(&args, &mut results);
+    print_results(&args, &results)
+}
+
+/// Prints the results with their scores and neighbors
+fn print_results(args: &QueryArgs, results: &[Value]) -> Result<(), Box<dyn Error>> {
+    if !results.is_empty() {
+        println!("\nSearch Results:");
+        println!("---------------");
+        for (i, item) in results.iter().enumerate() {
+            let doc = &item["doc"];
+            let score = item["score"].as_f64().unwrap();
+            let name = doc["name"].as_str().unwrap_or("Unknown");
+            match item["raw_score"].as_f64() {
+                Some(raw_score) => println!(
+                    "{}. {} (Score: {:.4}, raw: {:.4})",
+                    i + 1,
+                    name,
+                    score,
+                    raw_score
+                ),
+                None => println!("{}. {} (Score: {:.4})", i + 1, name, score),
+            }
+            let neighbors: Vec<&str> = [&doc["prev_chunk"], &doc["next_chunk"]]
+                .iter()
+                .filter_map(|neighbor| neighbor.as_str())
+                .collect();
+            if !neighbors.is_empty() {
+                println!("   See also: {}", neighbors.join(", "));
+            }
+            if let Some(explain) = item.get("explain") {
+                println!("   Explain: {}", explain);
+            }
+            if args.show_src {
+                println!("{}\n", document_src(doc)?);
+                if doc["src_truncated"].as_bool() == Some(true) {
+                    println!(
+                        "   [Source truncated, {} characters in total, see {}]\n",
+                        doc["src_length"],
+                        doc["source_file"].as_str().unwrap_or("the source file")
+                    );
+                }
+            }
+        }
+    } else {
+        println!("No results found");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arango_mock::{accept_all, MockArango};
+    use crate::embedding_common::enable_test_embedder;
+    use std::time::Duration;
+
+    /// Parses the arguments of a query of the collection `chunks`
+    fn query_args(extra: &[&str]) -> QueryArgs {
+        let command = [
+            "query",
+            "--arango-endpoint",
+            "http://localhost:8529",
+            "--arango-username",
+            "root",
+            "--arango-password",
+            "",
+            "--arango-database",
+            "test",
+            "--arango-collection",
+            "chunks",
+        ];
+        let mut args = QueryArgs::try_parse_from(command.iter().chain(extra)).unwrap();
+        args.collection = "chunks".to_string();
+        args
+    }
+
+    /// Parses the arguments of a query of the collection `chunks` served
+    /// by the mock
+    fn mock_query_args(mock: &MockArango, extra: &[&str]) -> QueryArgs {
+        let command = std::iter::once("query".to_string())
+            .chain(mock.args("chunks"))
+            .chain(extra.iter().map(|arg| arg.to_string()));
+        let mut args = QueryArgs::try_parse_from(command).unwrap();
+        args.collection = "chunks".to_string();
+        args
+    }
+
+    #[test]
+    fn deprecated_chunks_are_filtered_before_the_limit() {
+        for keywords in [false, true] {
+            let mut extra = vec!["--query", "parse the config", "--exclude-deprecated"];
+            if keywords {
+                extra.extend(["--keyword-weight", "0.5"]);
+            }
+            let args = query_args(&extra);
+            let query_body = build_query(&args, "parse the config", &[1.0, 0.0], 5);
+            let query = query_body["query"].as_str().unwrap();
+            let filter = query.find("FILTER doc.deprecated != true").expect(query);
+            assert!(filter < query.find("LIMIT").unwrap(), "{}", query);
+
+            let exact = exact_fallback(&query_body);
+            assert!(exact["query"]
+                .as_str()
+                .unwrap()
+                .contains("FILTER doc.deprecated != true"));
+        }
+    }
+
+    #[tokio::test]
+    async fn json_cmd_output_is_read_while_writing_its_input() {
+        // More than fits into the pipe buffers, echoed back by cat
+        let input = json!({ "src": "x".repeat(4 * 1024 * 1024) });
+        let output: Value = tokio::time::timeout(
+            Duration::from_secs(60),
+            run_json_cmd("cat", "rerank", &input),
+        )
+        .await
+        .expect("the command deadlocked")
+        .unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn name_search_uses_the_name_analyzer() {
+        let args = query_args(&["--name-search", "parse", "--limit", "3"]);
+        let body = build_name_search(&args, "parse");
+        let query = body["query"].as_str().unwrap();
+        assert!(
+            query.contains("ANALYZER(doc.name == LOWER(@text), @analyzer)"),
+            "{}",
+            query
+        );
+        assert_eq!(body["bindVars"]["analyzer"], NAME_ANALYZER);
+        assert_eq!(body["bindVars"]["@view"], search_view_name("chunks"));
+        assert_eq!(body["bindVars"]["text"], "parse");
+        assert_eq!(body["bindVars"]["limit"], 3);
+    }
+
+    #[tokio::test]
+    async fn servers_without_vector_search_get_the_exact_query() {
+        let mock = MockArango::start(|request| {
+            let query = request.json()["query"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            if query.contains("APPROX_NEAR_COSINE") {
+                let error = json!({
+                    "error": true,
+                    "code": 400,
+                    "errorNum": 1540,
+                    "errorMessage": "usage of unknown function 'APPROX_NEAR_COSINE()'",
+                });
+                (400, error)
+            } else if request.path == "/_api/cursor" {
+                let doc = json!({ "_key": "add", "name": "add", "v": [1.0, 0.0] });
+                (
+                    201,
+                    json!({ "result": [{ "doc": doc, "score": 1.0 }], "hasMore": false }),
+                )
+            } else {
+                accept_all(request)
+            }
+        })
+        .await;
+        let query_embedding = [vec![1.0, 0.0]];
+
+        let args = mock_query_args(&mock, &["--query", "add"]);
+        let client = args.arango.client();
+        let results = search(&args, &client, "add", &query_embedding)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["doc"]["_key"], "add");
+        let queries: Vec<String> = mock
+            .requests()
+            .iter()
+            .map(|request| request.json()["query"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(queries.len(), 2);
+        assert!(queries[0].contains(APPROX_COSINE), "{}", queries[0]);
+        assert!(!queries[1].contains("APPROX_NEAR_COSINE"), "{}", queries[1]);
+        assert!(queries[1].contains(EXACT_COSINE), "{}", queries[1]);
+
+        // Unless the fallback is disabled
+        let args = mock_query_args(&mock, &["--query", "add", "--no-exact-fallback"]);
+        let error = search(&args, &client, "add", &query_embedding)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown function"), "{}", error);
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn keyword_weight_blends_the_keyword_score_into_the_query() {
+        let args = query_args(&["--query", "parse_config file", "--keyword-weight", "0.3"]);
+        let body = build_query(&args, "parse_config file", &[0.5, 0.5], 5);
+        let query = body["query"].as_str().unwrap();
+        assert!(
+            query.contains("LET vector_score = APPROX_NEAR_COSINE(doc.v, @query)"),
+            "{}",
+            query
+        );
+        assert!(query.contains("CONTAINS(LOWER(doc.name), t)"), "{}", query);
+        assert!(
+            query.contains("LET score = vector_score + @keyword_weight * keyword_score"),
+            "{}",
+            query
+        );
+        let bind_vars = &body["bindVars"];
+        assert_eq!(bind_vars["keyword_weight"], 0.3);
+        assert_eq!(bind_vars["terms"], json!(["parse_config", "file"]));
+        assert_eq!(bind_vars["candidates"], 50);
+        assert_eq!(bind_vars["limit"], 5);
+
+        // Without a weight it is a plain vector search
+        let args = query_args(&["--query", "parse_config file"]);
+        let body = build_query(&args, "parse_config file", &[0.5, 0.5], 5);
+        assert!(!body["query"].as_str().unwrap().contains("keyword_score"));
+        assert!(body["bindVars"].get("keyword_weight").is_none());
+    }
+
+    #[test]
+    fn max_sim_sums_the_best_match_of_every_query_vector() {
+        let query = [vec![1.0, 0.0], vec![0.0, 1.0]];
+        let document = [vec![1.0, 0.0], vec![0.6, 0.8], vec![-1.0, 0.0]];
+        // 1.0 for the first query vector, 0.8 for the second
+        assert!((max_sim(&query, &document) - 1.8).abs() < 1e-6);
+        assert_eq!(max_sim_matches(&query, &document), [0, 1]);
+        // Opposite vectors do not count against the score
+        assert_eq!(max_sim(&query, &[vec![-1.0, -1.0]]), 0.0);
+        assert_eq!(max_sim(&query, &[]), 0.0);
+    }
+
+    #[test]
+    fn header_penalty_lowers_only_header_results() {
+        let result = |key: &str, source_ext: &str| json!({ "doc": { "_key": key, "source_ext": source_ext }, "score": 0.9, "explain": {} });
+        let mut results = vec![
+            result("decl", "hpp"),
+            result("impl", "cpp"),
+            result("h", "h"),
+        ];
+        apply_header_penalty(&mut results, 0.25);
+        let scores: Vec<f64> = results
+            .iter()
+            .map(|item| item["score"].as_f64().unwrap())
+            .collect();
+        assert_eq!(scores, [0.65, 0.9, 0.65]);
+        assert_eq!(results[0]["explain"]["header_penalty"], 0.25);
+        assert!(results[1]["explain"].get("header_penalty").is_none());
+    }
+
+    #[tokio::test]
+    async fn like_key_searches_with_the_seed_vector_and_leaves_out_the_seed() {
+        let mock = MockArango::start(|request| match request.path.as_str() {
+            "/_api/document/chunks/seed" => (
+                200,
+                json!({ "_key": "seed", "name": "parse", "v": [0.25, 0.75] }),
+            ),
+            "/_api/cursor" => {
+                let result = |key: &str, score: f64| {
+                    json!({ "doc": { "_key": key, "name": key }, "score": score })
+                };
+                let results = [result("seed", 1.0), result("lex", 0.8), result("read", 0.7)];
+                (201, json!({ "result": results, "hasMore": false }))
+            }
+            _ => accept_all(request),
+        })
+        .await;
+
+        let args = mock_query_args(&mock, &["--like-key", "seed", "--limit", "2"]);
+        run(args).await.unwrap();
+        let requests = mock.requests();
+        let search = requests
+            .iter()
+            .find(|request| request.path == "/_api/cursor")
+            .unwrap()
+            .json();
+        assert_eq!(search["bindVars"]["query"], json!([0.25, 0.75]));
+        // One more, as the seed is its own best match
+        assert_eq!(search["bindVars"]["limit"], 3);
+
+        let args = mock_query_args(&mock, &["--like-key", "seed", "--limit", "2"]);
+        let client = args.arango.client();
+        let results = super::search(&args, &client, "", &[vec![0.25, 0.75]])
+            .await
+            .unwrap();
+        let keys: Vec<&str> = results
+            .iter()
+            .map(|item| item["doc"]["_key"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, ["lex", "read"]);
+    }
+
+    #[test]
+    fn minmax_calibration_scales_the_scores_from_zero_to_one() {
+        let mut results: Vec<Value> = [0.8, 0.5, 0.2]
+            .iter()
+            .map(|score| json!({ "doc": {}, "score": score }))
+            .collect();
+        calibrate_scores(&mut results, ScoreScale::Minmax, 0.0, 1.0);
+        let scores: Vec<f64> = results
+            .iter()
+            .map(|item| item["score"].as_f64().unwrap())
+            .collect();
+        assert_eq!(scores[0], 1.0);
+        assert!((scores[1] - 0.5).abs() < 1e-9);
+        assert_eq!(scores[2], 0.0);
+        assert_eq!(results[0]["raw_score"], 0.8);
+
+        let mut equal = vec![json!({ "score": 0.4 }), json!({ "score": 0.4 })];
+        calibrate_scores(&mut equal, ScoreScale::Minmax, 0.0, 1.0);
+        assert!(equal.iter().all(|item| item["score"] == 1.0));
+    }
+
+    #[test]
+    fn rank_fusion_favours_documents_found_by_both_variants() {
+        let list = |keys: &[&str]| -> Vec<Value> {
+            keys.iter()
+                .map(|key| json!({ "doc": { "_key": key }, "score": 0.5 }))
+                .collect()
+        };
+        let lists = vec![list(&["a", "b", "c"]), list(&["c", "d", "a"])];
+        let fused = reciprocal_rank_fusion(lists, 60.0, 3);
+        let keys: Vec<&str> = fused
+            .iter()
+            .map(|item| item["doc"]["_key"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, ["a", "c", "b"]);
+        let expected = 1.0 / 61.0 + 1.0 / 63.0;
+        assert!((fused[0]["score"].as_f64().unwrap() - expected).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn info_shows_the_count_the_embeddings_and_the_indexes() {
+        let mock = MockArango::start(|request| match request.path.as_str() {
+            "/_api/cursor" => {
+                let summary = json!({
+                    "count": 42,
+                    "embeddings": [{
+                        "model": "test/deterministic-8",
+                        "dimension": 8,
+                        "instruction": null,
+                        "documents": 42
+                    }],
+                    "expires_at": "2026-12-31T00:00:00Z"
+                });
+                (201, json!({ "result": [summary], "hasMore": false }))
+            }
+            "/_api/index" => {
+                let index = json!({
+                    "id": "chunks/7",
+                    "type": "vector",
+                    "params": { "dimension": 8, "metric": "cosine" }
+                });
+                (200, json!({ "indexes": [{ "type": "primary" }, index] }))
+            }
+            "/_api/view/chunks_search" => (404, json!({ "error": true })),
+            _ => accept_all(request),
+        })
+        .await;
+
+        let args = mock_query_args(&mock, &["--info"]);
+        let client = args.arango.client();
+        let mut out = Vec::new();
+        print_info(&args, &client, &mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Collection: chunks",
+                "Documents: 42",
+                "Expires: 2026-12-31T00:00:00Z",
+                "Embeddings:",
+                "  test/deterministic-8, dimension 8: 42 documents",
+                "Vector index: chunks/7 (dimension 8, metric cosine)",
+                "Search view chunks_search: missing, import with --create-search-view for --name-search",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn explain_records_the_score_and_the_matching_windows() {
+        let mock = MockArango::start(|request| match request.path.as_str() {
+            "/_api/cursor" => {
+                let doc = json!({
+                    "_key": "parse",
+                    "name": "parse",
+                    "v": [1.0, 0.0],
+                    "mv": [[0.0, 1.0], [1.0, 0.0]]
+                });
+                let results = [json!({ "doc": doc, "score": 0.5 })];
+                (201, json!({ "result": results, "hasMore": false }))
+            }
+            _ => accept_all(request),
+        })
+        .await;
+
+        let args = mock_query_args(&mock, &["--query", "parse", "--explain"]);
+        let client = args.arango.client();
+        let results = super::search(&args, &client, "parse", &[vec![1.0, 0.0]])
+            .await
+            .unwrap();
+        let record = result_record(&args, &results[0]).unwrap();
+        assert_eq!(record["explain"]["score"], 0.5);
+        assert_eq!(record["explain"]["vector_score"], 0.5);
+
+        // Multi-vector documents also tell which window matched each query vector
+        let args = mock_query_args(&mock, &["--query", "parse", "--explain", "--max-sim"]);
+        let query = [vec![0.0, 1.0], vec![1.0, 0.0]];
+        let results = super::search(&args, &client, "parse", &query)
+            .await
+            .unwrap();
+        let record = result_record(&args, &results[0]).unwrap();
+        assert_eq!(record["explain"]["score"], record["score"]);
+        assert_eq!(record["explain"]["max_sim_windows"], json!([0, 1]));
+    }
+
+    #[tokio::test]
+    async fn query_instruction_is_prepended_to_the_embedded_query() {
+        enable_test_embedder();
+        let mock = MockArango::start(accept_all).await;
+        let args = mock_query_args(
+            &mock,
+            &[
+                "--query",
+                "parse the config",
+                "--query-instruction",
+                "query: ",
+                "--embedding-model",
+                "test/deterministic-8",
+            ],
+        );
+        run(args).await.unwrap();
+
+        let expected = EmbeddingBackend::Deterministic(8)
+            .embed(vec!["query: parse the config"], None)
+            .unwrap();
+        let requests = mock.requests();
+        let search = requests
+            .iter()
+            .find(|request| request.path == "/_api/cursor")
+            .unwrap()
+            .json();
+        assert_eq!(search["bindVars"]["query"], json!(expected[0]));
+    }
+}
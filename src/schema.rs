@@ -0,0 +1,175 @@
+use serde_json::{json, Value};
+
+use cppembedder::arango::META_KEY;
+
+use crate::src_codec::GZIP_ENCODING;
+
+/// JSON Schema of a chunk entry of the `_index.txt` files the chunker
+/// writes. The index is a text file with one `Key: value` line per
+/// property; the schema describes an entry as the object those lines
+/// amount to, keys in snake case and `true` as boolean. Keep in sync with
+/// `Chunker::chunk_files` and `link_declarations`.
+fn chunk_index_schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Chunk index entry",
+        "description": "A chunk as listed in the _index.txt of its source file's chunk directory",
+        "type": "object",
+        "properties": {
+            "chunk": { "type": "string", "description": "File name of the chunk in the chunk directory" },
+            "name": { "type": "string", "description": "Qualified name of the symbol" },
+            "kind": { "enum": ["function", "method", "class", "namespace", "variable"] },
+            "lines": {
+                "type": "string",
+                "pattern": "^[0-9]+-[0-9]+$",
+                "description": "First and last line of the chunk, 1-based and inclusive"
+            },
+            "context": {
+                "type": "string",
+                "pattern": "^[0-9]+-[0-9]+$",
+                "description": "Lines of the chunk including the context around the symbol, with --chunk-context-lines"
+            },
+            "parent": { "type": "string", "description": "Qualified name of the enclosing symbol" },
+            "signature": { "type": "string", "description": "Declaration of the symbol before its body, on one line" },
+            "deprecated": { "const": true },
+            "has_body": { "const": true, "description": "Set if the chunk is a definition with a body" },
+            "prev": { "type": "string", "description": "Name of the preceding chunk in the source file" },
+            "next": { "type": "string", "description": "Name of the following chunk in the source file" },
+            "declaration_of": { "type": "string", "description": "Path of the definition, relative to the output directory" },
+            "definition_of": { "type": "string", "description": "Path of the declaration, relative to the output directory" }
+        },
+        "required": ["chunk", "name", "kind", "lines"]
+    })
+}
+
+/// JSON Schema of the documents the importer writes to ArangoDB. Keep in
+/// sync with `Importer::document_json`.
+fn document_schema() -> Value {
+    let vector = json!({ "type": "array", "items": { "type": "number" } });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Chunk document",
+        "description": "A chunk with its embedding as imported into ArangoDB",
+        "type": "object",
+        "properties": {
+            "_key": { "type": "string", "description": "Derived from the chunk's path in the output directory" },
+            "name": { "type": "string", "description": "Qualified name of the symbol" },
+            "chunk_file": { "type": "string" },
+            "v": { "allOf": [vector], "description": "Missing if imported with --allow-missing-vectors before embedding" },
+            "src": { "type": "string", "description": "Source of the chunk, encoded as given by src_encoding" },
+            "src_encoding": { "const": GZIP_ENCODING },
+            "src_hash": { "type": "string", "description": "Hash of the uncompressed source" },
+            "src_truncated": { "const": true, "description": "Set if src holds only the start of the source" },
+            "src_length": { "type": "integer", "description": "Length of the whole source in characters" },
+            "source_file": { "type": "string" },
+            "source_path": { "type": "string", "description": "Path of the source file relative to the project" },
+            "source_ext": { "type": "string", "description": "Lowercase extension of the source file" },
+            "commit": { "type": "string" },
+            "blob_sha": { "type": "string" },
+            "mv": { "type": "array", "items": vector, "description": "One vector per window of lines" },
+            "comment_v": vector,
+            "prev_chunk": { "type": "string" },
+            "next_chunk": { "type": "string" },
+            "deprecated": { "const": true },
+            "chunker": { "const": "heuristic" },
+            "declaration_of": { "type": "string", "description": "Key of the definition's document" },
+            "definition_of": { "type": "string", "description": "Key of the declaration's document" },
+            "model": { "type": "string", "description": "Embedding model the vectors were computed with" },
+            "instruction": { "type": "string", "description": "Task instruction prepended before embedding" },
+            "embed_hash": { "type": "string", "description": "Hash of the model, instruction, template and path prefix the vectors were computed with" }
+        },
+        "required": ["_key", "name", "chunk_file", "src", "src_hash"]
+    })
+}
+
+/// JSON Schema of the edges `--edge-collection` imports. Keep in sync with
+/// `Importer::import_edges`.
+fn edge_schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Call edge",
+        "description": "A call from one chunk document to another",
+        "type": "object",
+        "properties": {
+            "_key": { "type": "string", "description": "Derived from the keys of the connected documents" },
+            "_from": { "type": "string", "description": "Document id of the caller" },
+            "_to": { "type": "string", "description": "Document id of the callee" },
+            "type": { "const": "calls" }
+        },
+        "required": ["_key", "_from", "_to", "type"]
+    })
+}
+
+/// JSON Schema of the `_meta` document of a collection imported with an
+/// expiry. Keep in sync with `Importer::stamp_expiry`.
+fn meta_schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Collection metadata",
+        "description": "The document with key _meta, stating when the collection expires",
+        "type": "object",
+        "properties": {
+            "_key": { "const": META_KEY },
+            "created_at": { "type": "string", "format": "date-time", "description": "Time of the first import" },
+            "expires_at": { "type": "string", "format": "date-time", "description": "Time after which `cleanup` drops the collection" }
+        },
+        "required": ["_key", "created_at", "expires_at"]
+    })
+}
+
+/// The schemas of the chunk index entries and of the ArangoDB documents
+/// and edges
+pub fn schemas() -> Value {
+    json!({
+        "chunk_index": chunk_index_schema(),
+        "document": document_schema(),
+        "edge": edge_schema(),
+        "meta": meta_schema()
+    })
+}
+
+/// Checks a value against the parts of JSON Schema the schemas use
+#[cfg(test)]
+pub fn check_schema(value: &Value, schema: &Value, path: &str) {
+    if let Some(expected) = schema.get("const") {
+        assert_eq!(value, expected, "{}", path);
+    }
+    if let Some(options) = schema["enum"].as_array() {
+        assert!(options.contains(value), "{}: {} not in enum", path, value);
+    }
+    for part in schema["allOf"].as_array().into_iter().flatten() {
+        check_schema(value, part, path);
+    }
+    match schema["type"].as_str() {
+        Some("object") => {
+            let object = value.as_object().expect(path);
+            for required in schema["required"].as_array().into_iter().flatten() {
+                let required = required.as_str().unwrap();
+                assert!(
+                    object.contains_key(required),
+                    "{}: missing {}",
+                    path,
+                    required
+                );
+            }
+            for (name, property) in object {
+                let property_schema = schema["properties"]
+                    .get(name)
+                    .unwrap_or_else(|| panic!("{}: {} is not in the schema", path, name));
+                check_schema(property, property_schema, &format!("{}.{}", path, name));
+            }
+        }
+        Some("array") => {
+            for (i, item) in value.as_array().expect(path).iter().enumerate() {
+                check_schema(item, &schema["items"], &format!("{}[{}]", path, i));
+            }
+        }
+        Some("string") => assert!(value.is_string(), "{}: not a string", path),
+        Some("number") => assert!(value.is_number(), "{}: not a number", path),
+        Some("integer") => {
+            assert!(value.is_u64() || value.is_i64(), "{}: not an integer", path)
+        }
+        Some(other) => panic!("{}: unexpected type {}", path, other),
+        None => {}
+    }
+}
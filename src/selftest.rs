@@ -0,0 +1,224 @@
+use serde_json::json;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cppembedder::arango::ArangoArgs;
+
+use crate::chunking::{Chunker, ChunkerOptions};
+use crate::cleanup::drop_collection;
+use crate::embedding::{Embedder, EmbeddingLayout};
+use crate::embedding_common::{cosine_similarity, enable_test_embedder};
+use crate::importer::{ImportOptions, Importer};
+use crate::{in_phase, Phase, PhaseError, SelfTestArgs};
+
+/// The bundled C++ project the self-test runs on
+const FIXTURE: &str = include_str!("../fixtures/selftest.cpp");
+
+/// The query of the self-test and the function it must rank first
+const QUERY: &str = "compute the CRC-32 checksum of a byte buffer";
+const EXPECTED_NAME: &str = "crc32";
+
+/// The embedder of `--mock`, which needs neither download nor ONNX Runtime
+const MOCK_MODEL: &str = "test/deterministic-256";
+
+/// Runs the whole pipeline on the bundled fixture in a temporary directory
+/// and checks that the known query finds the expected function. With `mock`
+/// the fixture is chunked heuristically, embedded by the deterministic test
+/// embedder and ranked locally instead of in ArangoDB.
+pub async fn run(args: SelfTestArgs) -> Result<(), PhaseError> {
+    let dir = std::env::temp_dir().join(format!("cppembedder-selftest-{}", std::process::id()));
+    let result = run_in(&dir, args).await;
+    let _ = fs::remove_dir_all(&dir);
+    if result.is_ok() {
+        println!(
+            "Self-test passed: '{}' ranked first for query \"{}\"",
+            EXPECTED_NAME, QUERY
+        );
+    }
+    result
+}
+
+async fn run_in(dir: &Path, args: SelfTestArgs) -> Result<(), PhaseError> {
+    let arango = if args.mock {
+        None
+    } else {
+        Some(arango_args(&args).map_err(in_phase(Phase::Config))?)
+    };
+    let embedding_model = if args.mock {
+        enable_test_embedder();
+        MOCK_MODEL
+    } else {
+        &args.embed.embedding_model
+    };
+
+    let project_dir = dir.join("project");
+    let output_dir = dir.join("chunks");
+    write_fixture(&project_dir).map_err(in_phase(Phase::Config))?;
+
+    let chunker = Chunker::builder()
+        .project_dir(project_dir.to_string_lossy())
+        .output_dir(output_dir.to_string_lossy())
+        .clangd_path(args.clangd_path)
+        .lsp_log_file(dir.join("lsp_communication.log").to_string_lossy())
+        .options(ChunkerOptions {
+            clangd_args: args.clangd_args.clone(),
+            no_clangd: args.mock || args.no_clangd,
+            ..Default::default()
+        })
+        .build()
+        .map_err(in_phase(Phase::Config))?;
+    chunker.run().map_err(in_phase(Phase::Chunking))?;
+
+    let embedder = Embedder::new(
+        output_dir.to_string_lossy().into_owned(),
+        embedding_model,
+        args.embed.min_chunk_chars,
+        args.embed.tokenizer_truncation_length,
+        args.embed.embed_threads,
+        None,
+        EmbeddingLayout::PerChunk,
+    )
+    .map_err(in_phase(Phase::Config))?;
+
+    let best = if let Some(arango) = arango {
+        embedder.run().map_err(in_phase(Phase::Embedding))?;
+        let query = embedder
+            .embed_content(QUERY.to_string())
+            .map_err(in_phase(Phase::Embedding))?;
+        rank_in_arango(&arango, &output_dir, query)
+            .await
+            .map_err(in_phase(Phase::Import))?
+    } else {
+        rank_locally(&embedder).map_err(in_phase(Phase::Embedding))?
+    };
+
+    if !best.contains(EXPECTED_NAME) {
+        return Err(PhaseError {
+            phase: Phase::Embedding,
+            error: format!(
+                "Self-test failed: expected '{}' to rank first for query \"{}\", got '{}'",
+                EXPECTED_NAME, QUERY, best
+            )
+            .into(),
+        });
+    }
+    Ok(())
+}
+
+/// The connection to ArangoDB, with a new collection of this self-test
+fn arango_args(args: &SelfTestArgs) -> Result<ArangoArgs, Box<dyn Error>> {
+    let required = |value: &Option<String>, name: &str| {
+        value
+            .clone()
+            .ok_or_else(|| format!("The self-test needs --{} unless --mock is given", name))
+    };
+    let created = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    Ok(ArangoArgs {
+        arango_endpoint: required(&args.arango_endpoint, "arango-endpoint")?,
+        arango_username: required(&args.arango_username, "arango-username")?,
+        arango_password: required(&args.arango_password, "arango-password")?,
+        arango_database: required(&args.arango_database, "arango-database")?,
+        arango_collection: Some(format!(
+            "cppembedder_selftest_{}_{}",
+            created,
+            std::process::id()
+        )),
+        collection_prefix: None,
+        project_name: None,
+    })
+}
+
+fn write_fixture(project_dir: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(project_dir).map_err(|e| {
+        format!(
+            "Failed to create self-test directory '{}': {}",
+            project_dir.display(),
+            e
+        )
+    })?;
+    fs::write(project_dir.join("selftest.cpp"), FIXTURE)
+        .map_err(|e| format!("Failed to write self-test fixture: {}", e))?;
+    Ok(())
+}
+
+/// Returns the file name of the chunk most similar to the query
+fn rank_locally(embedder: &Embedder) -> Result<String, Box<dyn Error>> {
+    let query = embedder.embed_content(QUERY.to_string())?;
+    let mut best: Option<(f32, PathBuf)> = None;
+    embedder.embed_each(|path, _, vectors| {
+        let Some((v, _)) = vectors else {
+            return Ok(());
+        };
+        let score = cosine_similarity(&query, &v);
+        if best
+            .as_ref()
+            .is_none_or(|(best_score, _)| score > *best_score)
+        {
+            best = Some((score, path));
+        }
+        Ok(())
+    })?;
+    let (_, path) = best.ok_or("Self-test produced no chunks")?;
+    Ok(path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Imports the chunks into the collection of the self-test, returns the
+/// name of the document most similar to the query and drops the collection
+/// again, also if the import or the query failed
+async fn rank_in_arango(
+    arango: &ArangoArgs,
+    output_dir: &Path,
+    query: Vec<f32>,
+) -> Result<String, Box<dyn Error>> {
+    let collection = arango.collection(None)?;
+    let client = arango.client();
+    let best = import_and_rank(arango, &collection, output_dir, query).await;
+    let dropped = drop_collection(&client, &collection).await;
+    let best = best?;
+    dropped?;
+    Ok(best)
+}
+
+async fn import_and_rank(
+    arango: &ArangoArgs,
+    collection: &str,
+    output_dir: &Path,
+    query: Vec<f32>,
+) -> Result<String, Box<dyn Error>> {
+    let importer = Importer::builder()
+        .output_dir(output_dir.to_string_lossy())
+        .endpoint(&arango.arango_endpoint)
+        .username(&arango.arango_username)
+        .password(&arango.arango_password)
+        .database(&arango.arango_database)
+        .collection(collection)
+        .options(ImportOptions::default())
+        .build()?;
+    importer.run().await?;
+
+    let results = arango
+        .client()
+        .query(&json!({
+            "query": "FOR doc IN @@chunks \
+                      LET score = COSINE_SIMILARITY(doc.v, @query) \
+                      SORT score DESC LIMIT 1 \
+                      RETURN doc.name",
+            "bindVars": {
+                "@chunks": collection,
+                "query": query
+            }
+        }))
+        .await?;
+    Ok(results
+        .first()
+        .and_then(|name| name.as_str())
+        .ok_or("Self-test found no imported documents")?
+        .to_string())
+}
@@ -0,0 +1,59 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::Value;
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// Value of the `src_encoding` field for gzip compressed, base64 encoded source
+pub const GZIP_ENCODING: &str = "gzip";
+
+/// Compresses source code with gzip and encodes it as base64 for storage in
+/// the `src` field of a document
+pub fn compress_src(src: &str) -> Result<String, Box<dyn Error>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(src.as_bytes())?;
+    Ok(STANDARD.encode(encoder.finish()?))
+}
+
+/// Reverses `compress_src`
+pub fn decompress_src(encoded: &str) -> Result<String, Box<dyn Error>> {
+    let compressed = STANDARD.decode(encoded)?;
+    let mut src = String::new();
+    GzDecoder::new(&compressed[..]).read_to_string(&mut src)?;
+    Ok(src)
+}
+
+/// Returns the source of a stored document, decompressing it if needed
+pub fn document_src(doc: &Value) -> Result<String, Box<dyn Error>> {
+    let src = doc["src"].as_str().unwrap_or_default();
+    match doc["src_encoding"].as_str() {
+        None => Ok(src.to_string()),
+        Some(GZIP_ENCODING) => decompress_src(src),
+        Some(encoding) => Err(format!("Unknown src encoding: {}", encoding).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn compressed_sources_round_trip() {
+        let src = "// Größe\nint size() {\n    return 1;\n}\n".repeat(20);
+        let compressed = compress_src(&src).unwrap();
+        assert!(compressed.len() < src.len());
+        assert_eq!(decompress_src(&compressed).unwrap(), src);
+
+        let stored = json!({ "src": compressed, "src_encoding": GZIP_ENCODING });
+        assert_eq!(document_src(&stored).unwrap(), src);
+        let plain = json!({ "src": "int x;" });
+        assert_eq!(document_src(&plain).unwrap(), "int x;");
+        let unknown = json!({ "src": "int x;", "src_encoding": "zstd" });
+        let error = document_src(&unknown).unwrap_err().to_string();
+        assert!(error.contains("zstd"), "{}", error);
+    }
+}